@@ -0,0 +1,75 @@
+// how fast the VM runs instructions with and without the predecode cache.
+// `cargo bench --bench interpreter`
+//
+// without the cache, every step decodes with disassm_one over a MemIterator,
+// the decoder the VM has always used. on a 2.1GHz Xeon that's about 22 MIPS
+// stepping and 43MHz running. step with the cache is about 130 MIPS, 6x, since
+// it still checks for halts, fire and interrupts every time. run skips those
+// checks while there's nothing for them to find and goes through the Fast
+// forms of the ops, and gets to about 590MHz, 14x
+extern crate dcpu16;
+
+use std::collections::BTreeSet;
+use std::time::Instant;
+use dcpu16::{assemble_file, VirtualMachine};
+
+// a sieve of the primes below 0x4000 over and over, which is mostly the
+// loads, stores, adds and conditionals long simulations spend their time in
+const SIEVE: &str = "
+:again
+    SET I, 0x8000
+    SET J, 0x4000
+:clear
+    SET [I], 1
+    ADD I, 1
+    SUB J, 1
+    IFN J, 0
+        SET PC, clear
+    SET A, 2
+:outer
+    SET B, A
+    MUL B, A
+    IFG B, 0x3fff
+        SET PC, again
+    IFE [A+0x8000], 0
+        SET PC, next
+:strike
+    SET [B+0x8000], 0
+    ADD B, A
+    IFL B, 0x4000
+        SET PC, strike
+:next
+    ADD A, 1
+    SET PC, outer
+";
+
+const STEPS: usize = 20_000_000;
+const CYCLES: usize = 50_000_000;
+
+// instructions a second, stepping one at a time
+fn stepping(predecode: bool, program: &Vec<u16>) -> f64 {
+    let mut vm = VirtualMachine::new().predecode(predecode).load_program(program, 0);
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        vm.step().expect("the sieve only has valid instructions");
+    }
+    STEPS as f64 / start.elapsed().as_secs_f64()
+}
+
+// cycles a second, running to a cycle limit
+fn running(predecode: bool, program: &Vec<u16>) -> f64 {
+    let mut vm = VirtualMachine::new().predecode(predecode).load_program(program, 0);
+    let start = Instant::now();
+    vm.run(&BTreeSet::new(), Some(CYCLES)).expect("the sieve only has valid instructions");
+    vm.get_cycles() as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let program = assemble_file(None, SIEVE, 0).expect("the sieve assembles");
+    let (decoding, cached) = (stepping(false, &program), stepping(true, &program));
+    println!("step, disassm_one every step      {:>8.1} MIPS", decoding / 1e6);
+    println!("step, predecoded                  {:>8.1} MIPS  {:.1}x", cached / 1e6, cached / decoding);
+    let (decoding, cached) = (running(false, &program), running(true, &program));
+    println!("run, disassm_one every step       {:>8.1} MHz", decoding / 1e6);
+    println!("run, predecoded                   {:>8.1} MHz  {:.1}x", cached / 1e6, cached / decoding);
+}
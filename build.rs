@@ -0,0 +1,67 @@
+// assembles the Forth ROM into OUT_DIR/rom.rs, along with the addresses of
+// the labels the host watches for. a build script can't use the crate it
+// builds, so the assembler's modules are compiled into it again. they're
+// linted with the crate, and most of them go unused here
+#![allow(dead_code, unused_imports, clippy::all)]
+
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+extern crate thiserror;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use assembly::{Block, Intermediate};
+use parser::parse;
+
+#[path = "src/register.rs"]
+mod register;
+#[path = "src/opcodes.rs"]
+mod opcodes;
+#[path = "src/diagnostics.rs"]
+mod diagnostics;
+#[path = "src/parser.rs"]
+mod parser;
+#[path = "src/image.rs"]
+mod image;
+#[path = "src/abi/mod.rs"]
+mod abi;
+#[path = "src/assembly/mod.rs"]
+mod assembly;
+
+mod virtual_machine {
+    pub use register::Register;
+}
+
+const SOURCE: &str = "src/forth/forth.dasm";
+
+fn main() {
+    if env::var_os("CARGO_FEATURE_FORTH").is_none() {
+        return;
+    }
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    let text = fs::read_to_string(SOURCE).unwrap();
+    let mut items: Vec<Intermediate> = parse(&text).unwrap_or_else(|e| panic!("{}: {}", SOURCE, e))
+        .into_iter().map(Intermediate::from).collect();
+    let layout = Block::new().intermediate(&mut items).layout(0);
+    let words = layout.words().unwrap_or_else(|e| panic!("{}: {}", SOURCE, e));
+
+    let mut out = format!("pub const WORDS: [u16; {}] = [\n", words.len());
+    for line in words.chunks(8) {
+        out += &format!("    {},\n", line.iter().map(|w| format!("{:#06x}", w)).collect::<Vec<_>>().join(", "));
+    }
+    out += "];\n";
+    let labels = [
+        ("PUTC", "putc", "about to print the character in A"),
+        ("ACCEPTED", "accepted", "a line has been read, so what's printed now is its output"),
+        ("PROMPT", "prompt", "the line's done, and it's about to print ok"),
+        ("ERROR", "error", "about to print an error"),
+        ("IDLE", "idle", "waiting for a key with none left"),
+    ];
+    for &(name, label, what) in labels.iter() {
+        let addr = layout.symbols.get(label).unwrap_or_else(|| panic!("{} has no label {}", SOURCE, label));
+        out += &format!("// {}\npub const {}: u16 = {:#06x};\n", what, name, addr);
+    }
+    fs::write(Path::new(&env::var("OUT_DIR").unwrap()).join("rom.rs"), out).unwrap();
+}
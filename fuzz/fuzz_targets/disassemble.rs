@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use dcpu16::{disassm_one, Disassemble};
+
+fuzz_target!(|data: &[u8]| {
+    let words: Vec<u16> = data.chunks_exact(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]))
+        .collect();
+
+    // every position, including ones that run out of next words
+    for start in 0..words.len() {
+        let mut itr = words[start + 1..].iter().peekable();
+        let _ = disassm_one(words[start], &mut itr);
+    }
+    let _ = words.disassm();
+});
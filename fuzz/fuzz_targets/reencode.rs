@@ -0,0 +1,51 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use dcpu16::{disassm_one, Assemble, Opcode, Operand};
+
+// a literal the assembler will always put inline in a
+fn is_short(op: &Opcode) -> bool {
+    let a = match *op {
+        Opcode::SET(_, ref a) | Opcode::ADD(_, ref a) | Opcode::SUB(_, ref a) |
+        Opcode::MUL(_, ref a) | Opcode::MLI(_, ref a) | Opcode::DIV(_, ref a) |
+        Opcode::DVI(_, ref a) | Opcode::MOD(_, ref a) | Opcode::MDI(_, ref a) |
+        Opcode::AND(_, ref a) | Opcode::BOR(_, ref a) | Opcode::XOR(_, ref a) |
+        Opcode::SHR(_, ref a) | Opcode::ASR(_, ref a) | Opcode::SHL(_, ref a) |
+        Opcode::IFB(_, ref a) | Opcode::IFC(_, ref a) | Opcode::IFE(_, ref a) |
+        Opcode::IFN(_, ref a) | Opcode::IFG(_, ref a) | Opcode::IFA(_, ref a) |
+        Opcode::IFL(_, ref a) | Opcode::IFU(_, ref a) | Opcode::ADX(_, ref a) |
+        Opcode::SBX(_, ref a) | Opcode::STI(_, ref a) | Opcode::STD(_, ref a) |
+        Opcode::JSR(ref a) | Opcode::INT(ref a) | Opcode::IAG(ref a) |
+        Opcode::IAS(ref a) | Opcode::RFI(ref a) | Opcode::IAQ(ref a) |
+        Opcode::HWN(ref a) | Opcode::HWQ(ref a) | Opcode::HWI(ref a) => a,
+    };
+    match *a {
+        Operand::Literal(n) => (n as i16) >= -1 && (n as i16) <= 30,
+        _ => false,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let words: Vec<u16> = data.chunks_exact(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]))
+        .collect();
+    if words.is_empty() {
+        return;
+    }
+
+    let mut itr = words[1..].iter().peekable();
+    let (op, count) = match disassm_one(words[0], &mut itr) {
+        Ok(decoded) => decoded,
+        Err(_) => return,
+    };
+    let original = &words[..count + 1];
+    let encoded = op.assem().expect("decoded instructions always assemble");
+
+    if encoded != original {
+        // the only allowed difference is a long literal that fits inline
+        assert!(is_short(&op), "{} {:04x?} became {:04x?}", op, original, encoded);
+        assert_eq!(encoded.len() + 1, original.len());
+        let mut itr = encoded[1..].iter().peekable();
+        let (again, _) = disassm_one(encoded[0], &mut itr).expect("re-encoded word decodes");
+        assert_eq!(again, op);
+    }
+});
@@ -0,0 +1,48 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use dcpu16::VirtualMachine;
+use dcpu16::hardware::Clock;
+
+const CYCLE_BUDGET: usize = 20_000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    // first word is where the program goes, so wrapping at 0xFFFF gets hit
+    let org = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let program: Vec<u16> = data[2..].chunks_exact(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]))
+        .collect();
+
+    // one VM runs from the predecode cache and the other decodes every
+    // instruction, and they have to agree
+    let mut vms = [true, false].map(|predecode| {
+        let mut vm = VirtualMachine::new().predecode(predecode).attach_hardware(Box::new(Clock::new())).unwrap();
+        for (i, word) in program.iter().enumerate() {
+            vm.get_ram()[(org + i) & 0xFFFF] = *word;
+        }
+        *vm.get_pc() = org as u16;
+        vm
+    });
+
+    let mut cycles = 0;
+    while cycles < CYCLE_BUDGET {
+        let [cached, decoded] = &mut vms;
+        let (c, d) = (cached.step(), decoded.step());
+        assert_eq!(c.as_ref().map_err(|e| e.to_string()), d.as_ref().map_err(|e| e.to_string()));
+        for vm in vms.iter_mut() {
+            vm.update_hardware();
+        }
+        let [cached, decoded] = &mut vms;
+        assert_eq!((*cached.get_pc(), *cached.get_sp(), *cached.get_ex(), cached.get_registers().to_vec()),
+                   (*decoded.get_pc(), *decoded.get_sp(), *decoded.get_ex(), decoded.get_registers().to_vec()));
+        match c {
+            // reserved opcodes and the like are fine, panics aren't
+            Ok(c) => cycles += c.max(1),
+            Err(_) => break,
+        }
+    }
+    let [cached, decoded] = &mut vms;
+    assert!(cached.get_ram() == decoded.get_ram());
+});
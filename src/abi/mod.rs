@@ -0,0 +1,26 @@
+// the calling convention compiled code and the standard library follow, and
+// that assembly calling either should too. the details are in docs/abi.txt
+use opcodes::Operand;
+use virtual_machine::Register;
+
+mod stdlib;
+
+pub use self::stdlib::{link, Object, STDLIB};
+
+// the first three arguments, in order. the rest are pushed last first
+pub const ARGUMENT_REGISTERS: [Register; 3] = [Register::A, Register::B, Register::C];
+pub const RETURN_REGISTER: Register = Register::A;
+// what a call can change, along with EX
+pub const CALLER_SAVED: [Register; 3] = [Register::A, Register::B, Register::C];
+// what a function has to put back how it found them, along with SP
+pub const CALLEE_SAVED: [Register; 5] = [Register::X, Register::Y, Register::Z, Register::I, Register::J];
+
+// where argument `n` is when a function starts, once it has pushed `pushed`
+// words of its own. past the registers it's on the stack, just above the
+// return address
+pub fn argument(n: usize, pushed: u16) -> Operand {
+    match ARGUMENT_REGISTERS.get(n) {
+        Some(&reg) => Operand::Register(reg),
+        None => Operand::Pick(pushed + 1 + (n - ARGUMENT_REGISTERS.len()) as u16),
+    }
+}
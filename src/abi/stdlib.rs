@@ -0,0 +1,194 @@
+// routines every program ends up needing, as assembly source that's laid
+// out with whatever calls them
+use std::collections::HashSet;
+use assembly::Intermediate;
+use parser::parse;
+
+// a routine and the name it's called by. its other labels start with two
+// underscores and the name, so they stay out of the way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Object {
+    pub name: &'static str,
+    pub source: &'static str,
+}
+
+impl Object {
+    pub fn items(&self) -> Vec<Intermediate> {
+        parse(self.source).expect("library routines parse").into_iter().map(Intermediate::from).collect()
+    }
+}
+
+pub const STDLIB: [Object; 6] = [
+    Object { name: "memcpy", source: include_str!("stdlib/memcpy.dasm") },
+    Object { name: "memset", source: include_str!("stdlib/memset.dasm") },
+    Object { name: "multiply32", source: include_str!("stdlib/multiply32.dasm") },
+    Object { name: "divide32", source: include_str!("stdlib/divide32.dasm") },
+    Object { name: "itoa", source: include_str!("stdlib/itoa.dasm") },
+    Object { name: "strcmp", source: include_str!("stdlib/strcmp.dasm") },
+];
+
+// adds the objects from `library` that `items` uses but doesn't define, and
+// any they use in turn, to the end. gives the names of the ones it added
+pub fn link(items: &mut Vec<Intermediate>, library: &[Object]) -> Vec<&'static str> {
+    let mut linked = vec![];
+    loop {
+        let defined: HashSet<&str> = items.iter().filter_map(|item| match *item {
+            Intermediate::Label(ref s) | Intermediate::Constant(ref s, _) => Some(s.as_str()),
+            _ => None,
+        }).collect();
+        let missing: HashSet<&str> = items.iter().flat_map(|item| item.labels())
+            .filter(|label| !defined.contains(label))
+            .collect();
+        match library.iter().find(|object| missing.contains(object.name)) {
+            Some(object) => {
+                linked.push(object.name);
+                items.extend(object.items());
+            },
+            None => return linked,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembly::{Block, Symbols};
+    use virtual_machine::{StopReason, VirtualMachine};
+
+    // assembles and links `src`, then runs it to its HLT
+    fn run(src: &str) -> (VirtualMachine, Symbols) {
+        let mut items: Vec<Intermediate> = parse(src).unwrap().into_iter().map(Intermediate::from).collect();
+        link(&mut items, &STDLIB);
+        let layout = Block::new().intermediate(&mut items).layout(0);
+        let mut vm = VirtualMachine::new().load_program(&layout.words().unwrap(), 0);
+        let stop = vm.run(&Default::default(), Some(1_000_000)).unwrap();
+        assert!(matches!(stop, StopReason::Halted(_)), "{}", stop);
+        (vm, layout.symbols)
+    }
+
+    fn ram(vm: &mut VirtualMachine, symbols: &Symbols, label: &str, len: usize) -> Vec<u16> {
+        let at = symbols[label] as usize;
+        vm.get_ram()[at..at + len].to_vec()
+    }
+
+    // the callee-saved registers have to come back the same
+    const SAVED: &str = "SET X, 1\nSET Y, 2\nSET Z, 3\nSET I, 4\nSET J, 5\n";
+
+    fn check_saved(vm: &mut VirtualMachine) {
+        assert_eq!(vm.get_registers()[3..8], [1, 2, 3, 4, 5]);
+        assert_eq!(*vm.get_sp(), 0);
+    }
+
+    #[test]
+    fn links_what_is_used() {
+        let mut items: Vec<Intermediate> = parse("JSR itoa\nSET PC, memset\n:strcmp\nDAT 0").unwrap()
+            .into_iter().map(Intermediate::from).collect();
+        assert_eq!(link(&mut items, &STDLIB), vec!["memset", "itoa"]);
+        assert_eq!(link(&mut items, &STDLIB), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn memory() {
+        let (mut vm, symbols) = run(&format!("{}
+            SET A, dest
+            SET B, src
+            SET C, 3
+            JSR memcpy
+            SET PUSH, A
+            SET A, fill
+            SET B, 0xbeef
+            SET C, 2
+            JSR memset
+            SET B, POP
+            HLT
+            :src DAT 1, 2, 3, 4
+            :dest DAT 0, 0, 0, 0
+            :fill DAT 7, 7, 7", SAVED));
+        assert_eq!(ram(&mut vm, &symbols, "dest", 4), vec![1, 2, 3, 0]);
+        assert_eq!(ram(&mut vm, &symbols, "fill", 3), vec![0xbeef, 0xbeef, 7]);
+        assert_eq!(vm.get_registers()[..2], [symbols["fill"], symbols["dest"]]);
+        check_saved(&mut vm);
+    }
+
+    fn arithmetic(x: u32, y: u32) -> (u32, u32, u32) {
+        let (mut vm, symbols) = run(&format!("{}
+            SET A, product
+            SET B, left
+            SET C, right
+            JSR multiply32
+            SET PUSH, right
+            SET A, quotient
+            SET B, remainder
+            SET C, left
+            JSR divide32
+            ADD SP, 1
+            HLT
+            :left DAT {:#x}, {:#x}
+            :right DAT {:#x}, {:#x}
+            :product DAT 0, 0
+            :quotient DAT 0, 0
+            :remainder DAT 0, 0", SAVED, x & 0xffff, x >> 16, y & 0xffff, y >> 16));
+        check_saved(&mut vm);
+        assert_eq!(vm.get_registers()[0], symbols["quotient"]);
+        let word = |vm: &mut VirtualMachine, label| {
+            let w = ram(vm, &symbols, label, 2);
+            w[0] as u32 | (w[1] as u32) << 16
+        };
+        (word(&mut vm, "product"), word(&mut vm, "quotient"), word(&mut vm, "remainder"))
+    }
+
+    #[test]
+    fn thirty_two_bits() {
+        for &(x, y) in [(6u32, 7u32), (0x12345678, 0x9abc), (0xffffffff, 0xffffffff), (0xfedcba98, 0x10001),
+                        (0x80000000, 3), (0xffffffff, 0x80000001), (1000, 0)].iter() {
+            let (quotient, remainder) = (x.checked_div(y).unwrap_or(0), x.checked_rem(y).unwrap_or(0));
+            assert_eq!(arithmetic(x, y), (x.wrapping_mul(y), quotient, remainder), "{:#x} {:#x}", x, y);
+        }
+    }
+
+    fn itoa(value: u16, base: u16) -> String {
+        let (mut vm, symbols) = run(&format!("{}
+            SET A, {}
+            SET B, buffer
+            SET C, {}
+            JSR itoa
+            HLT
+            :buffer DAT 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0", SAVED, value, base));
+        check_saved(&mut vm);
+        assert_eq!(vm.get_registers()[0], symbols["buffer"]);
+        let buffer = ram(&mut vm, &symbols, "buffer", 18);
+        buffer.iter().take_while(|&&c| c != 0).map(|&c| c as u8 as char).collect()
+    }
+
+    #[test]
+    fn numbers_to_strings() {
+        assert_eq!(itoa(0, 10), "0");
+        assert_eq!(itoa(12345, 10), "12345");
+        assert_eq!(itoa(0xbeef, 16), "beef");
+        assert_eq!(itoa(0xffff, 2), "1111111111111111");
+        assert_eq!(itoa(35, 36), "z");
+    }
+
+    fn strcmp(a: &str, b: &str) -> i16 {
+        let dat = |s: &str| s.chars().map(|c| format!("{}, ", c as u16)).collect::<String>() + "0";
+        let (mut vm, _) = run(&format!("{}
+            SET A, left
+            SET B, right
+            JSR strcmp
+            HLT
+            :left DAT {}
+            :right DAT {}", SAVED, dat(a), dat(b)));
+        check_saved(&mut vm);
+        vm.get_registers()[0] as i16
+    }
+
+    #[test]
+    fn comparing_strings() {
+        assert_eq!(strcmp("same", "same"), 0);
+        assert_eq!(strcmp("", ""), 0);
+        assert!(strcmp("abc", "abd") < 0);
+        assert!(strcmp("abd", "abc") > 0);
+        assert!(strcmp("ab", "abc") < 0);
+        assert!(strcmp("abc", "ab") > 0);
+    }
+}
@@ -1,43 +1,313 @@
-use opcodes::{Opcode, Operand}; 
-use result::{DcpuError, DcpuResult, DcpuErrorKind};
-use std::collections::BTreeMap;
+use opcodes::{Opcode, Operand, NOP};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use super::{Assemble, DcpuAssemblerError, DcpuResult, Symbols};
+use super::opcode::is_short_literal;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Intermediate {
     Opcode(Opcode),
+    // an explicit NOP, which the optimizer leaves alone
+    Nop,
     Label(String),
     Data(Vec<u8>),
-    Reserve(usize)
+    Reserve(usize),
+    // DAT: a word for each number or label
+    Words(Vec<Operand>),
+    // a name for a number, which takes no room
+    Constant(String, u16),
+    // SET PC, label in the fewest words it can have where it ends up. the
+    // one word relative forms are ADD and SUB on PC, so they clobber EX
+    Jump(String),
+}
+
+// the instruction a jump from `address` to `label` assembles to
+fn jump(address: u16, label: &str, symbols: &Symbols) -> Opcode {
+    let target = match symbols.get(label) {
+        Some(&target) => target,
+        None => return Opcode::SET(Operand::Pc, Operand::Label(label.to_owned())),
+    };
+    // PC has already moved past the jump when it runs
+    let forward = target.wrapping_sub(address.wrapping_add(1));
+    let back = address.wrapping_add(1).wrapping_sub(target);
+    if is_short_literal(target) {
+        Opcode::SET(Operand::Pc, Operand::Literal(target))
+    } else if forward <= 30 {
+        Opcode::ADD(Operand::Pc, Operand::Literal(forward))
+    } else if back <= 30 {
+        Opcode::SUB(Operand::Pc, Operand::Literal(back))
+    } else {
+        Opcode::SET(Operand::Pc, Operand::Literal(target))
+    }
+}
+
+impl Intermediate {
+    pub fn size(&self) -> usize {
+        match *self {
+            Intermediate::Opcode(ref op) => op.size(),
+            Intermediate::Nop => 1,
+            Intermediate::Label(_) => 0,
+            Intermediate::Data(ref bytes) => bytes.len().div_ceil(2),
+            Intermediate::Reserve(n) => n,
+            Intermediate::Words(ref words) => words.len(),
+            Intermediate::Constant(..) => 0,
+            Intermediate::Jump(_) => 2,
+        }
+    }
+
+    // the labels the item refers to
+    pub fn labels(&self) -> Vec<&str> {
+        match *self {
+            Intermediate::Opcode(ref op) => {
+                let (b, a) = op.operands();
+                b.into_iter().chain(Some(a)).flat_map(|o| o.labels()).collect()
+            },
+            Intermediate::Words(ref words) => words.iter().flat_map(|w| w.labels()).collect(),
+            Intermediate::Jump(ref label) => vec![label],
+            _ => vec![],
+        }
+    }
+
+    // the size if every label it uses turns out to fit a short literal
+    pub fn min_size(&self) -> usize {
+        match *self {
+            Intermediate::Opcode(ref op) => op.min_size(),
+            Intermediate::Jump(_) => 1,
+            _ => self.size(),
+        }
+    }
+
+    // the words at `address`. relaxed, labels get short literals where they
+    // fit; otherwise the item is always size() words
+    fn assem_at(&self, address: u16, symbols: &Symbols, relaxed: bool) -> DcpuResult<Vec<u16>> {
+        match *self {
+            Intermediate::Opcode(ref op) if relaxed => op.assem_relaxed(symbols),
+            Intermediate::Opcode(ref op) => op.assem_with(symbols),
+            Intermediate::Nop => Ok(vec![NOP]),
+            Intermediate::Jump(ref label) if relaxed => jump(address, label, symbols).assem_with(symbols),
+            Intermediate::Jump(ref label) => Opcode::SET(Operand::Pc, Operand::Label(label.clone())).assem_with(symbols),
+            Intermediate::Label(_) => Ok(vec![]),
+            // big endian, with an odd byte out padded with zero
+            Intermediate::Data(ref bytes) => Ok(bytes.chunks(2)
+                .map(|pair| (pair[0] as u16) << 8 | pair.get(1).cloned().unwrap_or(0) as u16)
+                .collect()),
+            Intermediate::Reserve(n) => Ok(vec![0; n]),
+            Intermediate::Words(ref words) => words.iter().map(|word| match *word {
+                Operand::Literal(n) => Ok(n),
+                Operand::Label(ref s) => symbols.get(s).cloned()
+                    .ok_or_else(|| DcpuAssemblerError::UnresolvedLabel(s.clone())),
+                ref other => Err(DcpuAssemblerError::InvalidData(other.to_string())),
+            }).collect(),
+            Intermediate::Constant(..) => Ok(vec![]),
+        }
+    }
+}
+
+// as assembly source, a line each, that reads back as the same item
+impl Display for Intermediate {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let dat = |fmt: &mut Formatter, words: &mut dyn Iterator<Item = String>| {
+            fmt.write_str("    DAT ")?;
+            fmt.write_str(&words.collect::<Vec<_>>().join(", "))
+        };
+        match *self {
+            Intermediate::Opcode(ref op) => fmt.write_fmt(format_args!("    {}", op)),
+            Intermediate::Nop => fmt.write_str("    NOP"),
+            Intermediate::Label(ref label) => fmt.write_fmt(format_args!(":{}", label)),
+            Intermediate::Data(ref bytes) => dat(fmt, &mut bytes.chunks(2)
+                .map(|pair| format!("{:#x}", (pair[0] as u16) << 8 | pair.get(1).cloned().unwrap_or(0) as u16))),
+            Intermediate::Reserve(n) => dat(fmt, &mut (0..n).map(|_| "0".to_owned())),
+            Intermediate::Words(ref words) => dat(fmt, &mut words.iter().map(|w| w.to_string())),
+            Intermediate::Constant(ref name, value) => fmt.write_fmt(format_args!(".equ {} {:#x}", name, value)),
+            Intermediate::Jump(ref label) => fmt.write_fmt(format_args!("    SET PC, {}", label)),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Block {
     intermediate: Vec<Intermediate>,
     symbols: BTreeMap<String, usize>, //symbols in the block and their index
 }
 
+// where an item of a block ended up and what it assembled to
+#[derive(Debug, PartialEq)]
+pub struct Placed {
+    pub address: u16,
+    pub size: usize,
+    pub words: DcpuResult<Vec<u16>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Layout {
+    // one for every item in the block, in order
+    pub items: Vec<Placed>,
+    pub symbols: Symbols,
+    // the symbols that came from .equ
+    constants: BTreeSet<String>,
+}
+
+impl Layout {
+    // the symbols that are addresses, leaving out .equ constants
+    pub fn labels(&self) -> Symbols {
+        self.symbols.iter()
+            .filter(|&(name, _)| !self.constants.contains(name))
+            .map(|(name, &address)| (name.clone(), address))
+            .collect()
+    }
+
+    // all the block's words, or the first error
+    pub fn words(&self) -> DcpuResult<Vec<u16>> {
+        let mut words = vec![];
+        for item in self.items.iter() {
+            match item.words {
+                Ok(ref w) => words.extend_from_slice(w),
+                Err(ref e) => return Err(e.clone()),
+            }
+        }
+        Ok(words)
+    }
+}
+
 impl Block {
     pub fn new() -> Block {
-        Block {
-            intermediate: Vec::new(),
-            symbols: BTreeMap::new(),
-        }
+        Block::default()
     }
 
     pub fn intermediate(mut self, inter: &mut Vec<Intermediate>) -> Self {
-        for i in 0..inter.len() {
-            match inter[i] {
-                Intermediate::Label(ref s) => {
-                        self.symbols.insert(s.clone(), i + self.intermediate.len());
-                }
-                _ => continue
+        for (i, item) in inter.iter().enumerate() {
+            if let Intermediate::Label(ref s) | Intermediate::Constant(ref s, _) = *item {
+                self.symbols.insert(s.clone(), i + self.intermediate.len());
             }
         }
         self.intermediate.append(inter);
         self
     }
 
-    pub fn has_symbol(&self, s: &String) -> bool {
+    pub fn has_symbol(&self, s: &str) -> bool {
         self.symbols.contains_key(s)
     }
+
+    pub fn items(&self) -> &[Intermediate] {
+        &self.intermediate
+    }
+
+    // every item's address, and the symbols, if the items have these sizes
+    fn place(&self, origin: u16, sizes: &[usize]) -> (Vec<u16>, Symbols) {
+        let mut addresses = Vec::with_capacity(sizes.len());
+        let mut address = origin;
+        for &size in sizes.iter() {
+            addresses.push(address);
+            address = address.wrapping_add(size as u16);
+        }
+        let symbols = self.symbols.iter()
+            .map(|(s, &i)| match self.intermediate[i] {
+                Intermediate::Constant(_, value) => (s.clone(), value),
+                _ => (s.clone(), addresses[i]),
+            })
+            .collect();
+        (addresses, symbols)
+    }
+
+    // places the block at `origin`, with labels in a as short literals
+    // wherever their address fits one. that changes the sizes, which moves
+    // the labels, so it goes round until nothing changes. every item starts
+    // out at its smallest and any that doesn't fit where that puts it goes
+    // to its full size for good: sizes only grow, so there's at most one
+    // more pass than there are items, even where growing would make a label
+    // fit again (near the top of memory, say)
+    pub fn layout(&self, origin: u16) -> Layout {
+        let mut long = vec![false; self.intermediate.len()];
+        loop {
+            let sizes: Vec<usize> = self.intermediate.iter().zip(long.iter())
+                .map(|(item, &long)| if long { item.size() } else { item.min_size() })
+                .collect();
+            let (addresses, symbols) = self.place(origin, &sizes);
+            let words: Vec<_> = self.intermediate.iter().zip(addresses.iter()).zip(long.iter())
+                .map(|((item, &address), &long)| item.assem_at(address, &symbols, !long))
+                .collect();
+            let mut grew = false;
+            for ((words, size), long) in words.iter().zip(sizes.iter()).zip(long.iter_mut()) {
+                // errors are reported at whatever size, they don't move anything
+                if !*long && words.as_ref().is_ok_and(|w| w.len() != *size) {
+                    *long = true;
+                    grew = true;
+                }
+            }
+            if !grew {
+                let items = addresses.into_iter().zip(sizes).zip(words)
+                    .map(|((address, size), words)| Placed { address, size, words })
+                    .collect();
+                let constants = self.intermediate.iter().filter_map(|item| match *item {
+                    Intermediate::Constant(ref name, _) => Some(name.clone()),
+                    _ => None,
+                }).collect();
+                return Layout { items, symbols, constants };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opcodes::Operand;
+    use virtual_machine::Register;
+
+    #[test]
+    fn labels_resolve_to_addresses() {
+        let mut items = vec![
+            Intermediate::Opcode(Opcode::SET(Operand::Register(Register::A), Operand::Label("data".to_string()))),
+            Intermediate::Label("loop".to_string()),
+            Intermediate::Opcode(Opcode::SET(Operand::Pc, Operand::Label("loop".to_string()))),
+            Intermediate::Label("data".to_string()),
+            Intermediate::Data(b"abc".to_vec()),
+            Intermediate::Reserve(2),
+        ];
+        let layout = Block::new().intermediate(&mut items).layout(0x100);
+        assert_eq!(layout.symbols.get("loop"), Some(&0x102));
+        assert_eq!(layout.symbols.get("data"), Some(&0x104));
+        assert_eq!(layout.items[4].address, 0x104);
+        assert_eq!(layout.words(), Ok(vec![0x7c01, 0x104, 0x7f81, 0x102, 0x6162, 0x6300, 0, 0]));
+    }
+
+    fn set_a(label: &str) -> Intermediate {
+        Intermediate::Opcode(Opcode::SET(Operand::Register(Register::A), Operand::Label(label.to_string())))
+    }
+
+    #[test]
+    fn short_labels_where_they_fit() {
+        // end is 30 if the first SET is one word, so it is
+        let mut items = vec![set_a("end"), Intermediate::Reserve(29), Intermediate::Label("end".to_string())];
+        let layout = Block::new().intermediate(&mut items).layout(0);
+        assert_eq!(layout.symbols.get("end"), Some(&30));
+        assert_eq!(layout.items[0].words, Ok(vec![0xfc01]));
+
+        // one more word and it doesn't fit either way
+        let mut items = vec![set_a("end"), Intermediate::Reserve(30), Intermediate::Label("end".to_string())];
+        let layout = Block::new().intermediate(&mut items).layout(0);
+        assert_eq!(layout.symbols.get("end"), Some(&32));
+        assert_eq!(layout.items[0].words, Ok(vec![0x7c01, 32]));
+
+        // l0 fits until the others grow, which takes another pass
+        let mut items: Vec<_> = (0..10).map(|i| set_a(&format!("l{}", i))).collect();
+        items.push(Intermediate::Reserve(20));
+        for i in 0..10 {
+            items.push(Intermediate::Label(format!("l{}", i)));
+            items.push(Intermediate::Reserve(1));
+        }
+        let layout = Block::new().intermediate(&mut items).layout(0);
+        assert_eq!(layout.symbols.get("l0"), Some(&40));
+        assert!(layout.items[..10].iter().all(|p| p.size == 2));
+    }
+
+    #[test]
+    fn settles_at_the_top_of_memory() {
+        // short, end is 0xfffe which doesn't fit. long, it's 0xffff which
+        // does, but the SET stays long rather than going back and forth
+        let mut items = vec![set_a("end"), Intermediate::Reserve(0x1d), Intermediate::Label("end".to_string())];
+        let layout = Block::new().intermediate(&mut items).layout(0xffe0);
+        assert_eq!(layout.symbols.get("end"), Some(&0xffff));
+        assert_eq!(layout.items[0].words, Ok(vec![0x7c01, 0xffff]));
+    }
 }
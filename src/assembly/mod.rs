@@ -1,4 +1,25 @@
+use thiserror::Error;
+
 mod opcode;
 mod layout;
+mod optimize;
+mod source;
+
+pub use self::opcode::{Assemble, Symbols};
+pub use self::layout::{Block, Intermediate, Layout, Placed};
+pub use self::optimize::Optimizer;
+pub use self::source::{assemble_file, Assembler, SourceError};
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum DcpuAssemblerError {
+    #[error("Invalid operand A. Cannot put PUSH there.")]
+    PushInAOp,
+    #[error("Invalid operand B. Cannot put POP there.")]
+    PopInBOp,
+    #[error("Label {} hasn't been resolved", .0)]
+    UnresolvedLabel(String),
+    #[error("{} can't be used as data", .0)]
+    InvalidData(String),
+}
 
-pub use self::opcode::Assemble;
+pub type DcpuResult<T> = Result<T, DcpuAssemblerError>;
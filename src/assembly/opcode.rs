@@ -1,11 +1,37 @@
+use std::collections::BTreeMap;
 use opcodes::{Opcode, Operand};
-use result::{DcpuError, DcpuResult, DcpuErrorKind};
+use super::{DcpuAssemblerError, DcpuResult};
+
+pub type Symbols = BTreeMap<String, u16>;
 
 pub trait Assemble {
     fn assem(&self) -> DcpuResult<Vec<u16>>;
+    // labels are looked up in `symbols`. they always get a next word, so
+    // the size doesn't depend on where they end up
+    fn assem_with(&self, symbols: &Symbols) -> DcpuResult<Vec<u16>>;
+    // like assem_with, but a label in a that fits a short literal gets one,
+    // so the size depends on where the label ends up
+    fn assem_relaxed(&self, symbols: &Symbols) -> DcpuResult<Vec<u16>>;
+    // words assem_with will produce
+    fn size(&self) -> usize;
+    // the fewest words assem_relaxed could produce
+    fn min_size(&self) -> usize;
+}
+
+// where label operands get their values from
+#[derive(Clone, Copy)]
+struct Labels<'a> {
+    symbols: Option<&'a Symbols>,
+    short: bool,
+}
+
+fn lookup(labels: Labels, s: &str) -> DcpuResult<u16> {
+    labels.symbols.and_then(|symbols| symbols.get(s))
+        .cloned()
+        .ok_or_else(|| DcpuAssemblerError::UnresolvedLabel(s.to_owned()))
 }
 
-fn is_short_literal(n: u16) -> bool {
+pub fn is_short_literal(n: u16) -> bool {
     if n as i16 >= -1 && n as i16 <= 30 { return true; }
     false
 }
@@ -15,7 +41,19 @@ fn to_short_literal(n: u16) -> u16 {
         (0x21 + (n as i16)) as u16
 }
 
-fn build_operand(is_a: bool, op: &Operand) -> DcpuResult<(u16, Option<u16>)> {
+// next words the operand takes
+fn operand_size(is_a: bool, op: &Operand, short_labels: bool) -> usize {
+    match *op {
+        Operand::Literal(lit) => if is_a && is_short_literal(lit) { 0 } else { 1 },
+        Operand::Label(_) if is_a && short_labels => 0,
+        Operand::RegisterPlusDeref(..) | Operand::RegisterPlusLabelDeref(..) | Operand::Pick(_) |
+        Operand::LiteralDeref(_) | Operand::Label(_) | Operand::LabelDeref(_) |
+        Operand::LabelPlusDeref(..) | Operand::LabelPlusLabelDeref(..) => 1,
+        _ => 0
+    }
+}
+
+fn build_operand(is_a: bool, op: &Operand, labels: Labels) -> DcpuResult<(u16, Option<u16>)> {
     let shift = match is_a {
         true => 10,
         false => 5
@@ -23,16 +61,16 @@ fn build_operand(is_a: bool, op: &Operand) -> DcpuResult<(u16, Option<u16>)> {
     match *op {
         Operand::Register(ref reg) =>
             Ok(((*reg as u16) << shift, None)),
-        Operand::RegisterDeRef(ref reg) =>
+        Operand::RegisterDeref(ref reg) =>
             Ok(((*reg as u16 + 0x8) << shift, None)),
-        Operand::RegisterPlusDeRef(ref reg, ref lit) =>
+        Operand::RegisterPlusDeref(ref reg, ref lit) =>
             Ok(((*reg as u16 + 0x10) << shift, Some(*lit))),
         Operand::Pop => {
             if is_a {
                 Ok((0x18 << shift, None))
             }
             else {
-                Err(DcpuError{reason:DcpuErrorKind::PopInBOp})
+                Err(DcpuAssemblerError::PopInBOp)
             }
         },
         Operand::Push => {
@@ -40,7 +78,7 @@ fn build_operand(is_a: bool, op: &Operand) -> DcpuResult<(u16, Option<u16>)> {
                 Ok((0x18 << shift, None))
             }
             else {
-                Err(DcpuError{reason:DcpuErrorKind::PushInAOp})
+                Err(DcpuAssemblerError::PushInAOp)
             }
         },
         Operand::Peek =>
@@ -53,7 +91,7 @@ fn build_operand(is_a: bool, op: &Operand) -> DcpuResult<(u16, Option<u16>)> {
             Ok((0x1c << shift, None)),
         Operand::Ex =>
             Ok((0x1d << shift, None)),
-        Operand::LiteralDeRef(ref lit) =>
+        Operand::LiteralDeref(ref lit) =>
             Ok((0x1e << shift, Some(*lit))),
         Operand::Literal(ref lit) => {
             if is_a && is_short_literal(*lit) {
@@ -63,15 +101,33 @@ fn build_operand(is_a: bool, op: &Operand) -> DcpuResult<(u16, Option<u16>)> {
                 Ok((0x1f << shift, Some(*lit)))
             }
         },
-        _ => unimplemented!()
+        Operand::Label(ref s) => {
+            let value = lookup(labels, s)?;
+            if is_a && labels.short && is_short_literal(value) {
+                Ok((to_short_literal(value) << shift, None))
+            }
+            else {
+                Ok((0x1f << shift, Some(value)))
+            }
+        },
+        Operand::LabelDeref(ref s) =>
+            Ok((0x1e << shift, Some(lookup(labels, s)?))),
+        Operand::LabelPlusDeref(ref s, ref lit) =>
+            Ok((0x1e << shift, Some(lookup(labels, s)?.wrapping_add(*lit)))),
+        Operand::RegisterPlusLabelDeref(ref reg, ref s) =>
+            Ok(((*reg as u16 + 0x10) << shift, Some(lookup(labels, s)?))),
+        Operand::LabelPlusLabelDeref(ref s, ref t) => {
+            let sum = lookup(labels, s)?.wrapping_add(lookup(labels, t)?);
+            Ok((0x1e << shift, Some(sum)))
+        }
     }
 }
 
-fn build_op(opcode: u16, b: &Operand, a: &Operand) -> DcpuResult<Vec<u16>> {
+fn build_op(opcode: u16, b: &Operand, a: &Operand, labels: Labels) -> DcpuResult<Vec<u16>> {
     let mut op = opcode & 0x1f;
     let mut ret = Vec::<u16>::new();
 
-    op = op | match build_operand(true, a) {
+    op |= match build_operand(true, a, labels) {
         Ok((mask, Some(lit))) => {
             ret.push(lit);
             mask
@@ -80,7 +136,7 @@ fn build_op(opcode: u16, b: &Operand, a: &Operand) -> DcpuResult<Vec<u16>> {
         Err(err) => return Err(err)
     };
 
-    op = op | match build_operand(false, b) {
+    op |= match build_operand(false, b, labels) {
         Ok((mask, Some(lit))) => {
             ret.push(lit);
             mask
@@ -92,11 +148,11 @@ fn build_op(opcode: u16, b: &Operand, a: &Operand) -> DcpuResult<Vec<u16>> {
     Ok(ret)
 }
 
-fn build_special_op(opcode: u16, a: &Operand) -> DcpuResult<Vec<u16>> {
+fn build_special_op(opcode: u16, a: &Operand, labels: Labels) -> DcpuResult<Vec<u16>> {
     let mut op = (opcode & 0x1f) << 5 ;
     let mut ret = Vec::<u16>::new();
 
-    op = op | match build_operand(true, a) {
+    op |= match build_operand(true, a, labels) {
         Ok((mask, Some(lit))) => {
             ret.push(lit);
             mask
@@ -108,46 +164,116 @@ fn build_special_op(opcode: u16, a: &Operand) -> DcpuResult<Vec<u16>> {
     Ok(ret)
 }
 
+fn encode(opcode: &Opcode, labels: Labels) -> DcpuResult<Vec<u16>> {
+    match *opcode {
+        Opcode::SET(ref b, ref a) => build_op(0x01, b, a, labels),
+        Opcode::ADD(ref b, ref a) => build_op(0x02, b, a, labels),
+        Opcode::SUB(ref b, ref a) => build_op(0x03, b, a, labels),
+        Opcode::MUL(ref b, ref a) => build_op(0x04, b, a, labels),
+        Opcode::MLI(ref b, ref a) => build_op(0x05, b, a, labels),
+        Opcode::DIV(ref b, ref a) => build_op(0x06, b, a, labels),
+        Opcode::DVI(ref b, ref a) => build_op(0x07, b, a, labels),
+        Opcode::MOD(ref b, ref a) => build_op(0x08, b, a, labels),
+        Opcode::MDI(ref b, ref a) => build_op(0x09, b, a, labels),
+        Opcode::AND(ref b, ref a) => build_op(0x0a, b, a, labels),
+        Opcode::BOR(ref b, ref a) => build_op(0x0b, b, a, labels),
+        Opcode::XOR(ref b, ref a) => build_op(0x0c, b, a, labels),
+        Opcode::SHR(ref b, ref a) => build_op(0x0d, b, a, labels),
+        Opcode::ASR(ref b, ref a) => build_op(0x0e, b, a, labels),
+        Opcode::SHL(ref b, ref a) => build_op(0x0f, b, a, labels),
+        Opcode::IFB(ref b, ref a) => build_op(0x10, b, a, labels),
+        Opcode::IFC(ref b, ref a) => build_op(0x11, b, a, labels),
+        Opcode::IFE(ref b, ref a) => build_op(0x12, b, a, labels),
+        Opcode::IFN(ref b, ref a) => build_op(0x13, b, a, labels),
+        Opcode::IFG(ref b, ref a) => build_op(0x14, b, a, labels),
+        Opcode::IFA(ref b, ref a) => build_op(0x15, b, a, labels),
+        Opcode::IFL(ref b, ref a) => build_op(0x16, b, a, labels),
+        Opcode::IFU(ref b, ref a) => build_op(0x17, b, a, labels),
+        Opcode::ADX(ref b, ref a) => build_op(0x1a, b, a, labels),
+        Opcode::SBX(ref b, ref a) => build_op(0x1b, b, a, labels),
+        Opcode::STI(ref b, ref a) => build_op(0x1e, b, a, labels),
+        Opcode::STD(ref b, ref a) => build_op(0x1f, b, a, labels),
+        Opcode::JSR(ref a) => build_special_op(0x01, a, labels),
+        Opcode::INT(ref a) => build_special_op(0x08, a, labels),
+        Opcode::IAG(ref a) => build_special_op(0x09, a, labels),
+        Opcode::IAS(ref a) => build_special_op(0x0a, a, labels),
+        Opcode::RFI(ref a) => build_special_op(0x0b, a, labels),
+        Opcode::IAQ(ref a) => build_special_op(0x0c, a, labels),
+        Opcode::HWN(ref a) => build_special_op(0x10, a, labels),
+        Opcode::HWQ(ref a) => build_special_op(0x11, a, labels),
+        Opcode::HWI(ref a) => build_special_op(0x12, a, labels)
+    }
+}
+
 impl Assemble for Opcode {
     fn assem(&self) -> DcpuResult<Vec<u16>> {
-        match *self {
-            Opcode::SET(ref b, ref a) => build_op(0x01, b, a),
-            Opcode::ADD(ref b, ref a) => build_op(0x02, b, a),
-            Opcode::SUB(ref b, ref a) => build_op(0x03, b, a),
-            Opcode::MUL(ref b, ref a) => build_op(0x04, b, a),
-            Opcode::MLI(ref b, ref a) => build_op(0x05, b, a),
-            Opcode::DIV(ref b, ref a) => build_op(0x06, b, a),
-            Opcode::DVI(ref b, ref a) => build_op(0x07, b, a),
-            Opcode::MOD(ref b, ref a) => build_op(0x08, b, a),
-            Opcode::MDI(ref b, ref a) => build_op(0x09, b, a),
-            Opcode::AND(ref b, ref a) => build_op(0x0a, b, a),
-            Opcode::BOR(ref b, ref a) => build_op(0x0b, b, a),
-            Opcode::XOR(ref b, ref a) => build_op(0x0c, b, a),
-            Opcode::SHR(ref b, ref a) => build_op(0x0d, b, a),
-            Opcode::ASR(ref b, ref a) => build_op(0x0e, b, a),
-            Opcode::SHL(ref b, ref a) => build_op(0x0f, b, a),
-            Opcode::IFB(ref b, ref a) => build_op(0x10, b, a),
-            Opcode::IFC(ref b, ref a) => build_op(0x11, b, a),
-            Opcode::IFE(ref b, ref a) => build_op(0x12, b, a),
-            Opcode::IFN(ref b, ref a) => build_op(0x13, b, a),
-            Opcode::IFG(ref b, ref a) => build_op(0x14, b, a),
-            Opcode::IFA(ref b, ref a) => build_op(0x15, b, a),
-            Opcode::IFL(ref b, ref a) => build_op(0x16, b, a),
-            Opcode::IFU(ref b, ref a) => build_op(0x17, b, a),
-            Opcode::ADX(ref b, ref a) => build_op(0x1a, b, a),
-            Opcode::SBX(ref b, ref a) => build_op(0x1b, b, a),
-            Opcode::STI(ref b, ref a) => build_op(0x1e, b, a),
-            Opcode::STD(ref b, ref a) => build_op(0x1f, b, a),
-            Opcode::JSR(ref a) => build_special_op(0x01, a),
-            Opcode::INT(ref a) => build_special_op(0x08, a),
-            Opcode::IAG(ref a) => build_special_op(0x09, a),
-            Opcode::IAS(ref a) => build_special_op(0x0a, a),
-            Opcode::RFI(ref a) => build_special_op(0x0b, a),
-            Opcode::IAQ(ref a) => build_special_op(0x0c, a),
-            Opcode::HWN(ref a) => build_special_op(0x10, a),
-            Opcode::HWQ(ref a) => build_special_op(0x11, a),
-            Opcode::HWI(ref a) => build_special_op(0x12, a)
-        }
+        encode(self, Labels { symbols: None, short: false })
+    }
+
+    fn assem_with(&self, symbols: &Symbols) -> DcpuResult<Vec<u16>> {
+        encode(self, Labels { symbols: Some(symbols), short: false })
+    }
+
+    fn assem_relaxed(&self, symbols: &Symbols) -> DcpuResult<Vec<u16>> {
+        encode(self, Labels { symbols: Some(symbols), short: true })
+    }
+
+    fn size(&self) -> usize {
+        let (b, a) = self.operands();
+        1 + operand_size(true, a, false) + b.map_or(0, |b| operand_size(false, b, false))
+    }
+
+    fn min_size(&self) -> usize {
+        let (b, a) = self.operands();
+        1 + operand_size(true, a, true) + b.map_or(0, |b| operand_size(false, b, true))
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disassemble::disassm_one;
+
+    // every first word, with next words that can't be short literals
+    #[test]
+    fn reencode_all_instructions() {
+        for inst in 0..=0xFFFFu16 {
+            let words = [inst, 0x1234, 0x5678];
+            let mut itr = words[1..].iter().peekable();
+            let (op, count) = match disassm_one(inst, &mut itr) {
+                Ok(decoded) => decoded,
+                Err(_) => continue
+            };
+            assert_eq!(op.assem(), Ok(words[..count + 1].to_vec()), "{:#06x} {}", inst, op);
+        }
+    }
+
+    // a long literal in a that fits a short one comes back shorter
+    #[test]
+    fn reencode_shortens_literals() {
+        assert_eq!(Opcode::SET(Operand::Register(::Register::A), Operand::Literal(0xffff)).assem(),
+                   Ok(vec![0x8001]));
+        assert_eq!(Opcode::SET(Operand::Register(::Register::A), Operand::Literal(31)).assem(),
+                   Ok(vec![0x7c01, 31]));
+        assert_eq!(Opcode::JSR(Operand::Label("foo".to_string())).assem(),
+                   Err(DcpuAssemblerError::UnresolvedLabel("foo".to_string())));
+    }
+
+    #[test]
+    fn sizes_match_encoding() {
+        let mut symbols = Symbols::new();
+        symbols.insert("foo".to_string(), 3);
+        for inst in 0..=0xFFFFu16 {
+            let words = [inst, 0x1234, 0x5678];
+            let mut itr = words[1..].iter().peekable();
+            if let Ok((op, _)) = disassm_one(inst, &mut itr) {
+                assert_eq!(op.size(), op.assem().unwrap().len(), "{}", op);
+            }
+        }
+        let op = Opcode::IFE(Operand::LabelPlusLabelDeref("foo".to_string(), "foo".to_string()),
+                             Operand::Label("foo".to_string()));
+        assert_eq!(op.assem_with(&symbols), Ok(vec![0x7fd2, 3, 6]));
+        assert_eq!(op.size(), 3);
+    }
+}
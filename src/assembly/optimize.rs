@@ -0,0 +1,216 @@
+// peephole passes over a block's items before it's laid out, for when every
+// word counts. none of them change what the code does, except that relative
+// jumps clobber EX, so those are off unless asked for
+use opcodes::{Opcode, Operand};
+use super::{Block, Intermediate, Layout};
+use super::opcode::is_short_literal;
+
+#[derive(Clone, Debug)]
+pub struct Optimizer {
+    swap_comparisons: bool,
+    remove_self_sets: bool,
+    dead_code: bool,
+    relative_jumps: bool,
+}
+
+impl Default for Optimizer {
+    fn default() -> Optimizer {
+        Optimizer { swap_comparisons: true, remove_self_sets: true, dead_code: true, relative_jumps: false }
+    }
+}
+
+// IFE 5, A to IFE A, 5 and so on, so the literal can go in the instruction.
+// only a gets short literals
+fn swap_comparison(op: &Opcode) -> Option<Opcode> {
+    let (b, a) = match op.operands() {
+        (Some(&Operand::Literal(b)), a) if op.is_conditional() && is_short_literal(b) => (Operand::Literal(b), a.clone()),
+        _ => return None,
+    };
+    match a {
+        Operand::Literal(a) if is_short_literal(a) => return None,
+        // POP can't go in b
+        Operand::Pop | Operand::Push => return None,
+        _ => (),
+    }
+    Some(match *op {
+        Opcode::IFB(..) => Opcode::IFB(a, b),
+        Opcode::IFC(..) => Opcode::IFC(a, b),
+        Opcode::IFE(..) => Opcode::IFE(a, b),
+        Opcode::IFN(..) => Opcode::IFN(a, b),
+        Opcode::IFG(..) => Opcode::IFL(a, b),
+        Opcode::IFL(..) => Opcode::IFG(a, b),
+        Opcode::IFA(..) => Opcode::IFU(a, b),
+        Opcode::IFU(..) => Opcode::IFA(a, b),
+        _ => return None,
+    })
+}
+
+// SET A, A and the like, which do nothing at all. NOP is its own item, since
+// it's there on purpose
+fn is_self_set(op: &Opcode) -> bool {
+    match *op {
+        Opcode::SET(ref b, ref a) if b == a => matches!(*a, Operand::Register(_) | Operand::Sp | Operand::Ex),
+        _ => false,
+    }
+}
+
+// the next instruction only runs if it's jumped to
+fn is_unconditional_jump(item: &Intermediate) -> bool {
+    match *item {
+        // SET PC, PC just carries on, it's BRK
+        Intermediate::Opcode(Opcode::SET(Operand::Pc, ref a)) => *a != Operand::Pc,
+        Intermediate::Opcode(Opcode::RFI(_)) | Intermediate::Jump(_) => true,
+        // HLT, and SUB PC, 1, go round in place
+        Intermediate::Opcode(Opcode::ADD(Operand::Pc, Operand::Literal(0xffff))) |
+        Intermediate::Opcode(Opcode::SUB(Operand::Pc, Operand::Literal(1))) => true,
+        _ => false,
+    }
+}
+
+fn is_conditional(item: &Intermediate) -> bool {
+    matches!(*item, Intermediate::Opcode(ref op) if op.is_conditional())
+}
+
+impl Optimizer {
+    pub fn new() -> Optimizer {
+        Optimizer::default()
+    }
+
+    // puts short literals in conditionals where they fit in a but were
+    // written in b
+    pub fn swap_comparisons(mut self, swap: bool) -> Self {
+        self.swap_comparisons = swap;
+        self
+    }
+
+    pub fn remove_self_sets(mut self, remove: bool) -> Self {
+        self.remove_self_sets = remove;
+        self
+    }
+
+    // drops instructions after an unconditional jump up to the next label,
+    // since nothing can get to them
+    pub fn dead_code(mut self, remove: bool) -> Self {
+        self.dead_code = remove;
+        self
+    }
+
+    // lets SET PC, label become ADD or SUB PC when the label's near enough,
+    // which saves a word but sets EX
+    pub fn relative_jumps(mut self, relative: bool) -> Self {
+        self.relative_jumps = relative;
+        self
+    }
+
+    pub fn optimize(&self, items: Vec<Intermediate>) -> Vec<Intermediate> {
+        let mut out: Vec<Intermediate> = Vec::with_capacity(items.len());
+        // whether the last instruction was a conditional, which the next one
+        // mustn't be taken away from
+        let mut guarded = false;
+        let mut dead = false;
+        for item in items {
+            let item = match item {
+                Intermediate::Constant(..) => {
+                    out.push(item);
+                    continue;
+                },
+                Intermediate::Label(_) | Intermediate::Data(_) | Intermediate::Reserve(_) | Intermediate::Words(_) => {
+                    dead = false;
+                    if !matches!(item, Intermediate::Label(_)) {
+                        guarded = false;
+                    }
+                    out.push(item);
+                    continue;
+                },
+                _ if dead => continue,
+                Intermediate::Opcode(ref op) if !guarded && self.remove_self_sets && is_self_set(op) => continue,
+                Intermediate::Opcode(Opcode::SET(Operand::Pc, Operand::Label(label))) if self.relative_jumps =>
+                    Intermediate::Jump(label),
+                Intermediate::Opcode(op) => match swap_comparison(&op) {
+                    Some(swapped) if self.swap_comparisons => Intermediate::Opcode(swapped),
+                    _ => Intermediate::Opcode(op),
+                },
+                item => item,
+            };
+            dead = self.dead_code && !guarded && is_unconditional_jump(&item);
+            guarded = is_conditional(&item);
+            out.push(item);
+        }
+        out
+    }
+
+    // optimizes the items and places them at `origin`
+    pub fn layout(&self, items: Vec<Intermediate>, origin: u16) -> Layout {
+        Block::new().intermediate(&mut self.optimize(items)).layout(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::Register;
+
+    fn op(op: Opcode) -> Intermediate {
+        Intermediate::Opcode(op)
+    }
+
+    fn label(name: &str) -> Intermediate {
+        Intermediate::Label(name.to_string())
+    }
+
+    #[test]
+    fn removes_what_does_nothing() {
+        let a = || Operand::Register(Register::A);
+        let b = || Operand::Register(Register::B);
+        let end = || Operand::Label("end".to_string());
+        let items = vec![
+            op(Opcode::SET(a(), a())),
+            op(Opcode::IFN(a(), b())),
+            op(Opcode::SET(a(), a())),
+            op(Opcode::IFE(Operand::Literal(5), a())),
+            op(Opcode::SET(Operand::Pc, end())),
+            op(Opcode::SET(b(), Operand::Literal(1))),
+            op(Opcode::SET(Operand::Pc, Operand::Pop)),
+            op(Opcode::SET(b(), Operand::Literal(2))),
+            op(Opcode::SET(a(), a())),
+            label("end"),
+            op(Opcode::SET(Operand::Pc, Operand::Pc)),
+            op(Opcode::SET(Operand::Sp, Operand::Sp)),
+            Intermediate::Nop,
+        ];
+        assert_eq!(Optimizer::new().optimize(items), vec![
+            op(Opcode::IFN(a(), b())),
+            // still what the IFN skips
+            op(Opcode::SET(a(), a())),
+            op(Opcode::IFE(a(), Operand::Literal(5))),
+            op(Opcode::SET(Operand::Pc, end())),
+            op(Opcode::SET(b(), Operand::Literal(1))),
+            op(Opcode::SET(Operand::Pc, Operand::Pop)),
+            label("end"),
+            op(Opcode::SET(Operand::Pc, Operand::Pc)),
+            // NOP was written on purpose
+            Intermediate::Nop,
+        ]);
+    }
+
+    #[test]
+    fn shortens_labels_and_jumps() {
+        let set_pc = |l: &str| op(Opcode::SET(Operand::Pc, Operand::Label(l.to_string())));
+        let mut items = vec![label("start"), op(Opcode::IFG(Operand::Literal(3), Operand::Register(Register::A)))];
+        items.extend((0..40).map(|_| op(Opcode::SET(Operand::Register(Register::B), Operand::Literal(0x100)))));
+        items.extend(vec![set_pc("start"), label("near"), set_pc("near"), label("mid"), set_pc("far"),
+                          Intermediate::Reserve(5), label("far")]);
+
+        let words = Optimizer::new().layout(items.clone(), 0).words().unwrap();
+        // IFL A, 3
+        assert_eq!(words[0], 0x9016);
+        // start is 0, which fits a short literal
+        assert_eq!(&words[81..86], &[0x8781, 0x7f81, 82, 0x7f81, 91]);
+
+        let layout = Optimizer::new().relative_jumps(true).layout(items, 0x1000);
+        let words = layout.words().unwrap();
+        // too far back, SUB PC, 1 and ADD PC, 5
+        assert_eq!(&words[81..85], &[0x7f81, 0x1000, 0x8b83, 0x9b82]);
+        assert_eq!(layout.symbols.get("far"), Some(&0x105a));
+    }
+}
@@ -0,0 +1,194 @@
+use thiserror::Error;
+use parser::{parse_dialect, Dialect, Located, ParseError, Span, Statement};
+use abi::{link, STDLIB};
+use image::Image;
+use super::{Block, DcpuAssemblerError, Intermediate, Optimizer};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SourceError {
+    #[error("{}", .0)]
+    Parse(#[from] ParseError),
+    #[error("{}", .0)]
+    Assembler(#[from] DcpuAssemblerError),
+}
+
+impl From<Statement> for Intermediate {
+    fn from(statement: Statement) -> Intermediate {
+        match statement {
+            Statement::LabelDef(label) => Intermediate::Label(label),
+            Statement::Instruction(op) => Intermediate::Opcode(op),
+            Statement::Nop => Intermediate::Nop,
+            Statement::Data(words) => Intermediate::Words(words),
+            Statement::Constant(name, value) => Intermediate::Constant(name, value),
+        }
+    }
+}
+
+// where in the statement an unresolved label is used, so the caret can point
+// right at it
+fn label_span(src: &str, span: Span, label: &str) -> Span {
+    let text = &src[span.start..span.end];
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(label)
+        .find(|&(i, _)| i > 0 && !text[..i].ends_with(is_ident) && !text[i + label.len()..].starts_with(is_ident))
+        .map_or(span, |(i, _)| Span { start: span.start + i, end: span.start + i + label.len() })
+}
+
+// how source files get assembled
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    origin: u16,
+    dialect: Dialect,
+    stdlib: bool,
+    optimizer: Option<Optimizer>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    // where the first word goes
+    pub fn origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    // whether to add the standard library routines the source uses without
+    // defining them
+    pub fn stdlib(mut self, stdlib: bool) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+
+    pub fn optimizer(mut self, optimizer: Optimizer) -> Self {
+        self.optimizer = Some(optimizer);
+        self
+    }
+
+    // assembles a whole source file, or says everything that's wrong with
+    // it, in the order it comes in the file
+    pub fn assemble(&self, file: Option<&str>, src: &str) -> Result<Vec<u16>, Vec<Located<SourceError>>> {
+        self.image(file, src).map(|image| image.flatten().words)
+    }
+
+    // the same, along with where it goes and its labels
+    pub fn image(&self, file: Option<&str>, src: &str) -> Result<Image, Vec<Located<SourceError>>> {
+        let parsed = parse_dialect(file, src, self.dialect);
+        let mut errors: Vec<_> = parsed.errors.into_iter().map(|e| e.map(SourceError::Parse)).collect();
+        let (mut items, spans): (Vec<Intermediate>, Vec<_>) = parsed.statements.into_iter()
+            .map(|(s, span)| (s.into(), span))
+            .unzip();
+        // linked routines go after the source, past the end of the spans
+        if self.stdlib {
+            link(&mut items, &STDLIB);
+        }
+        // errors come from the items as written, so they line up with the source
+        let layout = Block::new().intermediate(&mut items.clone()).layout(self.origin);
+        for (placed, span) in layout.items.iter().zip(spans) {
+            if let Err(ref e) = placed.words {
+                let span = match *e {
+                    DcpuAssemblerError::UnresolvedLabel(ref label) => label_span(src, span, label),
+                    _ => span,
+                };
+                errors.push(Located::new(SourceError::Assembler(e.clone()), file, src, span));
+            }
+        }
+        if !errors.is_empty() {
+            errors.sort_by_key(|e| e.location.span);
+            return Err(errors);
+        }
+        let layout = match self.optimizer {
+            Some(ref optimizer) => optimizer.layout(items, self.origin),
+            None => layout,
+        };
+        Ok(Image::new(self.origin, layout.words().expect("every item assembled")).symbols(layout.labels()))
+    }
+}
+
+// assembles a source file at `origin` with the defaults
+pub fn assemble_file(file: Option<&str>, src: &str, origin: u16) -> Result<Vec<u16>, Vec<Located<SourceError>>> {
+    Assembler::new().origin(origin).assemble(file, src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_have_locations() {
+        assert_eq!(assemble_file(None, ":loop SET A, 1\nSET PC, loop", 0x100), Ok(vec![0x8801, 0x7f81, 0x100]));
+
+        let errors = assemble_file(Some("boot.dasm"), "JSR nowhere\nSET A, 0x10000\nSET [PC], 1", 0).unwrap_err();
+        let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "boot.dasm:1:5: Label nowhere hasn't been resolved",
+            "boot.dasm:2:8: Literal 65536 exceeds maximum size 65535",
+            "boot.dasm:3:5: Invalid deref on PC",
+        ]);
+    }
+
+    #[test]
+    fn optimizes() {
+        let src = ":loop SET A, A\nSET PC, loop\nSET B, 1\n";
+        let assembler = Assembler::new().optimizer(Optimizer::new());
+        assert_eq!(assembler.assemble(None, src), Ok(vec![0x8781]));
+        let assembler = Assembler::new().origin(0x100).optimizer(Optimizer::new().relative_jumps(true));
+        assert_eq!(assembler.assemble(None, src), Ok(vec![0x8b83]));
+        assert!(assembler.assemble(None, "SET PC, nowhere\nSET A, A").is_err());
+    }
+
+    #[test]
+    fn data_and_constants() {
+        let src = "#define SIZE 0x20\n:start SET A, SIZE\n  dat \"ab\", start, SIZE, LATER\n.equ LATER 3";
+        assert_eq!(assemble_file(None, src, 0), Ok(vec![0x7c01, 0x20, 0x61, 0x62, 0, 0x20, 3]));
+        // without the constants, their uses are errors too
+        let errors = Assembler::new().dialect(Dialect::Notch).assemble(None, src).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        // images get the labels, but not the constants
+        let image = Assembler::new().origin(0x10).image(None, src).unwrap();
+        assert_eq!((image.origin(), image.segments[0].words.len()), (0x10, 7));
+        assert_eq!(image.symbols.into_iter().collect::<Vec<_>>(), vec![("start".to_owned(), 0x10)]);
+    }
+
+    #[test]
+    fn links_the_standard_library() {
+        let src = "SET A, 0x100\nSET B, 0\nSET C, 4\nJSR memset\nHLT";
+        assert!(assemble_file(None, src, 0).is_err());
+        assert_eq!(Assembler::new().stdlib(true).assemble(None, src),
+                   assemble_file(None, &format!("{}\n{}", src, STDLIB[1].source), 0));
+    }
+
+    #[test]
+    fn pseudo_ops_round_trip() {
+        use disassemble::{disassm_one, pseudo};
+        use opcodes::{BRK, HLT, NOP};
+        let src = "JMP 0x100\nRET\nPUSH A\nPOP [0x10]\nNOP\nHLT\nBRK\nPOP B\n";
+        let words = assemble_file(None, src, 0).unwrap();
+        assert_eq!(&words[words.len() - 4..words.len() - 1], &[NOP, HLT, BRK]);
+        let mut itr = words.iter().peekable();
+        let mut lines = String::new();
+        while let Some(&inst) = itr.next() {
+            lines.push_str(&pseudo(&disassm_one(inst, &mut itr).unwrap().0));
+            lines.push('\n');
+        }
+        assert_eq!(lines, src);
+    }
+
+    #[test]
+    fn listings_assemble_back() {
+        use disassemble::listing;
+        let src = ":start SET A, [data]\nJSR sub\nHLT\n:sub ADD A, 1\nRET\n:data DAT 0x7c01, 5, 0";
+        let image = Assembler::new().origin(0x100).image(None, src).unwrap();
+        let list = listing(&image, true);
+        assert!(list.starts_with("; origin 0x0100\n:start\n    SET A, [0x107]"), "{}", list);
+        assert!(list.contains(":data\n    DAT 0x7c01"), "{}", list);
+        assert!(list.contains("    RET                             ; 0106\n"), "{}", list);
+        assert_eq!(Assembler::new().origin(0x100).image(None, &list), Ok(image));
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::Duration;
+use virtual_machine::{VirtualMachine, DcpuVMError};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("node {node} failed: {error}")]
+pub struct ClusterError {
+    pub node: usize,
+    #[source]
+    pub error: DcpuVMError,
+}
+
+#[derive(Debug)]
+struct Node {
+    vm: VirtualMachine,
+    // cycles that passed without executing anything, i.e. while on fire
+    idle: usize,
+}
+
+impl Node {
+    fn nanos(&self) -> u128 {
+        (self.vm.get_cycles() + self.idle) as u128 * 1_000_000_000 / self.vm.get_clock_rate() as u128
+    }
+}
+
+// runs several VMs against one virtual clock. the VM furthest behind always
+// goes next (lowest index on a tie), so runs only depend on the programs and
+// the clock rates, never on the host
+#[derive(Debug, Default)]
+pub struct Cluster {
+    nodes: Vec<Node>,
+}
+
+impl Cluster {
+    pub fn new() -> Cluster {
+        Cluster { nodes: Vec::new() }
+    }
+
+    // fails with ZeroClockRate for a VM whose clock never ticks, since
+    // virtual time is cycles over the clock rate
+    pub fn node(mut self, vm: VirtualMachine) -> Result<Self, ClusterError> {
+        if vm.get_clock_rate() == 0 {
+            return Err(ClusterError { node: self.nodes.len(), error: DcpuVMError::ZeroClockRate });
+        }
+        self.nodes.push(Node { vm, idle: 0 });
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, node: usize) -> Option<&VirtualMachine> {
+        self.nodes.get(node).map(|n| &n.vm)
+    }
+
+    pub fn get_mut(&mut self, node: usize) -> Option<&mut VirtualMachine> {
+        self.nodes.get_mut(node).map(|n| &mut n.vm)
+    }
+
+    // how far a node has got in virtual time
+    pub fn elapsed(&self, node: usize) -> Option<Duration> {
+        self.nodes.get(node).map(|n| Duration::from_nanos(n.nanos() as u64))
+    }
+
+    // virtual time every node has reached
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.nodes.iter().map(Node::nanos).min().unwrap_or(0) as u64)
+    }
+
+    fn next(&self) -> Option<usize> {
+        self.nodes.iter()
+            .enumerate()
+            .min_by_key(|&(i, n)| (n.nanos(), i))
+            .map(|(i, _)| i)
+    }
+
+    // steps the node furthest behind and updates its hardware, returning
+    // which node that was. a device asking to stop is left on its VM's
+    // take_stop
+    pub fn step(&mut self) -> Result<Option<usize>, ClusterError> {
+        let i = match self.next() {
+            Some(i) => i,
+            None => return Ok(None)
+        };
+        let node = &mut self.nodes[i];
+        match node.vm.step() {
+            Ok(0) => node.idle += 1,
+            Ok(_) => {},
+            Err(error) => return Err(ClusterError { node: i, error })
+        }
+        node.vm.update_hardware();
+        Ok(Some(i))
+    }
+
+    // runs until every node has reached `time` past the current time, or
+    // until a device asks to stop, returning its node and index
+    pub fn run_for(&mut self, time: Duration) -> Result<Option<(usize, usize)>, ClusterError> {
+        let until = self.now() + time;
+        while !self.nodes.is_empty() && self.now() < until {
+            if let Some(i) = self.step()? {
+                if let Some(device) = self.nodes[i].vm.take_stop() {
+                    return Ok(Some((i, device)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::*;
+    use assembly::Assemble;
+    use hardware::{SerialLink, Mailbox, Hardware, HardwareInfo, DeviceContext};
+    use std::fmt::{Formatter, Error};
+    use opcodes::{Opcode, Operand};
+    use virtual_machine::Register;
+
+    fn assemble(at: u16, program: Vec<Opcode>, vm: &mut VirtualMachine) {
+        let mut pc = at as usize;
+        for op in program {
+            for word in op.assem().unwrap() {
+                vm.get_ram()[pc] = word;
+                pc += 1;
+            }
+        }
+    }
+
+    fn set(reg: Register, n: u16) -> Opcode {
+        Opcode::SET(Operand::Register(reg), Operand::Literal(n))
+    }
+
+    fn hwi(n: u16) -> Opcode {
+        Opcode::HWI(Operand::Literal(n))
+    }
+
+    fn hang() -> Opcode {
+        Opcode::SUB(Operand::Pc, Operand::Literal(1))
+    }
+
+    #[test]
+    fn serial_link_interrupts_peer() {
+        let (left, right) = SerialLink::pair();
+        let mut sender = VirtualMachine::new().attach_hardware(Box::new(left)).unwrap();
+        assemble(0, vec![
+            set(Register::A, 2),
+            set(Register::B, 0x1234),
+            hwi(0),
+            hang(),
+        ], &mut sender);
+
+        let mut receiver = VirtualMachine::new().attach_hardware(Box::new(right)).unwrap();
+        assemble(0, vec![
+            Opcode::IAS(Operand::Literal(0x10)),
+            set(Register::A, 3),
+            set(Register::B, 0x55),
+            hwi(0),
+            hang(),
+        ], &mut receiver);
+        assemble(0x10, vec![
+            Opcode::SET(Operand::LiteralDeref(0x1001), Operand::Register(Register::A)),
+            set(Register::A, 1),
+            hwi(0),
+            Opcode::SET(Operand::LiteralDeref(0x1000), Operand::Register(Register::B)),
+            Opcode::RFI(Operand::Literal(0)),
+        ], &mut receiver);
+
+        let mut cluster = Cluster::new().node(sender).and_then(|c| c.node(receiver)).unwrap();
+        cluster.run_for(Duration::from_millis(1)).unwrap();
+        let receiver = cluster.get_mut(1).unwrap();
+        assert_eq!(receiver.get_ram()[0x1000], 0x1234);
+        assert_eq!(receiver.get_ram()[0x1001], 0x55);
+    }
+
+    #[test]
+    fn mailbox_round_trip() {
+        let (left, right) = Mailbox::pair();
+        // sends 3 words from 0x2000 then polls until the echo comes back
+        let mut client = VirtualMachine::new().attach_hardware(Box::new(left)).unwrap();
+        client.get_ram()[0x2000..0x2003].copy_from_slice(&[1, 2, 3]);
+        assemble(0, vec![
+            set(Register::A, 0),
+            set(Register::B, 0x2000),
+            set(Register::C, 3),
+            hwi(0),
+            set(Register::A, 1),
+            set(Register::B, 0x3000),
+            hwi(0),
+            Opcode::IFE(Operand::Register(Register::C), Operand::Literal(0)),
+            Opcode::SUB(Operand::Pc, Operand::Literal(6)),
+            hang(),
+        ], &mut client);
+
+        // echoes whatever arrives back to the sender
+        let mut server = VirtualMachine::new().attach_hardware(Box::new(right)).unwrap();
+        assemble(0, vec![
+            set(Register::A, 1),
+            set(Register::B, 0x100),
+            hwi(0),
+            Opcode::IFE(Operand::Register(Register::C), Operand::Literal(0)),
+            Opcode::SUB(Operand::Pc, Operand::Literal(6)),
+            set(Register::A, 0),
+            hwi(0),
+            hang(),
+        ], &mut server);
+
+        let mut cluster = Cluster::new().node(client).and_then(|c| c.node(server)).unwrap();
+        cluster.run_for(Duration::from_millis(2)).unwrap();
+        let client = cluster.get_mut(0).unwrap();
+        assert_eq!(&client.get_ram()[0x3000..0x3003], &[1, 2, 3]);
+        assert_eq!(client.get_registers()[Register::C as usize], 3);
+    }
+
+    #[test]
+    fn faster_nodes_run_more_cycles() {
+        let mut slow = VirtualMachine::new().clock_rate(100_000);
+        assemble(0, vec![hang()], &mut slow);
+        let mut fast = VirtualMachine::new().clock_rate(400_000);
+        assemble(0, vec![hang()], &mut fast);
+
+        let mut cluster = Cluster::new().node(slow).and_then(|c| c.node(fast)).unwrap();
+        cluster.run_for(Duration::from_millis(10)).unwrap();
+        assert_eq!(cluster.get(0).unwrap().get_cycles(), 1000);
+        assert_eq!(cluster.get(1).unwrap().get_cycles(), 4000);
+        assert_eq!(cluster.now(), Duration::from_millis(10));
+    }
+
+    struct Stopper(HardwareInfo);
+
+    impl Hardware for Stopper {
+        fn info(&self) -> &HardwareInfo {
+            &self.0
+        }
+        fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+            ctx.request_stop();
+            0
+        }
+        fn debug_dump_state(&self, _fmt: &mut Formatter) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn devices_stop_the_cluster() {
+        let mut idle = VirtualMachine::new();
+        assemble(0, vec![hang()], &mut idle);
+        let info = HardwareInfo { manufacturer: 0, model: 0, version: 0 };
+        let mut stopping = VirtualMachine::new().attach_hardware(Box::new(Stopper(info))).unwrap();
+        assemble(0, vec![set(Register::A, 0), set(Register::A, 0), hwi(0), hang()], &mut stopping);
+
+        let mut cluster = Cluster::new().node(idle).and_then(|c| c.node(stopping)).unwrap();
+        assert_eq!(cluster.run_for(Duration::from_millis(10)).unwrap(), Some((1, 0)));
+        assert_eq!(cluster.get(1).unwrap().get_cycles(), 1 + 1 + 4);
+        assert_eq!(cluster.run_for(Duration::from_millis(10)).unwrap(), None);
+        assert!(cluster.now() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn rejects_stopped_clocks() {
+        let cluster = Cluster::new().node(VirtualMachine::new()).unwrap();
+        let err = cluster.node(VirtualMachine::new().clock_rate(0)).unwrap_err();
+        assert_eq!(err.node, 1);
+        assert!(matches!(err.error, DcpuVMError::ZeroClockRate));
+    }
+}
@@ -0,0 +1,508 @@
+// the syntax tree of a C source file, and building it from the grammar
+use pest::Parser;
+use pest::error::{ErrorVariant, InputLocation};
+use pest::iterators::Pair;
+use std::fmt::{self, Display, Formatter};
+use diagnostics::Span;
+use super::CompileError;
+
+#[cfg(debug_assertions)]
+const _GRAMMAR: &str = include_str!("c.pest");
+#[derive(Parser)]
+#[grammar = "compiler/c.pest"]
+struct CParser;
+
+// every value is a word, so the only difference between u16 and i16 is which
+// instructions compare, divide and shift them
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+    U16,
+    I16,
+    Void,
+    Pointer(Box<Type>),
+    Array(Box<Type>, u16),
+}
+
+impl Type {
+    pub fn is_signed(&self) -> bool {
+        *self == Type::I16
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        matches!(*self, Type::Pointer(_) | Type::Array(..))
+    }
+
+    // what it points to, arrays being pointers to their first element
+    pub fn target(&self) -> Option<&Type> {
+        match *self {
+            Type::Pointer(ref t) | Type::Array(ref t, _) => Some(t),
+            _ => None,
+        }
+    }
+
+    // arrays are used as pointers to their first element
+    pub fn decay(&self) -> Type {
+        match *self {
+            Type::Array(ref t, _) => Type::Pointer(t.clone()),
+            ref t => t.clone(),
+        }
+    }
+
+    // the words it takes
+    pub fn size(&self) -> u16 {
+        match *self {
+            Type::Array(_, n) => n,
+            _ => 1,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            Type::U16 => fmt.write_str("u16"),
+            Type::I16 => fmt.write_str("i16"),
+            Type::Void => fmt.write_str("void"),
+            Type::Pointer(ref t) => fmt.write_fmt(format_args!("{}*", t)),
+            Type::Array(ref t, n) => fmt.write_fmt(format_args!("{}[{}]", t, n)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinOp {
+    fn named(op: &str) -> BinOp {
+        match op {
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "%" => BinOp::Mod,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "&" => BinOp::And,
+            "|" => BinOp::Or,
+            "^" => BinOp::Xor,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "<" => BinOp::Lt,
+            ">" => BinOp::Gt,
+            "<=" => BinOp::Le,
+            ">=" => BinOp::Ge,
+            "&&" => BinOp::LogicalAnd,
+            "||" => BinOp::LogicalOr,
+            _ => unreachable!("the grammar has no {} operator", op),
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(self, BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
+    Number(u16),
+    // the characters, without the terminating zero
+    Str(Vec<u16>),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    // with the operator of a compound assignment like +=
+    Assign(Option<BinOp>, Box<Expr>, Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Deref(Box<Expr>),
+    AddressOf(Box<Expr>),
+    // ++ and --, before or after
+    Step { increment: bool, prefix: bool, expr: Box<Expr> },
+    Cast(Type, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Init {
+    Expr(Expr),
+    List(Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Declaration {
+    pub ty: Type,
+    pub name: String,
+    pub init: Option<Init>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt {
+    Block(Vec<Stmt>),
+    Declare(Declaration),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    While(Expr, Box<Stmt>),
+    For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+    Return(Option<Expr>, Span),
+    Break(Span),
+    Continue(Span),
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    pub ty: Type,
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub ty: Type,
+    pub name: String,
+    pub params: Vec<Param>,
+    // prototypes have none
+    pub body: Option<Vec<Stmt>>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Global(Declaration),
+    Function(Function),
+}
+
+type Result<T> = ::std::result::Result<T, (CompileError, Span)>;
+
+fn describe(rule: &Rule) -> &'static str {
+    match *rule {
+        Rule::ident => "a name",
+        Rule::number => "a number",
+        Rule::base_type | Rule::type_name => "a type",
+        Rule::expr | Rule::assign | Rule::unary | Rule::primary | Rule::call => "an expression",
+        Rule::statement | Rule::block => "a statement",
+        Rule::function | Rule::global | Rule::declaration => "a declaration",
+        Rule::EOI => "the end of the file",
+        Rule::initializer => "an initializer",
+        Rule::param | Rule::params => "a parameter",
+        Rule::keyword | Rule::kw_if | Rule::kw_else | Rule::kw_while | Rule::kw_for | Rule::kw_return |
+        Rule::kw_break | Rule::kw_continue => "a keyword",
+        _ => "an operator",
+    }
+}
+
+fn syntax_error(error: ::pest::error::Error<Rule>) -> (CompileError, Span) {
+    let span = match error.location {
+        InputLocation::Pos(pos) => Span { start: pos, end: pos },
+        InputLocation::Span((start, end)) => Span { start, end },
+    };
+    let message = match error.variant {
+        ErrorVariant::ParsingError { ref positives, .. } if !positives.is_empty() => {
+            let mut expected: Vec<_> = positives.iter().map(describe).collect();
+            expected.sort();
+            expected.dedup();
+            // the expression could go on, or it's missing whatever ends it,
+            // which pest doesn't say
+            if expected == ["an operator"] {
+                expected.push("the end of the expression");
+            }
+            format!("expected {}", expected.join(" or "))
+        },
+        _ => error.variant.message().into_owned(),
+    };
+    (CompileError::Syntax(message), span)
+}
+
+// the escapes are the ones the grammar lets through
+fn unescape(quoted: &str) -> Vec<u16> {
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    let mut words = vec![];
+    while let Some(c) = chars.next() {
+        words.push(match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n' as u16,
+                Some('r') => '\r' as u16,
+                Some('t') => '\t' as u16,
+                Some('0') => 0,
+                Some(c) => c as u16,
+                None => '\\' as u16,
+            },
+            c => c as u16,
+        });
+    }
+    words
+}
+
+fn number(pair: Pair<Rule>) -> Result<u16> {
+    let text = pair.as_str();
+    let value = if text.len() > 2 && text[..2].eq_ignore_ascii_case("0x") {
+        u32::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse::<u32>()
+    };
+    match value {
+        Ok(n) if n <= 0xffff => Ok(n as u16),
+        _ => Err((CompileError::TooBig(text.to_owned()), pair.as_span().into())),
+    }
+}
+
+fn type_name(pair: Pair<Rule>) -> Type {
+    let mut inner = pair.into_inner();
+    let base = match inner.next().unwrap().as_str() {
+        "u16" | "unsigned" | "char" => Type::U16,
+        "i16" | "int" => Type::I16,
+        _ => Type::Void,
+    };
+    inner.fold(base, |t, _| Type::Pointer(Box::new(t)))
+}
+
+fn expr(pair: Pair<Rule>) -> Result<Expr> {
+    let span: Span = pair.as_span().into();
+    let leaf = match pair.as_rule() {
+        Rule::number => Some(ExprKind::Number(number(pair.clone())?)),
+        Rule::chr => Some(ExprKind::Number(unescape(pair.as_str())[0])),
+        Rule::string => Some(ExprKind::Str(unescape(pair.as_str()))),
+        Rule::ident => Some(ExprKind::Var(pair.as_str().to_owned())),
+        _ => None,
+    };
+    if let Some(kind) = leaf {
+        return Ok(Expr { kind, span });
+    }
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner();
+    let kind = match rule {
+        Rule::expr => return expr(inner.next().unwrap()),
+        Rule::assign => {
+            let first = expr(inner.next().unwrap())?;
+            let op = match inner.next() {
+                Some(op) => op,
+                None => return Ok(first),
+            };
+            let value = expr(inner.next().unwrap())?;
+            let op = match op.as_str() {
+                "=" => None,
+                op => Some(BinOp::named(&op[..op.len() - 1])),
+            };
+            ExprKind::Assign(op, Box::new(first), Box::new(value))
+        },
+        Rule::lor | Rule::land | Rule::bor | Rule::bxor | Rule::band | Rule::equality |
+        Rule::relational | Rule::shift | Rule::additive | Rule::term => {
+            // left to right
+            let mut left = expr(inner.next().unwrap())?;
+            while let Some(op) = inner.next() {
+                let right = expr(inner.next().unwrap())?;
+                let span = Span { start: left.span.start, end: right.span.end };
+                left = Expr { kind: ExprKind::Binary(BinOp::named(op.as_str()), Box::new(left), Box::new(right)), span };
+            }
+            return Ok(left);
+        },
+        Rule::unary => {
+            let first = inner.next().unwrap();
+            match first.as_rule() {
+                Rule::postfix => return expr(first),
+                Rule::cast => {
+                    let ty = type_name(first.into_inner().next().unwrap());
+                    ExprKind::Cast(ty, Box::new(expr(inner.next().unwrap())?))
+                },
+                _ => {
+                    let operand = Box::new(expr(inner.next().unwrap())?);
+                    match first.as_str() {
+                        "++" => ExprKind::Step { increment: true, prefix: true, expr: operand },
+                        "--" => ExprKind::Step { increment: false, prefix: true, expr: operand },
+                        "-" => ExprKind::Unary(UnOp::Neg, operand),
+                        "!" => ExprKind::Unary(UnOp::Not, operand),
+                        "~" => ExprKind::Unary(UnOp::BitNot, operand),
+                        "*" => ExprKind::Deref(operand),
+                        _ => ExprKind::AddressOf(operand),
+                    }
+                },
+            }
+        },
+        Rule::postfix => {
+            let mut e = expr(inner.next().unwrap())?;
+            for suffix in inner {
+                let span = Span { start: e.span.start, end: suffix.as_span().end() };
+                let kind = match suffix.as_rule() {
+                    Rule::index => ExprKind::Index(Box::new(e), Box::new(expr(suffix.into_inner().next().unwrap())?)),
+                    _ => ExprKind::Step { increment: suffix.as_str() == "++", prefix: false, expr: Box::new(e) },
+                };
+                e = Expr { kind, span };
+            }
+            return Ok(e);
+        },
+        Rule::primary => return expr(inner.next().unwrap()),
+        Rule::call => {
+            let name = inner.next().unwrap().as_str().to_owned();
+            let args = inner.next().unwrap().into_inner().map(expr).collect::<Result<_>>()?;
+            ExprKind::Call(name, args)
+        },
+        unknown => unreachable!("{:?} isn't an expression", unknown),
+    };
+    Ok(Expr { kind, span })
+}
+
+fn initializer(pair: Pair<Rule>) -> Result<Init> {
+    let inner = pair.into_inner().next().unwrap();
+    match inner.as_rule() {
+        Rule::init_list => Ok(Init::List(inner.into_inner().map(expr).collect::<Result<_>>()?)),
+        _ => Ok(Init::Expr(expr(inner)?)),
+    }
+}
+
+fn declaration(pair: Pair<Rule>) -> Result<Declaration> {
+    let span = pair.as_span().into();
+    let mut inner = pair.into_inner();
+    let mut ty = type_name(inner.next().unwrap());
+    let name = inner.next().unwrap().as_str().to_owned();
+    let mut init = None;
+    for part in inner {
+        match part.as_rule() {
+            Rule::array_size => {
+                // without a size, it's as long as its initializer
+                let size = match part.into_inner().next() {
+                    Some(n) => Some(number(n)?),
+                    None => None,
+                };
+                ty = Type::Array(Box::new(ty), size.unwrap_or(0xffff));
+            },
+            _ => init = Some(initializer(part)?),
+        }
+    }
+    if let Type::Array(ref elem, 0xffff) = ty {
+        let size = match init {
+            Some(Init::List(ref values)) => values.len(),
+            Some(Init::Expr(Expr { kind: ExprKind::Str(ref s), .. })) => s.len() + 1,
+            _ => return Err((CompileError::NoArraySize(name), span)),
+        };
+        ty = Type::Array(elem.clone(), size as u16);
+    }
+    Ok(Declaration { ty, name, init, span })
+}
+
+// the statement's parts, without the keyword it starts with
+fn parts(pair: Pair<Rule>) -> impl Iterator<Item = Pair<Rule>> {
+    pair.into_inner().filter(|p| !matches!(p.as_rule(),
+        Rule::kw_if | Rule::kw_else | Rule::kw_while | Rule::kw_for | Rule::kw_return | Rule::kw_break | Rule::kw_continue))
+}
+
+fn optional_expr(pair: Pair<Rule>) -> Result<Option<Expr>> {
+    pair.into_inner().next().map(expr).transpose()
+}
+
+fn statement(pair: Pair<Rule>) -> Result<Option<Stmt>> {
+    let pair = match pair.as_rule() {
+        Rule::statement => pair.into_inner().next().unwrap(),
+        _ => pair,
+    };
+    if pair.as_rule() == Rule::declaration {
+        return Ok(Some(Stmt::Declare(declaration(pair)?)));
+    }
+    let span: Span = pair.as_span().into();
+    let rule = pair.as_rule();
+    let mut inner = parts(pair);
+    Ok(Some(match rule {
+        Rule::block => Stmt::Block(block(inner)?),
+        Rule::if_stmt => {
+            let cond = expr(inner.next().unwrap())?;
+            let then = Box::new(statement(inner.next().unwrap())?.unwrap_or(Stmt::Block(vec![])));
+            let otherwise = match inner.next() {
+                Some(s) => Some(Box::new(statement(s)?.unwrap_or(Stmt::Block(vec![])))),
+                None => None,
+            };
+            Stmt::If(cond, then, otherwise)
+        },
+        Rule::while_stmt => {
+            let cond = expr(inner.next().unwrap())?;
+            Stmt::While(cond, Box::new(statement(inner.next().unwrap())?.unwrap_or(Stmt::Block(vec![]))))
+        },
+        Rule::for_stmt => {
+            let init = inner.next().unwrap();
+            let init = match init.clone().into_inner().next() {
+                Some(d) if d.as_rule() == Rule::declaration => Some(Box::new(Stmt::Declare(declaration(d)?))),
+                Some(e) => Some(Box::new(Stmt::Expr(expr(e)?))),
+                None => None,
+            };
+            let cond = optional_expr(inner.next().unwrap())?;
+            let step = optional_expr(inner.next().unwrap())?;
+            let body = Box::new(statement(inner.next().unwrap())?.unwrap_or(Stmt::Block(vec![])));
+            Stmt::For(init, cond, step, body)
+        },
+        Rule::return_stmt => Stmt::Return(inner.next().map(expr).transpose()?, span),
+        Rule::break_stmt => Stmt::Break(span),
+        Rule::continue_stmt => Stmt::Continue(span),
+        Rule::expr_stmt => Stmt::Expr(expr(inner.next().unwrap())?),
+        _ => return Ok(None),
+    }))
+}
+
+fn block<'a, I: Iterator<Item = Pair<'a, Rule>>>(statements: I) -> Result<Vec<Stmt>> {
+    let mut out = vec![];
+    for s in statements {
+        out.extend(statement(s)?);
+    }
+    Ok(out)
+}
+
+fn function(pair: Pair<Rule>) -> Result<Function> {
+    let span = pair.as_span().into();
+    let mut inner = pair.into_inner();
+    let ty = type_name(inner.next().unwrap());
+    let name = inner.next().unwrap().as_str().to_owned();
+    let params = inner.next().unwrap().into_inner().map(|param| {
+        let span = param.as_span().into();
+        let mut inner = param.into_inner();
+        let ty = type_name(inner.next().unwrap());
+        Param { ty, name: inner.next().unwrap().as_str().to_owned(), span }
+    }).collect();
+    let body = inner.next().unwrap();
+    let body = match body.as_rule() {
+        Rule::block => Some(block(body.into_inner())?),
+        _ => None,
+    };
+    Ok(Function { ty, name, params, body, span })
+}
+
+// the declarations and functions in a file, or the first syntax error
+pub fn parse(src: &str) -> Result<Vec<Item>> {
+    let program = CParser::parse(Rule::program, src).map_err(syntax_error)?.next().unwrap();
+    let mut items = vec![];
+    for item in program.into_inner() {
+        match item.as_rule() {
+            Rule::function => items.push(Item::Function(function(item)?)),
+            Rule::global => items.push(Item::Global(declaration(item.into_inner().next().unwrap())?)),
+            _ => (),
+        }
+    }
+    Ok(items)
+}
@@ -0,0 +1,1191 @@
+// turns the syntax tree into instructions: which variables get registers,
+// stack frames and calls. the calling convention is the one in abi
+use std::collections::{HashMap, HashSet};
+use abi::{self, ARGUMENT_REGISTERS, CALLEE_SAVED, CALLER_SAVED, RETURN_REGISTER};
+use assembly::Intermediate;
+use diagnostics::Span;
+use opcodes::{Opcode, Operand};
+use virtual_machine::Register;
+use super::CompileError;
+use super::ast::{BinOp, Declaration, Expr, ExprKind, Function, Init, Item, Stmt, Type, UnOp};
+
+type Result<T> = ::std::result::Result<T, (CompileError, Span)>;
+
+// the names the assembler would read as something else
+const RESERVED: [&str; 15] = ["a", "b", "c", "x", "y", "z", "i", "j", "pc", "sp", "ex", "push", "pop", "peek", "pick"];
+
+fn reserved(name: &str) -> bool {
+    RESERVED.contains(&&name.to_lowercase()[..])
+}
+
+// the label a global is known by. `[a]` would be register A, so globals
+// named after registers move into the compiler's own __ names
+fn global_label(name: &str) -> String {
+    if reserved(name) {
+        format!("__{}", name)
+    }
+    else {
+        name.to_owned()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Signature {
+    ret: Type,
+    params: Vec<Type>,
+    defined: bool,
+}
+
+// where a variable lives
+#[derive(Clone, Debug, PartialEq)]
+enum Home {
+    Register(Register),
+    // counting up from SP when the function's own stack is empty
+    Frame(u16),
+    Global(String),
+}
+
+#[derive(Clone, Debug)]
+struct Variable {
+    ty: Type,
+    home: Home,
+}
+
+// what an expression left where, and the register it's holding onto, if it
+// needed one
+#[derive(Clone, Debug)]
+struct Value {
+    op: Operand,
+    ty: Type,
+    temp: Option<Register>,
+}
+
+impl Value {
+    fn constant(n: u16, ty: Type) -> Value {
+        Value { op: Operand::Literal(n), ty, temp: None }
+    }
+}
+
+// the usual arithmetic conversions, such as they are: unsigned wins, and
+// pointers stay pointers
+fn arithmetic_type(op: BinOp, l: &Type, r: &Type) -> Type {
+    match (l.decay(), r.decay()) {
+        (Type::Pointer(_), Type::Pointer(_)) if op == BinOp::Sub => Type::I16,
+        (p @ Type::Pointer(_), _) | (_, p @ Type::Pointer(_)) => p,
+        (Type::I16, Type::I16) => Type::I16,
+        _ => Type::U16,
+    }
+}
+
+fn arithmetic(op: BinOp, signed: bool, b: Operand, a: Operand) -> Opcode {
+    match op {
+        BinOp::Add => Opcode::ADD(b, a),
+        BinOp::Sub => Opcode::SUB(b, a),
+        // the low word is the same either way
+        BinOp::Mul => Opcode::MUL(b, a),
+        BinOp::Div if signed => Opcode::DVI(b, a),
+        BinOp::Div => Opcode::DIV(b, a),
+        BinOp::Mod if signed => Opcode::MDI(b, a),
+        BinOp::Mod => Opcode::MOD(b, a),
+        BinOp::Shl => Opcode::SHL(b, a),
+        BinOp::Shr if signed => Opcode::ASR(b, a),
+        BinOp::Shr => Opcode::SHR(b, a),
+        BinOp::And => Opcode::AND(b, a),
+        BinOp::Or => Opcode::BOR(b, a),
+        BinOp::Xor => Opcode::XOR(b, a),
+        _ => unreachable!("{:?} isn't arithmetic", op),
+    }
+}
+
+// the conditional that runs the next instruction when `b op a`. there's
+// nothing for <= and >=, so they're done as the opposite of > and <
+fn test(op: BinOp, signed: bool, b: Operand, a: Operand) -> Opcode {
+    match op {
+        BinOp::Eq => Opcode::IFE(b, a),
+        BinOp::Ne => Opcode::IFN(b, a),
+        BinOp::Lt if signed => Opcode::IFU(b, a),
+        BinOp::Lt => Opcode::IFL(b, a),
+        BinOp::Gt if signed => Opcode::IFA(b, a),
+        BinOp::Gt => Opcode::IFG(b, a),
+        _ => unreachable!("no single test for {:?}", op),
+    }
+}
+
+fn negate(op: BinOp) -> BinOp {
+    match op {
+        BinOp::Eq => BinOp::Ne,
+        BinOp::Ne => BinOp::Eq,
+        BinOp::Lt => BinOp::Ge,
+        BinOp::Ge => BinOp::Lt,
+        BinOp::Gt => BinOp::Le,
+        BinOp::Le => BinOp::Gt,
+        op => op,
+    }
+}
+
+// the same test with the operands the other way round
+fn mirror(op: BinOp) -> BinOp {
+    match op {
+        BinOp::Lt => BinOp::Gt,
+        BinOp::Gt => BinOp::Lt,
+        BinOp::Le => BinOp::Ge,
+        BinOp::Ge => BinOp::Le,
+        op => op,
+    }
+}
+
+// the value of an expression made only of numbers, and whether it's signed
+fn fold(e: &Expr) -> Option<(u16, bool)> {
+    match e.kind {
+        ExprKind::Number(n) => Some((n, true)),
+        ExprKind::Cast(ref ty, ref e) if !ty.is_pointer() => fold(e).map(|(n, _)| (n, ty.is_signed())),
+        ExprKind::Unary(op, ref e) => fold(e).map(|(n, signed)| match op {
+            UnOp::Neg => (n.wrapping_neg(), signed),
+            UnOp::Not => ((n == 0) as u16, true),
+            UnOp::BitNot => (!n, signed),
+        }),
+        ExprKind::Binary(op, ref l, ref r) => {
+            let ((l, ls), (r, rs)) = (fold(l)?, fold(r)?);
+            let signed = ls && rs;
+            let (sl, sr) = (l as i16, r as i16);
+            let compare = |lt: bool, eq: bool| (match op {
+                BinOp::Lt => lt,
+                BinOp::Le => lt || eq,
+                BinOp::Gt => !lt && !eq,
+                BinOp::Ge => !lt,
+                BinOp::Eq => eq,
+                _ => !eq,
+            }) as u16;
+            let n = match op {
+                BinOp::Add => l.wrapping_add(r),
+                BinOp::Sub => l.wrapping_sub(r),
+                BinOp::Mul => l.wrapping_mul(r),
+                // dividing by zero gives zero, like DIV
+                BinOp::Div | BinOp::Mod if r == 0 => 0,
+                BinOp::Div if signed => sl.wrapping_div(sr) as u16,
+                BinOp::Div => l / r,
+                BinOp::Mod if signed => sl.wrapping_rem(sr) as u16,
+                BinOp::Mod => l % r,
+                BinOp::Shl => l.checked_shl(r as u32).unwrap_or(0),
+                BinOp::Shr if signed => (sl >> r.min(15)) as u16,
+                BinOp::Shr => l.checked_shr(r as u32).unwrap_or(0),
+                BinOp::And => l & r,
+                BinOp::Or => l | r,
+                BinOp::Xor => l ^ r,
+                BinOp::LogicalAnd => (l != 0 && r != 0) as u16,
+                BinOp::LogicalOr => (l != 0 || r != 0) as u16,
+                _ if signed => compare(sl < sr, l == r),
+                _ => compare(l < r, l == r),
+            };
+            Some((n, signed || op.is_comparison()))
+        },
+        _ => None,
+    }
+}
+
+// how often each name is used, with uses in loops counting for more, and
+// which names have their address taken, so can't be in registers
+#[derive(Default)]
+struct Usage {
+    uses: HashMap<String, usize>,
+    addressed: HashSet<String>,
+    // every local, by where it's declared
+    locals: Vec<(usize, String, Type)>,
+}
+
+impl Usage {
+    fn expr(&mut self, e: &Expr, weight: usize) {
+        match e.kind {
+            ExprKind::Var(ref name) => *self.uses.entry(name.clone()).or_insert(0) += weight,
+            ExprKind::AddressOf(ref inner) => {
+                if let ExprKind::Var(ref name) = inner.kind {
+                    self.addressed.insert(name.clone());
+                }
+                self.expr(inner, weight);
+            },
+            ExprKind::Number(_) | ExprKind::Str(_) => (),
+            ExprKind::Call(_, ref args) => args.iter().for_each(|a| self.expr(a, weight)),
+            ExprKind::Unary(_, ref e) | ExprKind::Deref(ref e) | ExprKind::Cast(_, ref e) |
+            ExprKind::Step { expr: ref e, .. } => self.expr(e, weight),
+            ExprKind::Binary(_, ref l, ref r) | ExprKind::Assign(_, ref l, ref r) | ExprKind::Index(ref l, ref r) => {
+                self.expr(l, weight);
+                self.expr(r, weight);
+            },
+        }
+    }
+
+    fn stmt(&mut self, s: &Stmt, weight: usize) {
+        // a loop's body probably runs more than once
+        let inner = weight.saturating_mul(8);
+        match *s {
+            Stmt::Block(ref stmts) => stmts.iter().for_each(|s| self.stmt(s, weight)),
+            Stmt::Declare(ref d) => {
+                self.locals.push((d.span.start, d.name.clone(), d.ty.clone()));
+                match d.init {
+                    Some(Init::Expr(ref e)) => self.expr(e, weight),
+                    Some(Init::List(ref values)) => values.iter().for_each(|e| self.expr(e, weight)),
+                    None => (),
+                }
+            },
+            Stmt::If(ref c, ref t, ref e) => {
+                self.expr(c, weight);
+                self.stmt(t, weight);
+                if let Some(ref e) = *e {
+                    self.stmt(e, weight);
+                }
+            },
+            Stmt::While(ref c, ref body) => {
+                self.expr(c, inner);
+                self.stmt(body, inner);
+            },
+            Stmt::For(ref init, ref c, ref step, ref body) => {
+                if let Some(ref init) = *init {
+                    self.stmt(init, weight);
+                }
+                for e in c.iter().chain(step.iter()) {
+                    self.expr(e, inner);
+                }
+                self.stmt(body, inner);
+            },
+            Stmt::Return(ref e, _) => {
+                if let Some(ref e) = *e {
+                    self.expr(e, weight);
+                }
+            },
+            Stmt::Expr(ref e) => self.expr(e, weight),
+            Stmt::Break(_) | Stmt::Continue(_) => (),
+        }
+    }
+}
+
+// the whole file's output, and what every function can see
+#[derive(Default)]
+pub struct Program {
+    functions: HashMap<String, Signature>,
+    globals: HashMap<String, Type>,
+    strings: Vec<Vec<u16>>,
+    pub items: Vec<Intermediate>,
+    pub errors: Vec<(CompileError, Span)>,
+    // functions that are called but only declared, for the linker to find
+    pub externs: Vec<(String, Span)>,
+}
+
+impl Program {
+    fn string(&mut self, s: &[u16]) -> Operand {
+        let label = format!("__string_{}", self.strings.len());
+        self.strings.push(s.to_vec());
+        Operand::Label(label)
+    }
+
+    fn signature(&mut self, f: &Function) -> Result<()> {
+        if self.globals.contains_key(&f.name) {
+            return Err((CompileError::Redefined(f.name.clone()), f.span));
+        }
+        let signature = Signature {
+            ret: f.ty.clone(),
+            params: f.params.iter().map(|p| p.ty.clone()).collect(),
+            defined: f.body.is_some(),
+        };
+        let declared = match self.functions.get_mut(&f.name) {
+            Some(existing) if existing.defined && signature.defined => Err((CompileError::Redefined(f.name.clone()), f.span)),
+            Some(existing) if existing.ret != signature.ret || existing.params != signature.params =>
+                Err((CompileError::Conflicting(f.name.clone()), f.span)),
+            Some(existing) => {
+                existing.defined |= signature.defined;
+                Ok(())
+            },
+            None => {
+                self.functions.insert(f.name.clone(), signature);
+                Ok(())
+            },
+        };
+        // functions keep their names for assembly to call them by, so a
+        // register name is an error. it's still declared, so calls to it
+        // don't pile more errors on top
+        if reserved(&f.name) {
+            return Err((CompileError::Reserved(f.name.clone()), f.span));
+        }
+        declared
+    }
+
+    // what goes in memory for a global: numbers, strings and the addresses
+    // of other globals
+    fn constant(&mut self, e: &Expr) -> Result<Operand> {
+        if let Some((n, _)) = fold(e) {
+            return Ok(Operand::Literal(n));
+        }
+        match e.kind {
+            ExprKind::Str(ref s) => Ok(self.string(s)),
+            ExprKind::Var(ref name) if matches!(self.globals.get(name), Some(&Type::Array(..))) => Ok(Operand::Label(global_label(name))),
+            ExprKind::AddressOf(ref inner) => match inner.kind {
+                ExprKind::Var(ref name) if self.globals.contains_key(name) => Ok(Operand::Label(global_label(name))),
+                _ => Err((CompileError::NotConstant, e.span)),
+            },
+            ExprKind::Cast(_, ref inner) => self.constant(inner),
+            _ => Err((CompileError::NotConstant, e.span)),
+        }
+    }
+
+    fn global(&mut self, d: &Declaration) -> Result<()> {
+        if self.globals.contains_key(&d.name) || self.functions.contains_key(&d.name) {
+            return Err((CompileError::Redefined(d.name.clone()), d.span));
+        }
+        if d.ty == Type::Void {
+            return Err((CompileError::VoidVariable(d.name.clone()), d.span));
+        }
+        self.globals.insert(d.name.clone(), d.ty.clone());
+        let words = match (&d.ty, &d.init) {
+            (&Type::Array(_, n), &None) => {
+                self.items.push(Intermediate::Label(global_label(&d.name)));
+                self.items.push(Intermediate::Reserve(n as usize));
+                return Ok(());
+            },
+            (&Type::Array(_, n), Some(init)) => {
+                let mut words = match *init {
+                    Init::List(ref values) => values.iter().map(|e| self.constant(e)).collect::<Result<Vec<_>>>()?,
+                    Init::Expr(Expr { kind: ExprKind::Str(ref s), .. }) => s.iter().map(|&c| Operand::Literal(c)).collect(),
+                    Init::Expr(ref e) => return Err((CompileError::NotConstant, e.span)),
+                };
+                if words.len() > n as usize {
+                    return Err((CompileError::TooManyValues(d.name.clone()), d.span));
+                }
+                words.resize(n as usize, Operand::Literal(0));
+                words
+            },
+            (_, &None) => vec![Operand::Literal(0)],
+            (_, &Some(Init::Expr(ref e))) => vec![self.constant(e)?],
+            (_, &Some(Init::List(_))) => return Err((CompileError::TooManyValues(d.name.clone()), d.span)),
+        };
+        self.items.push(Intermediate::Label(global_label(&d.name)));
+        self.items.push(Intermediate::Words(words));
+        Ok(())
+    }
+}
+
+struct FunctionGen<'p> {
+    program: &'p mut Program,
+    name: String,
+    ret: Type,
+    code: Vec<Intermediate>,
+    scopes: Vec<HashMap<String, Variable>>,
+    // where each local lives, by where it's declared
+    homes: HashMap<usize, Home>,
+    frame: u16,
+    // words pushed since the frame was set up, which Pick has to get past
+    depth: u16,
+    // the registers expressions can use, and the ones they are
+    temps: Vec<Register>,
+    busy: HashSet<Register>,
+    // the callee-saved registers the function touches
+    saved: HashSet<Register>,
+    // where continue and break go
+    loops: Vec<(String, String)>,
+    labels: usize,
+    exit: String,
+}
+
+impl<'p> FunctionGen<'p> {
+    fn new(program: &'p mut Program, f: &Function, body: &[Stmt]) -> FunctionGen<'p> {
+        let mut usage = Usage::default();
+        for p in f.params.iter() {
+            usage.locals.push((p.span.start, p.name.clone(), p.ty.clone()));
+        }
+        body.iter().for_each(|s| usage.stmt(s, 1));
+
+        // the most used scalars whose address isn't needed get registers,
+        // everything else goes in the frame
+        let mut candidates: Vec<_> = usage.locals.iter()
+            .filter(|(_, name, ty)| !matches!(*ty, Type::Array(..)) && !usage.addressed.contains(name))
+            .filter(|(_, name, _)| usage.uses.get(name).is_some_and(|&n| n > 0))
+            .collect();
+        candidates.sort_by_key(|(_, name, _)| ::std::cmp::Reverse(usage.uses[name]));
+        let mut homes = HashMap::new();
+        let mut saved = HashSet::new();
+        for (&&(at, _, _), &reg) in candidates.iter().zip(CALLEE_SAVED.iter()) {
+            homes.insert(at, Home::Register(reg));
+            saved.insert(reg);
+        }
+        let mut frame = 0;
+        for &(at, _, ref ty) in usage.locals.iter() {
+            homes.entry(at).or_insert_with(|| {
+                frame += ty.size();
+                Home::Frame(frame - ty.size())
+            });
+        }
+        let temps = ARGUMENT_REGISTERS.iter().chain(CALLEE_SAVED.iter().rev())
+            .filter(|r| !saved.contains(r))
+            .cloned()
+            .collect();
+        FunctionGen {
+            program,
+            name: f.name.clone(),
+            ret: f.ty.clone(),
+            code: vec![],
+            scopes: vec![HashMap::new()],
+            homes,
+            frame,
+            depth: 0,
+            temps,
+            busy: HashSet::new(),
+            saved,
+            loops: vec![],
+            labels: 0,
+            exit: format!("__{}_return", f.name),
+        }
+    }
+
+    fn emit(&mut self, op: Opcode) {
+        self.code.push(Intermediate::Opcode(op));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.code.push(Intermediate::Label(label.to_owned()));
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("__{}_{}", self.name, self.labels)
+    }
+
+    fn jump(&mut self, label: &str) {
+        self.emit(Opcode::SET(Operand::Pc, Operand::Label(label.to_owned())));
+    }
+
+    fn push(&mut self, op: Operand) {
+        self.emit(Opcode::SET(Operand::Push, op));
+        self.depth += 1;
+    }
+
+    fn pop(&mut self, reg: Register) {
+        self.emit(Opcode::SET(Operand::Register(reg), Operand::Pop));
+        self.depth -= 1;
+    }
+
+    // a word of the frame, wherever SP has got to
+    fn frame_slot(&self, slot: u16) -> Operand {
+        match self.depth + slot {
+            0 => Operand::Peek,
+            n => Operand::Pick(n),
+        }
+    }
+
+    fn alloc(&mut self, span: Span) -> Result<Register> {
+        let reg = self.temps.iter().find(|r| !self.busy.contains(r)).cloned()
+            .ok_or((CompileError::TooComplex, span))?;
+        self.busy.insert(reg);
+        if CALLEE_SAVED.contains(&reg) {
+            self.saved.insert(reg);
+        }
+        Ok(reg)
+    }
+
+    fn release(&mut self, value: &Value) {
+        if let Some(reg) = value.temp {
+            self.busy.remove(&reg);
+        }
+    }
+
+    fn free_registers(&self) -> usize {
+        self.temps.len() - self.busy.len()
+    }
+
+    fn lookup(&self, name: &str, span: Span) -> Result<Variable> {
+        self.scopes.iter().rev().filter_map(|s| s.get(name)).next().cloned()
+            .or_else(|| self.program.globals.get(name).map(|ty| Variable { ty: ty.clone(), home: Home::Global(global_label(name)) }))
+            .ok_or_else(|| (CompileError::Undeclared(name.to_owned()), span))
+    }
+
+    // roughly how many registers working out `e` takes
+    fn needs(&self, e: &Expr) -> usize {
+        if fold(e).is_some() {
+            return 0;
+        }
+        match e.kind {
+            ExprKind::Str(_) => 0,
+            ExprKind::Var(ref name) => match self.lookup(name, e.span) {
+                Ok(Variable { home: Home::Frame(_), ty: Type::Array(..) }) => 1,
+                _ => 0,
+            },
+            ExprKind::Binary(_, ref l, ref r) | ExprKind::Assign(_, ref l, ref r) | ExprKind::Index(ref l, ref r) => {
+                let (l, r) = (self.needs(l).max(1), self.needs(r));
+                if l == r { l + 1 } else { l.max(r) }
+            },
+            ExprKind::Unary(_, ref e) | ExprKind::Deref(ref e) | ExprKind::AddressOf(ref e) |
+            ExprKind::Step { expr: ref e, .. } => self.needs(e).max(1),
+            ExprKind::Cast(_, ref e) => self.needs(e),
+            _ => 1,
+        }
+    }
+
+    // a register of its own holding the value, which can be changed
+    fn in_register(&mut self, value: Value, span: Span) -> Result<Register> {
+        let reg = match value.temp {
+            Some(reg) => reg,
+            None => self.alloc(span)?,
+        };
+        if value.op != Operand::Register(reg) {
+            self.emit(Opcode::SET(Operand::Register(reg), value.op));
+        }
+        Ok(reg)
+    }
+
+    fn register(&mut self, e: &Expr) -> Result<(Register, Type)> {
+        let value = self.value(e)?;
+        let ty = value.ty.clone();
+        Ok((self.in_register(value, e.span)?, ty))
+    }
+
+    // the value of an expression that has to have one
+    fn value(&mut self, e: &Expr) -> Result<Value> {
+        let value = self.expr(e)?;
+        if value.ty == Type::Void {
+            return Err((CompileError::VoidValue, e.span));
+        }
+        Ok(value)
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<Value> {
+        if let Some((n, signed)) = fold(e) {
+            return Ok(Value::constant(n, if signed { Type::I16 } else { Type::U16 }));
+        }
+        match e.kind {
+            ExprKind::Str(ref s) => Ok(Value { op: self.program.string(s), ty: Type::Pointer(Box::new(Type::U16)), temp: None }),
+            ExprKind::Var(ref name) => {
+                let var = self.lookup(name, e.span)?;
+                match (var.home, &var.ty) {
+                    (Home::Global(name), &Type::Array(..)) => Ok(Value { op: Operand::Label(name), ty: var.ty.decay(), temp: None }),
+                    (Home::Frame(slot), &Type::Array(..)) => {
+                        let reg = self.frame_address(slot, e.span)?;
+                        Ok(Value { op: Operand::Register(reg), ty: var.ty.decay(), temp: Some(reg) })
+                    },
+                    (home, _) => Ok(Value { op: self.place_of(&home), ty: var.ty, temp: None }),
+                }
+            },
+            ExprKind::Call(ref name, ref args) => self.call(name, args, e.span),
+            ExprKind::Unary(UnOp::Not, _) | ExprKind::Binary(BinOp::LogicalAnd, ..) | ExprKind::Binary(BinOp::LogicalOr, ..) => {
+                let reg = self.alloc(e.span)?;
+                let skip = self.new_label();
+                self.emit(Opcode::SET(Operand::Register(reg), Operand::Literal(0)));
+                self.branch(e, &skip, false)?;
+                self.emit(Opcode::SET(Operand::Register(reg), Operand::Literal(1)));
+                self.label(&skip);
+                Ok(Value { op: Operand::Register(reg), ty: Type::I16, temp: Some(reg) })
+            },
+            ExprKind::Unary(op, ref inner) => {
+                let (reg, ty) = self.register(inner)?;
+                match op {
+                    // times -1, which fits in the instruction
+                    UnOp::Neg => self.emit(Opcode::MUL(Operand::Register(reg), Operand::Literal(0xffff))),
+                    _ => self.emit(Opcode::XOR(Operand::Register(reg), Operand::Literal(0xffff))),
+                }
+                Ok(Value { op: Operand::Register(reg), ty, temp: Some(reg) })
+            },
+            ExprKind::Binary(op, ref l, ref r) if op.is_comparison() => self.comparison(op, l, r, e.span),
+            ExprKind::Binary(op, ref l, ref r) => self.binary(op, l, r, e.span),
+            ExprKind::Assign(op, ref target, ref value) => self.assign(op, target, value),
+            ExprKind::Index(..) | ExprKind::Deref(_) => self.place(e),
+            ExprKind::AddressOf(ref inner) => self.address(inner, e.span),
+            ExprKind::Step { increment, prefix, ref expr } => {
+                let place = self.place(expr)?;
+                let step = |op: Operand| if increment {
+                    Opcode::ADD(op, Operand::Literal(1))
+                } else {
+                    Opcode::SUB(op, Operand::Literal(1))
+                };
+                if prefix {
+                    self.emit(step(place.op.clone()));
+                    return Ok(place);
+                }
+                let reg = self.alloc(e.span)?;
+                self.emit(Opcode::SET(Operand::Register(reg), place.op.clone()));
+                self.emit(step(place.op.clone()));
+                self.release(&place);
+                Ok(Value { op: Operand::Register(reg), ty: place.ty, temp: Some(reg) })
+            },
+            ExprKind::Cast(ref ty, ref inner) => {
+                let mut value = self.expr(inner)?;
+                value.ty = ty.clone();
+                Ok(value)
+            },
+            ExprKind::Number(_) => unreachable!("numbers are folded"),
+        }
+    }
+
+    fn place_of(&self, home: &Home) -> Operand {
+        match *home {
+            Home::Register(reg) => Operand::Register(reg),
+            Home::Frame(slot) => self.frame_slot(slot),
+            Home::Global(ref name) => Operand::LabelDeref(name.clone()),
+        }
+    }
+
+    fn frame_address(&mut self, slot: u16, span: Span) -> Result<Register> {
+        let reg = self.alloc(span)?;
+        let offset = self.depth + slot;
+        self.emit(Opcode::SET(Operand::Register(reg), Operand::Sp));
+        if offset > 0 {
+            self.emit(Opcode::ADD(Operand::Register(reg), Operand::Literal(offset)));
+        }
+        Ok(reg)
+    }
+
+    fn address(&mut self, e: &Expr, span: Span) -> Result<Value> {
+        match e.kind {
+            ExprKind::Var(ref name) => {
+                let var = self.lookup(name, e.span)?;
+                let ty = Type::Pointer(Box::new(var.ty.target().cloned().unwrap_or_else(|| var.ty.clone())));
+                match var.home {
+                    Home::Global(name) => Ok(Value { op: Operand::Label(name), ty, temp: None }),
+                    Home::Frame(slot) => {
+                        let reg = self.frame_address(slot, span)?;
+                        Ok(Value { op: Operand::Register(reg), ty, temp: Some(reg) })
+                    },
+                    Home::Register(_) => unreachable!("variables whose address is taken live in memory"),
+                }
+            },
+            ExprKind::Deref(ref pointer) => self.value(pointer),
+            ExprKind::Index(ref base, ref index) => {
+                let sum = Expr { kind: ExprKind::Binary(BinOp::Add, base.clone(), index.clone()), span };
+                self.value(&sum)
+            },
+            _ => Err((CompileError::NoAddress, span)),
+        }
+    }
+
+    // somewhere an expression can be stored
+    fn place(&mut self, e: &Expr) -> Result<Value> {
+        match e.kind {
+            ExprKind::Var(ref name) => {
+                let var = self.lookup(name, e.span)?;
+                if let Type::Array(..) = var.ty {
+                    return Err((CompileError::NotAssignable, e.span));
+                }
+                Ok(Value { op: self.place_of(&var.home), ty: var.ty, temp: None })
+            },
+            ExprKind::Deref(ref pointer) => {
+                let value = self.value(pointer)?;
+                let ty = value.ty.target().cloned()
+                    .ok_or_else(|| (CompileError::NotAPointer(value.ty.clone()), pointer.span))?;
+                let (op, temp) = match value.op {
+                    Operand::Register(reg) => (Operand::RegisterDeref(reg), value.temp),
+                    Operand::Literal(n) => (Operand::LiteralDeref(n), None),
+                    Operand::Label(ref label) => (Operand::LabelDeref(label.clone()), None),
+                    _ => {
+                        let reg = self.in_register(value, e.span)?;
+                        (Operand::RegisterDeref(reg), Some(reg))
+                    },
+                };
+                Ok(Value { op, ty, temp })
+            },
+            ExprKind::Index(ref base, ref index) => self.element(base, index, e.span),
+            _ => Err((CompileError::NotAssignable, e.span)),
+        }
+    }
+
+    // base[index], using whichever addressing mode fits
+    fn element(&mut self, base: &Expr, index: &Expr, span: Span) -> Result<Value> {
+        let constant = fold(index).map(|(n, _)| n);
+        // arrays in the frame and globals have addresses known up front
+        if let ExprKind::Var(ref name) = base.kind {
+            let var = self.lookup(name, base.span)?;
+            let ty = var.ty.target().cloned();
+            match (var.home, constant, ty) {
+                (Home::Frame(slot), Some(n), Some(ty)) if matches!(var.ty, Type::Array(..)) =>
+                    return Ok(Value { op: self.frame_slot(slot.wrapping_add(n)), ty, temp: None }),
+                (Home::Global(name), Some(n), Some(ty)) if matches!(var.ty, Type::Array(..)) =>
+                    return Ok(Value { op: Operand::LabelPlusDeref(name, n), ty, temp: None }),
+                (Home::Global(name), None, Some(ty)) if matches!(var.ty, Type::Array(..)) => {
+                    let value = self.value(index)?;
+                    return Ok(match value.op {
+                        Operand::Register(reg) => Value { op: Operand::RegisterPlusLabelDeref(reg, name), ty, temp: value.temp },
+                        _ => {
+                            let reg = self.in_register(value, index.span)?;
+                            Value { op: Operand::RegisterPlusLabelDeref(reg, name), ty, temp: Some(reg) }
+                        },
+                    });
+                },
+                _ => (),
+            }
+        }
+        let pointer = self.value(base)?;
+        let ty = pointer.ty.target().cloned()
+            .ok_or_else(|| (CompileError::NotAPointer(pointer.ty.clone()), base.span))?;
+        if let Some(n) = constant {
+            return Ok(match pointer.op {
+                Operand::Register(reg) => Value { op: Operand::RegisterPlusDeref(reg, n), ty, temp: pointer.temp },
+                Operand::Label(label) => Value { op: Operand::LabelPlusDeref(label, n), ty, temp: None },
+                _ => {
+                    let reg = self.in_register(pointer, span)?;
+                    Value { op: Operand::RegisterPlusDeref(reg, n), ty, temp: Some(reg) }
+                },
+            });
+        }
+        let reg = self.in_register(pointer, span)?;
+        let index = self.value(index)?;
+        self.emit(Opcode::ADD(Operand::Register(reg), index.op.clone()));
+        self.release(&index);
+        Ok(Value { op: Operand::RegisterDeref(reg), ty, temp: Some(reg) })
+    }
+
+    // the right operand of something done to `reg`, pushing `reg` out of
+    // the way first if there aren't enough registers to work it out. the
+    // instruction then works on the pushed copy, which is popped back
+    fn operate<F>(&mut self, reg: Register, right: &Expr, span: Span, op: F) -> Result<Register>
+        where F: Fn(Operand, Operand) -> Opcode {
+        if self.needs(right) <= self.free_registers() {
+            let value = self.value(right)?;
+            self.emit(op(Operand::Register(reg), value.op.clone()));
+            self.release(&value);
+            return Ok(reg);
+        }
+        self.push(Operand::Register(reg));
+        self.busy.remove(&reg);
+        let value = self.value(right)?;
+        self.emit(op(Operand::Peek, value.op.clone()));
+        self.release(&value);
+        let reg = self.alloc(span)?;
+        self.pop(reg);
+        Ok(reg)
+    }
+
+    fn binary(&mut self, op: BinOp, l: &Expr, r: &Expr, span: Span) -> Result<Value> {
+        // a constant on the left can go on the right when the order doesn't matter
+        let commutes = matches!(op, BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor);
+        let (l, r) = if commutes && fold(l).is_some() { (r, l) } else { (l, r) };
+        let (reg, lt) = self.register(l)?;
+        // only the type's needed from the right, which has to be worked out anyway
+        let rt = self.type_of(r)?;
+        let ty = arithmetic_type(op, &lt, &rt);
+        let signed = ty.is_signed();
+        let reg = self.operate(reg, r, span, |b, a| arithmetic(op, signed, b, a))?;
+        Ok(Value { op: Operand::Register(reg), ty, temp: Some(reg) })
+    }
+
+    // the type of an expression without generating anything for it
+    fn type_of(&self, e: &Expr) -> Result<Type> {
+        if let Some((_, signed)) = fold(e) {
+            return Ok(if signed { Type::I16 } else { Type::U16 });
+        }
+        Ok(match e.kind {
+            ExprKind::Str(_) => Type::Pointer(Box::new(Type::U16)),
+            ExprKind::Var(ref name) => self.lookup(name, e.span)?.ty.decay(),
+            ExprKind::Call(ref name, _) => self.program.functions.get(name)
+                .ok_or_else(|| (CompileError::UnknownFunction(name.clone()), e.span))?.ret.clone(),
+            ExprKind::Unary(UnOp::Not, _) => Type::I16,
+            ExprKind::Unary(_, ref e) => self.type_of(e)?,
+            ExprKind::Binary(op, _, _) if op.is_comparison() || op == BinOp::LogicalAnd || op == BinOp::LogicalOr => Type::I16,
+            ExprKind::Binary(op, ref l, ref r) => arithmetic_type(op, &self.type_of(l)?, &self.type_of(r)?),
+            ExprKind::Assign(_, ref target, _) | ExprKind::Step { expr: ref target, .. } => self.type_of(target)?,
+            ExprKind::Index(ref base, _) | ExprKind::Deref(ref base) => {
+                let ty = self.type_of(base)?;
+                ty.target().cloned().ok_or((CompileError::NotAPointer(ty.clone()), base.span))?
+            },
+            ExprKind::AddressOf(ref e) => Type::Pointer(Box::new(self.type_of(e)?)),
+            ExprKind::Cast(ref ty, _) => ty.clone(),
+            ExprKind::Number(_) => Type::I16,
+        })
+    }
+
+    // both sides of a comparison, ready for a test, and whether it's signed.
+    // a constant goes second, where it can be a short literal
+    fn operands(&mut self, op: BinOp, l: &Expr, r: &Expr) -> Result<(BinOp, Value, Value, bool)> {
+        let signed = arithmetic_type(op, &self.type_of(l)?, &self.type_of(r)?).is_signed();
+        let (op, l, r) = if fold(l).is_some() && fold(r).is_none() { (mirror(op), r, l) } else { (op, l, r) };
+        let left = self.value(l)?;
+        let right = if self.needs(r) <= self.free_registers() {
+            self.value(r)?
+        } else {
+            // keep the left side safe in a register of its own
+            let reg = self.in_register(left.clone(), l.span)?;
+            let right = self.value(r)?;
+            return Ok((op, Value { op: Operand::Register(reg), ty: left.ty, temp: Some(reg) }, right, signed));
+        };
+        Ok((op, left, right, signed))
+    }
+
+    // jumps to `target` if `l op r`
+    fn jump_if(&mut self, op: BinOp, left: &Value, right: &Value, signed: bool, target: &str) {
+        let (b, a) = (left.op.clone(), right.op.clone());
+        match op {
+            BinOp::Le | BinOp::Ge => {
+                let skip = self.new_label();
+                self.emit(test(negate(op), signed, b, a));
+                self.jump(&skip);
+                self.jump(target);
+                self.label(&skip);
+            },
+            _ => {
+                self.emit(test(op, signed, b, a));
+                self.jump(target);
+            },
+        }
+    }
+
+    fn comparison(&mut self, op: BinOp, l: &Expr, r: &Expr, span: Span) -> Result<Value> {
+        let (op, left, right, signed) = self.operands(op, l, r)?;
+        let reg = self.alloc(span)?;
+        let set = |n| Opcode::SET(Operand::Register(reg), Operand::Literal(n));
+        // 1 unless the opposite's true, for the tests there isn't an instruction for
+        let (op, first) = match op {
+            BinOp::Le | BinOp::Ge => (negate(op), 1),
+            op => (op, 0),
+        };
+        self.emit(set(first));
+        self.emit(test(op, signed, left.op.clone(), right.op.clone()));
+        self.emit(set(1 - first));
+        self.release(&left);
+        self.release(&right);
+        Ok(Value { op: Operand::Register(reg), ty: Type::I16, temp: Some(reg) })
+    }
+
+    // jumps to `target` if `e` is true, or if it's false when `when` is
+    fn branch(&mut self, e: &Expr, target: &str, when: bool) -> Result<()> {
+        if let Some((n, _)) = fold(e) {
+            if (n != 0) == when {
+                self.jump(target);
+            }
+            return Ok(());
+        }
+        match e.kind {
+            ExprKind::Unary(UnOp::Not, ref inner) => self.branch(inner, target, !when),
+            ExprKind::Binary(BinOp::LogicalAnd, ref l, ref r) | ExprKind::Binary(BinOp::LogicalOr, ref l, ref r) => {
+                let and = matches!(e.kind, ExprKind::Binary(BinOp::LogicalAnd, ..));
+                // jumping when both are true, or when either's false, is
+                // the same test of the left and right
+                if and != when {
+                    self.branch(l, target, when)?;
+                    self.branch(r, target, when)
+                } else {
+                    let skip = self.new_label();
+                    self.branch(l, &skip, !when)?;
+                    self.branch(r, target, when)?;
+                    self.label(&skip);
+                    Ok(())
+                }
+            },
+            ExprKind::Binary(op, ref l, ref r) if op.is_comparison() => {
+                let op = if when { op } else { negate(op) };
+                let (op, left, right, signed) = self.operands(op, l, r)?;
+                self.jump_if(op, &left, &right, signed, target);
+                self.release(&left);
+                self.release(&right);
+                Ok(())
+            },
+            _ => {
+                let value = self.value(e)?;
+                let op = if when { BinOp::Ne } else { BinOp::Eq };
+                self.jump_if(op, &value, &Value::constant(0, Type::I16), false, target);
+                self.release(&value);
+                Ok(())
+            },
+        }
+    }
+
+    fn assign(&mut self, op: Option<BinOp>, target: &Expr, value: &Expr) -> Result<Value> {
+        let op = match op {
+            Some(op) => op,
+            None => {
+                let value = self.value(value)?;
+                let place = self.place(target)?;
+                if value.op != place.op {
+                    self.emit(Opcode::SET(place.op.clone(), value.op.clone()));
+                }
+                self.release(&place);
+                return Ok(Value { ty: place.ty, ..value });
+            },
+        };
+        // the arithmetic instructions work on memory as well as registers
+        let place = self.place(target)?;
+        let signed = arithmetic_type(op, &place.ty, &self.type_of(value)?).is_signed();
+        let value = self.value(value)?;
+        self.emit(arithmetic(op, signed, place.op.clone(), value.op.clone()));
+        self.release(&value);
+        Ok(place)
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr], span: Span) -> Result<Value> {
+        let signature = self.program.functions.get(name).cloned()
+            .ok_or_else(|| (CompileError::UnknownFunction(name.to_owned()), span))?;
+        if signature.params.len() != args.len() {
+            return Err((CompileError::Arguments(name.to_owned(), signature.params.len(), args.len()), span));
+        }
+        if !signature.defined && !self.program.externs.iter().any(|(n, _)| n == name) {
+            self.program.externs.push((name.to_owned(), span));
+        }
+        // A, B and C don't survive the call, so whatever's in them goes on
+        // the stack until it's over
+        let live: Vec<Register> = CALLER_SAVED.iter().filter(|r| self.busy.contains(r)).cloned().collect();
+        for &reg in live.iter() {
+            self.push(Operand::Register(reg));
+            self.busy.remove(&reg);
+        }
+        // straight into the registers, unless working out one argument
+        // could upset another that's already there
+        if args.len() <= ARGUMENT_REGISTERS.len() && args.iter().skip(1).all(|a| self.needs(a) == 0) {
+            for (arg, &reg) in args.iter().zip(ARGUMENT_REGISTERS.iter()) {
+                let value = self.value(arg)?;
+                if value.op != Operand::Register(reg) {
+                    self.emit(Opcode::SET(Operand::Register(reg), value.op.clone()));
+                }
+                self.release(&value);
+            }
+        } else {
+            // everything on the stack, last first, then the first three off it
+            for arg in args.iter().rev() {
+                let value = self.value(arg)?;
+                self.push(value.op.clone());
+                self.release(&value);
+            }
+            for &reg in ARGUMENT_REGISTERS.iter().take(args.len()) {
+                self.pop(reg);
+            }
+        }
+        self.emit(Opcode::JSR(Operand::Label(name.to_owned())));
+        let on_stack = args.len().saturating_sub(ARGUMENT_REGISTERS.len()) as u16;
+        if on_stack > 0 {
+            self.emit(Opcode::ADD(Operand::Sp, Operand::Literal(on_stack)));
+            self.depth -= on_stack;
+        }
+        self.busy.extend(live.iter().cloned());
+        let result = match signature.ret {
+            Type::Void => Value::constant(0, Type::Void),
+            ty => {
+                let reg = self.alloc(span)?;
+                if reg != RETURN_REGISTER {
+                    self.emit(Opcode::SET(Operand::Register(reg), Operand::Register(RETURN_REGISTER)));
+                }
+                Value { op: Operand::Register(reg), ty, temp: Some(reg) }
+            },
+        };
+        for &reg in live.iter().rev() {
+            self.pop(reg);
+        }
+        Ok(result)
+    }
+
+    fn declare(&mut self, d: &Declaration) -> Result<()> {
+        if d.ty == Type::Void {
+            return Err((CompileError::VoidVariable(d.name.clone()), d.span));
+        }
+        if self.scopes.last().unwrap().contains_key(&d.name) {
+            return Err((CompileError::Redefined(d.name.clone()), d.span));
+        }
+        let home = self.homes[&d.span.start].clone();
+        let var = Variable { ty: d.ty.clone(), home: home.clone() };
+        self.scopes.last_mut().unwrap().insert(d.name.clone(), var);
+        let slot = match home {
+            Home::Frame(slot) => slot,
+            _ => 0,
+        };
+        match (&d.ty, &d.init) {
+            (_, &None) => (),
+            (&Type::Array(_, n), Some(init)) => {
+                let values = match *init {
+                    Init::List(ref values) => values.clone(),
+                    Init::Expr(Expr { kind: ExprKind::Str(ref s), span }) =>
+                        s.iter().map(|&c| Expr { kind: ExprKind::Number(c), span }).collect(),
+                    Init::Expr(ref e) => return Err((CompileError::NotAssignable, e.span)),
+                };
+                if values.len() > n as usize {
+                    return Err((CompileError::TooManyValues(d.name.clone()), d.span));
+                }
+                // what isn't given is zero
+                for i in 0..n {
+                    let value = match values.get(i as usize) {
+                        Some(e) => self.value(e)?,
+                        None => Value::constant(0, Type::I16),
+                    };
+                    let place = self.frame_slot(slot + i);
+                    self.emit(Opcode::SET(place, value.op.clone()));
+                    self.release(&value);
+                }
+            },
+            (_, &Some(Init::Expr(ref e))) => {
+                let value = self.value(e)?;
+                let place = self.place_of(&home);
+                if value.op != place {
+                    self.emit(Opcode::SET(place, value.op.clone()));
+                }
+                self.release(&value);
+            },
+            (_, &Some(Init::List(_))) => return Err((CompileError::TooManyValues(d.name.clone()), d.span)),
+        }
+        Ok(())
+    }
+
+    // the statements of a block, carrying on past errors so they're all found
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.scopes.push(HashMap::new());
+        for s in stmts {
+            if let Err(e) = self.statement(s) {
+                self.program.errors.push(e);
+                self.busy.clear();
+                self.depth = 0;
+            }
+        }
+        self.scopes.pop();
+    }
+
+    // a loop, tested at the bottom so each time round takes one jump
+    fn loop_body(&mut self, cond: Option<&Expr>, step: Option<&Expr>, body: &Stmt) -> Result<()> {
+        let (top, next, test, end) = (self.new_label(), self.new_label(), self.new_label(), self.new_label());
+        self.jump(&test);
+        self.label(&top);
+        self.loops.push((next.clone(), end.clone()));
+        self.statement(body)?;
+        self.loops.pop();
+        self.label(&next);
+        if let Some(step) = step {
+            self.discard(step)?;
+        }
+        self.label(&test);
+        match cond {
+            Some(cond) => self.branch(cond, &top, true)?,
+            None => self.jump(&top),
+        }
+        self.label(&end);
+        Ok(())
+    }
+
+    // an expression for what it does, not its value
+    fn discard(&mut self, e: &Expr) -> Result<()> {
+        let value = match e.kind {
+            // x++ on its own is ++x, without the copy
+            ExprKind::Step { increment, prefix: false, ref expr } =>
+                self.expr(&Expr { kind: ExprKind::Step { increment, prefix: true, expr: expr.clone() }, span: e.span })?,
+            _ => self.expr(e)?,
+        };
+        self.release(&value);
+        Ok(())
+    }
+
+    fn statement(&mut self, s: &Stmt) -> Result<()> {
+        match *s {
+            Stmt::Block(ref stmts) => self.block(stmts),
+            Stmt::Declare(ref d) => self.declare(d)?,
+            Stmt::If(ref cond, ref then, ref otherwise) => {
+                let skip = self.new_label();
+                self.branch(cond, &skip, false)?;
+                self.statement(then)?;
+                match *otherwise {
+                    Some(ref otherwise) => {
+                        let end = self.new_label();
+                        self.jump(&end);
+                        self.label(&skip);
+                        self.statement(otherwise)?;
+                        self.label(&end);
+                    },
+                    None => self.label(&skip),
+                }
+            },
+            Stmt::While(ref cond, ref body) => self.loop_body(Some(cond), None, body)?,
+            Stmt::For(ref init, ref cond, ref step, ref body) => {
+                self.scopes.push(HashMap::new());
+                let result = match *init {
+                    Some(ref init) => self.statement(init),
+                    None => Ok(()),
+                }.and_then(|_| self.loop_body(cond.as_ref(), step.as_ref(), body));
+                self.scopes.pop();
+                result?;
+            },
+            Stmt::Return(ref value, span) => {
+                match (value, &self.ret) {
+                    (Some(e), &Type::Void) => return Err((CompileError::ReturnValue(self.name.clone()), e.span)),
+                    (&None, &Type::Void) => (),
+                    (&None, _) => return Err((CompileError::NoReturnValue(self.name.clone()), span)),
+                    (Some(e), _) => {
+                        let value = self.value(e)?;
+                        if value.op != Operand::Register(RETURN_REGISTER) {
+                            self.emit(Opcode::SET(Operand::Register(RETURN_REGISTER), value.op.clone()));
+                        }
+                        self.release(&value);
+                    },
+                }
+                let exit = self.exit.clone();
+                self.jump(&exit);
+            },
+            Stmt::Break(span) | Stmt::Continue(span) => {
+                let is_break = matches!(*s, Stmt::Break(_));
+                let target = match self.loops.last() {
+                    Some((next, end)) => if is_break { end.clone() } else { next.clone() },
+                    None => return Err((CompileError::OutsideLoop(if is_break { "break" } else { "continue" }), span)),
+                };
+                self.jump(&target);
+            },
+            Stmt::Expr(ref e) => self.discard(e)?,
+        }
+        Ok(())
+    }
+
+    // the function with its prologue and epilogue around the body
+    fn generate(mut self, f: &Function, body: &[Stmt]) -> Vec<Intermediate> {
+        for p in f.params.iter() {
+            if self.scopes[0].contains_key(&p.name) {
+                self.program.errors.push((CompileError::Redefined(p.name.clone()), p.span));
+            }
+            let var = Variable { ty: p.ty.decay(), home: self.homes[&p.span.start].clone() };
+            self.scopes[0].insert(p.name.clone(), var);
+        }
+        self.block(body);
+        // falling off the end is the same as the last return
+        let exit = Intermediate::Opcode(Opcode::SET(Operand::Pc, Operand::Label(self.exit.clone())));
+        if self.code.last() == Some(&exit) {
+            self.code.pop();
+        }
+
+        let saved: Vec<Register> = CALLEE_SAVED.iter().filter(|r| self.saved.contains(r)).cloned().collect();
+        let mut out = vec![Intermediate::Label(f.name.clone())];
+        let op = |op| Intermediate::Opcode(op);
+        for &reg in saved.iter() {
+            out.push(op(Opcode::SET(Operand::Push, Operand::Register(reg))));
+        }
+        if self.frame > 0 {
+            out.push(op(Opcode::SUB(Operand::Sp, Operand::Literal(self.frame))));
+        }
+        // the arguments to where they live: the first three come in
+        // registers, the rest are past the frame, what was saved and the
+        // return address
+        for (i, p) in f.params.iter().enumerate() {
+            let home = self.place_of(&self.homes[&p.span.start]);
+            out.push(op(Opcode::SET(home, abi::argument(i, self.frame + saved.len() as u16))));
+        }
+        out.append(&mut self.code);
+        out.push(Intermediate::Label(self.exit.clone()));
+        if self.frame > 0 {
+            out.push(op(Opcode::ADD(Operand::Sp, Operand::Literal(self.frame))));
+        }
+        for &reg in saved.iter().rev() {
+            out.push(op(Opcode::SET(Operand::Register(reg), Operand::Pop)));
+        }
+        out.push(op(Opcode::SET(Operand::Pc, Operand::Pop)));
+        out
+    }
+}
+
+// everything in the file, in the order it's written, with the strings after
+pub fn generate(items: &[Item]) -> Program {
+    let mut program = Program::default();
+    for item in items {
+        let result = match *item {
+            Item::Function(ref f) => program.signature(f),
+            Item::Global(ref d) => program.global(d),
+        };
+        if let Err(e) = result {
+            program.errors.push(e);
+        }
+    }
+    for item in items {
+        if let Item::Function(ref f) = *item {
+            if let Some(ref body) = f.body {
+                let code = FunctionGen::new(&mut program, f, body).generate(f, body);
+                program.items.extend(code);
+            }
+        }
+    }
+    for (i, s) in ::std::mem::take(&mut program.strings).into_iter().enumerate() {
+        program.items.push(Intermediate::Label(format!("__string_{}", i)));
+        program.items.push(Intermediate::Words(s.into_iter().chain(Some(0)).map(Operand::Literal).collect()));
+    }
+    program.errors.sort_by_key(|e| e.1);
+    program
+}
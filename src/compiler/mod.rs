@@ -0,0 +1,307 @@
+// a compiler for a small C-like language, to the same instructions the
+// assembler works with, so its output can be laid out, optimized and
+// linked like anything else. the language and calling convention are in
+// docs/compiler.txt
+use thiserror::Error;
+use abi::{link, STDLIB};
+use assembly::{Block, DcpuAssemblerError, Intermediate, Optimizer};
+use diagnostics::{Located, Span};
+use image::Image;
+use opcodes::{Opcode, Operand};
+
+mod ast;
+mod codegen;
+
+pub use self::ast::Type;
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum CompileError {
+    #[error("Syntax error: {}", .0)]
+    Syntax(String),
+    #[error("{} doesn't fit in a word", .0)]
+    TooBig(String),
+    #[error("{} needs a size or an initializer", .0)]
+    NoArraySize(String),
+    #[error("{} isn't declared", .0)]
+    Undeclared(String),
+    #[error("there's no function called {}", .0)]
+    UnknownFunction(String),
+    #[error("{} takes {} arguments, not {}", .0, .1, .2)]
+    Arguments(String, usize, usize),
+    #[error("{} is already defined", .0)]
+    Redefined(String),
+    #[error("{} is declared differently elsewhere", .0)]
+    Conflicting(String),
+    #[error("{} is a register, so it can't be a function name", .0)]
+    Reserved(String),
+    #[error("{} can't be void", .0)]
+    VoidVariable(String),
+    #[error("this is void, so it has no value")]
+    VoidValue,
+    #[error("{} doesn't return a value", .0)]
+    ReturnValue(String),
+    #[error("{} has to return a value", .0)]
+    NoReturnValue(String),
+    #[error("can't assign to this")]
+    NotAssignable,
+    #[error("this has no address")]
+    NoAddress,
+    #[error("{} isn't a pointer", .0)]
+    NotAPointer(Type),
+    #[error("global initializers have to be constants")]
+    NotConstant,
+    #[error("too many values for {}", .0)]
+    TooManyValues(String),
+    #[error("{} has to be in a loop", .0)]
+    OutsideLoop(&'static str),
+    #[error("this needs more registers than there are")]
+    TooComplex,
+    #[error("there's no main function to start at")]
+    NoMain,
+    #[error("{}", .0)]
+    Assembler(#[from] DcpuAssemblerError),
+}
+
+// how C sources get compiled
+#[derive(Clone, Debug)]
+pub struct Compiler {
+    origin: u16,
+    startup: bool,
+    stdlib: bool,
+    optimizer: Option<Optimizer>,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler { origin: 0, startup: true, stdlib: true, optimizer: None }
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::default()
+    }
+
+    // where the first word goes
+    pub fn origin(mut self, origin: u16) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    // whether to start with a call to main and a HLT after it. without it,
+    // the output is just the functions and globals, for linking with others
+    pub fn startup(mut self, startup: bool) -> Self {
+        self.startup = startup;
+        self
+    }
+
+    // whether to add the standard library routines the program calls
+    // without defining them
+    pub fn stdlib(mut self, stdlib: bool) -> Self {
+        self.stdlib = stdlib;
+        self
+    }
+
+    pub fn optimizer(mut self, optimizer: Optimizer) -> Self {
+        self.optimizer = Some(optimizer);
+        self
+    }
+
+    // the instructions and data for a source file, not yet laid out, or
+    // everything that's wrong with it
+    pub fn compile(&self, file: Option<&str>, src: &str) -> Result<Vec<Intermediate>, Vec<Located<CompileError>>> {
+        self.generate(file, src).map(|(items, _)| items)
+    }
+
+    // the items, and the functions that are only declared with where
+    // they're first called
+    #[allow(clippy::type_complexity)]
+    fn generate(&self, file: Option<&str>, src: &str)
+        -> Result<(Vec<Intermediate>, Vec<(String, Span)>), Vec<Located<CompileError>>> {
+        let items = ast::parse(src).map_err(|(e, span)| vec![Located::new(e, file, src, span)])?;
+        let program = codegen::generate(&items);
+        let mut errors = program.errors;
+        let has_main = items.iter().any(|item| matches!(*item, ast::Item::Function(ref f) if f.name == "main" && f.body.is_some()));
+        if self.startup && !has_main {
+            errors.push((CompileError::NoMain, Span { start: src.len(), end: src.len() }));
+        }
+        if !errors.is_empty() {
+            return Err(errors.into_iter().map(|(e, span)| Located::new(e, file, src, span)).collect());
+        }
+        let mut out = vec![];
+        if self.startup {
+            out.push(Intermediate::Opcode(Opcode::JSR(Operand::Label("main".to_owned()))));
+            out.push(Intermediate::Opcode(Opcode::ADD(Operand::Pc, Operand::Literal(0xffff))));
+        }
+        out.extend(program.items);
+        if self.stdlib {
+            link(&mut out, &STDLIB);
+        }
+        Ok((out, program.externs))
+    }
+
+    // compiles and lays out a whole program. functions that are declared
+    // but not defined anywhere, or in the standard library, are errors where
+    // they're called
+    pub fn assemble(&self, file: Option<&str>, src: &str) -> Result<Vec<u16>, Vec<Located<CompileError>>> {
+        self.image(file, src).map(|image| image.flatten().words)
+    }
+
+    // the same, along with where it goes and its labels
+    pub fn image(&self, file: Option<&str>, src: &str) -> Result<Image, Vec<Located<CompileError>>> {
+        let (items, externs) = self.generate(file, src)?;
+        let layout = match self.optimizer {
+            Some(ref optimizer) => optimizer.layout(items, self.origin),
+            None => Block::new().intermediate(&mut items.clone()).layout(self.origin),
+        };
+        let words = layout.words().map_err(|e| {
+            let span = match e {
+                DcpuAssemblerError::UnresolvedLabel(ref name) =>
+                    externs.iter().find(|(n, _)| n == name).map_or(Span::default(), |&(_, span)| span),
+                _ => Span::default(),
+            };
+            vec![Located::new(CompileError::Assembler(e), file, src, span)]
+        })?;
+        Ok(Image::new(self.origin, words).symbols(layout.labels()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembly::assemble_file;
+    use virtual_machine::{StopReason, VirtualMachine};
+
+    // runs a program to its HLT, giving what main returned
+    fn run(src: &str) -> u16 {
+        let words = Compiler::new().assemble(None, src)
+            .unwrap_or_else(|errors| panic!("{}", errors.iter().map(|e| e.render(src)).collect::<String>()));
+        let mut vm = VirtualMachine::new().load_program(&words, 0);
+        let stop = vm.run(&Default::default(), Some(1_000_000)).unwrap();
+        assert!(matches!(stop, StopReason::Halted(_)), "{}", stop);
+        vm.get_registers()[0]
+    }
+
+    #[test]
+    fn arithmetic_and_loops() {
+        assert_eq!(run("int main() { return 6 * 7; }"), 42);
+        assert_eq!(run("
+            u16 factorial(u16 n) {
+                u16 f = 1;
+                while (n > 1) f *= n--;
+                return f;
+            }
+            int main() { return factorial(8) / 8; }"), 5040);
+        assert_eq!(run("
+            int main() {
+                i16 neg = -7;
+                u16 big = 0xfff9;
+                int sum = 0;
+                for (int i = 0; i < 10; i++) {
+                    if (i == 3) continue;
+                    if (i >= 8) break;
+                    sum += i;
+                }
+                // 25, signed -1, unsigned 0x7ffc, and both comparisons
+                return sum + (neg / 7 == -1) * 0x100 + (big / 2 == 0x7ffc) * 0x200
+                    + (neg < 0 && big > 0) * 0x400 + !(neg <= -8 || big >= 0xfffa) * 0x800;
+            }"), 25 + 0xf00);
+    }
+
+    #[test]
+    fn pointers_arrays_and_calls() {
+        assert_eq!(run(r#"
+            u16 table[4] = {1, 2, 3};
+            u16 *greeting = "hello";
+            u16 count;
+
+            u16 strlen(u16 *s) {
+                u16 n = 0;
+                while (*s++) n++;
+                return n;
+            }
+
+            void swap(u16 *a, u16 *b) {
+                u16 t = *a;
+                *a = *b;
+                *b = t;
+            }
+
+            // more than three arguments, so some come on the stack
+            int sum5(int a, int b, int c, int d, int e) { return a + b * 2 + c * 3 + d * 4 + e * 5; }
+
+            int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+
+            int main() {
+                u16 local[3];
+                for (u16 i = 0; i < 3; ++i) local[i] = table[i] * 10;
+                swap(&local[0], &local[2]);
+                count += strlen(greeting);
+                table[3] = local[0];
+                return sum5(count, table[3], local[2], fib(10), strlen("four"));
+            }"#), 5 + 30 * 2 + 10 * 3 + 55 * 4 + 4 * 5);
+    }
+
+    #[test]
+    fn runs_out_of_registers() {
+        // more locals than registers, and more live values than registers
+        let vars: Vec<String> = (0..10).map(|i| format!("int v{} = {};", i, i + 1)).collect();
+        let sum = (0..10).map(|i| format!("v{}", i)).collect::<Vec<_>>().join(" + ");
+        let nested = (0..10).fold("v9".to_owned(), |e, i| format!("(v{} * 2 + {})", i, e));
+        let src = format!("int main() {{ {} return ({}) + {} - f(1, {}); }} int f(int a, int b) {{ return a + b; }}",
+                          vars.join(" "), sum, nested, nested);
+        let nested_value = (0..10).fold(10u16, |e, i| (i + 1) * 2 + e);
+        assert_eq!(run(&src), 55u16.wrapping_add(nested_value).wrapping_sub(1 + nested_value));
+    }
+
+    #[test]
+    fn links_the_standard_library() {
+        let src = r#"
+            u16 *memset(u16 *dest, u16 value, u16 count);
+            u16 *itoa(u16 value, u16 *buffer, u16 base);
+            i16 strcmp(u16 *a, u16 *b);
+
+            int main() {
+                u16 buffer[8];
+                memset(buffer, 0xffff, 8);
+                return strcmp(itoa(1234, buffer, 10), "1234") == 0 && buffer[5] == 0xffff;
+            }"#;
+        assert_eq!(run(src), 1);
+        let errors = Compiler::new().stdlib(false).assemble(None, src).unwrap_err();
+        assert_eq!(errors[0].to_string(), "<input>:8:17: Label memset hasn't been resolved");
+    }
+
+    #[test]
+    fn globals_named_after_registers() {
+        assert_eq!(run("u16 a = 2; u16 pc[2] = {3, 4}; u16 *push = &a;\n\
+                        int main() { int b = 5; return a * b + pc[1] + *push; }"), 16);
+        let src = "int x(void) { return 1; }\nint main() { return x(); }";
+        let errors: Vec<_> = Compiler::new().compile(None, src).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec!["<input>:1:1: x is a register, so it can't be a function name"]);
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        let src = "int f(int a) { return a; }\nint main() {\n  int x = y;\n  f(1, 2);\n  break;\n  return *x;\n}\n";
+        let errors: Vec<_> = Compiler::new().compile(Some("bad.c"), src).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "bad.c:3:11: y isn't declared",
+            "bad.c:4:3: f takes 1 arguments, not 2",
+            "bad.c:5:3: break has to be in a loop",
+            "bad.c:6:11: i16 isn't a pointer",
+        ]);
+        let errors = Compiler::new().compile(None, "int main() { return 1 }").unwrap_err();
+        assert_eq!(errors[0].to_string(), "<input>:1:23: Syntax error: expected an operator or the end of the expression");
+        let errors = Compiler::new().assemble(None, "int g(void);\nint main() { return g(); }").unwrap_err();
+        assert_eq!(errors[0].to_string(), "<input>:2:21: Label g hasn't been resolved");
+    }
+
+    #[test]
+    fn listing_assembles_the_same() {
+        let src = "u16 buf[3] = {1, 2};\nint f(int a, int b, int c, int d) { int t[2]; t[1] = d; return a + t[1] * buf[c]; }\n\
+                   int main() { u16 *p = buf; int n = p[1]; return f(n, 2, 1, 4) + *\"a\"; }";
+        let compiler = Compiler::new().origin(0x100);
+        let listing: String = compiler.compile(None, src).unwrap().iter().map(|i| format!("{}\n", i)).collect();
+        assert_eq!(assemble_file(None, &listing, 0x100), Ok(compiler.assemble(None, src).unwrap()));
+    }
+}
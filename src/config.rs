@@ -0,0 +1,321 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use thiserror::Error;
+use serde_json::{Map, Value};
+use image::{Format, Image, ImageError};
+use registry::{DeviceRegistry, DeviceParams, DeviceHandle, RegistryError};
+use virtual_machine::{VirtualMachine, DcpuVMError, Overflow, StopReason};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Couldn't read {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    #[error("Invalid TOML: {}", .0)]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid JSON: {}", .0)]
+    Json(#[from] serde_json::Error),
+    #[error("Couldn't load {}: {}", .0.display(), .1)]
+    Load(PathBuf, #[source] DcpuVMError),
+    #[error("Couldn't load {}: {}", .0.display(), .1)]
+    Image(PathBuf, #[source] ImageError),
+    #[error("Couldn't create device {}: {}", .0, .1)]
+    Registry(usize, #[source] RegistryError),
+    #[error("Couldn't attach device {}: {}", .0, .1)]
+    Device(usize, #[source] DcpuVMError),
+}
+
+fn default_clock_rate() -> usize {
+    100000
+}
+
+// a whole machine: what's in RAM, where it starts and what's plugged in.
+// relative paths are relative to the file the config was loaded from
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MachineConfig {
+    pub image: Option<PathBuf>,
+    // how the image is stored, by default going by its extension
+    pub format: Option<String>,
+    // where a raw image goes. the other formats say where they go
+    #[serde(default)]
+    pub origin: u16,
+    // more images, loaded after the first in order
+    #[serde(default)]
+    pub segments: Vec<SegmentConfig>,
+    // whether images running past 0xffff go round to 0, rather than being
+    // an error
+    #[serde(default)]
+    pub wrap: bool,
+    pub pc: Option<u16>,
+    pub sp: Option<u16>,
+    #[serde(default = "default_clock_rate")]
+    pub clock_rate: usize,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub breakpoints: Vec<u16>,
+    pub cycle_limit: Option<usize>,
+    #[serde(skip)]
+    pub base: PathBuf,
+}
+
+// another image, like a font or data that lives up high
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentConfig {
+    pub image: PathBuf,
+    pub format: Option<String>,
+    #[serde(default)]
+    pub origin: u16,
+}
+
+// a device by the name it's registered under, along with whatever
+// parameters it takes
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DeviceConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+impl MachineConfig {
+    pub fn from_toml(s: &str) -> Result<MachineConfig, ConfigError> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<MachineConfig, ConfigError> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    // .json files are JSON, anything else is TOML
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MachineConfig, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let mut config = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => MachineConfig::from_json(&text)?,
+            _ => MachineConfig::from_toml(&text)?,
+        };
+        config.base = path.parent().map(Path::to_owned).unwrap_or_default();
+        Ok(config)
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.base.join(path)
+    }
+
+    fn load_image(&self, image: &Path, format: Option<&String>, origin: u16) -> Result<(PathBuf, Image), ConfigError> {
+        let path = self.resolve(image);
+        let format = match format {
+            Some(name) => Format::named(name).map_err(|e| ConfigError::Image(path.clone(), e))?,
+            None => Format::for_path(&path),
+        };
+        let bytes = fs::read(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        let image = Image::read(&bytes, format).map_err(|e| ConfigError::Image(path.clone(), e))?;
+        Ok((path, if format.is_raw() { image.relocate(origin) } else { image }))
+    }
+
+    pub fn build(&self) -> Result<Machine, ConfigError> {
+        self.build_with(&DeviceRegistry::with_builtins())
+    }
+
+    pub fn build_with(&self, registry: &DeviceRegistry) -> Result<Machine, ConfigError> {
+        let mut vm = VirtualMachine::new().clock_rate(self.clock_rate);
+        let mut images = vec![];
+        if let Some(ref image) = self.image {
+            images.push(self.load_image(image, self.format.as_ref(), self.origin)?);
+        }
+        for segment in self.segments.iter() {
+            images.push(self.load_image(&segment.image, segment.format.as_ref(), segment.origin)?);
+        }
+        let overflow = if self.wrap { Overflow::Wrap } else { Overflow::Error };
+        for (path, image) in images.iter() {
+            vm.load_image(image, overflow).map_err(|e| ConfigError::Load(path.clone(), e))?;
+        }
+        // the first entry point any image has, or where the first one goes
+        let entry = images.iter().filter_map(|(_, image)| image.entry).next()
+            .or_else(|| images.first().map(|(_, image)| image.origin()));
+        *vm.get_pc() = self.pc.or(entry).unwrap_or(self.origin);
+        *vm.get_sp() = self.sp.unwrap_or(0);
+
+        let mut devices = Vec::with_capacity(self.devices.len());
+        for (i, config) in self.devices.iter().enumerate() {
+            let params = DeviceParams::new(config.params.clone()).base(&self.base);
+            let instance = registry.create(&config.kind, &params)
+                .map_err(|e| ConfigError::Registry(i, e))?;
+            vm.plug(instance.device).map_err(|e| ConfigError::Device(i, e))?;
+            devices.push(instance.handle);
+        }
+
+        Ok(Machine {
+            vm,
+            breakpoints: self.breakpoints.iter().cloned().collect(),
+            cycle_limit: self.cycle_limit,
+            devices,
+        })
+    }
+}
+
+// a VM built from a config, along with handles to the devices it was given
+#[derive(Debug)]
+pub struct Machine {
+    pub vm: VirtualMachine,
+    pub breakpoints: BTreeSet<u16>,
+    pub cycle_limit: Option<usize>,
+    devices: Vec<DeviceHandle>,
+}
+
+impl Machine {
+    pub fn run(&mut self) -> Result<StopReason, DcpuVMError> {
+        self.vm.run(&self.breakpoints, self.cycle_limit)
+    }
+
+    pub fn devices(&self) -> &[DeviceHandle] {
+        &self.devices
+    }
+
+    // the handle for the device at `index` in the config
+    pub fn device(&self, index: usize) -> Option<&DeviceHandle> {
+        self.devices.get(index)
+    }
+
+    // everything written to memory backed serial ports, in device order
+    pub fn serial_output(&self) -> String {
+        self.devices.iter()
+            .filter_map(|d| match *d {
+                DeviceHandle::Serial(ref memory) => Some(memory.output_string()),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::Register;
+
+    const MACHINE: &str = r#"
+        image = "hello.bin"
+        origin = 0x100
+        clock_rate = 200000
+        breakpoints = [0x106]
+        cycle_limit = 100000
+
+        [[devices]]
+        type = "serial"
+        backend = "memory"
+
+        [[devices]]
+        type = "keyboard"
+        script = "hi"
+        interval = 10
+
+        [[devices]]
+        type = "m35fd"
+        write_protect = true
+    "#;
+
+    #[test]
+    fn toml_and_json_agree() {
+        let toml = MachineConfig::from_toml(MACHINE).unwrap();
+        let json = MachineConfig::from_json(r#"{
+            "image": "hello.bin",
+            "origin": 256,
+            "clock_rate": 200000,
+            "breakpoints": [262],
+            "cycle_limit": 100000,
+            "devices": [
+                { "type": "serial", "backend": "memory" },
+                { "type": "keyboard", "script": "hi", "interval": 10 },
+                { "type": "m35fd", "write_protect": true }
+            ]
+        }"#).unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(toml.devices[2].kind, "m35fd");
+        assert_eq!(toml.devices[2].params.get("write_protect"), Some(&Value::Bool(true)));
+
+        let config = MachineConfig::from_toml("[[devices]]\ntype = \"toaster\"").unwrap();
+        match config.build() {
+            Err(ConfigError::Registry(0, RegistryError::UnknownDevice(_))) => {},
+            other => panic!("expected an unknown device, got {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn builds_and_runs_a_machine() {
+        let dir = ::std::env::temp_dir().join(format!("dcpu16-config-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // SET A, 4; SET B, 0x200; SET C, 2; HWI 0; SET X, 1; SUB PC, 1
+        let program: [u16; 9] = [0x9401, 0x7c21, 0x0200, 0x8c41, 0x8640, 0x8861, 0x8b83, 0, 0];
+        let mut image: Vec<u8> = program.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).collect();
+        image.resize(0x200, 0);
+        image.extend_from_slice(&[0, b'o', 0, b'k']);
+        fs::write(dir.join("hello.bin"), &image).unwrap();
+        fs::write(dir.join("machine.toml"), MACHINE).unwrap();
+
+        let config = MachineConfig::load(dir.join("machine.toml")).unwrap();
+        let mut machine = config.build().unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(machine.vm.get_clock_rate(), 200000);
+        assert_eq!(*machine.vm.get_pc(), 0x100);
+        assert_eq!(machine.run().unwrap(), StopReason::Breakpoint(0x106));
+        assert_eq!(machine.serial_output(), "ok");
+        assert_eq!(machine.vm.get_registers()[Register::X as usize], 1);
+        machine.breakpoints.clear();
+        assert_eq!(machine.run().unwrap(), StopReason::CycleLimit);
+
+        match machine.device(1) {
+            Some(DeviceHandle::Keyboard(keys)) => assert_eq!(keys.buffered(), 2),
+            other => panic!("expected a keyboard, got {:?}", other)
+        }
+        match machine.device(2) {
+            Some(DeviceHandle::Media(slot)) => assert!(slot.is_empty()),
+            other => panic!("expected a media slot, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn images_in_other_formats() {
+        let dir = ::std::env::temp_dir().join(format!("dcpu16-formats-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image = Image::new(0x200, vec![0x8b83, 0x8801, 0x8b83]).entry(0x201);
+        fs::write(dir.join("entry.hex"), image.write(Format::IntelHex)).unwrap();
+        fs::write(dir.join("raw.img"), image.write(Format::LittleEndian)).unwrap();
+        let load = |toml: &str| {
+            let mut config = MachineConfig::from_toml(toml).unwrap();
+            config.base = dir.clone();
+            config.build()
+        };
+
+        // Intel HEX says where it goes and where it starts
+        let mut machine = load("image = \"entry.hex\"\norigin = 0x10").unwrap();
+        assert_eq!(*machine.vm.get_pc(), 0x201);
+        assert_eq!(machine.vm.get_ram()[0x200..0x203], [0x8b83, 0x8801, 0x8b83]);
+        let mut machine = load("image = \"raw.img\"\nformat = \"little-endian\"\norigin = 0x10").unwrap();
+        assert_eq!(*machine.vm.get_pc(), 0x10);
+        assert_eq!(machine.vm.get_ram()[0x10..0x13], [0x8b83, 0x8801, 0x8b83]);
+        match load("image = \"raw.img\"\nformat = \"headered\"") {
+            Err(ConfigError::Image(_, ImageError::BadMagic)) => {},
+            other => panic!("expected a bad image, got {:?}", other.map(|_| ()))
+        }
+        match load("image = \"raw.img\"\norigin = 0xfffe") {
+            Err(ConfigError::Load(_, DcpuVMError::SegmentOverflow(3, 0xfffe))) => {},
+            other => panic!("expected an image too large, got {:?}", other.map(|_| ()))
+        }
+
+        // a raw image wrapped round, and another at an origin of its own
+        let mut machine = load("image = \"raw.img\"\nformat = \"le\"\norigin = 0xfffe\nwrap = true\n\
+                                [[segments]]\nimage = \"entry.hex\"\n\
+                                [[segments]]\nimage = \"raw.img\"\nformat = \"le\"\norigin = 0x8000").unwrap();
+        assert_eq!(*machine.vm.get_pc(), 0x201);
+        assert_eq!(machine.vm.get_ram()[0xfffe..], [0x8b83, 0x8801]);
+        assert_eq!(machine.vm.get_ram()[0], 0x8b83);
+        assert_eq!(machine.vm.get_ram()[0x8000..0x8003], [0x8b83, 0x8801, 0x8b83]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
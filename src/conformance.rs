@@ -0,0 +1,908 @@
+use std::fmt::{Display, Formatter, Error};
+use virtual_machine::{VirtualMachine, Register, DcpuVMError};
+use hardware::{Hardware, HardwareInfo, DeviceContext};
+
+// Conformance suite for the DCPU-16 1.7 spec (docs/dcpu-1-7.txt).
+//
+// Every case loads a small program at 0x0000, sets up registers and RAM, runs
+// a number of steps and then checks registers, RAM and the cycles taken. Any
+// emulator can be checked by implementing Emulator for it and calling run().
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Location {
+    Register(Register),
+    Pc,
+    Sp,
+    Ex,
+    Ia,
+    Ram(u16),
+}
+
+impl Display for Location {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            Location::Register(reg) => reg.fmt(fmt),
+            Location::Pc => fmt.write_str("PC"),
+            Location::Sp => fmt.write_str("SP"),
+            Location::Ex => fmt.write_str("EX"),
+            Location::Ia => fmt.write_str("IA"),
+            Location::Ram(addr) => fmt.write_fmt(format_args!("[{:#06x}]", addr)),
+        }
+    }
+}
+
+pub trait Emulator {
+    type Error: Display;
+    // zero all registers, RAM and pending interrupts and unplug all hardware
+    fn reset(&mut self);
+    // copy program to 0x0000 without touching anything else
+    fn load(&mut self, program: &[u16]);
+    // execute one instruction, returning the cycles it took
+    fn step(&mut self) -> Result<usize, Self::Error>;
+    fn read(&mut self, loc: Location) -> u16;
+    fn write(&mut self, loc: Location, data: u16);
+    // raise an interrupt the way a piece of hardware would
+    fn interrupt(&mut self, msg: u16);
+    // plug in a device that behaves like Stub, as device 0
+    fn attach_stub(&mut self);
+}
+
+// the only device cases use. HWQ reports the STUB_ ids, and HWI sets B to
+// A + 1 and takes STUB_CYCLES on top of the 4 HWI costs
+pub const STUB_MANUFACTURER: u32 = 0x1c6c8b36;
+pub const STUB_MODEL: u32 = 0x5eed0001;
+pub const STUB_VERSION: u16 = 0x0107;
+pub const STUB_CYCLES: usize = 7;
+
+pub struct Stub {
+    hw_info: HardwareInfo,
+}
+
+impl Default for Stub {
+    fn default() -> Self {
+        Stub { hw_info: HardwareInfo {
+            manufacturer: STUB_MANUFACTURER,
+            model: STUB_MODEL,
+            version: STUB_VERSION,
+        } }
+    }
+}
+
+impl Hardware for Stub {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+        let (a, _) = ctx.read_register(Register::A);
+        ctx.write_register(Register::B, a.wrapping_add(1));
+        STUB_CYCLES
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_str("conformance stub")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Case {
+    pub name: String,
+    pub program: Vec<u16>,
+    pub setup: Vec<(Location, u16)>,
+    pub interrupts: Vec<u16>,
+    // whether Stub is plugged in
+    pub stub: bool,
+    pub steps: usize,
+    pub expect: Vec<(Location, u16)>,
+    pub cycles: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub case: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub passed: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Display for Failure {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(format_args!("{}: {}", self.case, self.reason))
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(format_args!("{} passed, {} failed", self.passed, self.failures.len()))?;
+        for failure in &self.failures {
+            fmt.write_fmt(format_args!("\n  {}", failure))?;
+        }
+        Ok(())
+    }
+}
+
+impl Case {
+    pub fn check<E: Emulator>(&self, emu: &mut E) -> Result<(), String> {
+        emu.reset();
+        if self.stub {
+            emu.attach_stub();
+        }
+        emu.load(&self.program);
+        for &(loc, data) in &self.setup {
+            emu.write(loc, data);
+        }
+        for &msg in &self.interrupts {
+            emu.interrupt(msg);
+        }
+
+        let mut cycles = 0;
+        for i in 0..self.steps {
+            match emu.step() {
+                Ok(c) => cycles += c,
+                Err(e) => return Err(format!("step {} failed: {}", i, e)),
+            }
+        }
+
+        let mut problems = Vec::new();
+        for &(loc, want) in &self.expect {
+            let got = emu.read(loc);
+            if got != want {
+                problems.push(format!("{} is {:#06x}, expected {:#06x}", loc, got, want));
+            }
+        }
+        if let Some(want) = self.cycles {
+            if cycles != want {
+                problems.push(format!("took {} cycles, expected {}", cycles, want));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(problems.join(", "))
+        }
+    }
+}
+
+pub fn run<E: Emulator>(emu: &mut E) -> Report {
+    run_cases(emu, &cases())
+}
+
+pub fn run_cases<E: Emulator>(emu: &mut E, cases: &[Case]) -> Report {
+    let mut report = Report::default();
+    for case in cases {
+        match case.check(emu) {
+            Ok(()) => report.passed += 1,
+            Err(reason) => report.failures.push(Failure { case: case.name.clone(), reason }),
+        }
+    }
+    report
+}
+
+fn basic(op: u16, b: u16, a: u16) -> u16 {
+    op | (b << 5) | (a << 10)
+}
+
+fn special(op: u16, a: u16) -> u16 {
+    (op << 5) | (a << 10)
+}
+
+// SET X, 1 - the instruction guarded by the conditionals
+const GUARDED: u16 = 0x8861;
+
+// where operands keep their values. a and b never share a register or an
+// address so every combination can be run together. I and J are left alone
+// for STI/STD.
+const B_REG: Register = Register::A;
+const B_PTR: Register = Register::C;
+const A_REG: Register = Register::Y;
+const A_PTR: Register = Register::Z;
+const STACK: u16 = 0xfff0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    Register,
+    RegisterDeref,
+    RegisterPlusDeref,
+    Push,
+    Pop,
+    Peek,
+    Pick,
+    NextDeref,
+    NextLiteral,
+    ShortLiteral,
+}
+
+const A_KINDS: [Kind; 9] = [Kind::Register, Kind::RegisterDeref, Kind::RegisterPlusDeref,
+    Kind::Pop, Kind::Peek, Kind::Pick, Kind::NextDeref, Kind::NextLiteral, Kind::ShortLiteral];
+const B_KINDS: [Kind; 8] = [Kind::Register, Kind::RegisterDeref, Kind::RegisterPlusDeref,
+    Kind::Push, Kind::Peek, Kind::Pick, Kind::NextDeref, Kind::NextLiteral];
+
+impl Kind {
+    fn uses_stack(self) -> bool {
+        matches!(self, Kind::Push | Kind::Pop | Kind::Peek | Kind::Pick)
+    }
+}
+
+struct Placed {
+    code: u16,
+    next: Option<u16>,
+    setup: Vec<(Location, u16)>,
+    // where the value ends up, None for literals
+    home: Option<Location>,
+    sp: Option<u16>,
+}
+
+fn place_a(kind: Kind, v: u16) -> Option<Placed> {
+    let mut p = Placed { code: 0, next: None, setup: vec![], home: None, sp: None };
+    match kind {
+        Kind::Register => {
+            p.code = A_REG as u16;
+            p.home = Some(Location::Register(A_REG));
+        },
+        Kind::RegisterDeref => {
+            p.code = 0x08 + A_PTR as u16;
+            p.setup.push((Location::Register(A_PTR), 0x2000));
+            p.home = Some(Location::Ram(0x2000));
+        },
+        Kind::RegisterPlusDeref => {
+            p.code = 0x10 + A_PTR as u16;
+            p.next = Some(0x10);
+            p.setup.push((Location::Register(A_PTR), 0x2000));
+            p.home = Some(Location::Ram(0x2010));
+        },
+        Kind::Pop => {
+            p.code = 0x18;
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK));
+            p.sp = Some(STACK + 1);
+        },
+        Kind::Peek => {
+            p.code = 0x19;
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK));
+            p.sp = Some(STACK);
+        },
+        Kind::Pick => {
+            p.code = 0x1a;
+            p.next = Some(2);
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK + 2));
+            p.sp = Some(STACK);
+        },
+        Kind::NextDeref => {
+            p.code = 0x1e;
+            p.next = Some(0x3000);
+            p.home = Some(Location::Ram(0x3000));
+        },
+        Kind::NextLiteral => {
+            p.code = 0x1f;
+            p.next = Some(v);
+        },
+        Kind::ShortLiteral => {
+            if (v as i16) < -1 || (v as i16) > 30 {
+                return None
+            }
+            p.code = 0x21u16.wrapping_add(v);
+        },
+        Kind::Push => return None
+    }
+    if let Some(home) = p.home {
+        p.setup.push((home, v));
+    }
+    Some(p)
+}
+
+fn place_b(kind: Kind, v: u16) -> Option<Placed> {
+    let mut p = Placed { code: 0, next: None, setup: vec![], home: None, sp: None };
+    match kind {
+        Kind::Register => {
+            p.code = B_REG as u16;
+            p.home = Some(Location::Register(B_REG));
+        },
+        Kind::RegisterDeref => {
+            p.code = 0x08 + B_PTR as u16;
+            p.setup.push((Location::Register(B_PTR), 0x1000));
+            p.home = Some(Location::Ram(0x1000));
+        },
+        Kind::RegisterPlusDeref => {
+            p.code = 0x10 + B_PTR as u16;
+            p.next = Some(0x20);
+            p.setup.push((Location::Register(B_PTR), 0x1000));
+            p.home = Some(Location::Ram(0x1020));
+        },
+        Kind::Push => {
+            p.code = 0x18;
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK - 1));
+            p.sp = Some(STACK - 1);
+        },
+        Kind::Peek => {
+            p.code = 0x19;
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK));
+            p.sp = Some(STACK);
+        },
+        Kind::Pick => {
+            p.code = 0x1a;
+            p.next = Some(3);
+            p.setup.push((Location::Sp, STACK));
+            p.home = Some(Location::Ram(STACK + 3));
+            p.sp = Some(STACK);
+        },
+        Kind::NextDeref => {
+            p.code = 0x1e;
+            p.next = Some(0x4000);
+            p.home = Some(Location::Ram(0x4000));
+        },
+        Kind::NextLiteral => {
+            p.code = 0x1f;
+            p.next = Some(v);
+        },
+        _ => return None
+    }
+    if let Some(home) = p.home {
+        p.setup.push((home, v));
+    }
+    Some(p)
+}
+
+// assembles a basic instruction from placed operands, a's next word first
+fn encode(op: u16, b: &Placed, a: &Placed) -> Vec<u16> {
+    let mut program = vec![basic(op, b.code, a.code)];
+    program.extend(a.next);
+    program.extend(b.next);
+    program
+}
+
+struct Vector {
+    b: u16,
+    a: u16,
+    ex: u16,
+    res: u16,
+    ex_out: u16,
+}
+
+fn v(b: u16, a: u16, ex: u16, res: u16, ex_out: u16) -> Vector {
+    Vector { b, a, ex, res, ex_out }
+}
+
+struct Arith {
+    name: &'static str,
+    op: u16,
+    cycles: usize,
+    vectors: Vec<Vector>,
+}
+
+fn arithmetic() -> Vec<Arith> {
+    // EX of 0x5555 going in and out means the opcode must leave EX alone
+    let n = 0x5555;
+    vec![
+        Arith { name: "SET", op: 0x01, cycles: 1, vectors: vec![
+            v(0, 0x1234, n, 0x1234, n), v(5, 0xffff, n, 0xffff, n), v(7, 0, n, 0, n)] },
+        Arith { name: "ADD", op: 0x02, cycles: 2, vectors: vec![
+            v(1, 2, 0, 3, 0), v(0xffff, 1, 0, 0, 1), v(0xffff, 0xffff, 0, 0xfffe, 1),
+            v(0x8000, 0x8000, n, 0, 1), v(5, 0, n, 5, 0), v(0x7fff, 1, 0, 0x8000, 0)] },
+        Arith { name: "SUB", op: 0x03, cycles: 2, vectors: vec![
+            v(3, 1, 0, 2, 0), v(0, 1, 0, 0xffff, 0xffff), v(0x8000, 1, n, 0x7fff, 0),
+            v(1, 2, n, 0xffff, 0xffff), v(5, 5, n, 0, 0)] },
+        Arith { name: "MUL", op: 0x04, cycles: 2, vectors: vec![
+            v(3, 4, n, 12, 0), v(0xffff, 0xffff, 0, 1, 0xfffe), v(0x100, 0x100, 0, 0, 1),
+            v(0x8000, 2, 0, 0, 1)] },
+        Arith { name: "MLI", op: 0x05, cycles: 2, vectors: vec![
+            v(0xfffe, 3, n, 0xfffa, 0xffff), v(0x8000, 0xffff, n, 0x8000, 0),
+            v(0x7fff, 2, n, 0xfffe, 0), v(0xffff, 0xffff, n, 1, 0), v(0x8000, 0x8000, n, 0, 0x4000)] },
+        Arith { name: "DIV", op: 0x06, cycles: 3, vectors: vec![
+            v(7, 2, n, 3, 0x8000), v(5, 0, n, 0, 0), v(0xffff, 1, n, 0xffff, 0),
+            v(1, 3, n, 0, 0x5555), v(0, 7, n, 0, 0)] },
+        Arith { name: "DVI", op: 0x07, cycles: 3, vectors: vec![
+            v(0xfff9, 2, n, 0xfffd, 0x8000), v(7, 0xfffe, n, 0xfffd, 0x8000),
+            v(0x8000, 0xffff, n, 0x8000, 0), v(5, 0, n, 0, 0), v(0xfff9, 0xfff9, n, 1, 0)] },
+        Arith { name: "MOD", op: 0x08, cycles: 3, vectors: vec![
+            v(7, 3, n, 1, n), v(5, 0, n, 0, n), v(0xffff, 0x10, n, 0xf, n)] },
+        Arith { name: "MDI", op: 0x09, cycles: 3, vectors: vec![
+            v(0xfff9, 16, n, 0xfff9, n), v(7, 0xfff0, n, 7, n), v(0xfff9, 0, n, 0, n),
+            v(0x8000, 0xffff, n, 0, n)] },
+        Arith { name: "AND", op: 0x0a, cycles: 1, vectors: vec![
+            v(0xf0f0, 0xff00, n, 0xf000, n), v(0xffff, 0, n, 0, n)] },
+        Arith { name: "BOR", op: 0x0b, cycles: 1, vectors: vec![
+            v(0xf0f0, 0xff00, n, 0xfff0, n), v(0, 0, n, 0, n)] },
+        Arith { name: "XOR", op: 0x0c, cycles: 1, vectors: vec![
+            v(0xf0f0, 0xff00, n, 0x0ff0, n), v(0xffff, 0xffff, n, 0, n)] },
+        Arith { name: "SHR", op: 0x0d, cycles: 1, vectors: vec![
+            v(0x8001, 1, n, 0x4000, 0x8000), v(0x1234, 16, n, 0, 0x1234), v(0x1234, 32, n, 0, 0),
+            v(0xffff, 0, n, 0xffff, 0), v(0x1234, 0xffff, n, 0, 0), v(0x1234, 20, n, 0, 0x0123)] },
+        Arith { name: "ASR", op: 0x0e, cycles: 1, vectors: vec![
+            v(0x8001, 1, n, 0xc000, 0x8000), v(0x8000, 16, n, 0xffff, 0x8000),
+            v(0x8000, 40, n, 0xffff, 0xffff), v(0x4000, 20, n, 0, 0x0400),
+            v(0x7fff, 0xffff, n, 0, 0), v(0x8000, 0xffff, n, 0xffff, 0xffff)] },
+        Arith { name: "SHL", op: 0x0f, cycles: 1, vectors: vec![
+            v(0x8001, 1, n, 0x0002, 1), v(0x1234, 16, n, 0, 0x1234), v(0x1234, 20, n, 0, 0x2340),
+            v(0x1234, 40, n, 0, 0), v(0x1234, 0, n, 0x1234, 0), v(0xffff, 0xffff, n, 0, 0)] },
+        Arith { name: "ADX", op: 0x1a, cycles: 3, vectors: vec![
+            v(0xffff, 0, 1, 0, 1), v(1, 2, 1, 4, 0), v(0xffff, 0xffff, 1, 0xffff, 1), v(0, 0, 0, 0, 0)] },
+        Arith { name: "SBX", op: 0x1b, cycles: 3, vectors: vec![
+            v(0, 0, 0xffff, 0xffff, 0xffff), v(5, 3, 0xffff, 1, 0), v(5, 3, 0, 2, 0),
+            v(0, 1, 0, 0xffff, 0xffff), v(2, 3, 1, 0, 0)] },
+    ]
+}
+
+struct Cond {
+    name: &'static str,
+    op: u16,
+    vectors: Vec<(u16, u16, bool)>,
+}
+
+fn conditionals() -> Vec<Cond> {
+    vec![
+        Cond { name: "IFB", op: 0x10, vectors: vec![(0x0f, 0xf0, false), (0x18, 0x08, true)] },
+        Cond { name: "IFC", op: 0x11, vectors: vec![(0x0f, 0xf0, true), (0x18, 0x08, false)] },
+        Cond { name: "IFE", op: 0x12, vectors: vec![(5, 5, true), (5, 6, false)] },
+        Cond { name: "IFN", op: 0x13, vectors: vec![(5, 6, true), (5, 5, false)] },
+        Cond { name: "IFG", op: 0x14, vectors: vec![(6, 5, true), (5, 6, false), (0xffff, 1, true), (5, 5, false)] },
+        Cond { name: "IFA", op: 0x15, vectors: vec![(6, 5, true), (0xffff, 1, false), (1, 0xffff, true), (5, 5, false)] },
+        Cond { name: "IFL", op: 0x16, vectors: vec![(5, 6, true), (0xffff, 1, false), (5, 5, false)] },
+        Cond { name: "IFU", op: 0x17, vectors: vec![(0xffff, 1, true), (1, 0xffff, false), (5, 5, false)] },
+    ]
+}
+
+fn kind_pairs() -> Vec<(Kind, Kind)> {
+    let mut pairs = vec![];
+    for &b in B_KINDS.iter() {
+        for &a in A_KINDS.iter() {
+            if !(b.uses_stack() && a.uses_stack()) {
+                pairs.push((b, a));
+            }
+        }
+    }
+    pairs
+}
+
+fn binary_case(name: String, op: u16, b: &Placed, a: &Placed) -> Case {
+    let program = encode(op, b, a);
+    let mut setup = a.setup.clone();
+    setup.extend(b.setup.iter().cloned());
+    let mut expect = vec![(Location::Pc, program.len() as u16)];
+    if let Some(sp) = b.sp.or(a.sp) {
+        expect.push((Location::Sp, sp));
+    }
+    Case { name, program, setup, interrupts: vec![], stub: false, steps: 1, expect, cycles: None }
+}
+
+fn arithmetic_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for arith in arithmetic() {
+        for (i, vector) in arith.vectors.iter().enumerate() {
+            // the first vector goes through every operand combination, the
+            // rest just registers
+            let pairs = if i == 0 { kind_pairs() } else { vec![(Kind::Register, Kind::Register)] };
+            for (bk, ak) in pairs {
+                let (b, a) = match (place_b(bk, vector.b), place_a(ak, vector.a)) {
+                    (Some(b), Some(a)) => (b, a),
+                    _ => continue
+                };
+                let name = format!("{} {:?}={:#06x}, {:?}={:#06x} (EX={:#06x})",
+                    arith.name, bk, vector.b, ak, vector.a, vector.ex);
+                let mut case = binary_case(name, arith.op, &b, &a);
+                case.setup.push((Location::Ex, vector.ex));
+                if let Some(home) = b.home {
+                    case.expect.push((home, vector.res));
+                }
+                case.expect.push((Location::Ex, vector.ex_out));
+                case.cycles = Some(arith.cycles + case.program.len() - 1);
+                cases.push(case);
+            }
+        }
+    }
+    cases
+}
+
+fn conditional_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for cond in conditionals() {
+        for (i, &(bv, av, pass)) in cond.vectors.iter().enumerate() {
+            let pairs = if i == 0 { kind_pairs() } else { vec![(Kind::Register, Kind::Register)] };
+            for (bk, ak) in pairs {
+                let (b, a) = match (place_b(bk, bv), place_a(ak, av)) {
+                    (Some(b), Some(a)) => (b, a),
+                    _ => continue
+                };
+                let name = format!("{} {:?}={:#06x}, {:?}={:#06x}", cond.name, bk, bv, ak, av);
+                let mut case = binary_case(name, cond.op, &b, &a);
+                let len = case.program.len();
+                case.program.push(GUARDED);
+                case.expect[0] = (Location::Pc, if pass { len } else { len + 1 } as u16);
+                // b is only read
+                if let Some(home) = b.home {
+                    case.expect.push((home, bv));
+                }
+                case.expect.push((Location::Register(Register::X), 0));
+                case.cycles = Some(2 + len - 1 + if pass { 0 } else { 1 });
+                cases.push(case);
+            }
+        }
+    }
+    cases
+}
+
+fn sti_std_cases() -> Vec<Case> {
+    let mut cases = vec![];
+    for &(name, op, i_out, j_out) in [("STI", 0x1e, 0x0011, 0x0000), ("STD", 0x1f, 0x000f, 0xfffe)].iter() {
+        for (bk, ak) in kind_pairs() {
+            let (b, a) = match (place_b(bk, 0), place_a(ak, 0x0042)) {
+                (Some(b), Some(a)) => (b, a),
+                _ => continue
+            };
+            let mut case = binary_case(format!("{} {:?}, {:?}", name, bk, ak), op, &b, &a);
+            case.setup.push((Location::Register(Register::I), 0x0010));
+            case.setup.push((Location::Register(Register::J), 0xffff));
+            if let Some(home) = b.home {
+                case.expect.push((home, 0x0042));
+            }
+            case.expect.push((Location::Register(Register::I), i_out));
+            case.expect.push((Location::Register(Register::J), j_out));
+            case.cycles = Some(2 + case.program.len() - 1);
+            cases.push(case);
+        }
+    }
+    cases
+}
+
+fn reader_case(name: String, op: u16, ak: Kind, value: u16) -> Option<Case> {
+    let a = place_a(ak, value)?;
+    let mut program = vec![special(op, a.code)];
+    program.extend(a.next);
+    let mut expect = vec![];
+    if let Some(sp) = a.sp {
+        expect.push((Location::Sp, sp));
+    }
+    Some(Case { name, program, setup: a.setup, interrupts: vec![], stub: false, steps: 1, expect, cycles: None })
+}
+
+// special opcodes that write to a can only use b style operands in it
+fn writer_case(name: String, op: u16, bk: Kind) -> Option<Case> {
+    if bk == Kind::Push {
+        return None
+    }
+    let b = place_b(bk, 0xdead)?;
+    let mut program = vec![special(op, b.code)];
+    program.extend(b.next);
+    let mut expect = vec![(Location::Pc, program.len() as u16)];
+    if let Some(sp) = b.sp {
+        expect.push((Location::Sp, sp));
+    }
+    Some(Case { name, program, setup: b.setup, interrupts: vec![], stub: false, steps: 1, expect, cycles: None })
+}
+
+fn special_cases() -> Vec<Case> {
+    let mut cases = vec![];
+
+    for &ak in A_KINDS.iter() {
+        if let Some(mut case) = reader_case(format!("JSR {:?}", ak), 0x01, ak, 0x0018) {
+            let len = case.program.len() as u16;
+            let sp = match ak {
+                Kind::Pop => STACK,
+                _ if ak.uses_stack() => STACK - 1,
+                _ => 0xffff
+            };
+            case.expect = vec![(Location::Pc, 0x0018), (Location::Sp, sp), (Location::Ram(sp), len)];
+            case.cycles = Some(3 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        if let Some(mut case) = reader_case(format!("IAS {:?}", ak), 0x0a, ak, 0x0018) {
+            case.expect.push((Location::Ia, 0x0018));
+            case.expect.push((Location::Pc, case.program.len() as u16));
+            case.cycles = Some(1 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        if let Some(mut case) = reader_case(format!("INT {:?} with IA=0", ak), 0x08, ak, 0x0005) {
+            case.setup.push((Location::Register(Register::A), 0x7777));
+            case.expect.push((Location::Pc, case.program.len() as u16));
+            case.expect.push((Location::Register(Register::A), 0x7777));
+            case.cycles = Some(4 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        // the saved A and PC sit above whatever a pops
+        if let Some(mut case) = reader_case(format!("RFI {:?}", ak), 0x0b, ak, 0x0005) {
+            let top = if ak == Kind::Pop { STACK + 1 } else { STACK };
+            case.setup.push((Location::Sp, STACK));
+            case.setup.push((Location::Ram(top), 0x7777));
+            case.setup.push((Location::Ram(top + 1), 0x0030));
+            case.expect = vec![(Location::Pc, 0x0030), (Location::Sp, top + 2),
+                (Location::Register(Register::A), 0x7777)];
+            case.cycles = Some(3 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        if let Some(mut case) = reader_case(format!("HWQ {:?}", ak), 0x11, ak, 0) {
+            case.stub = true;
+            case.expect.push((Location::Pc, case.program.len() as u16));
+            case.expect.push((Location::Register(Register::A), STUB_MODEL as u16));
+            case.expect.push((Location::Register(Register::B), (STUB_MODEL >> 16) as u16));
+            case.expect.push((Location::Register(Register::C), STUB_VERSION));
+            case.expect.push((Location::Register(Register::X), STUB_MANUFACTURER as u16));
+            case.expect.push((Location::Register(Register::Y), (STUB_MANUFACTURER >> 16) as u16));
+            case.cycles = Some(4 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        if let Some(mut case) = reader_case(format!("HWI {:?}", ak), 0x12, ak, 0) {
+            case.stub = true;
+            case.setup.push((Location::Register(Register::A), 0x0041));
+            case.expect.push((Location::Pc, case.program.len() as u16));
+            case.expect.push((Location::Register(Register::B), 0x0042));
+            case.cycles = Some(4 + STUB_CYCLES + case.program.len() - 1);
+            cases.push(case);
+        }
+    }
+
+    // a device that isn't there leaves the registers alone, at the same cost
+    for &(stub, index) in [(false, 0), (true, 1), (true, 0xffff)].iter() {
+        let regs = [Register::A, Register::B, Register::C, Register::X, Register::Y];
+        let setup: Vec<_> = regs.iter().map(|&r| (Location::Register(r), 0x1111 * (r as u16 + 1))).collect();
+        for &(name, op) in [("HWQ", 0x11), ("HWI", 0x12)].iter() {
+            cases.push(Case {
+                name: format!("{} {:#06x} with {} devices", name, index, stub as usize),
+                program: vec![special(op, 0x1f), index],
+                setup: setup.clone(), interrupts: vec![], stub, steps: 1,
+                expect: setup.iter().cloned().chain(Some((Location::Pc, 2))).collect(),
+                cycles: Some(4 + 1),
+            });
+        }
+    }
+
+    for &bk in B_KINDS.iter() {
+        if let Some(mut case) = writer_case(format!("IAG {:?}", bk), 0x09, bk) {
+            case.setup.push((Location::Ia, 0x1234));
+            if let Some(home) = place_b(bk, 0).and_then(|b| b.home) {
+                case.expect.push((home, 0x1234));
+            }
+            case.cycles = Some(1 + case.program.len() - 1);
+            cases.push(case);
+        }
+
+        if let Some(mut case) = writer_case(format!("HWN {:?}", bk), 0x10, bk) {
+            if let Some(home) = place_b(bk, 0).and_then(|b| b.home) {
+                case.expect.push((home, 0));
+            }
+            case.cycles = Some(2 + case.program.len() - 1);
+            cases.push(case);
+        }
+    }
+
+    cases
+}
+
+fn manual_cases() -> Vec<Case> {
+    let a = Location::Register(Register::A);
+    let b = Location::Register(Register::B);
+    let c = Location::Register(Register::C);
+    vec![
+        Case {
+            name: "SET PC, next word jumps".to_string(),
+            program: vec![basic(0x01, 0x1c, 0x1f), 0x0030],
+            setup: vec![], interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Pc, 0x0030)],
+            cycles: Some(2),
+        },
+        Case {
+            name: "SET A, PC reads the address after the instruction".to_string(),
+            program: vec![basic(0x01, 0x00, 0x1c)],
+            setup: vec![], interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(a, 0x0001), (Location::Pc, 0x0001)],
+            cycles: Some(1),
+        },
+        Case {
+            name: "SET PUSH, POP".to_string(),
+            program: vec![basic(0x01, 0x18, 0x18)],
+            setup: vec![(Location::Sp, STACK), (Location::Ram(STACK), 9)],
+            interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Sp, STACK), (Location::Ram(STACK), 9)],
+            cycles: Some(1),
+        },
+        Case {
+            name: "stack wraps around from 0x0000".to_string(),
+            program: vec![basic(0x01, 0x18, 0x2a), basic(0x01, 0x01, 0x18)],
+            setup: vec![], interrupts: vec![], stub: false, steps: 2,
+            expect: vec![(Location::Sp, 0), (Location::Ram(0xffff), 9), (b, 9)],
+            cycles: Some(2),
+        },
+        Case {
+            name: "writes to a literal fail silently".to_string(),
+            program: vec![basic(0x01, 0x1f, 0x26), 0x1234],
+            setup: vec![], interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Pc, 2), (Location::Ram(1), 0x1234)],
+            cycles: Some(2),
+        },
+        Case {
+            name: "[register + next word] wraps".to_string(),
+            program: vec![basic(0x01, 0x12, 0x27), 0x0010],
+            setup: vec![(c, 0xfff8)], interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Ram(0x0008), 6)],
+            cycles: Some(2),
+        },
+        Case {
+            name: "failed IF skips a three word instruction".to_string(),
+            program: vec![basic(0x12, 0x00, 0x22), basic(0x01, 0x1e, 0x1f), 0x1234, 0x1000, GUARDED],
+            setup: vec![], interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Pc, 4), (Location::Ram(0x1000), 0)],
+            cycles: Some(3),
+        },
+        Case {
+            name: "chained IFs are skipped together".to_string(),
+            program: vec![
+                basic(0x12, 0x00, 0x22),             // IFE A, 1
+                basic(0x12, 0x00, 0x00),             // IFE A, A
+                basic(0x13, 0x1e, 0x1f), 0x1234, 0x3000, // IFN [0x3000], 0x1234
+                basic(0x01, 0x01, 0x22),             // SET B, 1
+                basic(0x01, 0x02, 0x22),             // SET C, 1
+            ],
+            setup: vec![], interrupts: vec![], stub: false, steps: 2,
+            // 2 + 1 for failing + 1 per skipped IF, then SET C, 1
+            expect: vec![(Location::Pc, 7), (b, 0), (c, 1)],
+            cycles: Some(5 + 1),
+        },
+        Case {
+            name: "passing IF runs the chained IF".to_string(),
+            program: vec![
+                basic(0x12, 0x00, 0x21),             // IFE A, 0
+                basic(0x12, 0x00, 0x22),             // IFE A, 1
+                basic(0x01, 0x01, 0x22),             // SET B, 1
+                basic(0x01, 0x02, 0x22),             // SET C, 1
+            ],
+            setup: vec![], interrupts: vec![], stub: false, steps: 3,
+            expect: vec![(Location::Pc, 4), (b, 0), (c, 1)],
+            cycles: Some(2 + 3 + 1),
+        },
+        Case {
+            name: "INT with IA set enters the handler".to_string(),
+            program: vec![special(0x08, 0x26)],
+            setup: vec![(Location::Ia, 0x0100), (a, 0x7777)],
+            interrupts: vec![], stub: false, steps: 1,
+            expect: vec![(Location::Pc, 0x0100), (a, 5), (Location::Sp, 0xfffe),
+                (Location::Ram(0xffff), 1), (Location::Ram(0xfffe), 0x7777)],
+            cycles: Some(4),
+        },
+        Case {
+            name: "RFI returns from the handler".to_string(),
+            program: vec![special(0x08, 0x26), 0, 0, 0, 0, special(0x0b, 0x21)],
+            setup: vec![(Location::Ia, 0x0005), (a, 0x7777)],
+            interrupts: vec![], stub: false, steps: 2,
+            expect: vec![(Location::Pc, 1), (a, 0x7777), (Location::Sp, 0)],
+            cycles: Some(4 + 3),
+        },
+        Case {
+            name: "hardware interrupts are dropped while IA=0".to_string(),
+            // IAS 0x0010, SET C, 1
+            program: vec![special(0x0a, 0x1f), 0x0010, basic(0x01, 0x02, 0x22)],
+            setup: vec![], interrupts: vec![3], stub: false, steps: 2,
+            expect: vec![(Location::Pc, 3), (a, 0), (c, 1), (Location::Sp, 0)],
+            cycles: Some(2 + 1),
+        },
+        Case {
+            name: "IAQ queues interrupts until it is turned off".to_string(),
+            program: vec![
+                special(0x0c, 0x22),                 // IAQ 1
+                special(0x08, 0x28),                 // INT 7
+                special(0x08, 0x29),                 // INT 8
+                special(0x0c, 0x21),                 // IAQ 0
+            ],
+            setup: vec![(Location::Ia, 0x0100)],
+            interrupts: vec![], stub: false, steps: 4,
+            expect: vec![(Location::Pc, 0x0100), (a, 7), (Location::Ram(0xffff), 4), (Location::Sp, 0xfffe)],
+            cycles: Some(2 + 4 + 4 + 2),
+        },
+        Case {
+            name: "queued interrupts trigger one per instruction".to_string(),
+            program: vec![
+                special(0x0c, 0x22),                 // IAQ 1
+                special(0x08, 0x28),                 // INT 7
+                special(0x08, 0x29),                 // INT 8
+                special(0x0c, 0x21),                 // IAQ 0
+                0, 0, 0, 0,
+                special(0x0b, 0x21),                 // RFI 0 at 0x0008
+            ],
+            setup: vec![(Location::Ia, 0x0008)],
+            interrupts: vec![], stub: false, steps: 5,
+            // the handler for 7 returns straight into the handler for 8
+            expect: vec![(Location::Pc, 0x0008), (a, 8), (Location::Ram(0xffff), 4), (Location::Sp, 0xfffe)],
+            cycles: Some(2 + 4 + 4 + 2 + 3),
+        },
+        Case {
+            name: "interrupts are queued while in a handler".to_string(),
+            program: vec![basic(0x01, 0x01, 0x22), 0, 0, 0, basic(0x01, 0x02, 0x22)],
+            setup: vec![(Location::Ia, 0x0004)],
+            interrupts: vec![1, 2], stub: false, steps: 2,
+            // 1 triggers after the first instruction, 2 waits for RFI
+            expect: vec![(Location::Pc, 0x0005), (a, 1), (b, 1), (c, 1), (Location::Sp, 0xfffe)],
+            cycles: Some(2),
+        },
+    ]
+}
+
+pub fn cases() -> Vec<Case> {
+    let mut cases = arithmetic_cases();
+    cases.extend(conditional_cases());
+    cases.extend(sti_std_cases());
+    cases.extend(special_cases());
+    cases.extend(manual_cases());
+    cases
+}
+
+impl Emulator for VirtualMachine {
+    type Error = DcpuVMError;
+
+    fn reset(&mut self) {
+        while self.unplug(0).is_some() {}
+        VirtualMachine::reset(self)
+    }
+
+    fn load(&mut self, program: &[u16]) {
+        self.get_ram()[..program.len()].copy_from_slice(program);
+    }
+
+    fn step(&mut self) -> Result<usize, DcpuVMError> {
+        VirtualMachine::step(self)
+    }
+
+    fn read(&mut self, loc: Location) -> u16 {
+        match loc {
+            Location::Register(reg) => self.get_registers()[reg as usize],
+            Location::Pc => *self.get_pc(),
+            Location::Sp => *self.get_sp(),
+            Location::Ex => *self.get_ex(),
+            Location::Ia => *self.get_ia(),
+            Location::Ram(addr) => self.get_ram()[addr as usize],
+        }
+    }
+
+    fn write(&mut self, loc: Location, data: u16) {
+        match loc {
+            Location::Register(reg) => self.get_registers()[reg as usize] = data,
+            Location::Pc => *self.get_pc() = data,
+            Location::Sp => *self.get_sp() = data,
+            Location::Ex => *self.get_ex() = data,
+            Location::Ia => *self.get_ia() = data,
+            Location::Ram(addr) => self.get_ram()[addr as usize] = data,
+        }
+    }
+
+    fn interrupt(&mut self, msg: u16) {
+        VirtualMachine::interrupt(self, msg)
+    }
+
+    fn attach_stub(&mut self) {
+        self.plug(Box::new(Stub::default())).expect("room for one device");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtual_machine() {
+        let report = run(&mut VirtualMachine::new());
+        assert!(report.failures.is_empty(), "{}", report);
+        assert!(report.passed > 1000);
+        let report = run(&mut VirtualMachine::new().predecode(false));
+        assert!(report.failures.is_empty(), "{}", report);
+    }
+
+    #[test]
+    fn catches_a_bad_expectation() {
+        let mut case = manual_cases().remove(0);
+        case.expect = vec![(Location::Pc, 0x0031)];
+        let report = run_cases(&mut VirtualMachine::new(), &[case]);
+        assert_eq!(report.passed, 0);
+        assert_eq!(report.failures.len(), 1);
+    }
+}
@@ -0,0 +1,267 @@
+// a concrete syntax tree: every byte of the source ends up in exactly one
+// token, comments and whitespace included, so the source can be rebuilt from
+// the tree and tools like the formatter can move things around without
+// losing anything
+use std::fmt::{self, Display, Formatter};
+use diagnostics::{Located, Span};
+use parser::{parse_file, ParseError, Statement};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Whitespace,
+    Newline,
+    Comment,
+    Colon,
+    Comma,
+    Plus,
+    LBracket,
+    RBracket,
+    Mnemonic,
+    // .dw, .equ and #define
+    Directive,
+    // registers, PUSH and POP
+    Register,
+    Number,
+    // strings and characters, quotes included
+    String,
+    Ident,
+    Unknown,
+}
+
+impl TokenKind {
+    pub fn is_trivia(self) -> bool {
+        matches!(self, TokenKind::Whitespace | TokenKind::Newline | TokenKind::Comment)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Label,
+    Instruction,
+    // text the parser couldn't make sense of, up to the end of its line
+    Error,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+    pub tokens: Vec<Token>,
+    // what the parser made of it, if anything
+    pub statement: Option<Statement>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Element {
+    Trivia(Token),
+    Node(Node),
+}
+
+pub struct SyntaxTree {
+    text: String,
+    elements: Vec<Element>,
+    errors: Vec<Located<ParseError>>,
+}
+
+const REGISTERS: [&str; 13] = ["A", "B", "C", "X", "Y", "Z", "I", "J", "PC", "SP", "EX", "PUSH", "POP"];
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// splits src[span] into tokens. the first identifier in an instruction is
+// its mnemonic, unless it comes after a directive
+fn lex(src: &str, span: Span, instruction: bool) -> Vec<Token> {
+    let mut tokens = vec![];
+    let text = &src[span.start..span.end];
+    let mut chars = text.char_indices().peekable();
+    let mut seen_mnemonic = !instruction;
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            '\n' => TokenKind::Newline,
+            ' ' | '\t' | '\r' => {
+                while chars.next_if(|&(_, c)| c == ' ' || c == '\t' || c == '\r').is_some() {}
+                TokenKind::Whitespace
+            },
+            ';' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                TokenKind::Comment
+            },
+            ':' => TokenKind::Colon,
+            ',' => TokenKind::Comma,
+            '+' => TokenKind::Plus,
+            '[' => TokenKind::LBracket,
+            ']' => TokenKind::RBracket,
+            '.' | '#' if !seen_mnemonic && chars.peek().is_some_and(|&(_, c)| c.is_ascii_alphabetic()) => {
+                while chars.next_if(|&(_, c)| is_ident_char(c)).is_some() {}
+                seen_mnemonic = true;
+                TokenKind::Directive
+            },
+            '"' | '\'' => {
+                let mut escaped = false;
+                while let Some((_, next)) = chars.next_if(|&(_, n)| n != '\n') {
+                    if next == c && !escaped {
+                        break;
+                    }
+                    escaped = next == '\\' && !escaped;
+                }
+                TokenKind::String
+            },
+            '#' | '0'..='9' | '-' => {
+                while chars.next_if(|&(_, c)| is_ident_char(c)).is_some() {}
+                TokenKind::Number
+            },
+            c if is_ident_char(c) => {
+                while chars.next_if(|&(_, c)| is_ident_char(c)).is_some() {}
+                TokenKind::Ident
+            },
+            _ => TokenKind::Unknown,
+        };
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        let kind = match kind {
+            TokenKind::Ident if !seen_mnemonic => {
+                seen_mnemonic = true;
+                TokenKind::Mnemonic
+            },
+            TokenKind::Ident if REGISTERS.iter().any(|r| r.eq_ignore_ascii_case(&text[start..end])) => TokenKind::Register,
+            kind => kind,
+        };
+        tokens.push(Token { kind, span: Span { start: start + span.start, end: end + span.start } });
+    }
+    tokens
+}
+
+// whitespace and comments between statements become trivia. anything else
+// there is an error, which runs to the end of the line
+fn lex_gap(src: &str, span: Span, elements: &mut Vec<Element>) {
+    let mut tokens = lex(src, span, false).into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        if token.kind.is_trivia() {
+            elements.push(Element::Trivia(token));
+            continue;
+        }
+        let mut error = vec![token];
+        // trailing whitespace and comments aren't part of the error
+        while let Some(&next) = tokens.peek() {
+            if next.kind == TokenKind::Newline || next.kind == TokenKind::Comment {
+                break;
+            }
+            error.push(next);
+            tokens.next();
+        }
+        let trailing = error.iter().rev().take_while(|t| t.kind == TokenKind::Whitespace).count();
+        let whitespace = error.split_off(error.len() - trailing);
+        elements.push(Element::Node(Node {
+            kind: NodeKind::Error,
+            span: Span { start: error[0].span.start, end: error[error.len() - 1].span.end },
+            tokens: error,
+            statement: None,
+        }));
+        elements.extend(whitespace.into_iter().map(Element::Trivia));
+    }
+}
+
+impl SyntaxTree {
+    pub fn parse(file: Option<&str>, src: &str) -> SyntaxTree {
+        let parsed = parse_file(file, src);
+        let mut elements = vec![];
+        let mut pos = 0;
+        for (statement, span) in parsed.statements {
+            lex_gap(src, Span { start: pos, end: span.start }, &mut elements);
+            let kind = match statement {
+                Statement::LabelDef(_) => NodeKind::Label,
+                Statement::Instruction(_) | Statement::Nop | Statement::Data(_) | Statement::Constant(..) => NodeKind::Instruction,
+            };
+            let mut tokens = lex(src, span, kind == NodeKind::Instruction);
+            if kind == NodeKind::Label {
+                // labels can look like registers, :a is still a label
+                for token in tokens.iter_mut().filter(|t| t.kind == TokenKind::Register) {
+                    token.kind = TokenKind::Ident;
+                }
+            }
+            elements.push(Element::Node(Node { kind, span, tokens, statement: Some(statement) }));
+            pos = span.end;
+        }
+        lex_gap(src, Span { start: pos, end: src.len() }, &mut elements);
+        SyntaxTree { text: src.to_owned(), elements, errors: parsed.errors }
+    }
+
+    pub fn elements(&self) -> &[Element] {
+        &self.elements
+    }
+
+    pub fn errors(&self) -> &[Located<ParseError>] {
+        &self.errors
+    }
+
+    pub fn source(&self) -> &str {
+        &self.text
+    }
+
+    pub fn text(&self, token: &Token) -> &str {
+        &self.text[token.span.start..token.span.end]
+    }
+
+    // every token in source order
+    pub fn tokens(&self) -> impl Iterator<Item = &Token> {
+        self.elements.iter().flat_map(|e| match *e {
+            Element::Trivia(ref t) => ::std::slice::from_ref(t).iter(),
+            Element::Node(ref n) => n.tokens.iter(),
+        })
+    }
+}
+
+impl Display for SyntaxTree {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        for token in self.tokens() {
+            fmt.write_str(self.text(token))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless() {
+        let src = "; boot\r\n:start SET [A + 0x10], -1 ;; set\n\tSET ,  ; oops\n  IFE a,\n #1f\n\n garbage! here\n";
+        let tree = SyntaxTree::parse(None, src);
+        assert_eq!(tree.to_string(), src);
+        let mut end = 0;
+        for token in tree.tokens() {
+            assert_eq!(token.span.start, end);
+            end = token.span.end;
+        }
+        assert_eq!(end, src.len());
+        assert_eq!(tree.errors().len(), 2);
+    }
+
+    #[test]
+    fn kinds() {
+        let tree = SyntaxTree::parse(None, ":start SET [A+0x10], label ; hi\nbad line ; kept\n");
+        let nodes: Vec<_> = tree.elements().iter().filter_map(|e| match *e {
+            Element::Node(ref n) => Some((n.kind, &tree.source()[n.span.start..n.span.end])),
+            _ => None,
+        }).collect();
+        assert_eq!(nodes, vec![
+            (NodeKind::Label, ":start"),
+            (NodeKind::Instruction, "SET [A+0x10], label"),
+            (NodeKind::Error, "bad line"),
+        ]);
+        let kinds: Vec<_> = tree.tokens().map(|t| t.kind).filter(|k| *k != TokenKind::Whitespace).collect();
+        assert_eq!(kinds, vec![
+            TokenKind::Colon, TokenKind::Ident, TokenKind::Mnemonic, TokenKind::LBracket, TokenKind::Register,
+            TokenKind::Plus, TokenKind::Number, TokenKind::RBracket, TokenKind::Comma, TokenKind::Ident,
+            TokenKind::Comment, TokenKind::Newline, TokenKind::Ident, TokenKind::Ident, TokenKind::Comment,
+            TokenKind::Newline,
+        ]);
+    }
+}
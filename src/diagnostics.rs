@@ -0,0 +1,116 @@
+// where errors in source files happened, and showing them with the source
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+// byte offsets into the source
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn offset(self, by: usize) -> Span {
+        Span { start: self.start + by, end: self.end + by }
+    }
+}
+
+impl<'a> From<::pest::Span<'a>> for Span {
+    fn from(span: ::pest::Span<'a>) -> Span {
+        Span { start: span.start(), end: span.end() }
+    }
+}
+
+// lines and columns count from 1, columns in characters
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl Location {
+    pub fn new(file: Option<&str>, src: &str, span: Span) -> Location {
+        let before = &src[..span.start.min(src.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Location {
+            file: file.map(|f| f.to_owned()),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_fmt(format_args!("{}:{}:{}", self.file.as_ref().map_or("<input>", |f| &f[..]),
+                                   self.line, self.column))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located<E> {
+    pub error: E,
+    pub location: Location,
+}
+
+impl<E> Located<E> {
+    pub fn new(error: E, file: Option<&str>, src: &str, span: Span) -> Located<E> {
+        Located { error, location: Location::new(file, src, span) }
+    }
+
+    pub fn map<F, T>(self, f: F) -> Located<T> where F: FnOnce(E) -> T {
+        Located { error: f(self.error), location: self.location }
+    }
+}
+
+impl<E: Display> Located<E> {
+    // the error, the line it's on and carets under the span (or as much of
+    // it as is on that line)
+    pub fn render(&self, src: &str) -> String {
+        let span = self.location.span;
+        let start = span.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let line = src[line_start..line_end].trim_end_matches('\r');
+        let number = self.location.line.to_string();
+        let gutter = " ".repeat(number.len());
+        // tabs stay tabs so the carets line up however they're shown
+        let pad: String = src[line_start..start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = src[start..span.end.clamp(start, line_end)].chars().count().max(1);
+        format!("{}: error: {}\n{} |\n{} | {}\n{} | {}{}\n",
+                self.location, self.error, gutter, number, line, gutter, pad, "^".repeat(width))
+    }
+}
+
+impl<E: Display> Display for Located<E> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_fmt(format_args!("{}: {}", self.location, self.error))
+    }
+}
+
+impl<E: Error> Error for Located<E> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_with_carets() {
+        let src = "SET A, 1\n\tSET B, 123456 ; big\n";
+        let error = Located::new("Literal too big", Some("test.dasm"), src, Span { start: 17, end: 23 });
+        assert_eq!(error.location.line, 2);
+        assert_eq!(error.location.column, 9);
+        assert_eq!(error.to_string(), "test.dasm:2:9: Literal too big");
+        assert_eq!(error.render(src),
+                   "test.dasm:2:9: error: Literal too big\n  |\n2 | \tSET B, 123456 ; big\n  | \t       ^^^^^^\n");
+
+        // past the end of the input still gets a caret
+        let error = Located::new("Expected an operand", None, "SET A,", Span { start: 6, end: 6 });
+        assert_eq!(error.render("SET A,"), "<input>:1:7: error: Expected an operand\n  |\n1 | SET A,\n  |       ^\n");
+    }
+}
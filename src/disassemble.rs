@@ -1,7 +1,9 @@
 use opcodes::{Opcode, Operand};
+use virtual_machine::Register;
 use std::mem::transmute;
 use std::iter::Peekable;
 use thiserror::Error;
+use image::Image;
 
 const A_MASK:u16 = 0xfc00;
 const B_MASK:u16 = 0x03e0;
@@ -25,6 +27,27 @@ fn from_short_literal(n: u16) -> u16 {
     ((n as i16) - 0x21) as u16
 }
 
+fn operand_words(op: u16) -> usize {
+    match op {
+        0x10..=0x17 | 0x1a | 0x1e | 0x1f => 1,
+        _ => 0
+    }
+}
+
+// number of words following inst, worked out from the operand fields alone so
+// reserved opcodes can still be skipped over
+pub fn next_words(inst: u16) -> usize {
+    let a = operand_words((inst & A_MASK) >> 10);
+    if inst & 0x1f == 0 {
+        return a;
+    }
+    a + operand_words((inst & B_MASK) >> 5)
+}
+
+pub fn is_conditional(inst: u16) -> bool {
+    (0x10..=0x17).contains(&(inst & 0x1f))
+}
+
 fn get_operand(is_a: bool, inst: u16, next: Option<&u16>) -> Result<(Operand, bool), DcpuDisassmError> {
     let op = match is_a {
         true => (inst & A_MASK) >> 10,
@@ -87,38 +110,38 @@ fn handle_special_op(inst: u16, next: Option<&u16>) -> Result<(Opcode, bool), Dc
         },
         0x08 => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::INT(a), eat))
         },
         0x09 => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::IAG(a), eat))
         },
         0x0a => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::IAS(a), eat))
         },
         0x0b => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::RFI(a), eat))
         },
         0x0c => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::IAQ(a), eat))
         },
         0x0d..=0x0f => {
             Err(DcpuDisassmError::ReservedOpcode { op: inst })
         },
         0x10 => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::HWN(a), eat))
         },
         0x11 => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::HWQ(a), eat))
         },
         0x12 => {
             let (a, eat) = get_operand(true, inst, next)?;
-            Ok((Opcode::JSR(a), eat))
+            Ok((Opcode::HWI(a), eat))
         },
         0x13..=0x1f => {
             Err(DcpuDisassmError::ReservedOpcode {op: inst})
@@ -499,6 +522,58 @@ pub fn disassm_one<'a, I>(inst: u16, itr: &mut Peekable<I>) -> Result<(Opcode, u
     }
 }
 
+// the instruction as the assembler's pseudo-ops write it, where one of them
+// does
+pub fn pseudo(op: &Opcode) -> String {
+    match *op {
+        Opcode::SET(Operand::Pc, Operand::Pop) => "RET".to_owned(),
+        Opcode::SET(Operand::Pc, Operand::Pc) => "BRK".to_owned(),
+        Opcode::SET(Operand::Register(Register::A), Operand::Register(Register::A)) => "NOP".to_owned(),
+        Opcode::ADD(Operand::Pc, Operand::Literal(0xffff)) => "HLT".to_owned(),
+        Opcode::SET(Operand::Pc, ref a) => format!("JMP {}", a),
+        Opcode::SET(Operand::Push, ref a) => format!("PUSH {}", a),
+        Opcode::SET(ref b, Operand::Pop) => format!("POP {}", b),
+        ref op => op.to_string(),
+    }
+}
+
+// the whole image as source that assembles back to the same words, with
+// its labels and each line's address. anything that won't decode, would
+// run into a label, or has a long literal the assembler would make short,
+// is a DAT. each segment starts with a comment saying where it goes
+pub fn listing(image: &Image, pseudo_ops: bool) -> String {
+    let mut labels: Vec<(u16, &str)> = image.symbols.iter().map(|(name, &address)| (address, name.as_str())).collect();
+    labels.sort();
+    let labelled = |from: u16, to: u16| labels.iter().any(|&(address, _)| address > from && address < to);
+    let mut out = String::new();
+    if let Some(entry) = image.entry {
+        out.push_str(&format!("; entry {:#06x}\n", entry));
+    }
+    for segment in image.segments.iter() {
+        out.push_str(&format!("; origin {:#06x}\n", segment.origin));
+        let words = &segment.words;
+        let mut offset = 0;
+        while offset < words.len() {
+            let address = segment.origin.wrapping_add(offset as u16);
+            for &(_, name) in labels.iter().filter(|&&(a, _)| a == address) {
+                out.push_str(&format!(":{}\n", name));
+            }
+            let inst = words[offset];
+            let mut itr = words[offset + 1..].iter().peekable();
+            let shortens = (inst & A_MASK) >> 10 == 0x1f
+                && words.get(offset + 1).is_some_and(|&n| n <= 30 || n == 0xffff);
+            let (text, size) = match disassm_one(inst, &mut itr) {
+                Ok((op, size)) if !shortens && !labelled(address, address.wrapping_add(size as u16 + 1)) =>
+                    (if pseudo_ops { pseudo(&op) } else { op.to_string() }, size + 1),
+                _ => (format!("DAT {:#06x}", inst), 1),
+            };
+            out.push_str(&format!("    {:<32}; {:04x}\n", text, address));
+            offset += size;
+        }
+    }
+    out
+}
+
 impl Disassemble for Vec<u16> {
     //XXX: this is garbage!
     fn disassm(&self) -> Result<Vec<Opcode>, DcpuDisassmError> {
@@ -0,0 +1,272 @@
+// formats assembly source on top of the syntax tree: one statement a line,
+// mnemonics in one case, operands spaced the same way, literals in one style
+// and comments lined up
+use cst::{Element, Node, NodeKind, SyntaxTree, TokenKind};
+use diagnostics::Located;
+use parser::ParseError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LiteralStyle {
+    // as they were written
+    Keep,
+    // 0x1f
+    Hex,
+    // 31
+    Decimal,
+    // #1f
+    Hash,
+}
+
+#[derive(Clone, Debug)]
+pub struct FormatOptions {
+    lowercase: bool,
+    literals: LiteralStyle,
+    indent: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions { lowercase: false, literals: LiteralStyle::Hex, indent: 4 }
+    }
+}
+
+impl FormatOptions {
+    pub fn new() -> FormatOptions {
+        FormatOptions::default()
+    }
+
+    // mnemonics and registers in lowercase rather than uppercase
+    pub fn lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    pub fn literals(mut self, style: LiteralStyle) -> Self {
+        self.literals = style;
+        self
+    }
+
+    // the least instructions are indented by. they go further in if a label
+    // sharing their line needs the room
+    pub fn indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct Line {
+    label: Option<String>,
+    code: Option<String>,
+    comments: Vec<String>,
+    // a comment on a line of its own, and not at the start of it
+    indented_comment: bool,
+}
+
+impl Line {
+    fn is_empty(&self) -> bool {
+        self.label.is_none() && self.code.is_none() && self.comments.is_empty()
+    }
+}
+
+fn number(text: &str, style: LiteralStyle) -> String {
+    let value = if let Some(hex) = text.strip_prefix('#') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if text.len() > 2 && text[..2].eq_ignore_ascii_case("0x") {
+        u16::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse::<i32>().ok().map(|n| n as u16)
+    };
+    match (value, style) {
+        (None, _) | (_, LiteralStyle::Keep) => text.to_owned(),
+        (Some(n), LiteralStyle::Hex) => format!("{:#x}", n),
+        // negative numbers stay negative
+        (Some(_), LiteralStyle::Decimal) if text.starts_with('-') => text.to_owned(),
+        (Some(n), LiteralStyle::Decimal) => n.to_string(),
+        (Some(n), LiteralStyle::Hash) => format!("#{:x}", n),
+    }
+}
+
+fn instruction(tree: &SyntaxTree, node: &Node, options: &FormatOptions, comments: &mut Vec<String>) -> String {
+    let case = |s: &str| if options.lowercase { s.to_lowercase() } else { s.to_uppercase() };
+    let mut code = String::new();
+    let mut last = TokenKind::Whitespace;
+    let is_word = |kind| matches!(kind, TokenKind::Register | TokenKind::Number | TokenKind::String | TokenKind::Ident);
+    for token in node.tokens.iter() {
+        let text = tree.text(token);
+        // words next to each other, like the name and value of a #define
+        if is_word(token.kind) && (is_word(last) || last == TokenKind::RBracket) {
+            code.push(' ');
+        }
+        match token.kind {
+            TokenKind::Whitespace | TokenKind::Newline => continue,
+            TokenKind::Comment => comments.push(text.trim_end().to_owned()),
+            TokenKind::Mnemonic => {
+                code.push_str(&case(text));
+                code.push(' ');
+            },
+            // directives are written the same way everywhere
+            TokenKind::Directive => {
+                code.push_str(&text.to_lowercase());
+                code.push(' ');
+            },
+            TokenKind::Register => code.push_str(&case(text)),
+            TokenKind::Number => code.push_str(&number(text, options.literals)),
+            TokenKind::Comma => code.push_str(", "),
+            TokenKind::Plus => code.push_str(" + "),
+            _ => code.push_str(text),
+        }
+        last = token.kind;
+    }
+    // RET and the like have nothing after the mnemonic
+    code.truncate(code.trim_end().len());
+    code
+}
+
+// where each line's comment starts. lines with code in a run of non-blank
+// lines share a column
+fn comment_columns(lines: &[Option<(String, Vec<String>)>]) -> Vec<usize> {
+    let mut columns = vec![0; lines.len()];
+    let mut start = 0;
+    while start < lines.len() {
+        let end = lines[start..].iter().position(|l| l.is_none()).map_or(lines.len(), |i| start + i);
+        let column = lines[start..end].iter().flatten()
+            .filter(|(code, comments)| !code.trim().is_empty() && !comments.is_empty())
+            .map(|(code, _)| code.chars().count() + 1)
+            .max()
+            .unwrap_or(0);
+        for c in columns[start..end].iter_mut() {
+            *c = column;
+        }
+        start = end + 1;
+    }
+    columns
+}
+
+// formats `src`, or returns the errors that stopped it. files that don't
+// parse aren't touched, since there's no telling what they meant
+pub fn format(src: &str, options: &FormatOptions) -> Result<String, Vec<Located<ParseError>>> {
+    let tree = SyntaxTree::parse(None, src);
+    if !tree.errors().is_empty() {
+        return Err(tree.errors().to_vec());
+    }
+
+    let mut lines: Vec<Line> = vec![];
+    let mut line = Line::default();
+    let mut at_line_start = true;
+    for element in tree.elements() {
+        match *element {
+            Element::Trivia(ref token) => match token.kind {
+                TokenKind::Newline => {
+                    lines.push(::std::mem::take(&mut line));
+                    at_line_start = true;
+                    continue;
+                },
+                TokenKind::Comment => {
+                    if line.is_empty() {
+                        line.indented_comment = !at_line_start;
+                    }
+                    line.comments.push(tree.text(token).trim_end().to_owned());
+                },
+                _ => (),
+            },
+            Element::Node(ref node) => {
+                // one label and one instruction a line, at most
+                if line.code.is_some() || (node.kind == NodeKind::Label && line.label.is_some()) {
+                    lines.push(::std::mem::take(&mut line));
+                }
+                let mut comments = vec![];
+                match node.kind {
+                    NodeKind::Label => {
+                        let name = node.tokens.iter().find(|t| t.kind == TokenKind::Ident).map_or("", |t| tree.text(t));
+                        // the colon stays on whichever side the dialect has it
+                        line.label = Some(match node.tokens.first() {
+                            Some(t) if t.kind == TokenKind::Colon => format!(":{}", name),
+                            _ => format!("{}:", name),
+                        });
+                    },
+                    _ => line.code = Some(instruction(&tree, node, options, &mut comments)),
+                }
+                line.comments.extend(comments);
+            },
+        }
+        at_line_start = false;
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+
+    let column = lines.iter()
+        .filter(|l| l.code.is_some())
+        .filter_map(|l| l.label.as_ref().map(|label| label.chars().count() + 1))
+        .fold(options.indent, usize::max);
+    let code: Vec<Option<(String, Vec<String>)>> = lines.into_iter().map(|l| {
+        if l.is_empty() {
+            return None;
+        }
+        let mut code = l.label.unwrap_or_default();
+        if let Some(instruction) = l.code {
+            let pad = column.saturating_sub(code.chars().count()).max(if code.is_empty() { 0 } else { 1 });
+            code.push_str(&" ".repeat(pad));
+            code.push_str(&instruction);
+        } else if code.is_empty() && l.indented_comment {
+            code = " ".repeat(column);
+        }
+        Some((code, l.comments))
+    }).collect();
+
+    let mut out = String::new();
+    for (line, comment_column) in code.iter().zip(comment_columns(&code)) {
+        if let Some((ref code, ref comments)) = *line {
+            out.push_str(code);
+            if !comments.is_empty() && !code.trim().is_empty() {
+                out.push_str(&" ".repeat(comment_column.saturating_sub(code.chars().count()).max(1)));
+            }
+            out.push_str(&comments.join(" "));
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let src = "; boot code\n:start set a,#1F ; one\n  :loop_forever  SUB [ a+0x10 ],-1 ; minus one\n\
+                   \n\n   ; indented\nSET PC,\n  loop_forever ; two\n:end\n";
+        assert_eq!(format(src, &FormatOptions::new()).unwrap(),
+                   "; boot code\n\
+                    :start        SET A, 0x1f            ; one\n\
+                    :loop_forever SUB [A + 0x10], 0xffff ; minus one\n\
+                    \n\n              ; indented\n\
+                    \x20             SET PC, loop_forever ; two\n\
+                    :end\n");
+        let options = FormatOptions::new().lowercase(true).literals(LiteralStyle::Decimal).indent(2);
+        assert_eq!(format(":a SET A, 0x10 :b :c SET B, -1\n", &options).unwrap(),
+                   ":a set a, 16\n:b\n:c set b, -1\n");
+        assert_eq!(format("  ret ; done\n", &FormatOptions::new()).unwrap(), "    RET ; done\n");
+        // formatting again changes nothing
+        let once = format(src, &FormatOptions::new()).unwrap();
+        assert_eq!(format(&once, &FormatOptions::new()).unwrap(), once);
+    }
+
+    #[test]
+    fn keeps_the_dialect() {
+        let src = "loop: set a,count\n.DW 1,'a' ; data\n#define SIZE 5\ndat \"a, b;c\"\n";
+        assert_eq!(format(src, &FormatOptions::new()).unwrap(),
+                   "loop: SET A, count\n      .dw 0x1, 'a' ; data\n      #define SIZE 0x5\n      DAT \"a, b;c\"\n");
+    }
+
+    #[test]
+    fn leaves_broken_files_alone() {
+        let errors = format("SET A, 1\nSET ,\n", &FormatOptions::new()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location.line, 2);
+    }
+}
@@ -0,0 +1,237 @@
+// a Forth system that runs on the DCPU, with the screen and keyboard as its
+// console, and a way for the host to type lines into it and get back what
+// they printed. the ROM is forth.dasm, which build.rs assembles;
+// docs/forth.txt has the words
+use std::collections::{BTreeSet, VecDeque};
+use thiserror::Error;
+use hardware::{key_for_char, Hardware, Keyboard, KeyboardInput, Lem1802, Lem1802Screen, KEYBOARD_BUFFER_SIZE,
+               KEY_RETURN};
+use virtual_machine::{DcpuVMError, StopReason, VirtualMachine};
+
+mod rom {
+    include!(concat!(env!("OUT_DIR"), "/rom.rs"));
+}
+
+pub const SOURCE: &str = include_str!("forth.dasm");
+
+// how long a line gets to run by default
+const CYCLE_LIMIT: usize = 10_000_000;
+
+#[derive(Debug, Error)]
+pub enum ForthError {
+    // what the input printed, up to and including the error that stopped it
+    #[error("{}", .0)]
+    Aborted(String),
+    #[error("still running after {} cycles", .0)]
+    Timeout(usize),
+    #[error("forth has stopped")]
+    Bye,
+    #[error("{}", .0)]
+    Machine(#[from] DcpuVMError),
+}
+
+// the ROM, to load at 0. it finds the keyboard and LEM1802 itself
+pub fn rom() -> Vec<u16> {
+    rom::WORDS.to_vec()
+}
+
+pub struct Forth {
+    vm: VirtualMachine,
+    input: KeyboardInput,
+    screen: Lem1802Screen,
+    breakpoints: BTreeSet<u16>,
+    cycle_limit: usize,
+}
+
+impl Default for Forth {
+    fn default() -> Forth {
+        Forth::new()
+    }
+}
+
+impl Forth {
+    // a machine with the ROM loaded and a keyboard and screen attached,
+    // that hasn't started yet
+    pub fn new() -> Forth {
+        let keyboard = Keyboard::new();
+        let input = keyboard.input();
+        let lem = Lem1802::new();
+        let screen = lem.screen();
+        let vm = VirtualMachine::new().load_program(&rom(), 0)
+            .attach_hardware(Box::new(keyboard))
+            .and_then(|vm| vm.attach_hardware(Box::new(lem)))
+            .expect("a new machine has room for two devices");
+        let breakpoints = [rom::PUTC, rom::ACCEPTED, rom::PROMPT, rom::ERROR, rom::IDLE].iter().cloned().collect();
+        Forth { vm, input, screen, breakpoints, cycle_limit: CYCLE_LIMIT }
+    }
+
+    // another device for it to find when it boots, like one being brought up
+    pub fn attach_hardware(mut self, hardware: Box<dyn Hardware>) -> Result<Self, ForthError> {
+        self.vm = self.vm.attach_hardware(hardware)?;
+        Ok(self)
+    }
+
+    // how many cycles a call to eval gets before it gives up
+    pub fn cycle_limit(mut self, cycles: usize) -> Self {
+        self.cycle_limit = cycles;
+        self
+    }
+
+    // runs the ROM until it's waiting for its first line
+    pub fn boot(mut self) -> Result<Self, ForthError> {
+        self.run(VecDeque::new())?;
+        Ok(self)
+    }
+
+    // types the text in, a line at a time, and gives back what it printed,
+    // without the echo or the ok after each line. if a line goes wrong, the
+    // lines after it still run, but it's an error
+    pub fn eval(&mut self, text: &str) -> Result<String, ForthError> {
+        let mut keys: VecDeque<u16> = text.chars().filter_map(key_for_char).collect();
+        if keys.back() != Some(&KEY_RETURN) {
+            keys.push_back(KEY_RETURN);
+        }
+        match self.run(keys)? {
+            (output, false) => Ok(output),
+            (output, true) => Err(ForthError::Aborted(output.trim_end().to_owned())),
+        }
+    }
+
+    // what's on screen
+    pub fn screen(&self) -> &Lem1802Screen {
+        &self.screen
+    }
+
+    pub fn vm(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+
+    // feeds the keys in as there's room for them, until they've all been
+    // read and it's waiting for more. gives what was printed and whether
+    // there were any errors
+    fn run(&mut self, mut keys: VecDeque<u16>) -> Result<(String, bool), ForthError> {
+        let limit = self.vm.get_cycles() + self.cycle_limit;
+        let mut output = String::new();
+        let mut printing = false;
+        let mut failed = false;
+        loop {
+            while self.input.buffered() < KEYBOARD_BUFFER_SIZE {
+                match keys.pop_front() {
+                    Some(key) => self.input.type_key(key),
+                    None => break,
+                }
+            }
+            match self.vm.run(&self.breakpoints, Some(limit))? {
+                StopReason::Breakpoint(pc) if pc == rom::PUTC && printing =>
+                    output.push((self.vm.get_registers()[0] & 0x7f) as u8 as char),
+                StopReason::Breakpoint(pc) if pc == rom::ACCEPTED => printing = true,
+                StopReason::Breakpoint(pc) if pc == rom::PROMPT => printing = false,
+                StopReason::Breakpoint(pc) if pc == rom::ERROR => failed = true,
+                StopReason::Breakpoint(pc) if pc == rom::IDLE && keys.is_empty() => break,
+                StopReason::Halted(_) => return Err(ForthError::Bye),
+                StopReason::CycleLimit => return Err(ForthError::Timeout(self.cycle_limit)),
+                StopReason::OnFire => return Err(ForthError::Machine(DcpuVMError::OnFire)),
+                _ => (),
+            }
+        }
+        // a frame more, so the screen has caught up
+        let frame = self.vm.get_cycles() + self.vm.get_clock_rate() / 60;
+        self.vm.run(&BTreeSet::new(), Some(frame))?;
+        Ok((output, failed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forth() -> Forth {
+        Forth::new().boot().unwrap()
+    }
+
+    #[test]
+    fn boots_to_a_prompt() {
+        let mut forth = forth();
+        assert!(forth.screen().text().starts_with("dcpu forth"));
+        assert_eq!(forth.eval("").unwrap(), "");
+        assert_eq!(forth.eval("2 3 + .").unwrap(), "5 ");
+        assert!(forth.screen().text().contains("2 3 + . 5  ok"), "{}", forth.screen().text());
+    }
+
+    #[test]
+    fn arithmetic_and_the_stack() {
+        let mut forth = forth();
+        assert_eq!(forth.eval("1 2 3 .s rot .s -rot .s 2drop drop .s").unwrap(), "<3> 1 2 3 <3> 2 3 1 <3> 1 2 3 <0> ");
+        assert_eq!(forth.eval("-7 2 / . -7 2 mod . 7 2 /mod . . -5 abs . 3 negate .").unwrap(), "-3 -1 3 1 5 -3 ");
+        assert_eq!(forth.eval("hex ff . -1 u. 1f 2 lshift . decimal 100 .").unwrap(), "ff ffff 7c 100 ");
+        assert_eq!(forth.eval("1 2 < . 2 1 < . -1 0 u< . 5 0= . 3 7 max . 3 7 min .").unwrap(), "-1 0 0 0 7 3 ");
+        assert_eq!(forth.eval("1 2 3 depth . 2dup . . . . . ").unwrap(), "3 3 2 3 2 1 ");
+    }
+
+    #[test]
+    fn definitions_and_control_flow() {
+        let mut forth = forth();
+        assert_eq!(forth.eval(": square dup * ;").unwrap(), "");
+        assert_eq!(forth.eval("7 square .").unwrap(), "49 ");
+        forth.eval(": fact ( n -- n! ) dup 1 > if dup 1- recurse * then ;").unwrap();
+        assert_eq!(forth.eval("6 fact .").unwrap(), "720 ");
+        forth.eval(": count-down begin dup . 1- dup 0= until drop ;").unwrap();
+        assert_eq!(forth.eval("3 count-down").unwrap(), "3 2 1 ");
+        forth.eval(": sum 0 swap 0 do i + loop ;\n: evens 10 0 do i . 2 +loop ;").unwrap();
+        assert_eq!(forth.eval("10 sum . evens").unwrap(), "45 0 2 4 6 8 ");
+        forth.eval(": down 0 10 do i . -3 +loop ; : grid 3 1 do 3 1 do j i * . loop loop ;").unwrap();
+        assert_eq!(forth.eval("down grid").unwrap(), "10 7 4 1 1 2 2 4 ");
+        forth.eval(": sign dup 0< if drop .\" negative\" else 0> if .\" positive\" else .\" zero\" then then ;").unwrap();
+        assert_eq!(forth.eval("-4 sign space 0 sign space 9 sign").unwrap(), "negative zero positive");
+        forth.eval(": halve begin dup 1 > while 2/ repeat ;").unwrap();
+        assert_eq!(forth.eval("100 halve .").unwrap(), "1 ");
+    }
+
+    #[test]
+    fn memory_and_defining_words() {
+        let mut forth = forth();
+        forth.eval("variable x 5 x ! 3 x +! 10 constant ten create table 1 , 2 , 3 ,").unwrap();
+        assert_eq!(forth.eval("x @ . ten . table 2 + @ . here table - .").unwrap(), "8 10 3 3 ");
+        forth.eval(": array create allot does> + ; 4 array a 7 2 a ! table 0 a 3 move").unwrap();
+        assert_eq!(forth.eval("2 a @ . 0 a @ . 0 a 4 0 fill 1 a @ .").unwrap(), "3 1 0 ");
+        assert_eq!(forth.eval(": greet s\" hi there\" type ; greet char A emit char z . ' greet execute").unwrap(),
+                   "hi thereA122 hi there");
+        assert_eq!(forth.eval("5 ' dup execute * . \\ the rest is ignored . . .").unwrap(), "25 ");
+        assert_eq!(forth.eval(": seven [ 3 4 + ] literal ; seven .").unwrap(), "7 ");
+    }
+
+    #[test]
+    fn errors_start_the_line_again() {
+        let mut forth = forth();
+        assert_eq!(forth.eval("1 2 frob 3").unwrap_err().to_string(), "frob ?");
+        assert_eq!(forth.eval("depth .").unwrap(), "0 ");
+        assert_eq!(forth.eval("drop 1").unwrap_err().to_string(), "stack underflow");
+        assert_eq!(forth.eval(": broken 1 nothing ;").unwrap_err().to_string(), "nothing ?");
+        assert_eq!(forth.eval("broken").unwrap_err().to_string(), "broken ?");
+        assert_eq!(forth.eval(": half").unwrap(), "");
+        assert_eq!(forth.eval("2/ ; 10 half .").unwrap(), "5 ");
+        assert!(matches!(Forth::new().cycle_limit(100_000).boot().unwrap().eval(": spin begin again ; spin"),
+                         Err(ForthError::Timeout(100_000))));
+        assert!(matches!(forth.eval("bye"), Err(ForthError::Bye)));
+    }
+
+    #[test]
+    fn long_lines_and_scrolling() {
+        let mut forth = forth();
+        // more than the keyboard holds at once
+        let line = (1..=30).map(|n| n.to_string()).collect::<Vec<_>>().join(" ") + " depth .";
+        assert_eq!(forth.eval(&line).unwrap(), "30 ");
+        let lines = (0..20).map(|n| format!("{} .", n)).collect::<Vec<_>>().join("\n");
+        assert_eq!(forth.eval(&lines).unwrap(), (0..20).map(|n| format!("{} ", n)).collect::<String>());
+        assert!(forth.screen().text().contains("19 . 19  ok"), "{}", forth.screen().text());
+    }
+
+    #[test]
+    fn talks_to_hardware() {
+        let mut forth = forth();
+        assert_eq!(forth.eval("hwn . 1 hwq hex u. u. u. u. u. decimal").unwrap(), "2 1c6c 8b36 1802 7349 f615 ");
+        // the border colour, through the LEM1802's interrupt 3
+        forth.eval("3 5 0 0 0 1 hwi 2drop 2drop drop").unwrap();
+        assert_eq!(forth.screen().border(), forth.screen().palette()[5]);
+    }
+}
@@ -1,13 +1,21 @@
-use super::super::virtual_machine::{VMExposed, Register};
+use super::super::virtual_machine::Register;
 use self::super::core::{Hardware, HardwareInfo};
-use std::fmt::{Formatter, Display, Error};
+use self::super::context::{DeviceContext, DeviceState, StateError};
+use std::fmt::{Formatter, Error};
+
+const TICK: u32 = 0;
 
 pub struct Clock {
     hw_info: HardwareInfo,
     clock_rate: u16,
     last_cycles: usize,
     interrupt: u16,
-    last_interrupt: usize
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new()
+    }
 }
 
 impl Clock {
@@ -20,9 +28,13 @@ impl Clock {
             clock_rate: 0,
             last_cycles: 0,
             interrupt: 0,
-            last_interrupt: 0
         }
     }
+
+    // cycles between ticks, at clock_rate/60ths of a second
+    fn period(&self, ctx: &DeviceContext) -> usize {
+        ctx.clock_rate() * self.clock_rate as usize / 60
+    }
 }
 
 impl Hardware for Clock {
@@ -30,23 +42,28 @@ impl Hardware for Clock {
         &self.hw_info
     }
 
-    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
-        let (a, c) = vm.read_register(Register::A);
+    fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+        let (a, c) = ctx.read_register(Register::A);
         let mut cycles = c;
         match a {
             0x0 => {
-                let (cr, c) = vm.read_register(Register::B);
+                let (cr, c) = ctx.read_register(Register::B);
                 self.clock_rate = cr;
-                self.last_cycles = vm.get_cycles();
+                self.last_cycles = ctx.cycles();
                 cycles += c;
+                ctx.cancel(TICK);
+                if cr != 0 {
+                    let period = self.period(ctx);
+                    ctx.schedule(period, TICK);
+                }
             },
             0x1 => {
-                let ticks = (((vm.get_cycles() - self.last_cycles) as f64 *
-                 (60 as f64 / self.clock_rate as f64))/vm.get_clock_rate() as f64) as u16;
-                cycles += vm.write_register(Register::C, ticks);
+                let ticks = (((ctx.cycles() - self.last_cycles) as f64 *
+                 (60 as f64 / self.clock_rate as f64))/ctx.clock_rate() as f64) as u16;
+                cycles += ctx.write_register(Register::C, ticks);
             },
             0x2 => {
-                let (i, c) = vm.read_register(Register::B);
+                let (i, c) = ctx.read_register(Register::B);
                 self.interrupt = i;
                 cycles += c;
             },
@@ -55,20 +72,78 @@ impl Hardware for Clock {
         cycles
     }
 
-    fn update(&mut self, vm: &mut VMExposed) {
+    fn callback(&mut self, ctx: &mut DeviceContext, _token: u32) {
+        if self.interrupt != 0 {
+            ctx.interrupt(self.interrupt);
+        }
+        let period = self.period(ctx);
+        ctx.schedule(period, TICK);
+    }
+
+    fn save_state(&self, ctx: &DeviceContext) -> Option<DeviceState> {
+        let mut state = DeviceState::new(1);
+        state.push(self.clock_rate);
+        state.push(self.interrupt);
+        state.push_u64((ctx.cycles() - self.last_cycles) as u64);
+        Some(state)
+    }
+
+    fn load_state(&mut self, ctx: &mut DeviceContext, state: &DeviceState) -> Result<(), StateError> {
+        if state.version != 1 {
+            return Err(StateError::Version(state.version));
+        }
+        let mut reader = state.reader();
+        self.clock_rate = reader.word()?;
+        self.interrupt = reader.word()?;
+        let since = reader.u64()? as usize;
+        self.last_cycles = ctx.cycles().checked_sub(since).ok_or(StateError::Invalid("clock set in the future"))?;
         if self.clock_rate != 0 {
-            if self.interrupt != 0 {
-                if (vm.get_cycles() - self.last_interrupt) as f64 > vm.get_clock_rate() as f64 / (60 as f64 / self.clock_rate as f64) {
-                    self.last_interrupt = vm.get_cycles();
-                    vm.interrupt(self.interrupt);
-                }
-            }
+            // pick up the ticks where they left off
+            let period = self.period(ctx);
+            ctx.schedule(period - since % period.max(1), TICK);
         }
+        Ok(())
     }
 
     fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
         fmt.write_fmt(
-            format_args!("clock rate: {}, last cycles: {}, interrupt: {:02x}, last interrupt: {}",
-                self.clock_rate, self.last_cycles, self.interrupt, self.last_interrupt))
+            format_args!("clock rate: {}, last cycles: {}, interrupt: {:02x}",
+                self.clock_rate, self.last_cycles, self.interrupt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::VirtualMachine;
+    use hardware::hwi;
+
+    #[test]
+    fn ticks_on_schedule() {
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(Clock::new())).unwrap();
+        *vm.get_ia() = 0x100;
+        vm.interrupts_mut().set_queueing(true);
+        hwi(&mut vm, &[(Register::A, 2), (Register::B, 0x77)]);
+        // 30 ticks a second is one every 3333 cycles at 100kHz
+        hwi(&mut vm, &[(Register::A, 0), (Register::B, 2)]);
+        let state = vm.save_device(0).unwrap();
+        // SUB PC, 1
+        vm.get_ram()[1] = 0x8b83;
+        vm.run(&Default::default(), Some(3332)).unwrap();
+        assert_eq!(vm.interrupts().pending_count(), 0);
+        vm.run(&Default::default(), Some(10010)).unwrap();
+        assert_eq!(vm.interrupts().pending_count(), 3);
+
+        let mut copy = VirtualMachine::new().attach_hardware(Box::new(Clock::new())).unwrap();
+        *copy.get_ia() = 0x100;
+        copy.interrupts_mut().set_queueing(true);
+        copy.get_ram()[0] = 0x8b83;
+        for _ in 0..4 {
+            copy.step().unwrap();
+        }
+        copy.load_device(0, &state).unwrap();
+        let loaded_at = copy.get_cycles();
+        copy.run(&Default::default(), Some(loaded_at + 3333)).unwrap();
+        assert_eq!(copy.interrupts().pending().collect::<Vec<_>>(), vec![&0x77]);
     }
 }
@@ -0,0 +1,300 @@
+use std::collections::BTreeSet;
+use thiserror::Error;
+use super::super::virtual_machine::{VMExposed, Register, DcpuVMError};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StateError {
+    #[error("This device can't save or load its state")]
+    Unsupported,
+    #[error("There's no device {}", .0)]
+    NoDevice(usize),
+    #[error("Can't load state version {}", .0)]
+    Version(u16),
+    #[error("The saved state ends early")]
+    Truncated,
+    #[error("Invalid saved state: {}", .0)]
+    Invalid(&'static str),
+}
+
+// a device's state as words, tagged with the device's own format version so
+// newer builds can still load older saves
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceState {
+    pub version: u16,
+    pub words: Vec<u16>,
+}
+
+impl DeviceState {
+    pub fn new(version: u16) -> DeviceState {
+        DeviceState { version, words: Vec::new() }
+    }
+
+    pub fn push(&mut self, word: u16) {
+        self.words.push(word);
+    }
+
+    pub fn push_u64(&mut self, value: u64) {
+        for shift in [48, 32, 16, 0].iter() {
+            self.words.push((value >> shift) as u16);
+        }
+    }
+
+    pub fn extend(&mut self, words: &[u16]) {
+        self.words.extend_from_slice(words);
+    }
+
+    pub fn reader(&self) -> StateReader<'_> {
+        StateReader { words: &self.words }
+    }
+}
+
+pub struct StateReader<'a> {
+    words: &'a [u16],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn words(&mut self, count: usize) -> Result<&'a [u16], StateError> {
+        if count > self.words.len() {
+            return Err(StateError::Truncated);
+        }
+        let (head, rest) = self.words.split_at(count);
+        self.words = rest;
+        Ok(head)
+    }
+
+    pub fn word(&mut self) -> Result<u16, StateError> {
+        Ok(self.words(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(self.words(4)?.iter().fold(0, |v, w| v << 16 | *w as u64))
+    }
+}
+
+// what devices have asked the VM to do, kept until it gets round to it
+#[derive(Debug, Default)]
+pub(crate) struct DeviceEvents {
+    pub(crate) halt: usize,
+    pub(crate) stop: Option<usize>,
+    // (due, order scheduled, device, token)
+    callbacks: BTreeSet<(usize, u64, usize, u32)>,
+    scheduled: u64,
+}
+
+impl DeviceEvents {
+    fn schedule(&mut self, due: usize, device: usize, token: u32) {
+        self.callbacks.insert((due, self.scheduled, device, token));
+        self.scheduled += 1;
+    }
+
+    fn cancel(&mut self, device: usize, token: u32) {
+        self.callbacks.retain(|&(_, _, d, t)| d != device || t != token);
+    }
+
+    pub(crate) fn has_callbacks(&self) -> bool {
+        !self.callbacks.is_empty()
+    }
+
+    // the next callback that's due by `now`, if there is one
+    pub(crate) fn next_due(&mut self, now: usize) -> Option<(usize, u32)> {
+        let first = *self.callbacks.iter().next()?;
+        if first.0 > now {
+            return None
+        }
+        self.callbacks.remove(&first);
+        Some((first.2, first.3))
+    }
+
+    pub(crate) fn forget(&mut self, device: usize) {
+        self.callbacks.retain(|&(_, _, d, _)| d != device);
+    }
+
+    // a device was unplugged, so everything after it moves down an index
+    pub(crate) fn unplugged(&mut self, device: usize) {
+        self.callbacks = self.callbacks.iter()
+            .filter(|&&(_, _, d, _)| d != device)
+            .map(|&(due, n, d, t)| (due, n, if d > device { d - 1 } else { d }, t))
+            .collect();
+        self.stop = match self.stop {
+            Some(d) if d == device => None,
+            Some(d) if d > device => Some(d - 1),
+            other => other,
+        };
+    }
+}
+
+// everything a device can see and ask for while the VM calls into it
+pub struct DeviceContext<'a> {
+    vm: &'a mut VMExposed,
+    events: &'a mut DeviceEvents,
+    index: usize,
+    elapsed: usize,
+}
+
+impl<'a> DeviceContext<'a> {
+    pub(crate) fn new(vm: &'a mut VMExposed, events: &'a mut DeviceEvents,
+                      index: usize, elapsed: usize) -> DeviceContext<'a> {
+        DeviceContext { vm, events, index, elapsed }
+    }
+
+    pub fn vm(&mut self) -> &mut VMExposed {
+        self.vm
+    }
+
+    // where HWI reaches this device
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    // cycles since the device was last ticked
+    pub fn elapsed(&self) -> usize {
+        self.elapsed
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.vm.get_cycles()
+    }
+
+    pub fn clock_rate(&self) -> usize {
+        self.vm.get_clock_rate()
+    }
+
+    pub fn read_register(&self, reg: Register) -> (u16, usize) {
+        self.vm.read_register(reg)
+    }
+
+    pub fn write_register(&mut self, reg: Register, data: u16) -> usize {
+        self.vm.write_register(reg, data)
+    }
+
+    pub fn read_ram(&mut self, pos: usize, size: usize) -> Result<(&[u16], usize), DcpuVMError> {
+        self.vm.read_ram(pos, size)
+    }
+
+    pub fn write_ram(&mut self, pos: usize, data: &[u16], size: usize) -> usize {
+        self.vm.write_ram(pos, data, size)
+    }
+
+    pub fn interrupt(&mut self, msg: u16) {
+        self.vm.interrupt(msg);
+    }
+
+    // the CPU sits idle for this many cycles before its next instruction.
+    // halts from more than one call add up
+    pub fn halt(&mut self, cycles: usize) {
+        self.events.halt += cycles;
+    }
+
+    // calls the device's callback with `token` once `after` more cycles have
+    // gone by. callbacks are never due sooner than the next cycle
+    pub fn schedule(&mut self, after: usize, token: u32) {
+        let due = self.vm.get_cycles() + after.max(1);
+        self.events.schedule(due, self.index, token);
+    }
+
+    // drops every callback this device scheduled with `token`
+    pub fn cancel(&mut self, token: u32) {
+        self.events.cancel(self.index, token);
+    }
+
+    // makes VirtualMachine::run return once the current step's done
+    pub fn request_stop(&mut self) {
+        self.events.stop.get_or_insert(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::{Formatter, Error};
+    use hardware::{Hardware, HardwareInfo};
+    use virtual_machine::{VirtualMachine, StopReason};
+
+    // stops the VM a while after it's plugged in
+    struct Alarm {
+        info: HardwareInfo,
+    }
+
+    impl Hardware for Alarm {
+        fn info(&self) -> &HardwareInfo { &self.info }
+        fn attached(&mut self, ctx: &mut DeviceContext) {
+            ctx.schedule(100, 1);
+        }
+        fn callback(&mut self, ctx: &mut DeviceContext, _token: u32) {
+            ctx.request_stop();
+        }
+        fn debug_dump_state(&self, _fmt: &mut Formatter) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut state = DeviceState::new(3);
+        state.push(0x1234);
+        state.push_u64(0x0001_0002_0003_0004);
+        state.extend(&[5, 6]);
+
+        let mut reader = state.reader();
+        assert_eq!(reader.word(), Ok(0x1234));
+        assert_eq!(reader.u64(), Ok(0x0001_0002_0003_0004));
+        assert_eq!(reader.words(2), Ok(&[5u16, 6][..]));
+        assert_eq!(reader.word(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn devices_can_stop_the_vm() {
+        let alarm = || Box::new(Alarm { info: HardwareInfo { manufacturer: 0, model: 0, version: 0 } });
+        let mut vm = VirtualMachine::new().attach_hardware(alarm()).unwrap().attach_hardware(alarm()).unwrap();
+        assert!(vm.unplug(0).is_some());
+        // SUB PC, 1
+        vm.get_ram()[0] = 0x8b83;
+        assert_eq!(vm.run(&Default::default(), None).unwrap(), StopReason::Device(0));
+        assert_eq!(vm.get_cycles(), 100);
+    }
+
+    // writes SET A, 5 just after the HWI that reaches it
+    struct Patcher {
+        info: HardwareInfo,
+    }
+
+    impl Hardware for Patcher {
+        fn info(&self) -> &HardwareInfo { &self.info }
+        fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+            ctx.write_ram(1, &[0x9801], 1);
+            0
+        }
+        fn debug_dump_state(&self, _fmt: &mut Formatter) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn devices_writing_code_get_it_run() {
+        let patcher = Box::new(Patcher { info: HardwareInfo { manufacturer: 0, model: 0, version: 0 } });
+        let mut vm = VirtualMachine::new().attach_hardware(patcher).unwrap();
+        // HWI 0; SET A, 1
+        vm.get_ram()[..2].copy_from_slice(&[0x8640, 0x8801]);
+        // run SET A, 1 first, so it's been decoded
+        *vm.get_pc() = 1;
+        vm.step().unwrap();
+        *vm.get_pc() = 0;
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.get_registers()[0], 5);
+    }
+
+    #[test]
+    fn callbacks_follow_unplugged_devices() {
+        let mut events = DeviceEvents::default();
+        events.schedule(20, 2, 7);
+        events.schedule(10, 0, 1);
+        events.schedule(10, 1, 2);
+        events.schedule(10, 2, 3);
+        events.stop = Some(2);
+        events.unplugged(1);
+
+        assert_eq!(events.next_due(5), None);
+        assert_eq!(events.next_due(10), Some((0, 1)));
+        assert_eq!(events.next_due(10), Some((1, 3)));
+        assert_eq!(events.next_due(15), None);
+        assert_eq!(events.next_due(20), Some((1, 7)));
+        assert_eq!(events.stop, Some(1));
+    }
+}
@@ -1,5 +1,12 @@
 use std::fmt::{Display, Debug, Formatter, Error};
 use super::super::virtual_machine::VMExposed;
+use super::context::{DeviceContext, DeviceState, StateError};
+
+// the first version of this trait only had hardware_interrupt and update,
+// which get the VM alone. the context versions call them by default, so
+// devices written against version 1 keep working unchanged. new devices
+// implement handle_interrupt and tick instead
+pub const HARDWARE_API_VERSION: u32 = 2;
 
 #[derive(Debug)]
 pub struct HardwareInfo {
@@ -18,8 +25,33 @@ impl Display for HardwareInfo {
 
 pub trait Hardware {
     fn info(&self) -> &HardwareInfo;
-    fn hardware_interrupt(&mut self, &mut VMExposed) -> usize;
-    fn update(&mut self, &mut VMExposed);
+
+    // version 1
+    fn hardware_interrupt(&mut self, _vm: &mut VMExposed) -> usize { 0 }
+    fn update(&mut self, _vm: &mut VMExposed) {}
+
+    // version 2. handle_interrupt returns the cycles HWI took, tick is called
+    // after every step and callback when something the device scheduled is due
+    fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+        self.hardware_interrupt(ctx.vm())
+    }
+    fn tick(&mut self, ctx: &mut DeviceContext) {
+        self.update(ctx.vm())
+    }
+    fn callback(&mut self, _ctx: &mut DeviceContext, _token: u32) {}
+
+    // called when the device is plugged into a VM and when it's unplugged
+    fn attached(&mut self, _ctx: &mut DeviceContext) {}
+    fn detached(&mut self, _ctx: &mut DeviceContext) {}
+
+    // devices that can be snapshotted return their state here. loading
+    // happens with the device's callbacks cancelled, so it should schedule
+    // whatever it still needs
+    fn save_state(&self, _ctx: &DeviceContext) -> Option<DeviceState> { None }
+    fn load_state(&mut self, _ctx: &mut DeviceContext, _state: &DeviceState) -> Result<(), StateError> {
+        Err(StateError::Unsupported)
+    }
+
     fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error>;
 }
 
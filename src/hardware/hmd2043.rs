@@ -0,0 +1,386 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use self::super::media::MediaSlot;
+use self::super::context::DeviceContext;
+use std::fmt::{Formatter, Error};
+
+pub const HMD2043_WORDS_PER_SECOND: u64 = 30700;
+
+pub const HMD2043_FLAG_NON_BLOCKING: u16 = 0x0001;
+pub const HMD2043_FLAG_MEDIA_STATUS_INTERRUPT: u16 = 0x0002;
+
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HmdInterruptType {
+    None = 0x0000,
+    MediaStatus = 0x0001,
+    ReadComplete = 0x0002,
+    WriteComplete = 0x0003,
+}
+
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HmdError {
+    None = 0x0000,
+    NoMedia = 0x0001,
+    InvalidSector = 0x0002,
+    Pending = 0x0003,
+    WriteLocked = 0x0004,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transfer {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Operation {
+    transfer: Transfer,
+    sector: usize,
+    count: usize,
+    addr: u16,
+    done_at: usize,
+}
+
+// Harold Media Drive 2043. media is inserted and ejected through its
+// MediaSlot while programs run, which they hear about through media status
+// interrupts if they ask for them
+pub struct Hmd2043 {
+    hw_info: HardwareInfo,
+    slot: MediaSlot,
+    generation: usize,
+    flags: u16,
+    interrupt: u16,
+    last_interrupt: HmdInterruptType,
+    operation: Option<Operation>,
+}
+
+impl Hmd2043 {
+    pub fn new(slot: MediaSlot) -> Hmd2043 {
+        Hmd2043 {
+            hw_info: HardwareInfo {
+                manufacturer: 0x21544948,
+                model: 0x74fa4cae,
+                version: 0x07c2
+            },
+            generation: slot.generation(),
+            slot,
+            flags: 0,
+            interrupt: 0,
+            last_interrupt: HmdInterruptType::None,
+            operation: None,
+        }
+    }
+
+    pub fn slot(&self) -> &MediaSlot {
+        &self.slot
+    }
+
+    fn notify(&mut self, kind: HmdInterruptType, vm: &mut VMExposed) {
+        self.last_interrupt = kind;
+        if self.interrupt != 0 {
+            vm.interrupt(self.interrupt);
+        }
+    }
+
+    fn check(&self, transfer: Transfer, sector: usize, count: usize) -> HmdError {
+        if self.operation.is_some() {
+            return HmdError::Pending;
+        }
+        match self.slot.with_disk(|d| (d.sectors(), d.is_write_protected())) {
+            None => HmdError::NoMedia,
+            Some((sectors, _)) if sector + count > sectors => HmdError::InvalidSector,
+            Some((_, true)) if transfer == Transfer::Write => HmdError::WriteLocked,
+            _ => HmdError::None,
+        }
+    }
+
+    fn transfer(&mut self, op: &Operation, vm: &mut VMExposed) {
+        let slot = &self.slot;
+        match op.transfer {
+            Transfer::Read => {
+                slot.with_disk(|disk| {
+                    let size = disk.sector_size();
+                    for i in 0..op.count {
+                        let addr = op.addr as usize + i * size;
+                        if let Ok(data) = disk.read_sector(op.sector + i) {
+                            vm.write_ram(addr, data, size);
+                        }
+                    }
+                });
+            },
+            Transfer::Write => {
+                slot.with_disk(|disk| {
+                    let size = disk.sector_size();
+                    for i in 0..op.count {
+                        let mut data = Vec::with_capacity(size);
+                        for w in 0..size {
+                            let addr = (op.addr as usize + i * size + w) & 0xFFFF;
+                            let (word, _) = vm.read_ram(addr, 1)
+                                .expect("a single word is always in bounds");
+                            data.push(word[0]);
+                        }
+                        let _ = disk.write_sector(op.sector + i, &data);
+                    }
+                });
+            }
+        }
+    }
+
+    fn start(&mut self, transfer: Transfer, vm: &mut VMExposed) -> (HmdError, usize) {
+        let (sector, mut cycles) = vm.read_register(Register::B);
+        let (count, c) = vm.read_register(Register::C);
+        cycles += c;
+        let (addr, c) = vm.read_register(Register::X);
+        cycles += c;
+
+        let (sector, count) = (sector as usize, count as usize);
+        let error = self.check(transfer, sector, count);
+        if error != HmdError::None {
+            return (error, cycles);
+        }
+
+        let words = (count * self.slot.with_disk(|d| d.sector_size()).unwrap_or(0)) as u64;
+        let duration = (words as u128 * vm.get_clock_rate() as u128
+            / HMD2043_WORDS_PER_SECOND as u128) as usize;
+        let op = Operation { transfer, sector, count, addr, done_at: vm.get_cycles() + duration };
+        if self.flags & HMD2043_FLAG_NON_BLOCKING != 0 {
+            self.operation = Some(op);
+        } else {
+            // blocking transfers hold the CPU up for as long as they take
+            self.transfer(&op, vm);
+            cycles += duration;
+        }
+        (HmdError::None, cycles)
+    }
+}
+
+impl Hardware for Hmd2043 {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        let error = match a {
+            0x0000 => {
+                cycles += vm.write_register(Register::B, !self.slot.is_empty() as u16);
+                HmdError::None
+            },
+            0x0001 => {
+                match self.slot.with_disk(|d| (d.sector_size(), d.sectors(), d.is_write_protected())) {
+                    Some((size, sectors, locked)) => {
+                        cycles += vm.write_register(Register::B, size as u16);
+                        cycles += vm.write_register(Register::C, sectors as u16);
+                        cycles += vm.write_register(Register::X, locked as u16);
+                        HmdError::None
+                    },
+                    None => HmdError::NoMedia
+                }
+            },
+            0x0002 => {
+                cycles += vm.write_register(Register::B, self.flags);
+                HmdError::None
+            },
+            0x0003 => {
+                let (flags, c) = vm.read_register(Register::B);
+                cycles += c;
+                self.flags = flags & (HMD2043_FLAG_NON_BLOCKING | HMD2043_FLAG_MEDIA_STATUS_INTERRUPT);
+                HmdError::None
+            },
+            0x0004 => {
+                cycles += vm.write_register(Register::B, self.last_interrupt as u16);
+                self.last_interrupt = HmdInterruptType::None;
+                HmdError::None
+            },
+            0x0005 => {
+                let (i, c) = vm.read_register(Register::B);
+                self.interrupt = i;
+                cycles += c;
+                HmdError::None
+            },
+            0x0010 | 0x0011 => {
+                let transfer = if a == 0x0010 { Transfer::Read } else { Transfer::Write };
+                let (error, c) = self.start(transfer, vm);
+                cycles += c;
+                error
+            },
+            0xffff => {
+                // all media here is genuine
+                cycles += vm.write_register(Register::B, 0x7fff);
+                HmdError::None
+            },
+            _ => return 0
+        };
+        cycles += vm.write_register(Register::A, error as u16);
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        let generation = self.slot.generation();
+        if generation != self.generation {
+            self.generation = generation;
+            self.operation = None;
+            if self.flags & HMD2043_FLAG_MEDIA_STATUS_INTERRUPT != 0 {
+                self.notify(HmdInterruptType::MediaStatus, vm);
+            }
+        }
+
+        if self.operation.as_ref().is_some_and(|op| vm.get_cycles() >= op.done_at) {
+            let op = self.operation.take().unwrap();
+            self.transfer(&op, vm);
+            let kind = match op.transfer {
+                Transfer::Read => HmdInterruptType::ReadComplete,
+                Transfer::Write => HmdInterruptType::WriteComplete,
+            };
+            self.notify(kind, vm);
+        }
+    }
+
+    fn detached(&mut self, _ctx: &mut DeviceContext) {
+        // whatever was in flight is lost with the cable
+        self.operation = None;
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(
+            format_args!("media: {}, flags: {:04x}, last interrupt: {:?}, operation: {:?}, interrupt: {:02x}",
+                !self.slot.is_empty(), self.flags, self.last_interrupt, self.operation, self.interrupt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hardware::media::Disk;
+    use hardware::hwi;
+    use hardware::core::HardwareInfo;
+    use virtual_machine::VirtualMachine;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // HWI 0; SUB PC, 1
+    const DRIVER: [u16; 2] = [0x8640, 0x8b83];
+
+    // the same as hwi, with the drive catching up straight after
+    fn send(vm: &mut VirtualMachine, regs: &[(Register, u16)]) -> [u16; 8] {
+        let out = hwi(vm, regs);
+        vm.update_hardware();
+        out
+    }
+
+    fn run(vm: &mut VirtualMachine, cycles: usize) {
+        let until = vm.get_cycles() + cycles;
+        *vm.get_pc() = 1;
+        while vm.get_cycles() < until {
+            vm.step().unwrap();
+            vm.update_hardware();
+        }
+    }
+
+    fn drive(slot: &MediaSlot) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.plug(Box::new(Hmd2043::new(slot.clone()))).unwrap();
+        vm.get_ram()[..2].copy_from_slice(&DRIVER);
+        // keep interrupts queued so tests can look at them
+        *vm.get_ia() = 0x100;
+        vm.interrupts_mut().set_queueing(true);
+        vm
+    }
+
+    #[test]
+    fn insert_and_eject_while_running() {
+        let slot = MediaSlot::new();
+        let mut vm = drive(&slot);
+        send(&mut vm, &[(Register::A, 5), (Register::B, 0x77)]);
+        send(&mut vm, &[(Register::A, 3), (Register::B, HMD2043_FLAG_MEDIA_STATUS_INTERRUPT)]);
+        let regs = send(&mut vm, &[(Register::A, 0)]);
+        assert_eq!((regs[0], regs[1]), (0, 0));
+        let regs = send(&mut vm, &[(Register::A, 0x10), (Register::B, 0), (Register::C, 1)]);
+        assert_eq!(regs[0], HmdError::NoMedia as u16);
+
+        slot.insert(Disk::floppy().write_protect(true));
+        run(&mut vm, 2);
+        assert_eq!(vm.interrupts().pending().collect::<Vec<_>>(), vec![&0x77]);
+        let regs = send(&mut vm, &[(Register::A, 4)]);
+        assert_eq!(regs[1], HmdInterruptType::MediaStatus as u16);
+        let regs = send(&mut vm, &[(Register::A, 1)]);
+        assert_eq!((regs[0], regs[1], regs[2], regs[3]), (0, 512, 1440, 1));
+        let regs = send(&mut vm, &[(Register::A, 0x11), (Register::B, 0), (Register::C, 1)]);
+        assert_eq!(regs[0], HmdError::WriteLocked as u16);
+
+        slot.eject();
+        run(&mut vm, 2);
+        assert_eq!(vm.interrupts().pending_count(), 2);
+        assert_eq!(send(&mut vm, &[(Register::A, 0)])[1], 0);
+    }
+
+    #[test]
+    fn blocking_and_non_blocking_reads() {
+        let mut disk = Disk::floppy();
+        disk.write_sector(3, &[0xaaaa; 512]).unwrap();
+        disk.write_sector(4, &[0xbbbb; 512]).unwrap();
+        let slot = MediaSlot::with(disk);
+        let mut vm = drive(&slot);
+
+        let before = vm.get_cycles();
+        let regs = send(&mut vm, &[(Register::A, 0x10), (Register::B, 3), (Register::C, 2), (Register::X, 0x1000)]);
+        assert_eq!(regs[0], 0);
+        // 1024 words at 30700 a second is 3335 cycles at 100kHz
+        assert!(vm.get_cycles() - before > 3335);
+        assert_eq!(vm.get_ram()[0x11ff], 0xaaaa);
+        assert_eq!(vm.get_ram()[0x1200], 0xbbbb);
+
+        send(&mut vm, &[(Register::A, 5), (Register::B, 0x66)]);
+        send(&mut vm, &[(Register::A, 3), (Register::B, HMD2043_FLAG_NON_BLOCKING)]);
+        let regs = send(&mut vm, &[(Register::A, 0x10), (Register::B, 4), (Register::C, 1), (Register::X, 0x3000)]);
+        assert_eq!(regs[0], 0);
+        let regs = send(&mut vm, &[(Register::A, 0x10), (Register::B, 4), (Register::C, 1), (Register::X, 0x3000)]);
+        assert_eq!(regs[0], HmdError::Pending as u16);
+        assert_eq!(vm.get_ram()[0x3000], 0);
+        run(&mut vm, 2000);
+        assert_eq!(vm.get_ram()[0x3000], 0xbbbb);
+        assert_eq!(send(&mut vm, &[(Register::A, 4)])[1], HmdInterruptType::ReadComplete as u16);
+    }
+
+    struct Probe {
+        info: HardwareInfo,
+        events: Rc<Cell<(usize, usize)>>,
+    }
+
+    impl Hardware for Probe {
+        fn info(&self) -> &HardwareInfo { &self.info }
+        fn hardware_interrupt(&mut self, _vm: &mut VMExposed) -> usize { 0 }
+        fn update(&mut self, _vm: &mut VMExposed) {}
+        fn attached(&mut self, _ctx: &mut DeviceContext) {
+            let (a, d) = self.events.get();
+            self.events.set((a + 1, d));
+        }
+        fn detached(&mut self, _ctx: &mut DeviceContext) {
+            let (a, d) = self.events.get();
+            self.events.set((a, d + 1));
+        }
+        fn debug_dump_state(&self, _fmt: &mut Formatter) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn plug_and_unplug_notify_devices() {
+        let events = Rc::new(Cell::new((0, 0)));
+        let probe = Probe {
+            info: HardwareInfo { manufacturer: 0, model: 0x1234, version: 1 },
+            events: events.clone(),
+        };
+        let mut vm = drive(&MediaSlot::new());
+        assert_eq!(vm.plug(Box::new(probe)).unwrap(), 1);
+        assert_eq!(events.get(), (1, 0));
+        assert_eq!(vm.hardware_count(), 2);
+
+        assert!(vm.unplug(0).is_some());
+        assert_eq!(vm.hardware(0).unwrap().info().model, 0x1234);
+        assert!(vm.unplug(0).is_some());
+        assert_eq!(events.get(), (1, 1));
+        assert!(vm.unplug(0).is_none());
+        assert_eq!(vm.hardware_count(), 0);
+    }
+}
@@ -0,0 +1,173 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use std::fmt::{Formatter, Error};
+use std::collections::{BTreeSet, VecDeque};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const KEYBOARD_BUFFER_SIZE: usize = 64;
+
+pub const KEY_BACKSPACE: u16 = 0x10;
+pub const KEY_RETURN: u16 = 0x11;
+pub const KEY_INSERT: u16 = 0x12;
+pub const KEY_DELETE: u16 = 0x13;
+pub const KEY_UP: u16 = 0x80;
+pub const KEY_DOWN: u16 = 0x81;
+pub const KEY_LEFT: u16 = 0x82;
+pub const KEY_RIGHT: u16 = 0x83;
+pub const KEY_SHIFT: u16 = 0x90;
+pub const KEY_CONTROL: u16 = 0x91;
+
+// the key number a character is typed as, if it has one
+pub fn key_for_char(c: char) -> Option<u16> {
+    match c {
+        '\n' | '\r' => Some(KEY_RETURN),
+        '\x08' | '\x7f' => Some(KEY_BACKSPACE),
+        ' '..='~' => Some(c as u16),
+        _ => None
+    }
+}
+
+#[derive(Debug, Default)]
+struct Keys {
+    typed: VecDeque<u16>,
+    pressed: BTreeSet<u16>,
+    changed: bool,
+}
+
+// feeds keys to a keyboard that's been attached to a VM
+#[derive(Clone, Debug, Default)]
+pub struct KeyboardInput {
+    keys: Rc<RefCell<Keys>>,
+}
+
+impl KeyboardInput {
+    pub fn press(&self, key: u16) {
+        let mut keys = self.keys.borrow_mut();
+        keys.pressed.insert(key);
+        keys.changed = true;
+    }
+
+    pub fn release(&self, key: u16) {
+        let mut keys = self.keys.borrow_mut();
+        keys.pressed.remove(&key);
+        keys.changed = true;
+    }
+
+    // a key typed while the buffer's full is lost
+    pub fn type_key(&self, key: u16) {
+        let mut keys = self.keys.borrow_mut();
+        if keys.typed.len() < KEYBOARD_BUFFER_SIZE {
+            keys.typed.push_back(key);
+        }
+        keys.changed = true;
+    }
+
+    pub fn type_str(&self, s: &str) {
+        for key in s.chars().filter_map(key_for_char) {
+            self.type_key(key);
+        }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.keys.borrow().typed.len()
+    }
+}
+
+// Generic Keyboard. keys come from its KeyboardInput, or from a script typed
+// out one key every so many cycles
+pub struct Keyboard {
+    hw_info: HardwareInfo,
+    input: KeyboardInput,
+    script: VecDeque<u16>,
+    interval: usize,
+    next_key: usize,
+    interrupt: u16,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Keyboard::new()
+    }
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard {
+            hw_info: HardwareInfo {
+                manufacturer: 0x904b3115,
+                model: 0x30cf7406,
+                version: 0x0001
+            },
+            input: KeyboardInput::default(),
+            script: VecDeque::new(),
+            interval: 0,
+            next_key: 0,
+            interrupt: 0,
+        }
+    }
+
+    pub fn script(mut self, text: &str, interval: usize) -> Self {
+        self.script = text.chars().filter_map(key_for_char).collect();
+        self.interval = interval;
+        self
+    }
+
+    pub fn input(&self) -> KeyboardInput {
+        self.input.clone()
+    }
+}
+
+impl Hardware for Keyboard {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        let mut keys = self.input.keys.borrow_mut();
+        match a {
+            0x0 => keys.typed.clear(),
+            0x1 => {
+                let key = keys.typed.pop_front().unwrap_or(0);
+                cycles += vm.write_register(Register::C, key);
+            },
+            0x2 => {
+                let (key, c) = vm.read_register(Register::B);
+                cycles += c;
+                let pressed = keys.pressed.contains(&key) as u16;
+                cycles += vm.write_register(Register::C, pressed);
+            },
+            0x3 => {
+                let (i, c) = vm.read_register(Register::B);
+                self.interrupt = i;
+                cycles += c;
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        if !self.script.is_empty() && vm.get_cycles() >= self.next_key {
+            self.next_key = vm.get_cycles() + self.interval;
+            let key = self.script.pop_front().unwrap();
+            self.input.type_key(key);
+        }
+
+        let mut keys = self.input.keys.borrow_mut();
+        if keys.changed {
+            keys.changed = false;
+            if self.interrupt != 0 {
+                vm.interrupt(self.interrupt);
+            }
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let keys = self.input.keys.borrow();
+        fmt.write_fmt(
+            format_args!("buffered: {}, pressed: {:?}, script: {}, interrupt: {:02x}",
+                keys.typed.len(), keys.pressed, self.script.len(), self.interrupt))
+    }
+}
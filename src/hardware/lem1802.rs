@@ -0,0 +1,332 @@
+use super::super::virtual_machine::Register;
+use self::super::core::{Hardware, HardwareInfo};
+use self::super::context::{DeviceContext, DeviceState, StateError};
+use std::fmt::{Formatter, Error};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const LEM1802_WIDTH: usize = 32;
+pub const LEM1802_HEIGHT: usize = 12;
+pub const LEM1802_CELLS: usize = LEM1802_WIDTH * LEM1802_HEIGHT;
+
+pub const LEM1802_DEFAULT_PALETTE: [u16; 16] = [
+    0x000, 0x00a, 0x0a0, 0x0aa, 0xa00, 0xa0a, 0xa50, 0xaaa,
+    0x555, 0x55f, 0x5f5, 0x5ff, 0xf55, 0xf5f, 0xff5, 0xfff,
+];
+
+// 3x5 glyphs for ' ' to '~', a digit per row with the leftmost pixel as the
+// high bit. they sit one row down from the top of the 4x8 cell
+const GLYPHS: [&str; 95] = [
+    "00000", "22202", "55000", "57575", "36363", "51245", "25253", "22000",
+    "12221", "42224", "05250", "02720", "00024", "00700", "00002", "11244",
+    "75557", "26227", "71747", "71717", "55711", "74717", "74757", "71111",
+    "75757", "75711", "02020", "02024", "12421", "07070", "42124", "71202",
+    "25743", "25755", "65656", "34443", "65556", "74647", "74644", "34553",
+    "55755", "72227", "11152", "55655", "44447", "57755", "57775", "25552",
+    "65644", "25573", "65655", "34216", "72222", "55557", "55552", "55775",
+    "55255", "55222", "71247", "64446", "44211", "31113", "25000", "00007",
+    "42000", "03553", "44656", "03443", "11353", "03563", "12722", "03536",
+    "44655", "20222", "10116", "45655", "62223", "07775", "06555", "02552",
+    "06564", "03531", "03444", "03616", "27223", "05553", "05552", "05577",
+    "05225", "05536", "07247", "32623", "22222", "62326", "03600",
+];
+
+// the font MEM_DUMP_FONT hands out and the screen starts with: two words a
+// character, each column a byte with the top row in the low bit
+pub fn lem1802_default_font() -> [u16; 256] {
+    let mut font = [0u16; 256];
+    for (i, glyph) in GLYPHS.iter().enumerate() {
+        let mut columns = [0u16; 4];
+        for (row, bits) in glyph.bytes().enumerate() {
+            let bits = bits - b'0';
+            for (col, column) in columns.iter_mut().take(3).enumerate() {
+                if bits >> (2 - col) & 1 != 0 {
+                    *column |= 1 << (row + 1);
+                }
+            }
+        }
+        let c = 0x20 + i;
+        font[c * 2] = columns[0] << 8 | columns[1];
+        font[c * 2 + 1] = columns[2] << 8 | columns[3];
+    }
+    font
+}
+
+#[derive(Clone, Debug)]
+struct Screen {
+    connected: bool,
+    cells: [u16; LEM1802_CELLS],
+    font: [u16; 256],
+    palette: [u16; 16],
+    border: u16,
+    blink: bool,
+}
+
+// what the LEM1802 last put on screen, refreshed about 60 times a second
+#[derive(Clone, Debug)]
+pub struct Lem1802Screen {
+    screen: Rc<RefCell<Screen>>,
+}
+
+impl Lem1802Screen {
+    // whether the program's mapped the screen yet
+    pub fn is_connected(&self) -> bool {
+        self.screen.borrow().connected
+    }
+
+    pub fn cells(&self) -> Vec<u16> {
+        self.screen.borrow().cells.to_vec()
+    }
+
+    pub fn cell(&self, x: usize, y: usize) -> u16 {
+        self.screen.borrow().cells[y * LEM1802_WIDTH + x]
+    }
+
+    pub fn font(&self) -> [u16; 256] {
+        self.screen.borrow().font
+    }
+
+    pub fn palette(&self) -> [u16; 16] {
+        self.screen.borrow().palette
+    }
+
+    pub fn border(&self) -> u16 {
+        self.screen.borrow().palette[self.screen.borrow().border as usize]
+    }
+
+    // true in the half second blinking characters are hidden for
+    pub fn blink(&self) -> bool {
+        self.screen.borrow().blink
+    }
+
+    // the characters on screen, a line per row. anything that isn't
+    // printable ASCII shows as a space
+    pub fn text(&self) -> String {
+        let screen = self.screen.borrow();
+        let mut text = String::with_capacity(LEM1802_CELLS + LEM1802_HEIGHT);
+        for row in screen.cells.chunks(LEM1802_WIDTH) {
+            for cell in row {
+                let c = (cell & 0x7f) as u8;
+                text.push(if (0x20..0x7f).contains(&c) { c as char } else { ' ' });
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+const REFRESH: u32 = 0;
+const FRAMES_PER_SECOND: usize = 60;
+const FRAMES_PER_BLINK: usize = 30;
+
+// NYA ELEKTRISKA LEM1802. the screen's copied out of RAM on every refresh,
+// so a host looking at its Lem1802Screen sees whole frames
+pub struct Lem1802 {
+    hw_info: HardwareInfo,
+    screen_map: u16,
+    font_map: u16,
+    palette_map: u16,
+    border: u16,
+    frame: usize,
+    screen: Lem1802Screen,
+}
+
+impl Default for Lem1802 {
+    fn default() -> Self {
+        Lem1802::new()
+    }
+}
+
+impl Lem1802 {
+    pub fn new() -> Lem1802 {
+        Lem1802 {
+            hw_info: HardwareInfo {
+                manufacturer: 0x1c6c8b36,
+                model: 0x7349f615,
+                version: 0x1802
+            },
+            screen_map: 0,
+            font_map: 0,
+            palette_map: 0,
+            border: 0,
+            frame: 0,
+            screen: Lem1802Screen {
+                screen: Rc::new(RefCell::new(Screen {
+                    connected: false,
+                    cells: [0; LEM1802_CELLS],
+                    font: lem1802_default_font(),
+                    palette: LEM1802_DEFAULT_PALETTE,
+                    border: 0,
+                    blink: false,
+                }))
+            },
+        }
+    }
+
+    pub fn screen(&self) -> Lem1802Screen {
+        self.screen.clone()
+    }
+
+    fn refresh(&mut self, ctx: &mut DeviceContext) {
+        let mut screen = self.screen.screen.borrow_mut();
+        let ram = ctx.vm().ram();
+        screen.connected = self.screen_map != 0;
+        if self.screen_map != 0 {
+            for (i, cell) in screen.cells.iter_mut().enumerate() {
+                *cell = ram[(self.screen_map as usize + i) & 0xffff];
+            }
+        }
+        screen.font = lem1802_default_font();
+        if self.font_map != 0 {
+            for (i, word) in screen.font.iter_mut().enumerate() {
+                *word = ram[(self.font_map as usize + i) & 0xffff];
+            }
+        }
+        screen.palette = LEM1802_DEFAULT_PALETTE;
+        if self.palette_map != 0 {
+            for (i, color) in screen.palette.iter_mut().enumerate() {
+                *color = ram[(self.palette_map as usize + i) & 0xffff] & 0xfff;
+            }
+        }
+        screen.border = self.border;
+        screen.blink = self.frame / FRAMES_PER_BLINK % 2 == 1;
+    }
+
+    fn frame_length(ctx: &DeviceContext) -> usize {
+        ctx.clock_rate() / FRAMES_PER_SECOND
+    }
+}
+
+impl Hardware for Lem1802 {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn handle_interrupt(&mut self, ctx: &mut DeviceContext) -> usize {
+        let (a, mut cycles) = ctx.read_register(Register::A);
+        let (b, c) = ctx.read_register(Register::B);
+        match a {
+            0x0 => self.screen_map = b,
+            0x1 => self.font_map = b,
+            0x2 => self.palette_map = b,
+            0x3 => self.border = b & 0xf,
+            // the dumps take the CPU as long as there are words to copy
+            0x4 => {
+                let font = lem1802_default_font();
+                cycles += ctx.write_ram(b as usize, &font, font.len());
+                ctx.halt(font.len());
+            },
+            0x5 => {
+                cycles += ctx.write_ram(b as usize, &LEM1802_DEFAULT_PALETTE, LEM1802_DEFAULT_PALETTE.len());
+                ctx.halt(LEM1802_DEFAULT_PALETTE.len());
+            },
+            _ => return 0
+        }
+        cycles + c
+    }
+
+    fn callback(&mut self, ctx: &mut DeviceContext, token: u32) {
+        if token == REFRESH {
+            self.frame = self.frame.wrapping_add(1);
+            self.refresh(ctx);
+            let length = Lem1802::frame_length(ctx);
+            ctx.schedule(length, REFRESH);
+        }
+    }
+
+    fn attached(&mut self, ctx: &mut DeviceContext) {
+        let length = Lem1802::frame_length(ctx);
+        ctx.schedule(length, REFRESH);
+    }
+
+    fn save_state(&self, _ctx: &DeviceContext) -> Option<DeviceState> {
+        let mut state = DeviceState::new(1);
+        state.extend(&[self.screen_map, self.font_map, self.palette_map, self.border]);
+        Some(state)
+    }
+
+    fn load_state(&mut self, ctx: &mut DeviceContext, state: &DeviceState) -> Result<(), StateError> {
+        if state.version != 1 {
+            return Err(StateError::Version(state.version));
+        }
+        let mut reader = state.reader();
+        self.screen_map = reader.word()?;
+        self.font_map = reader.word()?;
+        self.palette_map = reader.word()?;
+        self.border = reader.word()? & 0xf;
+        self.refresh(ctx);
+        let length = Lem1802::frame_length(ctx);
+        ctx.schedule(length, REFRESH);
+        Ok(())
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(
+            format_args!("screen: {:04x}, font: {:04x}, palette: {:04x}, border: {:x}",
+                self.screen_map, self.font_map, self.palette_map, self.border))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::VirtualMachine;
+    use hardware::hwi;
+
+    #[test]
+    fn shows_text_from_ram() {
+        let lem = Lem1802::new();
+        let screen = lem.screen();
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(lem)).unwrap();
+        for (i, c) in "hi!".bytes().enumerate() {
+            vm.get_ram()[0x8000 + i] = 0xf000 | c as u16;
+        }
+        vm.get_ram()[0x8000 + LEM1802_WIDTH] = 0x0080 | b'x' as u16;
+        hwi(&mut vm, &[(Register::A, 0), (Register::B, 0x8000)]);
+        hwi(&mut vm, &[(Register::A, 3), (Register::B, 4)]);
+        assert!(!screen.is_connected());
+
+        // SUB PC, 1
+        vm.get_ram()[1] = 0x8b83;
+        vm.run(&Default::default(), Some(2000)).unwrap();
+        assert!(screen.is_connected());
+        let text = screen.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), LEM1802_HEIGHT);
+        assert_eq!(lines[0].trim_end(), "hi!");
+        assert_eq!(lines[1].trim_end(), "x");
+        assert_eq!(screen.cell(1, 0), 0xf000 | b'i' as u16);
+        assert_eq!(screen.border(), 0xa00);
+    }
+
+    #[test]
+    fn dumps_halt_the_cpu() {
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(Lem1802::new())).unwrap();
+        hwi(&mut vm, &[(Register::A, 4), (Register::B, 0x1000)]);
+        assert_eq!(&vm.get_ram()[0x1000..0x1100], &lem1802_default_font()[..]);
+        assert_eq!(vm.step().unwrap(), 256);
+
+        hwi(&mut vm, &[(Register::A, 5), (Register::B, 0x2000)]);
+        assert_eq!(vm.get_ram()[0x2004], 0xa00);
+        assert_eq!(vm.step().unwrap(), 16);
+    }
+
+    #[test]
+    fn state_survives_a_new_vm() {
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(Lem1802::new())).unwrap();
+        hwi(&mut vm, &[(Register::A, 0), (Register::B, 0x8000)]);
+        hwi(&mut vm, &[(Register::A, 3), (Register::B, 0xc)]);
+        let state = vm.save_device(0).unwrap();
+
+        let lem = Lem1802::new();
+        let screen = lem.screen();
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(lem)).unwrap();
+        vm.get_ram()[0x8000] = b'A' as u16;
+        vm.load_device(0, &state).unwrap();
+        assert!(screen.is_connected());
+        assert_eq!(screen.cell(0, 0), b'A' as u16);
+        assert_eq!(screen.border(), 0xf55);
+        assert_eq!(vm.load_device(0, &DeviceState::new(2)), Err(StateError::Version(2)));
+        assert_eq!(vm.load_device(1, &state), Err(StateError::NoDevice(1)));
+    }
+}
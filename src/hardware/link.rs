@@ -0,0 +1,223 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use std::fmt::{Formatter, Error};
+use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const LINK_BUFFER_SIZE: usize = 256;
+pub const MAILBOX_SIZE: usize = 64;
+
+const MANUFACTURER: u32 = 0x4d44454b;
+
+// both ends of a link share the wire, each end has an index into it for the
+// words coming its way and whether any arrived since its last update
+#[derive(Debug, Default)]
+struct Wire {
+    queues: [VecDeque<u16>; 2],
+    arrived: [bool; 2],
+}
+
+pub struct SerialLink {
+    hw_info: HardwareInfo,
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+    interrupt: u16,
+}
+
+impl SerialLink {
+    // the two ends of one link, attach one to each VM
+    pub fn pair() -> (SerialLink, SerialLink) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        (SerialLink::end(wire.clone(), 0), SerialLink::end(wire, 1))
+    }
+
+    fn end(wire: Rc<RefCell<Wire>>, side: usize) -> SerialLink {
+        SerialLink {
+            hw_info: HardwareInfo {
+                manufacturer: MANUFACTURER,
+                model: 0x534c4e4b,
+                version: 0x0001
+            },
+            wire,
+            side,
+            interrupt: 0,
+        }
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.wire.borrow().queues[self.side].len()
+    }
+}
+
+impl Hardware for SerialLink {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        let mut wire = self.wire.borrow_mut();
+        let peer = 1 - self.side;
+        match a {
+            0x0 => {
+                let waiting = wire.queues[self.side].len() as u16;
+                let free = (LINK_BUFFER_SIZE - wire.queues[peer].len()) as u16;
+                cycles += vm.write_register(Register::B, waiting);
+                cycles += vm.write_register(Register::C, free);
+            },
+            0x1 => {
+                let (word, ok) = match wire.queues[self.side].pop_front() {
+                    Some(word) => (word, 1),
+                    None => (0, 0)
+                };
+                cycles += vm.write_register(Register::B, word);
+                cycles += vm.write_register(Register::C, ok);
+            },
+            0x2 => {
+                let (word, c) = vm.read_register(Register::B);
+                cycles += c;
+                let sent = if wire.queues[peer].len() < LINK_BUFFER_SIZE {
+                    wire.queues[peer].push_back(word);
+                    wire.arrived[peer] = true;
+                    1
+                } else {
+                    0
+                };
+                cycles += vm.write_register(Register::C, sent);
+            },
+            0x3 => {
+                let (i, c) = vm.read_register(Register::B);
+                self.interrupt = i;
+                cycles += c;
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        let mut wire = self.wire.borrow_mut();
+        if self.interrupt != 0 && wire.arrived[self.side] {
+            wire.arrived[self.side] = false;
+            vm.interrupt(self.interrupt);
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let wire = self.wire.borrow();
+        fmt.write_fmt(
+            format_args!("side: {}, waiting: {}, peer waiting: {}, interrupt: {:02x}",
+                self.side, wire.queues[self.side].len(), wire.queues[1 - self.side].len(),
+                self.interrupt))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    messages: [Option<Vec<u16>>; 2],
+    arrived: [bool; 2],
+}
+
+pub struct Mailbox {
+    hw_info: HardwareInfo,
+    slots: Rc<RefCell<Slots>>,
+    side: usize,
+    interrupt: u16,
+}
+
+impl Mailbox {
+    // the two ends of one mailbox, attach one to each VM
+    pub fn pair() -> (Mailbox, Mailbox) {
+        let slots = Rc::new(RefCell::new(Slots::default()));
+        (Mailbox::end(slots.clone(), 0), Mailbox::end(slots, 1))
+    }
+
+    fn end(slots: Rc<RefCell<Slots>>, side: usize) -> Mailbox {
+        Mailbox {
+            hw_info: HardwareInfo {
+                manufacturer: MANUFACTURER,
+                model: 0x4d424f58,
+                version: 0x0001
+            },
+            slots,
+            side,
+            interrupt: 0,
+        }
+    }
+}
+
+impl Hardware for Mailbox {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        let mut slots = self.slots.borrow_mut();
+        let peer = 1 - self.side;
+        match a {
+            0x0 => {
+                let (addr, c) = vm.read_register(Register::B);
+                cycles += c;
+                let (len, c) = vm.read_register(Register::C);
+                cycles += c;
+                let mut sent = 0;
+                if slots.messages[peer].is_none() {
+                    let mut message = Vec::with_capacity(MAILBOX_SIZE);
+                    for i in 0..(len as usize).min(MAILBOX_SIZE) {
+                        let (word, c) = vm.read_ram((addr as usize + i) & 0xFFFF, 1)
+                            .expect("a single word is always in bounds");
+                        message.push(word[0]);
+                        cycles += c;
+                    }
+                    sent = message.len() as u16;
+                    slots.messages[peer] = Some(message);
+                    slots.arrived[peer] = true;
+                }
+                cycles += vm.write_register(Register::C, sent);
+            },
+            0x1 => {
+                let (addr, c) = vm.read_register(Register::B);
+                cycles += c;
+                let len = match slots.messages[self.side].take() {
+                    Some(message) => {
+                        cycles += vm.write_ram(addr as usize, &message, message.len());
+                        message.len() as u16
+                    },
+                    None => 0
+                };
+                cycles += vm.write_register(Register::C, len);
+            },
+            0x2 => {
+                let waiting = slots.messages[self.side].as_ref().map_or(0, |m| m.len() as u16);
+                let free = slots.messages[peer].is_none() as u16;
+                cycles += vm.write_register(Register::C, waiting);
+                cycles += vm.write_register(Register::B, free);
+            },
+            0x3 => {
+                let (i, c) = vm.read_register(Register::B);
+                self.interrupt = i;
+                cycles += c;
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        let mut slots = self.slots.borrow_mut();
+        if self.interrupt != 0 && slots.arrived[self.side] {
+            slots.arrived[self.side] = false;
+            vm.interrupt(self.interrupt);
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let slots = self.slots.borrow();
+        fmt.write_fmt(
+            format_args!("side: {}, waiting: {:?}, peer waiting: {:?}, interrupt: {:02x}",
+                self.side, slots.messages[self.side].as_ref().map(|m| m.len()),
+                slots.messages[1 - self.side].as_ref().map(|m| m.len()), self.interrupt))
+    }
+}
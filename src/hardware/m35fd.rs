@@ -0,0 +1,348 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use self::super::media::{MediaSlot, MediaError};
+use std::fmt::{Formatter, Error};
+
+pub const M35FD_SECTORS_PER_TRACK: usize = 18;
+pub const M35FD_WORDS_PER_SECOND: u64 = 30700;
+pub const M35FD_SEEK_NS_PER_TRACK: u64 = 2_400_000;
+
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FloppyState {
+    NoMedia = 0x0000,
+    Ready = 0x0001,
+    ReadyWp = 0x0002,
+    Busy = 0x0003,
+}
+
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FloppyError {
+    None = 0x0000,
+    Busy = 0x0001,
+    NoMedia = 0x0002,
+    Protected = 0x0003,
+    Eject = 0x0004,
+    BadSector = 0x0005,
+    Broken = 0xffff,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transfer {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Operation {
+    transfer: Transfer,
+    sector: usize,
+    addr: u16,
+    done_at: usize,
+}
+
+// Mackapar 3.5" Floppy Drive. transfers take as long as seeking to the
+// sector's track and streaming 512 words would, and RAM is copied to or from
+// the disk in one go once they're done
+pub struct M35fd {
+    hw_info: HardwareInfo,
+    slot: MediaSlot,
+    generation: usize,
+    state: FloppyState,
+    error: FloppyError,
+    interrupt: u16,
+    track: usize,
+    operation: Option<Operation>,
+    changed: bool,
+}
+
+impl M35fd {
+    pub fn new(slot: MediaSlot) -> M35fd {
+        let mut drive = M35fd {
+            hw_info: HardwareInfo {
+                manufacturer: 0x1eb37e91,
+                model: 0x4fd524c5,
+                version: 0x000b
+            },
+            generation: slot.generation(),
+            slot,
+            state: FloppyState::NoMedia,
+            error: FloppyError::None,
+            interrupt: 0,
+            track: 0,
+            operation: None,
+            changed: false,
+        };
+        drive.refresh_state();
+        drive.changed = false;
+        drive
+    }
+
+    pub fn slot(&self) -> &MediaSlot {
+        &self.slot
+    }
+
+    pub fn state(&self) -> FloppyState {
+        self.state
+    }
+
+    pub fn error(&self) -> FloppyError {
+        self.error
+    }
+
+    fn set_state(&mut self, state: FloppyState) {
+        if self.state != state {
+            self.state = state;
+            self.changed = true;
+        }
+    }
+
+    fn set_error(&mut self, error: FloppyError) {
+        if self.error != error {
+            self.error = error;
+            self.changed = true;
+        }
+    }
+
+    fn refresh_state(&mut self) {
+        let state = if self.operation.is_some() {
+            FloppyState::Busy
+        } else if self.slot.is_empty() {
+            FloppyState::NoMedia
+        } else if self.slot.is_write_protected() {
+            FloppyState::ReadyWp
+        } else {
+            FloppyState::Ready
+        };
+        self.set_state(state);
+    }
+
+    fn start(&mut self, transfer: Transfer, vm: &mut VMExposed) -> (bool, usize) {
+        let (sector, mut cycles) = vm.read_register(Register::X);
+        let (addr, c) = vm.read_register(Register::Y);
+        cycles += c;
+
+        let error = if self.state == FloppyState::Busy {
+            FloppyError::Busy
+        } else if self.slot.is_empty() {
+            FloppyError::NoMedia
+        } else if self.slot.with_disk(|d| d.sectors()).unwrap_or(0) <= sector as usize {
+            FloppyError::BadSector
+        } else if transfer == Transfer::Write && self.slot.is_write_protected() {
+            FloppyError::Protected
+        } else {
+            FloppyError::None
+        };
+        if error != FloppyError::None {
+            self.set_error(error);
+            return (false, cycles);
+        }
+
+        let sector = sector as usize;
+        let track = sector / M35FD_SECTORS_PER_TRACK;
+        let seek = (track as i64 - self.track as i64).unsigned_abs();
+        let ns = seek * M35FD_SEEK_NS_PER_TRACK +
+            512 * 1_000_000_000 / M35FD_WORDS_PER_SECOND;
+        self.track = track;
+        self.operation = Some(Operation {
+            transfer,
+            sector,
+            addr,
+            done_at: vm.get_cycles() + (ns as u128 * vm.get_clock_rate() as u128 / 1_000_000_000) as usize,
+        });
+        self.set_state(FloppyState::Busy);
+        (true, cycles)
+    }
+
+    fn finish(&mut self, op: Operation, vm: &mut VMExposed) {
+        let result = match op.transfer {
+            Transfer::Read => {
+                self.slot.with_disk(|disk| {
+                    let data = disk.read_sector(op.sector)?;
+                    vm.write_ram(op.addr as usize, data, data.len());
+                    Ok(())
+                })
+            },
+            Transfer::Write => {
+                let mut data = Vec::with_capacity(512);
+                for i in 0..512u16 {
+                    let (word, _) = vm.read_ram(op.addr.wrapping_add(i) as usize, 1)
+                        .expect("a single word is always in bounds");
+                    data.push(word[0]);
+                }
+                self.slot.with_disk(|disk| disk.write_sector(op.sector, &data))
+            }
+        };
+        match result {
+            Some(Ok(())) => {},
+            Some(Err(MediaError::WriteProtected)) => self.set_error(FloppyError::Protected),
+            Some(Err(MediaError::BadSector(_))) => self.set_error(FloppyError::BadSector),
+            Some(Err(_)) => self.set_error(FloppyError::Broken),
+            None => self.set_error(FloppyError::Eject),
+        }
+    }
+}
+
+impl Hardware for M35fd {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        match a {
+            0x0 => {
+                cycles += vm.write_register(Register::B, self.state as u16);
+                cycles += vm.write_register(Register::C, self.error as u16);
+                self.error = FloppyError::None;
+            },
+            0x1 => {
+                let (i, c) = vm.read_register(Register::X);
+                self.interrupt = i;
+                cycles += c;
+            },
+            0x2 | 0x3 => {
+                let transfer = if a == 0x2 { Transfer::Read } else { Transfer::Write };
+                let (started, c) = self.start(transfer, vm);
+                cycles += c;
+                cycles += vm.write_register(Register::B, started as u16);
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        let generation = self.slot.generation();
+        if generation != self.generation {
+            self.generation = generation;
+            if self.operation.take().is_some() {
+                self.set_error(FloppyError::Eject);
+            }
+        }
+
+        if self.operation.as_ref().is_some_and(|op| vm.get_cycles() >= op.done_at) {
+            let op = self.operation.take().unwrap();
+            self.finish(op, vm);
+        }
+        self.refresh_state();
+
+        if self.changed {
+            self.changed = false;
+            if self.interrupt != 0 {
+                vm.interrupt(self.interrupt);
+            }
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(
+            format_args!("state: {:?}, error: {:?}, track: {}, operation: {:?}, interrupt: {:02x}",
+                self.state, self.error, self.track, self.operation, self.interrupt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hardware::media::Disk;
+    use hardware::hwi;
+    use virtual_machine::VirtualMachine;
+
+    // HWI 0; SUB PC, 1
+    const DRIVER: [u16; 2] = [0x8640, 0x8b83];
+
+    fn drive(slot: &MediaSlot) -> VirtualMachine {
+        let mut vm = VirtualMachine::new()
+            .attach_hardware(Box::new(M35fd::new(slot.clone()))).unwrap();
+        vm.get_ram()[..2].copy_from_slice(&DRIVER);
+        // keep interrupts queued so tests can look at them
+        *vm.get_ia() = 0x100;
+        vm.interrupts_mut().set_queueing(true);
+        vm
+    }
+
+    // the drive answers in B and C
+    fn floppy(vm: &mut VirtualMachine, a: u16, x: u16, y: u16) -> (u16, u16) {
+        let regs = hwi(vm, &[(Register::A, a), (Register::X, x), (Register::Y, y)]);
+        (regs[Register::B as usize], regs[Register::C as usize])
+    }
+
+    fn wait(vm: &mut VirtualMachine, cycles: usize) {
+        let until = vm.get_cycles() + cycles;
+        *vm.get_pc() = 1;
+        while vm.get_cycles() < until {
+            vm.step().unwrap();
+            vm.update_hardware();
+        }
+    }
+
+    #[test]
+    fn read_takes_seek_and_transfer_time() {
+        let mut disk = Disk::floppy();
+        disk.write_sector(20, &[0xbeef; 512]).unwrap();
+        let slot = MediaSlot::with(disk);
+        let mut vm = drive(&slot);
+
+        assert_eq!(floppy(&mut vm, 1, 0x42, 0), (0, 0));
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::Ready as u16, 0));
+        assert_eq!(floppy(&mut vm, 2, 20, 0x1000), (1, 0));
+        assert_eq!(floppy(&mut vm, 2, 20, 0x1000), (0, 0));
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::Busy as u16, FloppyError::Busy as u16));
+
+        // one track over at 100kHz: 240 cycles seeking, 1667 streaming
+        wait(&mut vm, 1800);
+        assert_eq!(vm.get_ram()[0x1000], 0);
+        wait(&mut vm, 200);
+        assert!(vm.get_ram()[0x1000..0x1200].iter().all(|w| *w == 0xbeef));
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::Ready as u16, 0));
+        assert!(vm.interrupts().pending().any(|m| *m == 0x42));
+    }
+
+    #[test]
+    fn write_protect_and_bad_sectors() {
+        let slot = MediaSlot::with(Disk::floppy().write_protect(true));
+        let mut vm = drive(&slot);
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::ReadyWp as u16, 0));
+        assert_eq!(floppy(&mut vm, 3, 0, 0), (0, 0));
+        assert_eq!(floppy(&mut vm, 0, 0, 0).1, FloppyError::Protected as u16);
+        assert_eq!(floppy(&mut vm, 2, 1440, 0).0, 0);
+        assert_eq!(floppy(&mut vm, 0, 0, 0).1, FloppyError::BadSector as u16);
+
+        slot.eject();
+        wait(&mut vm, 2);
+        assert_eq!(floppy(&mut vm, 2, 0, 0).0, 0);
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::NoMedia as u16, FloppyError::NoMedia as u16));
+    }
+
+    #[test]
+    fn eject_while_busy() {
+        let slot = MediaSlot::with(Disk::floppy());
+        let mut vm = drive(&slot);
+        assert_eq!(floppy(&mut vm, 3, 0, 0), (1, 0));
+        slot.eject();
+        wait(&mut vm, 2);
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::NoMedia as u16, FloppyError::Eject as u16));
+    }
+
+    #[test]
+    fn writes_persist_to_the_image() {
+        let path = ::std::env::temp_dir()
+            .join(format!("dcpu16-m35fd-{}.img", ::std::process::id()));
+        let slot = MediaSlot::with(Disk::open_floppy(&path, false).unwrap());
+        let mut vm = drive(&slot);
+        for w in vm.get_ram()[0x2000..0x2200].iter_mut() {
+            *w = 0x1234;
+        }
+        assert_eq!(floppy(&mut vm, 3, 1439, 0x2000), (1, 0));
+        wait(&mut vm, 100_000);
+        assert_eq!(floppy(&mut vm, 0, 0, 0), (FloppyState::Ready as u16, 0));
+        drop(slot.eject());
+
+        let disk = Disk::open_floppy(&path, true).unwrap();
+        let _ = ::std::fs::remove_file(&path);
+        assert!(disk.read_sector(1439).unwrap().iter().all(|w| *w == 0x1234));
+        assert!(disk.read_sector(1438).unwrap().iter().all(|w| *w == 0));
+    }
+}
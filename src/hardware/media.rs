@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use thiserror::Error;
+
+pub const SECTOR_SIZE: usize = 512;
+pub const FLOPPY_SECTORS: usize = 1440;
+
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("Image is {} words, the media only holds {}", .0, .1)]
+    ImageTooLarge(usize, usize),
+    #[error("Sector {} doesn't exist", .0)]
+    BadSector(usize),
+    #[error("The media is write protected")]
+    WriteProtected,
+    #[error("IO error: {}", .0)]
+    Io(#[from] io::Error),
+}
+
+// a disk's worth of words, optionally backed by an image file holding them
+// big endian. writes go straight through to the file
+#[derive(Debug)]
+pub struct Disk {
+    words: Vec<u16>,
+    sector_size: usize,
+    write_protected: bool,
+    file: Option<File>,
+}
+
+impl Disk {
+    pub fn blank(sectors: usize, sector_size: usize) -> Disk {
+        Disk {
+            words: vec![0; sectors * sector_size],
+            sector_size,
+            write_protected: false,
+            file: None,
+        }
+    }
+
+    pub fn floppy() -> Disk {
+        Disk::blank(FLOPPY_SECTORS, SECTOR_SIZE)
+    }
+
+    // images shorter than the disk are padded with zeroes, the file is only
+    // opened for writing if the disk isn't write protected
+    pub fn open<P: AsRef<Path>>(path: P, sectors: usize, sector_size: usize,
+                                write_protected: bool) -> Result<Disk, MediaError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!write_protected)
+            .create(!write_protected)
+            .truncate(false)
+            .open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut disk = Disk::blank(sectors, sector_size);
+        let len = bytes.len().div_ceil(2);
+        if len > disk.words.len() {
+            return Err(MediaError::ImageTooLarge(len, disk.words.len()));
+        }
+        for (word, chunk) in disk.words.iter_mut().zip(bytes.chunks(2)) {
+            *word = (chunk[0] as u16) << 8 | *chunk.get(1).unwrap_or(&0) as u16;
+        }
+        disk.write_protected = write_protected;
+        if !write_protected {
+            disk.file = Some(file);
+        }
+        Ok(disk)
+    }
+
+    pub fn open_floppy<P: AsRef<Path>>(path: P, write_protected: bool) -> Result<Disk, MediaError> {
+        Disk::open(path, FLOPPY_SECTORS, SECTOR_SIZE, write_protected)
+    }
+
+    pub fn write_protect(mut self, write_protected: bool) -> Self {
+        self.write_protected = write_protected;
+        self
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.words.len() / self.sector_size
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
+
+    pub fn read_sector(&self, sector: usize) -> Result<&[u16], MediaError> {
+        if sector >= self.sectors() {
+            return Err(MediaError::BadSector(sector));
+        }
+        Ok(&self.words[sector * self.sector_size .. (sector + 1) * self.sector_size])
+    }
+
+    pub fn write_sector(&mut self, sector: usize, data: &[u16]) -> Result<(), MediaError> {
+        if sector >= self.sectors() {
+            return Err(MediaError::BadSector(sector));
+        }
+        if self.write_protected {
+            return Err(MediaError::WriteProtected);
+        }
+        let start = sector * self.sector_size;
+        let len = data.len().min(self.sector_size);
+        self.words[start .. start + len].copy_from_slice(&data[..len]);
+
+        if let Some(ref mut file) = self.file {
+            let bytes: Vec<u8> = self.words[start .. start + self.sector_size].iter()
+                .flat_map(|w| vec![(w >> 8) as u8, *w as u8])
+                .collect();
+            file.seek(SeekFrom::Start(start as u64 * 2))?;
+            file.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Bay {
+    disk: Option<Disk>,
+    // bumped on every insert and eject so devices notice swaps between updates
+    generation: usize,
+}
+
+// the drive bay a disk sits in. devices hold one end and whoever's running
+// the VM keeps a clone to insert and eject disks while it runs
+#[derive(Clone, Debug, Default)]
+pub struct MediaSlot {
+    bay: Rc<RefCell<Bay>>,
+}
+
+impl MediaSlot {
+    pub fn new() -> MediaSlot {
+        MediaSlot::default()
+    }
+
+    pub fn with(disk: Disk) -> MediaSlot {
+        let slot = MediaSlot::new();
+        slot.insert(disk);
+        slot
+    }
+
+    // puts a disk in, handing back whatever was there before
+    pub fn insert(&self, disk: Disk) -> Option<Disk> {
+        let mut bay = self.bay.borrow_mut();
+        bay.generation += 1;
+        bay.disk.replace(disk)
+    }
+
+    pub fn eject(&self) -> Option<Disk> {
+        let mut bay = self.bay.borrow_mut();
+        bay.generation += 1;
+        bay.disk.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bay.borrow().disk.is_none()
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.bay.borrow().disk.as_ref().is_some_and(Disk::is_write_protected)
+    }
+
+    pub fn generation(&self) -> usize {
+        self.bay.borrow().generation
+    }
+
+    pub fn with_disk<T, F: FnOnce(&mut Disk) -> T>(&self, f: F) -> Option<T> {
+        self.bay.borrow_mut().disk.as_mut().map(f)
+    }
+}
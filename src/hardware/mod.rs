@@ -1,5 +1,41 @@
 pub mod core;
+mod context;
 mod clock;
+mod keyboard;
+mod link;
+mod serial;
+mod media;
+mod m35fd;
+mod sped3;
+mod hmd2043;
+mod lem1802;
 
 pub use self::core::*;
+pub use self::context::*;
 pub use self::clock::*;
+pub use self::keyboard::*;
+pub use self::link::*;
+pub use self::serial::*;
+pub use self::media::*;
+pub use self::m35fd::*;
+pub use self::sped3::*;
+pub use self::hmd2043::*;
+pub use self::lem1802::*;
+
+#[cfg(test)]
+use virtual_machine::{Register, VirtualMachine};
+
+// sends device 0 a HWI 0 from 0x0000 with the registers set, for the
+// devices' tests. returns the registers after
+#[cfg(test)]
+pub(crate) fn hwi(vm: &mut VirtualMachine, regs: &[(Register, u16)]) -> [u16; 8] {
+    for &(reg, value) in regs {
+        vm.get_registers()[reg as usize] = value;
+    }
+    vm.get_ram()[0] = 0x8640;
+    *vm.get_pc() = 0;
+    vm.step().unwrap();
+    let mut out = [0; 8];
+    out.copy_from_slice(vm.get_registers());
+    out
+}
@@ -0,0 +1,344 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use std::fmt::{Formatter, Error};
+use std::collections::VecDeque;
+use std::cell::RefCell;
+use std::io::{self, BufReader, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+pub const SERIAL_BUFFER_SIZE: usize = 256;
+
+// where the bytes of a serial port come from and go to on the host. receive
+// must never block, the VM calls it on every update
+pub trait SerialBackend {
+    fn receive(&mut self) -> Option<u8>;
+    fn transmit(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+// a reader and writer pair, e.g. stdin/stdout, a child process's pipes or a
+// socket. the reader is drained on its own thread so receive doesn't block
+pub struct StreamBackend<W: Write> {
+    rx: Receiver<u8>,
+    writer: W,
+}
+
+impl<W: Write> StreamBackend<W> {
+    pub fn new<R: Read + Send + 'static>(reader: R, writer: W) -> StreamBackend<W> {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for byte in BufReader::new(reader).bytes() {
+                match byte {
+                    Ok(b) => if tx.send(b).is_err() { break },
+                    Err(_) => break
+                }
+            }
+        });
+        StreamBackend { rx, writer }
+    }
+}
+
+impl StreamBackend<io::Stdout> {
+    pub fn stdio() -> StreamBackend<io::Stdout> {
+        StreamBackend::new(io::stdin(), io::stdout())
+    }
+}
+
+#[cfg(unix)]
+impl StreamBackend<::std::os::unix::net::UnixStream> {
+    pub fn unix(stream: ::std::os::unix::net::UnixStream) -> io::Result<Self> {
+        Ok(StreamBackend::new(stream.try_clone()?, stream))
+    }
+
+    pub fn unix_socket<P: AsRef<::std::path::Path>>(path: P) -> io::Result<Self> {
+        StreamBackend::unix(::std::os::unix::net::UnixStream::connect(path)?)
+    }
+}
+
+impl<W: Write> SerialBackend for StreamBackend<W> {
+    fn receive(&mut self) -> Option<u8> {
+        self.rx.try_recv().ok()
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.writer.flush()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+// keeps everything in memory, clone it before attaching the port to feed it
+// input and check what the program wrote
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    pub fn push_input(&self, data: &[u8]) {
+        self.buffers.borrow_mut().input.extend(data);
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.buffers.borrow().output).into_owned()
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        ::std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl SerialBackend for MemoryBackend {
+    fn receive(&mut self) -> Option<u8> {
+        self.buffers.borrow_mut().input.pop_front()
+    }
+
+    fn transmit(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buffers.borrow_mut().output.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+pub struct SerialPort {
+    hw_info: HardwareInfo,
+    backend: Box<dyn SerialBackend>,
+    rx: VecDeque<u8>,
+    tx: Vec<u8>,
+    interrupt: u16,
+    error: Option<io::Error>,
+}
+
+impl SerialPort {
+    pub fn new(backend: Box<dyn SerialBackend>) -> SerialPort {
+        SerialPort {
+            hw_info: HardwareInfo {
+                manufacturer: 0x4d44454b,
+                model: 0x55415254,
+                version: 0x0001
+            },
+            backend,
+            rx: VecDeque::with_capacity(SERIAL_BUFFER_SIZE),
+            tx: Vec::with_capacity(SERIAL_BUFFER_SIZE),
+            interrupt: 0,
+            error: None,
+        }
+    }
+
+    // the last error the backend gave while transmitting, the bytes it was
+    // given are lost
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    fn queue(&mut self, byte: u8) -> bool {
+        if self.tx.len() == SERIAL_BUFFER_SIZE {
+            return false
+        }
+        self.tx.push(byte);
+        true
+    }
+}
+
+impl Hardware for SerialPort {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        match a {
+            0x0 => {
+                let waiting = self.rx.len() as u16;
+                let free = (SERIAL_BUFFER_SIZE - self.tx.len()) as u16;
+                cycles += vm.write_register(Register::B, waiting);
+                cycles += vm.write_register(Register::C, free);
+            },
+            0x1 => {
+                let (byte, ok) = match self.rx.pop_front() {
+                    Some(byte) => (byte as u16, 1),
+                    None => (0, 0)
+                };
+                cycles += vm.write_register(Register::B, byte);
+                cycles += vm.write_register(Register::C, ok);
+            },
+            0x2 => {
+                let (byte, c) = vm.read_register(Register::B);
+                cycles += c;
+                let sent = self.queue(byte as u8) as u16;
+                cycles += vm.write_register(Register::C, sent);
+            },
+            0x3 => {
+                let (i, c) = vm.read_register(Register::B);
+                self.interrupt = i;
+                cycles += c;
+            },
+            0x4 => {
+                let (addr, c) = vm.read_register(Register::B);
+                cycles += c;
+                let (len, c) = vm.read_register(Register::C);
+                cycles += c;
+                let mut sent = 0;
+                while sent < len {
+                    let (word, c) = vm.read_ram((addr.wrapping_add(sent)) as usize, 1)
+                        .expect("a single word is always in bounds");
+                    cycles += c;
+                    if !self.queue(word[0] as u8) {
+                        break
+                    }
+                    sent += 1;
+                }
+                cycles += vm.write_register(Register::C, sent);
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        if !self.tx.is_empty() {
+            if let Err(e) = self.backend.transmit(&self.tx) {
+                self.error = Some(e);
+            }
+            self.tx.clear();
+        }
+
+        let mut received = false;
+        while self.rx.len() < SERIAL_BUFFER_SIZE {
+            match self.backend.receive() {
+                Some(byte) => {
+                    self.rx.push_back(byte);
+                    received = true;
+                },
+                None => break
+            }
+        }
+        if received && self.interrupt != 0 {
+            vm.interrupt(self.interrupt);
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(
+            format_args!("rx: {}, tx: {}, interrupt: {:02x}",
+                self.rx.len(), self.tx.len(), self.interrupt))
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod tests {
+    use super::*;
+    use assembly::Assemble;
+    use opcodes::{Opcode, Operand};
+    use virtual_machine::VirtualMachine;
+
+    fn assemble(at: usize, program: Vec<Opcode>, vm: &mut VirtualMachine) {
+        let words: Vec<u16> = program.iter().flat_map(|op| op.assem().unwrap()).collect();
+        vm.get_ram()[at..at + words.len()].copy_from_slice(&words);
+    }
+
+    fn set(reg: Register, n: u16) -> Opcode {
+        Opcode::SET(Operand::Register(reg), Operand::Literal(n))
+    }
+
+    fn run(vm: &mut VirtualMachine, steps: usize) {
+        for _ in 0..steps {
+            vm.step().unwrap();
+            vm.update_hardware();
+        }
+    }
+
+    // echoes every byte it receives, upper cased
+    fn echo(vm: &mut VirtualMachine) {
+        assemble(0, vec![
+            Opcode::IAS(Operand::Literal(0x10)),
+            set(Register::A, 3),
+            set(Register::B, 1),
+            Opcode::HWI(Operand::Literal(0)),
+            Opcode::SUB(Operand::Pc, Operand::Literal(1)),
+        ], vm);
+        // drains the receive buffer before returning
+        assemble(0x10, vec![
+            set(Register::A, 1),
+            Opcode::HWI(Operand::Literal(0)),
+            Opcode::IFE(Operand::Register(Register::C), Operand::Literal(0)),
+            Opcode::RFI(Operand::Literal(0)),
+            Opcode::SUB(Operand::Register(Register::B), Operand::Literal(0x20)),
+            set(Register::A, 2),
+            Opcode::HWI(Operand::Literal(0)),
+            Opcode::SET(Operand::Pc, Operand::Literal(0x10)),
+        ], vm);
+    }
+
+    #[test]
+    fn prints_from_memory() {
+        let mem = MemoryBackend::new();
+        let mut vm = VirtualMachine::new()
+            .attach_hardware(Box::new(SerialPort::new(Box::new(mem.clone())))).unwrap();
+        assemble(0, vec![
+            set(Register::A, 4),
+            set(Register::B, 0x100),
+            set(Register::C, 6),
+            Opcode::HWI(Operand::Literal(0)),
+            Opcode::SUB(Operand::Pc, Operand::Literal(1)),
+        ], &mut vm);
+        for (i, c) in "hello\n".bytes().enumerate() {
+            vm.get_ram()[0x100 + i] = c as u16;
+        }
+        run(&mut vm, 5);
+        assert_eq!(mem.output_string(), "hello\n");
+        assert_eq!(vm.get_registers()[Register::C as usize], 6);
+    }
+
+    #[test]
+    fn echoes_on_receive() {
+        let mem = MemoryBackend::new();
+        let mut vm = VirtualMachine::new()
+            .attach_hardware(Box::new(SerialPort::new(Box::new(mem.clone())))).unwrap();
+        echo(&mut vm);
+        run(&mut vm, 10);
+        mem.push_input(b"abc");
+        run(&mut vm, 100);
+        assert_eq!(mem.output_string(), "ABC");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        use std::os::unix::net::UnixStream;
+        use std::time::{Duration, Instant};
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let backend = StreamBackend::unix(theirs).unwrap();
+        let mut vm = VirtualMachine::new()
+            .attach_hardware(Box::new(SerialPort::new(Box::new(backend)))).unwrap();
+        echo(&mut vm);
+        run(&mut vm, 10);
+
+        (&ours).write_all(b"ok").unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let start = Instant::now();
+        let mut got = Vec::new();
+        while got.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            run(&mut vm, 100);
+            let mut buf = [0u8; 2];
+            if let Ok(n) = (&ours).read(&mut buf) {
+                got.extend_from_slice(&buf[..n]);
+            }
+        }
+        assert_eq!(got, b"OK");
+    }
+}
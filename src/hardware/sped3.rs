@@ -0,0 +1,363 @@
+use super::super::virtual_machine::{VMExposed, Register};
+use self::super::core::{Hardware, HardwareInfo};
+use std::fmt::{Formatter, Error};
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+pub const SPED3_MAX_VERTICES: usize = 128;
+pub const SPED3_DEGREES_PER_SECOND: f64 = 50.0;
+pub const SPED3_FRAME_SIZE: usize = 256;
+
+#[repr(u16)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sped3State {
+    NoData = 0x0000,
+    Running = 0x0001,
+    Turning = 0x0002,
+}
+
+// an RGB image, 3 bytes a pixel, row by row from the top left
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Frame {
+        Frame { width, height, pixels: vec![0; width * height * 3] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.pixels[i], self.pixels[i + 1], self.pixels[i + 2]]
+    }
+
+    pub fn clear(&mut self) {
+        for p in self.pixels.iter_mut() {
+            *p = 0;
+        }
+    }
+
+    // anything off the frame is clipped
+    pub fn plot(&mut self, x: i32, y: i32, rgb: [u8; 3]) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return
+        }
+        let i = (y as usize * self.width + x as usize) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    // bresenham, both ends included
+    pub fn line(&mut self, (mut x0, mut y0): (i32, i32), (x1, y1): (i32, i32), rgb: [u8; 3]) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        loop {
+            self.plot(x0, y0, rgb);
+            if x0 == x1 && y0 == y1 {
+                break
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x0 += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y0 += sy;
+            }
+        }
+    }
+
+    // binary PPM, handy for eyeballing snapshots
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend_from_slice(&self.pixels);
+        out
+    }
+}
+
+fn vertex_colour(word: u16) -> [u8; 3] {
+    let level = if word & 0x400 != 0 { 0xff } else { 0x7f };
+    match (word >> 8) & 0x3 {
+        1 => [level, 0, 0],
+        2 => [0, level, 0],
+        3 => [0, 0, level],
+        _ => [0, 0, 0],
+    }
+}
+
+// draws the vertices as seen from the side, turned `rotation` degrees around
+// the vertical axis. each vertex is two words: X in the low byte of the first
+// and Y in the high byte, Z in the low byte of the second followed by 2 bits
+// of colour and 1 of intensity. lines run from each vertex to the next one in
+// the colour of the vertex they end at
+pub fn rasterize(vertices: &[u16], rotation: f64, frame: &mut Frame) {
+    frame.clear();
+    let (sin, cos) = rotation.to_radians().sin_cos();
+    let half = (SPED3_FRAME_SIZE / 2) as f64;
+    let sx = frame.width() as f64 / SPED3_FRAME_SIZE as f64;
+    let sy = frame.height() as f64 / SPED3_FRAME_SIZE as f64;
+    let project = |first: u16, second: u16| {
+        let x = (first & 0xff) as f64 - half;
+        let y = (first >> 8) as f64 - half;
+        let z = (second & 0xff) as f64;
+        let rx = x * cos - y * sin + half;
+        let ry = SPED3_FRAME_SIZE as f64 - 1.0 - z;
+        ((rx * sx).round() as i32, (ry * sy).round() as i32)
+    };
+
+    let mut last = None;
+    for v in vertices.chunks(2).filter(|v| v.len() == 2) {
+        let point = project(v[0], v[1]);
+        match last {
+            Some(from) => frame.line(from, point, vertex_colour(v[1])),
+            None => frame.plot(point.0, point.1, vertex_colour(v[1])),
+        }
+        last = Some(point);
+    }
+}
+
+// Mackapar Suspended Particle Exciter Display, Rev 3. redraws into its frame
+// about 60 times a second, clone the frame handle before attaching it to look
+// at what's on screen
+pub struct Sped3 {
+    hw_info: HardwareInfo,
+    frame: Rc<RefCell<Frame>>,
+    address: u16,
+    count: u16,
+    rotation: f64,
+    target: u16,
+    last_cycles: Option<usize>,
+    last_frame: usize,
+}
+
+impl Default for Sped3 {
+    fn default() -> Self {
+        Sped3::new()
+    }
+}
+
+impl Sped3 {
+    pub fn new() -> Sped3 {
+        Sped3 {
+            hw_info: HardwareInfo {
+                manufacturer: 0x1eb37e91,
+                model: 0x42babf3c,
+                version: 0x0003
+            },
+            frame: Rc::new(RefCell::new(Frame::new(SPED3_FRAME_SIZE, SPED3_FRAME_SIZE))),
+            address: 0,
+            count: 0,
+            rotation: 0.0,
+            target: 0,
+            last_cycles: None,
+            last_frame: 0,
+        }
+    }
+
+    pub fn frame(&self) -> Sped3Frame {
+        Sped3Frame { frame: self.frame.clone() }
+    }
+
+    pub fn rotation(&self) -> f64 {
+        self.rotation
+    }
+
+    pub fn state(&self) -> Sped3State {
+        if self.count == 0 {
+            Sped3State::NoData
+        } else if self.rotation != self.target as f64 {
+            Sped3State::Turning
+        } else {
+            Sped3State::Running
+        }
+    }
+
+    fn turn(&mut self, degrees: f64) {
+        let target = self.target as f64;
+        // shortest way round
+        let mut diff = (target - self.rotation) % 360.0;
+        if diff > 180.0 {
+            diff -= 360.0;
+        } else if diff < -180.0 {
+            diff += 360.0;
+        }
+        if diff.abs() <= degrees {
+            self.rotation = target;
+        } else {
+            self.rotation = (self.rotation + degrees.copysign(diff)).rem_euclid(360.0);
+        }
+    }
+
+    fn draw(&mut self, vm: &mut VMExposed) {
+        let mut vertices = Vec::with_capacity(self.count as usize * 2);
+        for i in 0..self.count * 2 {
+            let (word, _) = vm.read_ram(self.address.wrapping_add(i) as usize, 1)
+                .expect("a single word is always in bounds");
+            vertices.push(word[0]);
+        }
+        rasterize(&vertices, self.rotation, &mut self.frame.borrow_mut());
+    }
+}
+
+// a look at the frame a SPED-3 last drew
+#[derive(Clone, Debug)]
+pub struct Sped3Frame {
+    frame: Rc<RefCell<Frame>>,
+}
+
+impl Sped3Frame {
+    pub fn borrow(&self) -> Ref<'_, Frame> {
+        self.frame.borrow()
+    }
+
+    pub fn snapshot(&self) -> Frame {
+        self.frame.borrow().clone()
+    }
+}
+
+impl Hardware for Sped3 {
+    fn info(&self) -> &HardwareInfo {
+        &self.hw_info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        let (a, mut cycles) = vm.read_register(Register::A);
+        match a {
+            0x0 => {
+                cycles += vm.write_register(Register::B, self.state() as u16);
+                cycles += vm.write_register(Register::C, 0);
+            },
+            0x1 => {
+                let (address, c) = vm.read_register(Register::X);
+                cycles += c;
+                let (count, c) = vm.read_register(Register::Y);
+                cycles += c;
+                self.address = address;
+                self.count = count.min(SPED3_MAX_VERTICES as u16);
+            },
+            0x2 => {
+                let (target, c) = vm.read_register(Register::X);
+                cycles += c;
+                self.target = target % 360;
+            },
+            _ => return 0
+        }
+        cycles
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        let cycles = vm.get_cycles();
+        let elapsed = cycles - self.last_cycles.unwrap_or(cycles);
+        self.last_cycles = Some(cycles);
+        self.turn(elapsed as f64 * SPED3_DEGREES_PER_SECOND / vm.get_clock_rate() as f64);
+
+        if cycles < self.last_frame {
+            return
+        }
+        self.last_frame = cycles + vm.get_clock_rate() / 60;
+        if self.count == 0 {
+            self.frame.borrow_mut().clear();
+        } else {
+            self.draw(vm);
+        }
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        fmt.write_fmt(
+            format_args!("state: {:?}, vertices: {:04x}+{}, rotation: {:.1}, target: {}",
+                self.state(), self.address, self.count, self.rotation, self.target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::VirtualMachine;
+
+    const RED: u16 = 0x0500;
+    const GREEN: u16 = 0x0200;
+
+    #[test]
+    fn rasterizes_lines() {
+        let mut frame = Frame::new(SPED3_FRAME_SIZE, SPED3_FRAME_SIZE);
+        // a vertical line up the middle, then one across the top
+        rasterize(&[
+            0x8080, RED,
+            0x8080, 100 | RED,
+            0x8090, 100 | GREEN,
+        ], 0.0, &mut frame);
+        assert_eq!(frame.pixel(128, 255), [0xff, 0, 0]);
+        assert_eq!(frame.pixel(128, 200), [0xff, 0, 0]);
+        assert_eq!(frame.pixel(128, 155), [0, 0x7f, 0]);
+        assert_eq!(frame.pixel(140, 155), [0, 0x7f, 0]);
+        assert_eq!(frame.pixel(144, 155), [0, 0x7f, 0]);
+        assert_eq!(frame.pixel(145, 155), [0, 0, 0]);
+        assert_eq!(frame.pixel(127, 200), [0, 0, 0]);
+    }
+
+    #[test]
+    fn rotation_turns_the_model() {
+        let mut frame = Frame::new(SPED3_FRAME_SIZE, SPED3_FRAME_SIZE);
+        rasterize(&[0x80a0, 10 | RED], 0.0, &mut frame);
+        assert_eq!(frame.pixel(160, 245), [0xff, 0, 0]);
+        // a quarter turn puts X on the depth axis and Y on the screen
+        rasterize(&[0xa080, 10 | RED], 90.0, &mut frame);
+        assert_eq!(frame.pixel(96, 245), [0xff, 0, 0]);
+    }
+
+    #[test]
+    fn maps_rotates_and_draws() {
+        let sped = Sped3::new();
+        let frame = sped.frame();
+        let mut vm = VirtualMachine::new().attach_hardware(Box::new(sped)).unwrap();
+        // HWI 0; SUB PC, 1
+        vm.get_ram()[..2].copy_from_slice(&[0x8640, 0x8b83]);
+        vm.get_ram()[0x1000..0x1004].copy_from_slice(&[0x8080, RED, 0x8080, 50 | RED]);
+
+        let hwi = |vm: &mut VirtualMachine, a: u16, x: u16, y: u16| {
+            {
+                let regs = vm.get_registers();
+                regs[Register::A as usize] = a;
+                regs[Register::X as usize] = x;
+                regs[Register::Y as usize] = y;
+            }
+            *vm.get_pc() = 0;
+            vm.step().unwrap();
+            vm.update_hardware();
+            *vm.get_pc() = 1;
+            vm.get_registers()[Register::B as usize]
+        };
+        assert_eq!(hwi(&mut vm, 0, 0, 0), Sped3State::NoData as u16);
+        hwi(&mut vm, 1, 0x1000, 2);
+        assert_eq!(hwi(&mut vm, 0, 0, 0), Sped3State::Running as u16);
+        hwi(&mut vm, 2, 370, 0);
+        assert_eq!(hwi(&mut vm, 0, 0, 0), Sped3State::Turning as u16);
+
+        // 10 degrees at 50 a second is 0.2s, 20000 cycles at 100kHz
+        while vm.get_cycles() < 21000 {
+            vm.step().unwrap();
+            vm.update_hardware();
+        }
+        assert_eq!(hwi(&mut vm, 0, 0, 0), Sped3State::Running as u16);
+        assert_eq!(frame.borrow().pixel(128, 230), [0xff, 0, 0]);
+        assert_eq!(frame.snapshot().to_ppm().len(), 15 + 256 * 256 * 3);
+    }
+}
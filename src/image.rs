@@ -0,0 +1,491 @@
+// programs as files: runs of words and where they go, and for the formats
+// that have room, where to start and the labels. the formats are in
+// docs/images.txt
+use std::collections::BTreeMap;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ImageError {
+    #[error("Not a DCPU image")]
+    BadMagic,
+    #[error("Image version {} isn't supported", .0)]
+    UnsupportedVersion(u16),
+    #[error("The image ends early")]
+    Truncated,
+    #[error("Line {}: {}", .0, .1)]
+    Syntax(usize, String),
+    #[error("Line {}: checksum should be {:02x}", .0, .1)]
+    Checksum(usize, u8),
+    #[error("Address {:#x} is past the end of memory", .0)]
+    OutOfRange(u32),
+    #[error("Unknown image format: {}", .0)]
+    UnknownFormat(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // raw words, high byte first, which is what most DCPU tools write
+    BigEndian,
+    LittleEndian,
+    // lines of an address and the words from there, in hex
+    Hexdump,
+    // byte addresses are twice the word's, and words are big endian
+    IntelHex,
+    // the segments with their origins, the entry point and symbols
+    Headered,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 5] = ["big-endian", "little-endian", "hexdump", "intel-hex", "headered"];
+
+    pub fn named(name: &str) -> Result<Format, ImageError> {
+        match name {
+            "big-endian" | "be" => Ok(Format::BigEndian),
+            "little-endian" | "le" => Ok(Format::LittleEndian),
+            "hexdump" => Ok(Format::Hexdump),
+            "intel-hex" | "ihex" => Ok(Format::IntelHex),
+            "headered" => Ok(Format::Headered),
+            other => Err(ImageError::UnknownFormat(other.to_owned())),
+        }
+    }
+
+    // by the extension, with anything unknown being big endian words
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Format {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("hex") | Some("ihx") | Some("ihex") => Format::IntelHex,
+            Some("txt") | Some("dump") => Format::Hexdump,
+            Some("dcpu") => Format::Headered,
+            Some("le") => Format::LittleEndian,
+            _ => Format::BigEndian,
+        }
+    }
+
+    // whether it's just the words, with nothing to say where they go
+    pub fn is_raw(self) -> bool {
+        self == Format::BigEndian || self == Format::LittleEndian
+    }
+}
+
+const MAGIC: &[u8; 4] = b"DCPU";
+const VERSION: u16 = 1;
+const HAS_ENTRY: u16 = 1;
+
+// words that go one after another from `origin`. they can run past 0xffff,
+// and what happens then is up to whatever loads them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Segment {
+    // one past the last word, without wrapping
+    pub fn end(&self) -> usize {
+        self.origin as usize + self.words.len()
+    }
+
+    // the segment in the pieces it ends up as in memory, split where it
+    // goes round from 0xffff to 0
+    pub fn wrapped(&self) -> Vec<(u16, &[u16])> {
+        let mut pieces = vec![];
+        let (mut origin, mut words) = (self.origin, &self.words[..]);
+        while !words.is_empty() {
+            let (head, tail) = words.split_at(words.len().min(0x10000 - origin as usize));
+            pieces.push((origin, head));
+            origin = 0;
+            words = tail;
+        }
+        pieces
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u16>,
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Image {
+    // just the one segment
+    pub fn new(origin: u16, words: Vec<u16>) -> Image {
+        Image::default().segment(origin, words)
+    }
+
+    pub fn segment(mut self, origin: u16, words: Vec<u16>) -> Self {
+        self.segments.push(Segment { origin, words });
+        self
+    }
+
+    pub fn entry(mut self, entry: u16) -> Self {
+        self.entry = Some(entry);
+        self
+    }
+
+    pub fn symbols(mut self, symbols: BTreeMap<String, u16>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // where the first segment goes
+    pub fn origin(&self) -> u16 {
+        self.segments.first().map_or(0, |s| s.origin)
+    }
+
+    // where it starts running: the entry point, or the first segment
+    pub fn start(&self) -> u16 {
+        self.entry.unwrap_or_else(|| self.origin())
+    }
+
+    // moves the first segment to `origin` and the rest along with it, for
+    // raw images, which are all at 0
+    pub fn relocate(mut self, origin: u16) -> Self {
+        let by = origin.wrapping_sub(self.origin());
+        for segment in self.segments.iter_mut() {
+            segment.origin = segment.origin.wrapping_add(by);
+        }
+        self
+    }
+
+    // every word from the lowest address to the highest, with zeroes in the
+    // gaps, for the formats that only hold one run of words. a segment on
+    // its own stays as it is, even if it wraps
+    pub fn flatten(&self) -> Segment {
+        if let [ref segment] = self.segments[..] {
+            return segment.clone();
+        }
+        let pieces: Vec<(u16, &[u16])> = self.segments.iter().flat_map(|s| s.wrapped()).collect();
+        let origin = pieces.iter().map(|&(o, _)| o).min().unwrap_or(0);
+        let end = pieces.iter().map(|&(o, w)| o as usize + w.len()).max().unwrap_or(0);
+        let mut words = vec![0; end - origin as usize];
+        for &(o, w) in pieces.iter() {
+            let at = (o - origin) as usize;
+            words[at..at + w.len()].copy_from_slice(w);
+        }
+        Segment { origin, words }
+    }
+
+    // raw formats have nothing but the words, so they're one segment at 0
+    // with no entry point or symbols. the others say where they go
+    pub fn read(bytes: &[u8], format: Format) -> Result<Image, ImageError> {
+        match format {
+            Format::BigEndian => Ok(Image::new(0, bytes.chunks(2)
+                .map(|c| (c[0] as u16) << 8 | *c.get(1).unwrap_or(&0) as u16)
+                .collect())),
+            Format::LittleEndian => Ok(Image::new(0, bytes.chunks(2)
+                .map(|c| c[0] as u16 | (*c.get(1).unwrap_or(&0) as u16) << 8)
+                .collect())),
+            Format::Hexdump => read_hexdump(&String::from_utf8_lossy(bytes)),
+            Format::IntelHex => read_intel_hex(&String::from_utf8_lossy(bytes)),
+            Format::Headered => read_headered(bytes),
+        }
+    }
+
+    pub fn write(&self, format: Format) -> Vec<u8> {
+        match format {
+            Format::BigEndian => self.flatten().words.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).collect(),
+            Format::LittleEndian => self.flatten().words.iter().flat_map(|w| vec![*w as u8, (w >> 8) as u8]).collect(),
+            Format::Hexdump => self.hexdump().into_bytes(),
+            Format::IntelHex => self.intel_hex().into_bytes(),
+            Format::Headered => self.headered(),
+        }
+    }
+
+    fn hexdump(&self) -> String {
+        let mut out = String::new();
+        for (origin, words) in self.segments.iter().flat_map(|s| s.wrapped()) {
+            for (i, line) in words.chunks(8).enumerate() {
+                out.push_str(&format!("{:04x}:", origin as usize + i * 8));
+                for word in line.iter() {
+                    out.push_str(&format!(" {:04x}", word));
+                }
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    fn intel_hex(&self) -> String {
+        let record = |kind: u8, address: u16, data: &[u8]| {
+            let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+            bytes.extend_from_slice(data);
+            let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            bytes.push(sum.wrapping_neg());
+            format!(":{}\n", bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+        };
+        // records start on multiples of 8 words, so none crosses into the
+        // next 64k bytes
+        let mut lines = vec![];
+        for (origin, words) in self.segments.iter().flat_map(|s| s.wrapped()) {
+            let mut start = 0;
+            while start < words.len() {
+                let end = (start + 8 - (origin as usize + start) % 8).min(words.len());
+                lines.push((origin as u32 + start as u32, &words[start..end]));
+                start = end;
+            }
+        }
+        let mut out = String::new();
+        let mut upper = 0;
+        for (word, line) in lines {
+            let address = word * 2;
+            if address >> 16 != upper {
+                upper = address >> 16;
+                out.push_str(&record(4, 0, &[(upper >> 8) as u8, upper as u8]));
+            }
+            let data: Vec<u8> = line.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).collect();
+            out.push_str(&record(0, address as u16, &data));
+        }
+        if let Some(entry) = self.entry {
+            let address = entry as u32 * 2;
+            out.push_str(&record(5, 0, &[(address >> 24) as u8, (address >> 16) as u8, (address >> 8) as u8, address as u8]));
+        }
+        out.push_str(&record(1, 0, &[]));
+        out
+    }
+
+    fn headered(&self) -> Vec<u8> {
+        let mut words = vec![VERSION, if self.entry.is_some() { HAS_ENTRY } else { 0 }, self.entry.unwrap_or(0),
+                             self.segments.len() as u16, self.symbols.len() as u16];
+        for segment in self.segments.iter() {
+            words.extend_from_slice(&[segment.origin, (segment.words.len() >> 16) as u16, segment.words.len() as u16]);
+            words.extend_from_slice(&segment.words);
+        }
+        for (name, &address) in self.symbols.iter() {
+            words.push(address);
+            words.push(name.len() as u16);
+            words.extend(name.as_bytes().chunks(2).map(|c| (c[0] as u16) << 8 | *c.get(1).unwrap_or(&0) as u16));
+        }
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(words.iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]));
+        bytes
+    }
+}
+
+fn hex(line: usize, s: &str) -> Result<u32, ImageError> {
+    u32::from_str_radix(s, 16).map_err(|_| ImageError::Syntax(line, format!("{} isn't a hex number", s)))
+}
+
+// words go on from the last line unless a line starts with an address and
+// a colon, and an address anywhere else starts another segment. # starts a
+// comment
+fn read_hexdump(text: &str) -> Result<Image, ImageError> {
+    let mut image = Image::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words = match line.find(':') {
+            Some(colon) => {
+                let address = hex(i + 1, line[..colon].trim())?;
+                if address > 0xffff {
+                    return Err(ImageError::OutOfRange(address));
+                }
+                if image.segments.last().is_none_or(|s| s.end() != address as usize) {
+                    image.segments.push(Segment { origin: address as u16, words: vec![] });
+                }
+                &line[colon + 1..]
+            },
+            None => line,
+        };
+        if image.segments.is_empty() {
+            image.segments.push(Segment::default());
+        }
+        let segment = image.segments.last_mut().unwrap();
+        for word in words.split_whitespace() {
+            match hex(i + 1, word)? {
+                w if w <= 0xffff => segment.words.push(w as u16),
+                _ => return Err(ImageError::Syntax(i + 1, format!("{} is more than a word", word))),
+            }
+        }
+    }
+    Ok(image)
+}
+
+// data, end of file, extended segment and linear addresses, and either
+// start address record for the entry point. each run of words with no
+// gap is a segment
+fn read_intel_hex(text: &str) -> Result<Image, ImageError> {
+    let mut bytes: BTreeMap<u32, u8> = BTreeMap::new();
+    let mut entry = None;
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let n = i + 1;
+        if !line.is_ascii() || !line.starts_with(':') || line.len() % 2 == 0 || line.len() < 11 {
+            return Err(ImageError::Syntax(n, "not a record".to_owned()));
+        }
+        let record = (1..line.len()).step_by(2).map(|j| hex(n, &line[j..j + 2]).map(|b| b as u8))
+            .collect::<Result<Vec<u8>, _>>()?;
+        if record.len() != record[0] as usize + 5 {
+            return Err(ImageError::Syntax(n, "the length is wrong".to_owned()));
+        }
+        let sum = record[..record.len() - 1].iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg();
+        if sum != record[record.len() - 1] {
+            return Err(ImageError::Checksum(n, sum));
+        }
+        let address = (record[1] as u32) << 8 | record[2] as u32;
+        let data = &record[4..record.len() - 1];
+        let value = || data.iter().fold(0u32, |v, b| v << 8 | *b as u32);
+        match (record[3], data.len()) {
+            (0, _) => for (j, b) in data.iter().enumerate() {
+                let at = base.checked_add(address + j as u32).ok_or(ImageError::OutOfRange(base))?;
+                bytes.insert(at, *b);
+            },
+            (1, _) => break,
+            (2, 2) => base = value() << 4,
+            (3, 4) => entry = Some((value() >> 16 << 4) + (value() & 0xffff)),
+            (4, 2) => base = value() << 16,
+            (5, 4) => entry = Some(value()),
+            (kind, _) => return Err(ImageError::Syntax(n, format!("unexpected record type {:02x}", kind))),
+        }
+    }
+    let mut image = Image::default();
+    let mut last = None;
+    for &address in bytes.keys() {
+        if address >= 0x20000 {
+            return Err(ImageError::OutOfRange(address));
+        }
+        let word = address / 2;
+        if last == Some(word) {
+            continue;
+        }
+        if last != Some(word.wrapping_sub(1)) {
+            image.segments.push(Segment { origin: word as u16, words: vec![] });
+        }
+        let byte = |a| *bytes.get(&a).unwrap_or(&0) as u16;
+        image.segments.last_mut().unwrap().words.push(byte(word * 2) << 8 | byte(word * 2 + 1));
+        last = Some(word);
+    }
+    image.entry = match entry {
+        Some(e) if e >= 0x20000 => return Err(ImageError::OutOfRange(e)),
+        e => e.map(|e| (e / 2) as u16),
+    };
+    Ok(image)
+}
+
+fn read_headered(bytes: &[u8]) -> Result<Image, ImageError> {
+    if bytes.len() < 4 || &bytes[..4] != MAGIC {
+        return Err(ImageError::BadMagic);
+    }
+    let mut words = bytes[4..].chunks(2).map(|c| (c[0] as u16) << 8 | *c.get(1).unwrap_or(&0) as u16);
+    let mut next = || words.next().ok_or(ImageError::Truncated);
+    let version = next()?;
+    if version != VERSION {
+        return Err(ImageError::UnsupportedVersion(version));
+    }
+    let flags = next()?;
+    let mut image = Image::default();
+    let entry = next()?;
+    if flags & HAS_ENTRY != 0 {
+        image.entry = Some(entry);
+    }
+    let segments = next()?;
+    let symbols = next()?;
+    for _ in 0..segments {
+        let origin = next()?;
+        let len = (next()? as usize) << 16 | next()? as usize;
+        let words = (0..len).map(|_| next()).collect::<Result<_, _>>()?;
+        image.segments.push(Segment { origin, words });
+    }
+    for _ in 0..symbols {
+        let address = next()?;
+        let len = next()? as usize;
+        let name: Vec<u8> = (0..len.div_ceil(2)).map(|_| next()).collect::<Result<Vec<u16>, _>>()?
+            .iter().flat_map(|w| vec![(w >> 8) as u8, *w as u8]).take(len).collect();
+        image.symbols.insert(String::from_utf8_lossy(&name).into_owned(), address);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Image {
+        let symbols = [("start".to_owned(), 0x7ffe), ("font".to_owned(), 0xf000)].iter().cloned().collect();
+        Image::new(0x7ffe, (0..20u16).map(|w| w.wrapping_mul(0x1111)).collect())
+            .segment(0xf000, vec![0xbeef; 3])
+            .entry(0x8000)
+            .symbols(symbols)
+    }
+
+    #[test]
+    fn round_trips() {
+        let image = image();
+        let headered = image.write(Format::Headered);
+        assert_eq!(&headered[..6], b"DCPU\x00\x01");
+        assert_eq!(Image::read(&headered, Format::Headered), Ok(image.clone()));
+        // the formats that keep addresses keep the entry point, but not symbols
+        let addressed = Image { symbols: BTreeMap::new(), ..image.clone() };
+        assert_eq!(Image::read(&image.write(Format::IntelHex), Format::IntelHex), Ok(addressed.clone()));
+        assert_eq!(Image::read(&image.write(Format::Hexdump), Format::Hexdump),
+                   Ok(Image { entry: None, ..addressed }));
+        // raw ones are everything from the lowest address to the highest
+        let raw = image.write(Format::BigEndian);
+        assert_eq!(raw.len(), (0xf003 - 0x7ffe) * 2);
+        assert_eq!(raw[2..6], [0x11, 0x11, 0x22, 0x22]);
+        assert_eq!(Image::read(&raw, Format::BigEndian).unwrap().relocate(0x7ffe).flatten(), image.flatten());
+        let little = Image::new(0, vec![0x1234, 0x5678]).write(Format::LittleEndian);
+        assert_eq!(little, [0x34, 0x12, 0x78, 0x56]);
+        assert_eq!(Image::read(&little, Format::LittleEndian), Ok(Image::new(0, vec![0x1234, 0x5678])));
+    }
+
+    #[test]
+    fn text_formats() {
+        let image = Image::new(0x10, vec![0x7c01, 0x30, 0x8b83]).entry(0x11);
+        assert_eq!(String::from_utf8(image.write(Format::Hexdump)).unwrap(), "0010: 7c01 0030 8b83\n");
+        assert_eq!(String::from_utf8(image.write(Format::IntelHex)).unwrap(),
+                   ":060020007C0100308B831F\n:0400000500000022D5\n:00000001FF\n");
+        // past the first 64k bytes it needs an extended address
+        let high = String::from_utf8(Image::new(0x8000, vec![1]).write(Format::IntelHex)).unwrap();
+        assert_eq!(high, ":020000040001F9\n:020000000001FD\n:00000001FF\n");
+        assert_eq!(Image::read(high.as_bytes(), Format::IntelHex), Ok(Image::new(0x8000, vec![1])));
+
+        let dump = "# a comment\n0100: 0001 0002\n0003\n\n0103: 0004\n0000: 0005 # another segment\n";
+        assert_eq!(Image::read(dump.as_bytes(), Format::Hexdump),
+                   Ok(Image::new(0x100, vec![1, 2, 3, 4]).segment(0, vec![5])));
+        assert_eq!(Image::read(b"10000: 1", Format::Hexdump), Err(ImageError::OutOfRange(0x10000)));
+        assert_eq!(Image::read(b"0000: 10000", Format::Hexdump),
+                   Err(ImageError::Syntax(1, "10000 is more than a word".to_owned())));
+        assert_eq!(Image::read(b":0400000500000022D6", Format::IntelHex), Err(ImageError::Checksum(1, 0xd5)));
+        assert_eq!(Image::read(b":020000040002F8\n:0100000000FF", Format::IntelHex),
+                   Err(ImageError::OutOfRange(0x20000)));
+        assert_eq!(Image::read(":0é0000000000000".as_bytes(), Format::IntelHex),
+                   Err(ImageError::Syntax(1, "not a record".to_owned())));
+        assert_eq!(Image::read(b":02000004FFFFFC
+:02FFFF000001FF", Format::IntelHex),
+                   Err(ImageError::OutOfRange(0xffff0000)));
+        assert_eq!(Image::read(b":0400000500020000F5", Format::IntelHex), Err(ImageError::OutOfRange(0x20000)));
+    }
+
+    #[test]
+    fn wrapping_segments() {
+        let image = Image::new(0xfffe, vec![1, 2, 3]);
+        assert_eq!(image.segments[0].end(), 0x10001);
+        assert_eq!(image.segments[0].wrapped(), vec![(0xfffe, &[1, 2][..]), (0, &[3][..])]);
+        assert_eq!(String::from_utf8(image.write(Format::Hexdump)).unwrap(), "fffe: 0001 0002\n0000: 0003\n");
+        assert_eq!(Image::read(&image.write(Format::IntelHex), Format::IntelHex),
+                   Ok(Image::new(0, vec![3]).segment(0xfffe, vec![1, 2])));
+        assert_eq!(Image::new(0, vec![1, 2]).relocate(0xffff).flatten(), Segment { origin: 0xffff, words: vec![1, 2] });
+        let flat = Image::new(0xffff, vec![1]).segment(0, vec![2]).flatten();
+        assert_eq!((flat.origin, flat.words.len(), flat.words[0], flat.words[0xffff]), (0, 0x10000, 2, 1));
+    }
+
+    #[test]
+    fn bad_headers() {
+        assert_eq!(Image::read(b"\x7c\x01", Format::Headered), Err(ImageError::BadMagic));
+        assert_eq!(Image::read(b"DCPU\x00\x02", Format::Headered), Err(ImageError::UnsupportedVersion(2)));
+        let mut truncated = image().write(Format::Headered);
+        truncated.truncate(30);
+        assert_eq!(Image::read(&truncated, Format::Headered), Err(ImageError::Truncated));
+        assert_eq!(Format::named("ihex"), Ok(Format::IntelHex));
+        assert_eq!(Format::named("elf"), Err(ImageError::UnknownFormat("elf".to_owned())));
+        assert_eq!(Format::for_path("rom.hex"), Format::IntelHex);
+        assert_eq!(Format::for_path("rom.bin"), Format::BigEndian);
+    }
+}
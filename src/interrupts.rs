@@ -0,0 +1,253 @@
+use std::collections::VecDeque;
+use std::collections::vec_deque::Iter;
+
+// the spec allows 256 queued interrupts, one more and the DCPU catches fire
+pub const MAX_QUEUED_INTERRUPTS: usize = 256;
+
+// every VM burns the same way unless it's given a seed of its own
+const SEED: u32 = 0x2545f491;
+
+// what a DCPU that's caught fire does from then on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FirePolicy {
+    // step() returns DcpuVMError::OnFire
+    Error,
+    // step() stops executing anything and takes no cycles, and run() stops
+    // with StopReason::OnFire
+    Halt,
+    // keeps running, but every step scribbles over a random word of RAM
+    CorruptMemory,
+}
+
+#[derive(Debug)]
+pub struct InterruptController {
+    queue: VecDeque<u16>,
+    ia: u16,
+    queueing: bool,
+    on_fire: bool,
+    policy: FirePolicy,
+    rng: u32,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        InterruptController::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController {
+            queue: VecDeque::with_capacity(MAX_QUEUED_INTERRUPTS),
+            ia: 0,
+            queueing: false,
+            on_fire: false,
+            policy: FirePolicy::Error,
+            rng: SEED | 1,
+        }
+    }
+
+    // software and hardware interrupts alike are ignored while IA is 0
+    pub fn raise(&mut self, msg: u16) {
+        if self.ia == 0 || self.on_fire {
+            return
+        }
+        if self.queue.len() == MAX_QUEUED_INTERRUPTS {
+            self.on_fire = true;
+            return
+        }
+        self.queue.push_back(msg);
+    }
+
+    // takes the interrupt to trigger between two instructions, if any. a
+    // queued interrupt is triggered when it leaves the queue, so it's dropped
+    // if IA has been set to 0 since it was raised
+    #[inline]
+    pub fn trigger(&mut self) -> Option<u16> {
+        if self.queueing {
+            return None
+        }
+        let msg = self.queue.pop_front()?;
+        if self.ia == 0 {
+            return None
+        }
+        self.queueing = true;
+        Some(msg)
+    }
+
+    pub fn pending(&self) -> Iter<'_, u16> {
+        self.queue.iter()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn ia(&self) -> u16 {
+        self.ia
+    }
+
+    pub fn set_ia(&mut self, ia: u16) {
+        self.ia = ia;
+    }
+
+    pub(crate) fn ia_mut(&mut self) -> &mut u16 {
+        &mut self.ia
+    }
+
+    pub fn is_queueing(&self) -> bool {
+        self.queueing
+    }
+
+    pub fn set_queueing(&mut self, queueing: bool) {
+        self.queueing = queueing;
+    }
+
+    pub fn is_on_fire(&self) -> bool {
+        self.on_fire
+    }
+
+    // nothing queued and nothing burning, so there's nothing to do between
+    // instructions until something is raised
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && !self.on_fire
+    }
+
+    pub fn policy(&self) -> FirePolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: FirePolicy) {
+        self.policy = policy;
+    }
+
+    // fixes the sequence of words FirePolicy::CorruptMemory destroys
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng = seed | 1;
+    }
+
+    // xorshift32, good enough for setting memory on fire
+    pub(crate) fn next_random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    // empties the queue and puts out the fire, keeping the policy
+    pub fn reset(&mut self) {
+        self.queue.clear();
+        self.ia = 0;
+        self.queueing = false;
+        self.on_fire = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use virtual_machine::{VirtualMachine, DcpuVMError, StopReason};
+
+    #[test]
+    fn dropped_while_ia_is_zero() {
+        let mut ic = InterruptController::new();
+        ic.raise(1);
+        assert_eq!(ic.pending_count(), 0);
+
+        ic.set_ia(0x100);
+        ic.raise(2);
+        ic.set_ia(0);
+        assert_eq!(ic.trigger(), None);
+        assert_eq!(ic.pending_count(), 0);
+        assert!(!ic.is_queueing());
+    }
+
+    #[test]
+    fn one_at_a_time_in_order() {
+        let mut ic = InterruptController::new();
+        ic.set_ia(0x100);
+        ic.raise(1);
+        ic.raise(2);
+        assert_eq!(ic.pending().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(ic.trigger(), Some(1));
+        assert!(ic.is_queueing());
+        assert_eq!(ic.trigger(), None);
+        ic.set_queueing(false);
+        assert_eq!(ic.trigger(), Some(2));
+    }
+
+    #[test]
+    fn catches_fire_past_the_limit() {
+        let mut ic = InterruptController::new();
+        ic.set_ia(0x100);
+        for i in 0..MAX_QUEUED_INTERRUPTS {
+            ic.raise(i as u16);
+        }
+        assert!(!ic.is_on_fire());
+        ic.raise(0xffff);
+        assert!(ic.is_on_fire());
+        assert_eq!(ic.pending_count(), MAX_QUEUED_INTERRUPTS);
+        ic.reset();
+        assert!(!ic.is_on_fire());
+    }
+
+    // IAS 1; IAQ 1; :loop INT 0; SET PC, loop
+    const FLOOD: [u16; 5] = [0x8940, 0x8980, 0x8500, 0x7f81, 0x0002];
+
+    fn flood(policy: FirePolicy) -> VirtualMachine {
+        let mut vm = VirtualMachine::new().fire_policy(policy);
+        vm.get_ram()[..FLOOD.len()].copy_from_slice(&FLOOD);
+        for _ in 0..2 + 2 * MAX_QUEUED_INTERRUPTS {
+            vm.step().unwrap();
+        }
+        assert!(!vm.interrupts().is_on_fire());
+        vm
+    }
+
+    #[test]
+    fn fire_policy_error() {
+        let mut vm = flood(FirePolicy::Error);
+        vm.step().unwrap();
+        assert!(vm.interrupts().is_on_fire());
+        match vm.step() {
+            Err(DcpuVMError::OnFire) => {},
+            other => panic!("expected OnFire, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn fire_policy_halt() {
+        let mut vm = flood(FirePolicy::Halt);
+        vm.step().unwrap();
+        let pc = *vm.get_pc();
+        assert_eq!(vm.step().unwrap(), 0);
+        assert_eq!(*vm.get_pc(), pc);
+    }
+
+    #[test]
+    fn fire_policy_halt_stops_run() {
+        let mut vm = flood(FirePolicy::Halt);
+        assert_eq!(vm.run(&BTreeSet::new(), Some(100_000)).unwrap(), StopReason::OnFire);
+        assert!(vm.get_cycles() < 100_000);
+        assert_eq!(vm.run(&BTreeSet::new(), None).unwrap(), StopReason::OnFire);
+    }
+
+    #[test]
+    fn fire_policy_corrupt_memory() {
+        let mut vm = flood(FirePolicy::CorruptMemory);
+        vm.step().unwrap();
+        let before = vm.get_ram().clone();
+        vm.step().unwrap();
+        // the first xorshift of SEED picks the word and what it's xored with
+        let changed: Vec<_> = (0..0x10000).filter(|&i| vm.get_ram()[i] != before[i]).collect();
+        assert_eq!(changed, vec![0xb63a]);
+        assert_eq!(vm.get_ram()[0xb63a], before[0xb63a] ^ 0xe125);
+
+        let mut other = flood(FirePolicy::CorruptMemory);
+        other.interrupts_mut().set_seed(1);
+        other.step().unwrap();
+        other.step().unwrap();
+        assert_eq!(other.get_ram()[0xb63a], before[0xb63a]);
+    }
+}
@@ -1,22 +1,68 @@
+#[cfg(feature = "parser")]
 extern crate pest;
+#[cfg(feature = "parser")]
 #[macro_use]
 extern crate pest_derive;
 extern crate time;
 extern crate thiserror;
+#[cfg(any(feature = "config", feature = "lsp"))]
+extern crate serde;
+#[cfg(feature = "config")]
+extern crate toml;
+#[cfg(any(feature = "config", feature = "lsp"))]
+extern crate serde_json;
+#[cfg(feature = "plugins")]
+extern crate libloading;
+#[cfg(feature = "tui")]
+extern crate ratatui;
+#[cfg(feature = "lsp")]
+extern crate lsp_server;
+#[cfg(feature = "lsp")]
+extern crate lsp_types;
 
 mod virtual_machine;
+mod register;
+mod interrupts;
 mod opcodes;
-#[cfg(feature = "assembly")]
+#[cfg(feature = "assembler")]
 mod assembly;
+#[cfg(feature = "assembler")]
+pub mod abi;
 mod disassemble;
+pub mod image;
 mod mem_iterator;
+mod predecode;
 pub mod hardware;
+pub mod conformance;
+mod cluster;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "config")]
+pub mod registry;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(feature = "parser")]
+pub mod diagnostics;
 #[cfg(feature = "parser")]
 pub mod parser;
+#[cfg(feature = "parser")]
+pub mod cst;
+#[cfg(feature = "parser")]
+pub mod format;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "compiler")]
+pub mod compiler;
+#[cfg(feature = "forth")]
+pub mod forth;
 
 pub use virtual_machine::*;
+pub use interrupts::*;
+pub use cluster::*;
 pub use opcodes::*;
-#[cfg(feature = "assembly")]
+#[cfg(feature = "assembler")]
 pub use assembly::*;
 
 pub use disassemble::*;
@@ -0,0 +1,360 @@
+use lsp_types::{CompletionItem, CompletionItemKind, Diagnostic, DiagnosticSeverity, DocumentSymbol,
+                Position, Range, SymbolKind};
+use assembly::{Block, DcpuAssemblerError, Intermediate, Layout};
+use opcodes::Opcode;
+use parser::{parse_file, Span, Statement};
+
+pub const MNEMONICS: [&str; 42] = [
+    "SET", "ADD", "SUB", "MUL", "MLI", "DIV", "DVI", "MOD", "MDI", "AND", "BOR", "XOR",
+    "SHR", "ASR", "SHL", "IFB", "IFC", "IFE", "IFN", "IFG", "IFA", "IFL", "IFU", "ADX",
+    "SBX", "STI", "STD", "JSR", "INT", "IAG", "IAS", "RFI", "IAQ", "HWN", "HWQ", "HWI",
+    "DAT", "JMP", "RET", "NOP", "HLT", "BRK",
+];
+
+pub const REGISTERS: [&str; 13] = ["A", "B", "C", "X", "Y", "Z", "I", "J", "PC", "SP", "EX", "PUSH", "POP"];
+
+// a label's name where it's defined or used
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelUse {
+    pub name: String,
+    pub span: Span,
+}
+
+// everything the server knows about one version of a document
+pub struct Analysis {
+    text: String,
+    lines: Vec<usize>,
+    statements: Vec<(Statement, Span)>,
+    layout: Option<Layout>,
+    definitions: Vec<LabelUse>,
+    references: Vec<LabelUse>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// identifiers in `src`, skipping comments, strings and numbers, as (name,
+// offset)
+fn identifiers(src: &str) -> Vec<(&str, usize)> {
+    let mut idents = vec![];
+    let mut chars = src.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c == ';' {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if c == '"' || c == '\'' {
+            let mut escaped = false;
+            while let Some((_, next)) = chars.next_if(|&(_, n)| n != '\n') {
+                if next == c && !escaped {
+                    break;
+                }
+                escaped = next == '\\' && !escaped;
+            }
+        } else if c == '#' || c.is_ascii_digit() {
+            while chars.next_if(|&(_, c)| is_ident_char(c)).is_some() {}
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start + 1;
+            while let Some((i, _)) = chars.next_if(|&(_, c)| is_ident_char(c)) {
+                end = i + 1;
+            }
+            idents.push((&src[start..end], start));
+        }
+    }
+    idents
+}
+
+impl Analysis {
+    pub fn new(text: &str) -> Analysis {
+        let lines = ::std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let mut analysis = Analysis {
+            text: text.to_owned(),
+            lines,
+            statements: vec![],
+            layout: None,
+            definitions: vec![],
+            references: vec![],
+            diagnostics: vec![],
+        };
+        let parsed = parse_file(None, text);
+        for e in parsed.errors {
+            analysis.error(e.location.span, e.error.to_string());
+        }
+        analysis.analyse(parsed.statements);
+        analysis
+    }
+
+    fn analyse(&mut self, statements: Vec<(Statement, Span)>) {
+        let mut items = vec![];
+        for (statement, span) in statements.iter() {
+            let span = *span;
+            let text = &self.text[span.start..span.end];
+            let labels: Vec<&str> = match *statement {
+                Statement::LabelDef(ref name) => {
+                    // skip the colon, wherever it is
+                    let start = if text.starts_with(':') { span.end - name.len() } else { span.start };
+                    self.define(name, Span { start, end: start + name.len() });
+                    items.push(Intermediate::Label(name.clone()));
+                    continue;
+                },
+                Statement::Constant(ref name, value) => {
+                    if let Some((_, offset)) = identifiers(text).into_iter().find(|&(n, _)| n == name) {
+                        let start = span.start + offset;
+                        self.define(name, Span { start, end: start + name.len() });
+                    }
+                    items.push(Intermediate::Constant(name.clone(), value));
+                    continue;
+                },
+                Statement::Nop => {
+                    items.push(Intermediate::Nop);
+                    vec![]
+                },
+                Statement::Instruction(ref op) => {
+                    items.push(Intermediate::Opcode(op.clone()));
+                    let (b, a) = op.operands();
+                    b.into_iter().chain(Some(a)).flat_map(|o| o.labels()).collect()
+                },
+                Statement::Data(ref words) => {
+                    items.push(Intermediate::Words(words.clone()));
+                    words.iter().flat_map(|w| w.labels()).collect()
+                },
+            };
+            // the first identifier is the mnemonic
+            for (name, offset) in identifiers(text).into_iter().skip(1) {
+                if labels.contains(&name) {
+                    let start = span.start + offset;
+                    self.references.push(LabelUse { name: name.to_owned(), span: Span { start, end: start + name.len() } });
+                }
+            }
+        }
+
+        for reference in self.references.clone() {
+            if !self.definitions.iter().any(|d| d.name == reference.name) {
+                self.error(reference.span, format!("Label {} isn't defined", reference.name));
+            }
+        }
+
+        let layout = Block::new().intermediate(&mut items).layout(0);
+        for (&(_, span), placed) in statements.iter().zip(layout.items.iter()) {
+            match placed.words {
+                // already reported where the label's used
+                Ok(_) | Err(DcpuAssemblerError::UnresolvedLabel(_)) => (),
+                Err(ref e) => self.error(span, e.to_string()),
+            }
+        }
+        self.statements = statements;
+        self.layout = Some(layout);
+    }
+
+    fn define(&mut self, name: &str, span: Span) {
+        if self.definitions.iter().any(|d| d.name == name) {
+            self.error(span, format!("Label {} is already defined", name));
+        }
+        self.definitions.push(LabelUse { name: name.to_owned(), span });
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        let range = self.range(span);
+        self.diagnostics.push(Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("dcpu".to_owned()),
+            message,
+            ..Default::default()
+        });
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn position(&self, offset: usize) -> Position {
+        let line = match self.lines.binary_search(&offset) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let character = self.text[self.lines[line]..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    // positions count UTF-16 code units, like the protocol does
+    pub fn offset(&self, position: Position) -> usize {
+        let start = match self.lines.get(position.line as usize) {
+            Some(&start) => start,
+            None => return self.text.len(),
+        };
+        let mut units = 0;
+        for (i, c) in self.text[start..].char_indices() {
+            if units >= position.character as usize || c == '\n' {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.text.len()
+    }
+
+    pub fn range(&self, span: Span) -> Range {
+        Range::new(self.position(span.start), self.position(span.end))
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn definitions(&self) -> &[LabelUse] {
+        &self.definitions
+    }
+
+    // the label defined or used at `position`
+    pub fn label_at(&self, position: Position) -> Option<&LabelUse> {
+        let offset = self.offset(position);
+        self.definitions.iter().chain(self.references.iter())
+            .find(|l| l.span.start <= offset && offset <= l.span.end)
+    }
+
+    pub fn definition(&self, position: Position) -> Option<Range> {
+        let label = self.label_at(position)?;
+        self.definitions.iter()
+            .find(|d| d.name == label.name)
+            .map(|d| self.range(d.span))
+    }
+
+    pub fn references(&self, position: Position, include_declaration: bool) -> Vec<Range> {
+        let label = match self.label_at(position) {
+            Some(label) => label,
+            None => return vec![],
+        };
+        let definitions = self.definitions.iter().filter(|_| include_declaration);
+        definitions.chain(self.references.iter())
+            .filter(|l| l.name == label.name)
+            .map(|l| self.range(l.span))
+            .collect()
+    }
+
+    // markdown describing the label or instruction at `position`
+    pub fn hover(&self, position: Position) -> Option<String> {
+        let layout = self.layout.as_ref()?;
+        if let Some(label) = self.label_at(position) {
+            return layout.symbols.get(&label.name)
+                .map(|address| format!("`{}` = `{:#06x}`", label.name, address));
+        }
+        let offset = self.offset(position);
+        let (i, op) = self.statements.iter().enumerate()
+            .filter(|(_, (_, span))| span.start <= offset && offset <= span.end)
+            .filter_map(|(i, (s, _))| match *s {
+                Statement::Instruction(ref op) => Some((i, op.clone())),
+                Statement::Nop => Some((i, Opcode::nop())),
+                _ => None,
+            })
+            .next()?;
+        let placed = &layout.items[i];
+        let words = match placed.words {
+            Ok(ref words) => words.iter().map(|w| format!("{:04x}", w)).collect::<Vec<_>>().join(" "),
+            Err(ref e) => e.to_string(),
+        };
+        Some(format!("```dasm\n{}\n```\naddress: `{:#06x}`  \nwords: `{}`  \ncycles: {}",
+                     op, placed.address, words, cycles(&op, placed.size)))
+    }
+
+    pub fn completions(&self) -> Vec<CompletionItem> {
+        let item = |label: &str, kind| CompletionItem {
+            label: label.to_owned(),
+            kind: Some(kind),
+            ..Default::default()
+        };
+        let mut items: Vec<CompletionItem> = MNEMONICS.iter().map(|m| item(m, CompletionItemKind::KEYWORD))
+            .chain(REGISTERS.iter().map(|r| item(r, CompletionItemKind::VARIABLE)))
+            .collect();
+        let mut labels: Vec<&str> = self.definitions.iter().map(|d| &d.name[..]).collect();
+        labels.sort();
+        labels.dedup();
+        items.extend(labels.into_iter().map(|l| item(l, CompletionItemKind::CONSTANT)));
+        items
+    }
+
+    #[allow(deprecated)]
+    pub fn symbols(&self) -> Vec<DocumentSymbol> {
+        let symbols = self.layout.as_ref().map(|l| &l.symbols);
+        self.definitions.iter().map(|d| DocumentSymbol {
+            name: d.name.clone(),
+            detail: symbols.and_then(|s| s.get(&d.name)).map(|a| format!("{:#06x}", a)),
+            kind: SymbolKind::FUNCTION,
+            tags: None,
+            deprecated: None,
+            range: self.range(d.span),
+            selection_range: self.range(d.span),
+            children: None,
+        }).collect()
+    }
+}
+
+fn cycles(op: &Opcode, size: usize) -> String {
+    // every next word takes a cycle to read
+    let cycles = op.cycles() + size - 1;
+    if op.is_conditional() {
+        format!("{}, {} if the test fails", cycles, cycles + 1)
+    } else {
+        cycles.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: &str = ":start SET A, data\n  IFE [data], 0x10 ; checks data\n  SET PC, start\n:data SET B, missing\n";
+
+    #[test]
+    fn labels() {
+        let analysis = Analysis::new(SRC);
+        // `data` on line 1
+        let on_data = Position::new(1, 8);
+        assert_eq!(analysis.definition(on_data), Some(Range::new(Position::new(3, 1), Position::new(3, 5))));
+        assert_eq!(analysis.references(on_data, true), vec![
+            Range::new(Position::new(3, 1), Position::new(3, 5)),
+            Range::new(Position::new(0, 14), Position::new(0, 18)),
+            Range::new(Position::new(1, 7), Position::new(1, 11)),
+        ]);
+        assert_eq!(analysis.diagnostics().len(), 1);
+        assert_eq!(analysis.diagnostics()[0].message, "Label missing isn't defined");
+        assert_eq!(analysis.diagnostics()[0].range.start, Position::new(3, 13));
+        assert_eq!(analysis.symbols().iter().map(|s| &s.name[..]).collect::<Vec<_>>(), vec!["start", "data"]);
+    }
+
+    #[test]
+    fn hover() {
+        let analysis = Analysis::new(SRC);
+        // start and data are both small enough for short literals
+        assert_eq!(analysis.hover(Position::new(2, 3)).unwrap(),
+                   "```dasm\nSET PC, start\n```\naddress: `0x0003`  \nwords: `8781`  \ncycles: 1");
+        assert_eq!(analysis.hover(Position::new(1, 4)).unwrap(),
+                   "```dasm\nIFE [data], 0x10\n```\naddress: `0x0001`  \nwords: `c7d2 0004`  \ncycles: 3, 4 if the test fails");
+        assert_eq!(analysis.hover(Position::new(3, 2)).unwrap(), "`data` = `0x0004`");
+    }
+
+    #[test]
+    fn constants_and_data() {
+        let analysis = Analysis::new("loop: SET A, SIZE\n.equ SIZE, 0x40\ndat \"loop\", loop\n");
+        assert_eq!(analysis.diagnostics(), &[]);
+        assert_eq!(analysis.definition(Position::new(0, 14)), Some(Range::new(Position::new(1, 5), Position::new(1, 9))));
+        // not the one in the string
+        assert_eq!(analysis.references(Position::new(0, 1), false), vec![Range::new(Position::new(2, 12), Position::new(2, 16))]);
+        assert_eq!(analysis.hover(Position::new(1, 6)).unwrap(), "`SIZE` = `0x0040`");
+    }
+
+    #[test]
+    fn parse_errors() {
+        let analysis = Analysis::new("SET A, 1\nSET ,\n:here SET A, 65536\nSET PC, here");
+        assert_eq!(analysis.diagnostics().len(), 2);
+        assert_eq!(analysis.diagnostics()[0].range.start.line, 1);
+        assert_eq!(analysis.diagnostics()[1].range.start, Position::new(2, 13));
+        // what did parse is still there to look up
+        assert_eq!(analysis.definition(Position::new(3, 9)), Some(Range::new(Position::new(2, 1), Position::new(2, 5))));
+
+        let analysis = Analysis::new("é SET A");
+        assert_eq!(analysis.offset(Position::new(0, 1)), 2);
+        assert_eq!(analysis.position(2), Position::new(0, 1));
+    }
+}
@@ -0,0 +1,239 @@
+// a language server for DCPU assembly, speaking LSP over stdio
+use std::collections::HashMap;
+use std::io;
+use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError, Request, Response};
+use lsp_types::notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+                              Notification as LspNotification, PublishDiagnostics};
+use lsp_types::request::{Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, References,
+                         Request as LspRequest};
+use lsp_types::{CompletionParams, CompletionResponse, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+                DidOpenTextDocumentParams, DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams,
+                GotoDefinitionResponse, Hover, HoverContents, HoverParams, HoverProviderCapability, Location,
+                MarkupContent, MarkupKind, OneOf, PublishDiagnosticsParams, ReferenceParams, ServerCapabilities,
+                TextDocumentSyncCapability, TextDocumentSyncKind, CompletionOptions, Url};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+mod analysis;
+
+pub use self::analysis::{Analysis, LabelUse, MNEMONICS, REGISTERS};
+
+#[derive(Debug, Error)]
+pub enum LspError {
+    #[error("{}", .0)]
+    Protocol(#[from] ProtocolError),
+    #[error("{}", .0)]
+    Io(#[from] io::Error),
+    #[error("{}", .0)]
+    Json(#[from] serde_json::Error),
+    #[error("The client went away")]
+    Disconnected,
+}
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<Url, Analysis>,
+}
+
+fn params<P: DeserializeOwned>(req: Request) -> Result<P, serde_json::Error> {
+    serde_json::from_value(req.params)
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    pub fn document(&self, uri: &Url) -> Option<&Analysis> {
+        self.documents.get(uri)
+    }
+
+    // answers until the client shuts the server down
+    pub fn serve(&mut self, connection: &Connection) -> Result<(), LspError> {
+        connection.initialize(serde_json::to_value(capabilities())?)?;
+        for msg in &connection.receiver {
+            match msg {
+                Message::Request(req) => {
+                    if connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    let response = self.request(req);
+                    connection.sender.send(response.into()).map_err(|_| LspError::Disconnected)?;
+                },
+                Message::Notification(n) => {
+                    // there's no one to answer a notification that doesn't
+                    // parse, so it's only logged
+                    let method = n.method.clone();
+                    match self.notification(n) {
+                        Ok(Some(published)) => {
+                            connection.sender.send(published.into()).map_err(|_| LspError::Disconnected)?;
+                        },
+                        Ok(None) => (),
+                        Err(e) => eprintln!("ignoring {}: {}", method, e),
+                    }
+                },
+                Message::Response(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, req: Request) -> Response {
+        let id = req.id.clone();
+        let result = match &req.method[..] {
+            GotoDefinition::METHOD => params(req).map(|p| serde_json::to_value(self.definition(p))),
+            References::METHOD => params(req).map(|p| serde_json::to_value(self.references(p))),
+            HoverRequest::METHOD => params(req).map(|p| serde_json::to_value(self.hover(p))),
+            Completion::METHOD => params(req).map(|p| serde_json::to_value(self.completion(p))),
+            DocumentSymbolRequest::METHOD => params(req).map(|p| serde_json::to_value(self.symbols(p))),
+            method => {
+                return Response::new_err(id, ErrorCode::MethodNotFound as i32, format!("Unknown method {}", method))
+            },
+        };
+        match result.and_then(|r| r) {
+            Ok(value) => Response::new_ok(id, value),
+            Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    // documents are synced in full, so every change is a fresh analysis.
+    // returns the diagnostics to publish, if there are any
+    fn notification(&mut self, n: Notification) -> Result<Option<Notification>, LspError> {
+        let uri = match &n.method[..] {
+            DidOpenTextDocument::METHOD => {
+                let p: DidOpenTextDocumentParams = serde_json::from_value(n.params)?;
+                self.documents.insert(p.text_document.uri.clone(), Analysis::new(&p.text_document.text));
+                p.text_document.uri
+            },
+            DidChangeTextDocument::METHOD => {
+                let p: DidChangeTextDocumentParams = serde_json::from_value(n.params)?;
+                if let Some(change) = p.content_changes.into_iter().last() {
+                    self.documents.insert(p.text_document.uri.clone(), Analysis::new(&change.text));
+                }
+                p.text_document.uri
+            },
+            DidCloseTextDocument::METHOD => {
+                let p: DidCloseTextDocumentParams = serde_json::from_value(n.params)?;
+                self.documents.remove(&p.text_document.uri);
+                // clear whatever was shown for it
+                return Ok(Some(Notification::new(PublishDiagnostics::METHOD.to_owned(),
+                    PublishDiagnosticsParams::new(p.text_document.uri, vec![], None))));
+            },
+            _ => return Ok(None),
+        };
+        // a change with no content for a document that was never opened
+        let diagnostics = match self.documents.get(&uri) {
+            Some(doc) => doc.diagnostics().to_vec(),
+            None => return Ok(None),
+        };
+        Ok(Some(Notification::new(PublishDiagnostics::METHOD.to_owned(),
+            PublishDiagnosticsParams::new(uri, diagnostics, None))))
+    }
+
+    fn definition(&self, p: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let doc = p.text_document_position_params.text_document;
+        let range = self.document(&doc.uri)?.definition(p.text_document_position_params.position)?;
+        Some(GotoDefinitionResponse::Scalar(Location::new(doc.uri, range)))
+    }
+
+    fn references(&self, p: ReferenceParams) -> Option<Vec<Location>> {
+        let doc = p.text_document_position.text_document;
+        let ranges = self.document(&doc.uri)?
+            .references(p.text_document_position.position, p.context.include_declaration);
+        Some(ranges.into_iter().map(|range| Location::new(doc.uri.clone(), range)).collect())
+    }
+
+    fn hover(&self, p: HoverParams) -> Option<Hover> {
+        let doc = p.text_document_position_params.text_document;
+        let value = self.document(&doc.uri)?.hover(p.text_document_position_params.position)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }),
+            range: None,
+        })
+    }
+
+    fn completion(&self, p: CompletionParams) -> Option<CompletionResponse> {
+        let doc = p.text_document_position.text_document;
+        Some(CompletionResponse::Array(self.document(&doc.uri)?.completions()))
+    }
+
+    fn symbols(&self, p: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        Some(DocumentSymbolResponse::Nested(self.document(&p.text_document.uri)?.symbols()))
+    }
+}
+
+// runs a server on stdin and stdout
+pub fn run() -> Result<(), LspError> {
+    let (connection, io_threads) = Connection::stdio();
+    Server::new().serve(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use lsp_server::RequestId;
+    use lsp_types::request::Initialize;
+    use lsp_types::notification::{Exit, Initialized};
+    use lsp_types::request::Shutdown;
+
+    #[test]
+    fn serves_a_session() {
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || Server::new().serve(&server));
+
+        let send = |msg: Message| client.sender.send(msg).unwrap();
+        let request = |id: i32, method: &str, params: serde_json::Value| {
+            send(Request::new(RequestId::from(id), method.to_owned(), params).into());
+            match client.receiver.recv().unwrap() {
+                Message::Response(r) => r.result.unwrap(),
+                other => panic!("expected a response, got {:?}", other),
+            }
+        };
+        let uri = "file:///test.dasm";
+
+        request(1, Initialize::METHOD, serde_json::json!({ "capabilities": {} }));
+        send(Notification::new(Initialized::METHOD.to_owned(), serde_json::json!({})).into());
+        // neither of these gets an answer or takes the server down
+        send(Notification::new(DidOpenTextDocument::METHOD.to_owned(), serde_json::json!({
+            "textDocument": 5
+        })).into());
+        send(Notification::new(DidChangeTextDocument::METHOD.to_owned(), serde_json::json!({
+            "textDocument": { "uri": "file:///unopened.dasm", "version": 2 }, "contentChanges": []
+        })).into());
+        send(Notification::new(DidOpenTextDocument::METHOD.to_owned(), serde_json::json!({
+            "textDocument": { "uri": uri, "languageId": "dasm", "version": 1, "text": ":loop SET PC, lop" }
+        })).into());
+        match client.receiver.recv().unwrap() {
+            Message::Notification(n) => {
+                assert_eq!(n.method, PublishDiagnostics::METHOD);
+                assert_eq!(n.params["diagnostics"][0]["message"], "Label lop isn't defined");
+            },
+            other => panic!("expected diagnostics, got {:?}", other),
+        }
+
+        let hover = request(2, HoverRequest::METHOD, serde_json::json!({
+            "textDocument": { "uri": uri }, "position": { "line": 0, "character": 2 }
+        }));
+        assert_eq!(hover["contents"]["value"], "`loop` = `0x0000`");
+
+        request(3, Shutdown::METHOD, serde_json::Value::Null);
+        send(Notification::new(Exit::METHOD.to_owned(), serde_json::Value::Null).into());
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,454 @@
+extern crate clap;
+extern crate dcpu16;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use dcpu16::config::MachineConfig;
+use dcpu16::registry::DeviceRegistry;
+#[cfg(feature = "tui")]
+use dcpu16::tui::Tui;
+use dcpu16::image::{Format, Image};
+use dcpu16::{VirtualMachine, Register};
+use std::fs;
+#[cfg(feature = "assembler")]
+use std::path::{Path, PathBuf};
+use std::process;
+#[cfg(feature = "parser")]
+use dcpu16::format::{format, FormatOptions, LiteralStyle};
+#[cfg(feature = "assembler")]
+use dcpu16::parser::Dialect;
+#[cfg(feature = "assembler")]
+use std::fmt::Display;
+#[cfg(feature = "assembler")]
+use dcpu16::diagnostics::Located;
+#[cfg(feature = "compiler")]
+use dcpu16::compiler::Compiler;
+#[cfg(feature = "forth")]
+use dcpu16::forth::{self, Forth, ForthError};
+#[cfg(feature = "forth")]
+use std::io::{self, BufRead, Write};
+
+fn dump_registers(vm: &mut VirtualMachine) {
+    let regs = [Register::A, Register::B, Register::C, Register::X,
+                Register::Y, Register::Z, Register::I, Register::J];
+    for reg in regs.iter() {
+        eprint!("{}: {:04x}  ", reg, vm.get_registers()[*reg as usize]);
+    }
+    eprintln!();
+    let (pc, sp, ex, ia) = (*vm.get_pc(), *vm.get_sp(), *vm.get_ex(), *vm.get_ia());
+    eprintln!("PC: {:04x}  SP: {:04x}  EX: {:04x}  IA: {:04x}  cycles: {}",
+              pc, sp, ex, ia, vm.get_cycles());
+}
+
+#[cfg(feature = "plugins")]
+fn registry(matches: &ArgMatches) -> Result<DeviceRegistry, String> {
+    let mut registry = DeviceRegistry::with_builtins();
+    for path in matches.values_of("plugin").into_iter().flatten() {
+        dcpu16::plugin::load_plugin(&mut registry, path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(registry)
+}
+
+#[cfg(not(feature = "plugins"))]
+fn registry(matches: &ArgMatches) -> Result<DeviceRegistry, String> {
+    if matches.is_present("plugin") {
+        return Err("dcpu was built without plugin support".to_owned());
+    }
+    Ok(DeviceRegistry::with_builtins())
+}
+
+fn plugin_arg() -> Arg<'static, 'static, 'static, 'static, 'static, 'static> {
+    Arg::with_name("plugin")
+        .long("plugin")
+        .help("load devices from a plugin library")
+        .takes_value(true)
+        .multiple(true)
+}
+
+fn devices(matches: &ArgMatches) -> Result<(), String> {
+    for entry in registry(matches)?.entries() {
+        println!("{:<12} {:08x} {:08x}", entry.name, entry.manufacturer, entry.model);
+    }
+    Ok(())
+}
+
+fn load(matches: &ArgMatches) -> Result<MachineConfig, String> {
+    let path = matches.value_of("config").unwrap();
+    let mut config = MachineConfig::load(path).map_err(|e| e.to_string())?;
+    if let Some(cycles) = matches.value_of("cycles") {
+        config.cycle_limit = Some(cycles.parse().map_err(|_| format!("invalid cycle count: {}", cycles))?);
+    }
+    Ok(config)
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    let registry = registry(matches)?;
+    let config = load(matches)?;
+    let mut machine = config.build_with(&registry).map_err(|e| e.to_string())?;
+    let result = machine.run();
+    match result {
+        Ok(reason) => eprintln!("stopped: {}", reason),
+        Err(ref e) => eprintln!("error: {}", e),
+    }
+    dump_registers(&mut machine.vm);
+    result.map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "tui")]
+fn tui(matches: &ArgMatches) -> Result<(), String> {
+    let registry = registry(matches)?;
+    let machine = load(matches)?.build_with(&registry).map_err(|e| e.to_string())?;
+    let mut machine = Tui::new(machine, &registry).run().map_err(|e| e.to_string())?;
+    dump_registers(&mut machine.vm);
+    Ok(())
+}
+
+// prints the errors with their lines, and says how many there were
+#[cfg(feature = "assembler")]
+fn report<E: Display>(src: &str, errors: &[Located<E>]) -> String {
+    for e in errors.iter() {
+        eprintln!("{}", e.render(src));
+    }
+    format!("{} error{}", errors.len(), if errors.len() == 1 { "" } else { "s" })
+}
+
+#[cfg(feature = "assembler")]
+fn asm(matches: &ArgMatches) -> Result<(), String> {
+    let path = matches.value_of("source").unwrap();
+    let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let dialect = matches.value_of("dialect").unwrap_or("auto");
+    let mut assembler = dcpu16::Assembler::new()
+        .dialect(Dialect::named(dialect).ok_or_else(|| format!("unknown dialect: {}", dialect))?)
+        .stdlib(matches.is_present("stdlib"));
+    if matches.is_present("optimize") {
+        assembler = assembler.optimizer(dcpu16::Optimizer::new().relative_jumps(matches.is_present("relative-jumps")));
+    }
+    let image = assembler.image(Some(path), &src).map_err(|errors| report(&src, &errors))?;
+    write_image(matches, path, &image)
+}
+
+// the format named by --format, or `by_default`
+fn format_of(matches: &ArgMatches, name: &str, by_default: Format) -> Result<Format, String> {
+    matches.value_of(name).map_or(Ok(by_default), |name| Format::named(name).map_err(|e| e.to_string()))
+}
+
+#[cfg(feature = "assembler")]
+fn extension(format: Format) -> &'static str {
+    match format {
+        Format::BigEndian => "bin",
+        Format::LittleEndian => "le",
+        Format::Hexdump => "txt",
+        Format::IntelHex => "hex",
+        Format::Headered => "dcpu",
+    }
+}
+
+// to -o or next to the source, in the --format given or the one -o's
+// extension goes with
+#[cfg(feature = "assembler")]
+fn write_image(matches: &ArgMatches, source: &str, image: &Image) -> Result<(), String> {
+    let format = format_of(matches, "format", matches.value_of("output").map_or(Format::BigEndian, Format::for_path))?;
+    let output = matches.value_of("output")
+        .map_or_else(|| Path::new(source).with_extension(extension(format)), PathBuf::from);
+    fs::write(&output, image.write(format)).map_err(|e| format!("{}: {}", output.display(), e))
+}
+
+fn read_image(path: &str, format: Format, origin: Option<&str>) -> Result<Image, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let image = Image::read(&bytes, format).map_err(|e| format!("{}: {}", path, e))?;
+    match origin {
+        Some(origin) => Ok(image.relocate(parse_word(origin)?)),
+        None => Ok(image),
+    }
+}
+
+fn parse_word(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address: {}", s))
+}
+
+// the image as assembly source, to stdout
+fn disasm(matches: &ArgMatches) -> Result<(), String> {
+    let path = matches.value_of("image").unwrap();
+    let image = read_image(path, format_of(matches, "format", Format::for_path(path))?, matches.value_of("origin"))?;
+    print!("{}", dcpu16::listing(&image, matches.is_present("pseudo")));
+    Ok(())
+}
+
+fn convert(matches: &ArgMatches) -> Result<(), String> {
+    let (input, output) = (matches.value_of("input").unwrap(), matches.value_of("output").unwrap());
+    let image = read_image(input, format_of(matches, "from", Format::for_path(input))?, matches.value_of("origin"))?;
+    let format = format_of(matches, "to", Format::for_path(output))?;
+    fs::write(output, image.write(format)).map_err(|e| format!("{}: {}", output, e))
+}
+
+#[cfg(feature = "compiler")]
+fn cc(matches: &ArgMatches) -> Result<(), String> {
+    let path = matches.value_of("source").unwrap();
+    let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let optimizer = if matches.is_present("optimize") { Some(dcpu16::Optimizer::new()) } else { None };
+    let mut compiler = Compiler::new().stdlib(!matches.is_present("no-stdlib"));
+    if let Some(ref optimizer) = optimizer {
+        compiler = compiler.optimizer(optimizer.clone());
+    }
+    if matches.is_present("assembly") {
+        let mut items = compiler.compile(Some(path), &src).map_err(|errors| report(&src, &errors))?;
+        if let Some(ref optimizer) = optimizer {
+            items = optimizer.optimize(items);
+        }
+        let output = matches.value_of("output").map_or_else(|| Path::new(path).with_extension("dasm"), PathBuf::from);
+        let listing: String = items.iter().map(|i| format!("{}\n", i)).collect();
+        return fs::write(&output, listing).map_err(|e| format!("{}: {}", output.display(), e));
+    }
+    let image = compiler.image(Some(path), &src).map_err(|errors| report(&src, &errors))?;
+    write_image(matches, path, &image)
+}
+
+// a line at a time from stdin, or the ROM to -o
+#[cfg(feature = "forth")]
+fn forth(matches: &ArgMatches) -> Result<(), String> {
+    if let Some(path) = matches.value_of("output") {
+        return write_image(matches, path, &Image::new(0, forth::rom()));
+    }
+    let mut forth = Forth::new().boot().map_err(|e| e.to_string())?;
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        match forth.eval(&line) {
+            Ok(output) => println!("{} ok", output),
+            Err(ForthError::Aborted(output)) => println!("{}", output),
+            Err(ForthError::Bye) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+        io::stdout().flush().map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parser")]
+fn fmt(matches: &ArgMatches) -> Result<(), String> {
+    let literals = match matches.value_of("literals").unwrap_or("hex") {
+        "hex" => LiteralStyle::Hex,
+        "decimal" => LiteralStyle::Decimal,
+        "hash" => LiteralStyle::Hash,
+        "keep" => LiteralStyle::Keep,
+        other => return Err(format!("unknown literal style: {}", other)),
+    };
+    let options = FormatOptions::new()
+        .lowercase(matches.is_present("lowercase"))
+        .literals(literals);
+    let mut failed = false;
+    for path in matches.values_of("source").into_iter().flatten() {
+        let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let formatted = match format(&src, &options) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                for mut e in errors {
+                    e.location.file = Some(path.to_owned());
+                    eprintln!("{}", e.render(&src));
+                }
+                failed = true;
+                continue;
+            },
+        };
+        if formatted == src {
+            continue;
+        }
+        if matches.is_present("check") {
+            println!("{}", path);
+            failed = true;
+        } else {
+            fs::write(path, formatted).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "lsp")]
+fn lsp() -> Result<(), String> {
+    dcpu16::lsp::run().map_err(|e| e.to_string())
+}
+
+fn config_arg() -> Arg<'static, 'static, 'static, 'static, 'static, 'static> {
+    Arg::with_name("config")
+        .help("the machine config")
+        .required(true)
+        .index(1)
+}
+
+fn cycles_arg() -> Arg<'static, 'static, 'static, 'static, 'static, 'static> {
+    Arg::with_name("cycles")
+        .long("cycles")
+        .help("stop after this many cycles, overriding the config")
+        .takes_value(true)
+}
+
+fn format_arg(name: &'static str) -> Arg<'static, 'static, 'static, 'static, 'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .help("big-endian, little-endian, hexdump, intel-hex or headered, by default going by the extension")
+        .takes_value(true)
+}
+
+fn origin_arg() -> Arg<'static, 'static, 'static, 'static, 'static, 'static> {
+    Arg::with_name("origin")
+        .long("origin")
+        .help("where the words go, for raw images that don't say")
+        .takes_value(true)
+}
+
+fn main() {
+    let app = App::new("dcpu")
+        .about("DCPU-16 emulator and tools")
+        .subcommand(SubCommand::new("run")
+            .about("Runs the machine described by a TOML or JSON config")
+            .arg(config_arg())
+            .arg(cycles_arg())
+            .arg(plugin_arg()))
+        .subcommand(SubCommand::new("devices")
+            .about("Lists the devices configs can use")
+            .arg(plugin_arg()))
+        .subcommand(SubCommand::new("disasm")
+            .about("Disassembles an image into assembly source")
+            .arg(Arg::with_name("image")
+                .help("the image")
+                .required(true)
+                .index(1))
+            .arg(format_arg("format"))
+            .arg(origin_arg())
+            .arg(Arg::with_name("pseudo")
+                .long("pseudo")
+                .help("show JMP, RET, PUSH and so on where they fit")))
+        .subcommand(SubCommand::new("convert")
+            .about("Converts an image from one format to another")
+            .arg(Arg::with_name("input")
+                .help("the image to read")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("output")
+                .help("where to write it")
+                .required(true)
+                .index(2))
+            .arg(format_arg("from"))
+            .arg(format_arg("to"))
+            .arg(origin_arg()));
+    #[cfg(feature = "tui")]
+    let app = app.subcommand(SubCommand::new("tui")
+        .about("Runs a machine in the terminal, with its screen and a debugger")
+        .arg(config_arg())
+        .arg(cycles_arg())
+        .arg(plugin_arg()));
+    #[cfg(feature = "assembler")]
+    let app = app.subcommand(SubCommand::new("asm")
+        .about("Assembles a source file into an image")
+        .arg(Arg::with_name("source")
+            .help("the assembly source")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .help("where to write the image, by default the source with the format's extension, .bin for big endian")
+            .takes_value(true))
+        .arg(Arg::with_name("optimize")
+            .short("O")
+            .long("optimize")
+            .help("shorten what can be shortened and drop code that does nothing"))
+        .arg(Arg::with_name("relative-jumps")
+            .long("relative-jumps")
+            .help("with -O, let jumps become ADD or SUB PC, which clobbers EX"))
+        .arg(Arg::with_name("dialect")
+            .long("dialect")
+            .help("the assembler the source was written for: notch, organic, dasm16 or auto (the default)")
+            .takes_value(true))
+        .arg(Arg::with_name("stdlib")
+            .long("stdlib")
+            .help("add the standard library routines the source calls, like memcpy"))
+        .arg(format_arg("format")));
+    #[cfg(feature = "compiler")]
+    let app = app.subcommand(SubCommand::new("cc")
+        .about("Compiles a C source file into an image")
+        .arg(Arg::with_name("source")
+            .help("the C source")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .help("where to write the image, by default the source with the format's extension, .bin for big endian")
+            .takes_value(true))
+        .arg(Arg::with_name("assembly")
+            .short("S")
+            .help("write assembly source instead, by default with a .dasm extension"))
+        .arg(Arg::with_name("optimize")
+            .short("O")
+            .long("optimize")
+            .help("shorten what can be shortened and drop code that does nothing"))
+        .arg(Arg::with_name("no-stdlib")
+            .long("no-stdlib")
+            .help("leave out the standard library, so only the source's own functions are there"))
+        .arg(format_arg("format")));
+    #[cfg(feature = "forth")]
+    let app = app.subcommand(SubCommand::new("forth")
+        .about("Runs the Forth ROM, reading lines from stdin")
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .help("write the ROM here instead")
+            .takes_value(true))
+        .arg(format_arg("format")));
+    #[cfg(feature = "parser")]
+    let app = app.subcommand(SubCommand::new("fmt")
+        .about("Formats assembly sources in place")
+        .arg(Arg::with_name("source")
+            .help("the assembly sources")
+            .required(true)
+            .multiple(true)
+            .index(1))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .help("list the files that would change instead of changing them"))
+        .arg(Arg::with_name("lowercase")
+            .long("lowercase")
+            .help("write mnemonics and registers in lowercase"))
+        .arg(Arg::with_name("literals")
+            .long("literals")
+            .help("how to write numbers: hex (the default), decimal, hash or keep")
+            .takes_value(true)));
+    #[cfg(feature = "lsp")]
+    let app = app.subcommand(SubCommand::new("lsp")
+        .about("Runs a language server for DCPU assembly on stdin and stdout"));
+    let matches = app.get_matches();
+
+    let result = match matches.subcommand() {
+        ("run", Some(m)) => run(m),
+        ("devices", Some(m)) => devices(m),
+        ("disasm", Some(m)) => disasm(m),
+        ("convert", Some(m)) => convert(m),
+        #[cfg(feature = "tui")]
+        ("tui", Some(m)) => tui(m),
+        #[cfg(feature = "assembler")]
+        ("asm", Some(m)) => asm(m),
+        #[cfg(feature = "compiler")]
+        ("cc", Some(m)) => cc(m),
+        #[cfg(feature = "forth")]
+        ("forth", Some(m)) => forth(m),
+        #[cfg(feature = "parser")]
+        ("fmt", Some(m)) => fmt(m),
+        #[cfg(feature = "lsp")]
+        ("lsp", Some(_)) => lsp(),
+        _ => {
+            eprintln!("{}", matches.usage());
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
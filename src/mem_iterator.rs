@@ -1,11 +1,11 @@
 pub struct MemIterator<'a> {
     curr: usize,
     max: usize,
-    data: &'a Vec<u16>
+    data: &'a [u16]
 }
 
 impl<'a> MemIterator<'a> {
-    pub fn new(src: &'a Vec<u16>, skip: usize, max: usize) -> MemIterator<'a> {
+    pub fn new(src: &'a [u16], skip: usize, max: usize) -> MemIterator<'a> {
         MemIterator{ curr: skip & max, max: max, data: src}
     }
 }
@@ -1,7 +1,7 @@
 use std::fmt::{Display, Formatter, Error};
 use virtual_machine::Register;
 
-#[derive(Debug,PartialEq)]
+#[derive(Clone,Debug,PartialEq)]
 pub enum Operand {
     Register(Register),
     RegisterDeref(Register),
@@ -22,7 +22,15 @@ pub enum Operand {
     LabelPlusLabelDeref(String, String),
 }
 
-#[derive(Debug,PartialEq)]
+// the encodings of the HLT, BRK and NOP pseudo-ops. HLT is ADD PC, -1, which
+// spins in place elsewhere but stops a run here; SUB PC, 1 is left alone,
+// since programs idle in it waiting for interrupts. BRK is SET PC, PC, which
+// does nothing elsewhere but breaks into the debugger here
+pub const HLT: u16 = 0x8382;
+pub const BRK: u16 = 0x7381;
+pub const NOP: u16 = 0x0001;
+
+#[derive(Clone,Debug,PartialEq)]
 pub enum Opcode {
     SET(Operand, Operand), // SET b, a -> b = a
     ADD(Operand, Operand), // ADD b, a -> b = b+a
@@ -62,6 +70,18 @@ pub enum Opcode {
     HWI(Operand)
 }
 
+impl Operand {
+    // the labels the operand refers to
+    pub fn labels(&self) -> Vec<&str> {
+        match *self {
+            Operand::Label(ref s) | Operand::LabelDeref(ref s) |
+            Operand::LabelPlusDeref(ref s, _) | Operand::RegisterPlusLabelDeref(_, ref s) => vec![s],
+            Operand::LabelPlusLabelDeref(ref s, ref t) => vec![s, t],
+            _ => vec![]
+        }
+    }
+}
+
 impl Display for Operand {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         match *self {
@@ -108,7 +128,7 @@ impl Display for Operand {
                 fmt.write_str(s)
             },
             Operand::LabelDeref(ref s) => {
-                fmt.write_str(s)
+                fmt.write_fmt(format_args!("[{}]", s))
             },
             Operand::LabelPlusDeref(ref s, l) => {
                 fmt.write_fmt(format_args!("[{}+{}]", s, l))
@@ -120,6 +140,53 @@ impl Display for Operand {
     }
 }
 
+impl Opcode {
+    // SET A, A, which is what NOP assembles to
+    pub fn nop() -> Opcode {
+        Opcode::SET(Operand::Register(Register::A), Operand::Register(Register::A))
+    }
+
+    // (b, a), with no b for special opcodes
+    pub fn operands(&self) -> (Option<&Operand>, &Operand) {
+        match *self {
+            Opcode::SET(ref b, ref a) | Opcode::ADD(ref b, ref a) | Opcode::SUB(ref b, ref a) |
+            Opcode::MUL(ref b, ref a) | Opcode::MLI(ref b, ref a) | Opcode::DIV(ref b, ref a) |
+            Opcode::DVI(ref b, ref a) | Opcode::MOD(ref b, ref a) | Opcode::MDI(ref b, ref a) |
+            Opcode::AND(ref b, ref a) | Opcode::BOR(ref b, ref a) | Opcode::XOR(ref b, ref a) |
+            Opcode::SHR(ref b, ref a) | Opcode::ASR(ref b, ref a) | Opcode::SHL(ref b, ref a) |
+            Opcode::IFB(ref b, ref a) | Opcode::IFC(ref b, ref a) | Opcode::IFE(ref b, ref a) |
+            Opcode::IFN(ref b, ref a) | Opcode::IFG(ref b, ref a) | Opcode::IFA(ref b, ref a) |
+            Opcode::IFL(ref b, ref a) | Opcode::IFU(ref b, ref a) | Opcode::ADX(ref b, ref a) |
+            Opcode::SBX(ref b, ref a) | Opcode::STI(ref b, ref a) | Opcode::STD(ref b, ref a) => (Some(b), a),
+            Opcode::JSR(ref a) | Opcode::INT(ref a) | Opcode::IAG(ref a) | Opcode::IAS(ref a) |
+            Opcode::RFI(ref a) | Opcode::IAQ(ref a) | Opcode::HWN(ref a) | Opcode::HWQ(ref a) |
+            Opcode::HWI(ref a) => (None, a),
+        }
+    }
+
+    // what the instruction costs before next words, failed IFs and whatever
+    // the hardware takes
+    pub fn cycles(&self) -> usize {
+        match *self {
+            Opcode::SET(..) | Opcode::AND(..) | Opcode::BOR(..) | Opcode::XOR(..) |
+            Opcode::SHR(..) | Opcode::ASR(..) | Opcode::SHL(..) | Opcode::IAG(..) |
+            Opcode::IAS(..) => 1,
+            Opcode::ADD(..) | Opcode::SUB(..) | Opcode::MUL(..) | Opcode::MLI(..) |
+            Opcode::IFB(..) | Opcode::IFC(..) | Opcode::IFE(..) | Opcode::IFN(..) |
+            Opcode::IFG(..) | Opcode::IFA(..) | Opcode::IFL(..) | Opcode::IFU(..) |
+            Opcode::STI(..) | Opcode::STD(..) | Opcode::IAQ(..) | Opcode::HWN(..) => 2,
+            Opcode::DIV(..) | Opcode::DVI(..) | Opcode::MOD(..) | Opcode::MDI(..) |
+            Opcode::ADX(..) | Opcode::SBX(..) | Opcode::JSR(..) | Opcode::RFI(..) => 3,
+            Opcode::INT(..) | Opcode::HWQ(..) | Opcode::HWI(..) => 4,
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        matches!(*self, Opcode::IFB(..) | Opcode::IFC(..) | Opcode::IFE(..) | Opcode::IFN(..) |
+                        Opcode::IFG(..) | Opcode::IFA(..) | Opcode::IFL(..) | Opcode::IFU(..))
+    }
+}
+
 impl Display for Opcode {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         match *self {
@@ -1,9 +1,11 @@
 use pest::Parser;
-use pest::error::Error;
+use pest::error::{Error, ErrorVariant, InputLocation};
 use pest::iterators::Pair;
 use virtual_machine::Register as VMRegister;
 use opcodes::{Opcode, Operand};
+use std::fmt::{self, Display, Formatter};
 use thiserror::Error;
+pub use diagnostics::{Located, Span};
 
 #[cfg(debug_assertions)]
 const _GRAMMAR: &'static str = include_str!("dcpu.pest");
@@ -11,22 +13,105 @@ const _GRAMMAR: &'static str = include_str!("dcpu.pest");
 #[grammar = "dcpu.pest"]
 struct DcpuParser;
 
-#[derive(Debug, Error, PartialEq)]
+#[derive(Clone, Debug, Error, PartialEq)]
 pub enum ParseError {
-    #[error("Parse Error: {}", .0)]
-    PestError(#[from] pest::error::Error<Rule>),
-    #[error("Literal {:04x} exceeds maximum size {:02x}", .0, u16::MAX)]
+    #[error("Syntax error: {}", .0)]
+    Syntax(String),
+    #[error("Literal {} exceeds maximum size {}", .0, u16::MAX)]
     ExceedsLiteralSize(u32),
     #[error("Failed to parse literal {}", .0)]
     ParseIntError(#[from] std::num::ParseIntError),
     #[error("Invalid deref on {}", .0)]
-    InvalidDeref(String)
+    InvalidDeref(String),
+    #[error("Unexpected {:?} in the parse tree", .0)]
+    UnexpectedRule(Rule),
+    #[error("{} isn't part of the {} dialect", .0, .1)]
+    NotInDialect(Feature, Dialect),
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Statement {
     LabelDef(String),
     Instruction(Opcode),
+    // written as NOP rather than SET A, A, so -O knows to keep it
+    Nop,
+    // DAT: numbers and labels, strings already split into characters
+    Data(Vec<Operand>),
+    // #define and .equ
+    Constant(String, u16),
+}
+
+// the syntax that differs between assemblers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    ColonFirstLabel,
+    ColonLastLabel,
+    Dat,
+    Dw,
+    Define,
+    Equ,
+}
+
+impl Display for Feature {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            Feature::ColonFirstLabel => "`:label`",
+            Feature::ColonLastLabel => "`label:`",
+            Feature::Dat => "`dat`",
+            Feature::Dw => "`.dw`",
+            Feature::Define => "`#define`",
+            Feature::Equ => "`.equ`",
+        })
+    }
+}
+
+// the community assemblers whose sources we can read. Auto takes any of
+// them, and a file can even mix them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dialect {
+    #[default]
+    Auto,
+    Notch,
+    Organic,
+    Dasm16,
+}
+
+impl Dialect {
+    pub fn named(name: &str) -> Option<Dialect> {
+        match &name.to_lowercase()[..] {
+            "auto" => Some(Dialect::Auto),
+            "notch" => Some(Dialect::Notch),
+            "organic" => Some(Dialect::Organic),
+            "dasm16" => Some(Dialect::Dasm16),
+            _ => None,
+        }
+    }
+
+    pub fn allows(self, feature: Feature) -> bool {
+        match self {
+            Dialect::Auto | Dialect::Organic => true,
+            Dialect::Notch => matches!(feature, Feature::ColonFirstLabel | Feature::Dat),
+            Dialect::Dasm16 => matches!(feature, Feature::ColonLastLabel | Feature::Dat | Feature::Dw | Feature::Define),
+        }
+    }
+
+    // the narrowest dialect that has all of `features`
+    fn detect(features: &[Feature]) -> Dialect {
+        [Dialect::Notch, Dialect::Dasm16, Dialect::Organic].iter().cloned()
+            .find(|d| features.iter().all(|&f| d.allows(f)))
+            .unwrap_or(Dialect::Auto)
+    }
+}
+
+impl Display for Dialect {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            Dialect::Auto => "auto",
+            Dialect::Notch => "Notch",
+            Dialect::Organic => "Organic",
+            Dialect::Dasm16 => "DASM16",
+        })
+    }
 }
 
 fn parse_int_literal(pair: Pair<Rule>) -> Result<u16, ParseError> {
@@ -76,7 +161,7 @@ fn emit_register(pair: Pair<Rule>) -> Result<Operand, ParseError> {
         Rule::pc => {
             return Ok(Operand::Pc)
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
     }
 }
 
@@ -97,7 +182,7 @@ fn emit_literal_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
         Rule::ident => {
             Ok(Operand::LabelDeref(String::from(pair.as_str())))
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
     }
 }
 
@@ -123,14 +208,30 @@ fn emit_register_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
         Rule::ex => {
             Err(ParseError::InvalidDeref(pair.as_str().to_string()))
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
     }
 }
 
 fn emit_register_plus_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
     let mut inner = pair.into_inner();
-    let lhs = parse_regsiter(inner.next().unwrap().as_str().to_string())?;
-    if let Some(rhs) = inner.next() {
+    let (mut first, mut second) = (inner.next().unwrap(), inner.next());
+    // [label+A] is the same as [A+label]
+    if first.as_rule() != Rule::register {
+        if let Some(register) = second {
+            second = Some(first);
+            first = register;
+        }
+    }
+    // [SP + n] is PICK n
+    if first.as_str().eq_ignore_ascii_case("SP") {
+        return match second {
+            Some(ref n) if n.as_rule() == Rule::int_literal => Ok(Operand::Pick(parse_int_literal(n.clone())?)),
+            Some(ref n) if n.as_rule() == Rule::hex_literal => Ok(Operand::Pick(parse_hex_literal(n.clone())?)),
+            _ => Err(ParseError::InvalidDeref(first.as_str().to_string())),
+        };
+    }
+    let lhs = parse_regsiter(first.as_str().to_string())?;
+    if let Some(rhs) = second {
         match rhs.as_rule() {
             Rule::ident => {
                 return Ok(Operand::RegisterPlusLabelDeref(lhs, String::from(rhs.as_str())))
@@ -141,7 +242,7 @@ fn emit_register_plus_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
             Rule::hex_literal => {
                 return Ok(Operand::RegisterPlusDeref(lhs, parse_hex_literal(rhs)?))
             },
-            unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+            unknown_term => return Err(ParseError::UnexpectedRule(unknown_term))
         }
     }
     unreachable!()
@@ -162,7 +263,7 @@ fn emit_ident_plus_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
             Rule::hex_literal => {
                 return Ok(Operand::LabelPlusDeref(lhs, parse_hex_literal(rhs)?))
             },
-            unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+            unknown_term => return Err(ParseError::UnexpectedRule(unknown_term))
         }
     }
     unreachable!()
@@ -171,7 +272,9 @@ fn emit_ident_plus_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
 fn emit_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
     match pair.as_rule() {
         Rule::register => {
-            return Ok(emit_register_deref(pair.into_inner().next().unwrap())?)
+            // PC, SP and EX have a rule of their own inside, A to J don't
+            let inner = pair.clone().into_inner().next().unwrap_or(pair);
+            return Ok(emit_register_deref(inner)?)
         },
         Rule::register_plus_deref => {
             return Ok(emit_register_plus_deref(pair)?)
@@ -182,7 +285,7 @@ fn emit_deref(pair: Pair<Rule>) -> Result<Operand, ParseError> {
         Rule::ident_plus_deref => {
             return Ok(emit_ident_plus_deref(pair)?)
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
     }
 }
 
@@ -206,7 +309,7 @@ fn emit_operand(pair: Pair<Rule>) -> Result<Operand, ParseError> {
                 Rule::ident => {
                      Ok(Operand::Label(String::from(inner.as_str())))
                 },
-                unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+                unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
             }
         },
         Rule::push_operand => {
@@ -215,44 +318,53 @@ fn emit_operand(pair: Pair<Rule>) -> Result<Operand, ParseError> {
         Rule::pop_operand => {
             Ok(Operand::Pop)
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term))
     }
 }
 
-fn emit_opcode_single(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+// errors in an operand point at the operand
+fn spanned_operand(pair: Pair<Rule>) -> Result<Operand, (ParseError, Span)> {
+    let span = Span::from(pair.as_span());
+    emit_operand(pair).map_err(|e| (e, span))
+}
+
+fn emit_opcode_single(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
     let mut inner = pair.into_inner();
     let op = inner.next().unwrap();
     let operand = inner.next().unwrap();
     match op.as_rule() {
         Rule::op_jsr => {
-            Ok(Statement::Instruction(Opcode::JSR(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::JSR(spanned_operand(operand)?)))
         },
         Rule::op_int => {
-            Ok(Statement::Instruction(Opcode::INT(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::INT(spanned_operand(operand)?)))
         },
         Rule::op_iag => {
-            Ok(Statement::Instruction(Opcode::IAG(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::IAG(spanned_operand(operand)?)))
+        },
+        Rule::op_ias => {
+            Ok(Statement::Instruction(Opcode::IAS(spanned_operand(operand)?)))
         },
         Rule::op_rfi => {
-            Ok(Statement::Instruction(Opcode::RFI(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::RFI(spanned_operand(operand)?)))
         },
         Rule::op_iaq => {
-            Ok(Statement::Instruction(Opcode::IAG(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::IAQ(spanned_operand(operand)?)))
         },
         Rule::op_hwn => {
-            Ok(Statement::Instruction(Opcode::HWN(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::HWN(spanned_operand(operand)?)))
         },
         Rule::op_hwq => {
-            Ok(Statement::Instruction(Opcode::HWQ(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::HWQ(spanned_operand(operand)?)))
         },
         Rule::op_hwi => {
-            Ok(Statement::Instruction(Opcode::HWI(emit_operand(operand)?)))
+            Ok(Statement::Instruction(Opcode::HWI(spanned_operand(operand)?)))
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err((ParseError::UnexpectedRule(unknown_term), op.as_span().into()))
     }
 }
 
-fn emit_opcode_double(pair: Pair<Rule>) -> Result<Statement, ParseError> {
+fn emit_opcode_double(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
     let mut inner = pair.into_inner();
     let op = inner.next().unwrap();
     let lhs = inner.next().unwrap();
@@ -260,122 +372,344 @@ fn emit_opcode_double(pair: Pair<Rule>) -> Result<Statement, ParseError> {
     
     match op.as_rule() {
         Rule::op_set => {
-            Ok(Statement::Instruction(Opcode::SET(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::SET(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_add => {
-            Ok(Statement::Instruction(Opcode::ADD(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::ADD(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_sub => {
-            Ok(Statement::Instruction(Opcode::SUB(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::SUB(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_mul => {
-            Ok(Statement::Instruction(Opcode::MUL(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::MUL(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_mli => {
-            Ok(Statement::Instruction(Opcode::MLI(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::MLI(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_div => {
-            Ok(Statement::Instruction(Opcode::DIV(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::DIV(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_dvi => {
-            Ok(Statement::Instruction(Opcode::DVI(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::DVI(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_mod => {
-            Ok(Statement::Instruction(Opcode::MOD(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::MOD(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_mdi => {
-            Ok(Statement::Instruction(Opcode::MDI(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::MDI(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_and => {
-            Ok(Statement::Instruction(Opcode::AND(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::AND(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_bor => {
-            Ok(Statement::Instruction(Opcode::BOR(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::BOR(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_xor => {
-            Ok(Statement::Instruction(Opcode::XOR(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::XOR(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_shr => {
-            Ok(Statement::Instruction(Opcode::SHR(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::SHR(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_asr => {
-            Ok(Statement::Instruction(Opcode::ASR(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::ASR(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_shl => {
-            Ok(Statement::Instruction(Opcode::SHL(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::SHL(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifb => {
-            Ok(Statement::Instruction(Opcode::IFB(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFB(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifc => {
-            Ok(Statement::Instruction(Opcode::IFC(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFC(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ife => {
-            Ok(Statement::Instruction(Opcode::IFE(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFE(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifn => {
-            Ok(Statement::Instruction(Opcode::IFN(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFN(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifg => {
-            Ok(Statement::Instruction(Opcode::IFG(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFG(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifa => {
-            Ok(Statement::Instruction(Opcode::IFA(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFA(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifl => {
-            Ok(Statement::Instruction(Opcode::IFL(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFL(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_ifu => {
-            Ok(Statement::Instruction(Opcode::IFU(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::IFU(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_adx => {
-            Ok(Statement::Instruction(Opcode::ADX(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::ADX(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_sbx => {
-            Ok(Statement::Instruction(Opcode::SBX(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::SBX(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_sti => {
-            Ok(Statement::Instruction(Opcode::STI(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::STI(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
         Rule::op_std => {
-            Ok(Statement::Instruction(Opcode::STD(emit_operand(lhs)?, emit_operand(rhs)?)))
+            Ok(Statement::Instruction(Opcode::STD(spanned_operand(lhs)?, spanned_operand(rhs)?)))
         },
-        unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
+        unknown_term => Err((ParseError::UnexpectedRule(unknown_term), op.as_span().into()))
     }
 }
 
+// JMP, RET and the rest, as the instructions they stand for
+fn emit_pseudo(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
+    let mut inner = pair.into_inner();
+    let op = inner.next().unwrap();
+    let op = match (op.as_rule(), inner.next()) {
+        (Rule::op_jmp, Some(x)) => Opcode::SET(Operand::Pc, spanned_operand(x)?),
+        (Rule::op_push, Some(x)) => Opcode::SET(Operand::Push, spanned_operand(x)?),
+        (Rule::op_pop, Some(x)) => Opcode::SET(spanned_operand(x)?, Operand::Pop),
+        (Rule::op_ret, None) => Opcode::SET(Operand::Pc, Operand::Pop),
+        (Rule::op_nop, None) => return Ok(Statement::Nop),
+        (Rule::op_hlt, None) => Opcode::ADD(Operand::Pc, Operand::Literal(0xffff)),
+        (Rule::op_brk, None) => Opcode::SET(Operand::Pc, Operand::Pc),
+        (unknown_term, _) => return Err((ParseError::UnexpectedRule(unknown_term), op.as_span().into())),
+    };
+    Ok(Statement::Instruction(op))
+}
+
 fn emit_label_def(pair: Pair<Rule>) -> Statement {
     //can only be label_def rule
     let ident = pair.into_inner();
     Statement::LabelDef(String::from(ident.as_str()))
 }
 
-pub fn parse(src: &str) -> Result<Vec<Statement>, ParseError> {
-    let mut ret = vec![];
-    let pairs = DcpuParser::parse(Rule::input, src)?;
-
-    for pair in pairs {
-        match pair.as_rule() {
-            Rule::label_def => {
-                ret.push(emit_label_def(pair));
+// the characters of a string or character literal, quotes and all. the
+// grammar only lets through escapes that are here
+fn unescape(quoted: &str) -> Vec<u16> {
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    let mut words = vec![];
+    while let Some(c) = chars.next() {
+        let word = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n' as u16,
+                Some('r') => '\r' as u16,
+                Some('t') => '\t' as u16,
+                Some('0') => 0,
+                Some('x') => u16::from_str_radix(&chars.by_ref().take(2).collect::<String>(), 16).unwrap_or(0),
+                Some(c) => c as u16,
+                None => '\\' as u16,
             },
-            Rule::opcode_double => {
-                ret.push(emit_opcode_double(pair)?)
+            c => c as u16,
+        };
+        words.push(word);
+    }
+    words
+}
+
+// a number, label, string or character in DAT or a constant
+fn emit_value(pair: Pair<Rule>) -> Result<Vec<Operand>, ParseError> {
+    match pair.as_rule() {
+        Rule::int_literal => Ok(vec![Operand::Literal(parse_int_literal(pair)?)]),
+        Rule::hex_literal => Ok(vec![Operand::Literal(parse_hex_literal(pair)?)]),
+        Rule::ident => Ok(vec![Operand::Label(String::from(pair.as_str()))]),
+        Rule::string | Rule::chr => Ok(unescape(pair.as_str()).into_iter().map(Operand::Literal).collect()),
+        unknown_term => Err(ParseError::UnexpectedRule(unknown_term)),
+    }
+}
+
+fn spanned_value(pair: Pair<Rule>) -> Result<Vec<Operand>, (ParseError, Span)> {
+    let span = Span::from(pair.as_span());
+    emit_value(pair).map_err(|e| (e, span))
+}
+
+fn emit_data(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
+    let mut values = vec![];
+    // the first is the mnemonic
+    for value in pair.into_inner().skip(1) {
+        values.extend(spanned_value(value)?);
+    }
+    Ok(Statement::Data(values))
+}
+
+fn emit_constant(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
+    let mut inner = pair.into_inner().skip(1);
+    let name = inner.next().unwrap().as_str().to_owned();
+    let value = inner.next().unwrap();
+    let span = Span::from(value.as_span());
+    match spanned_value(value)?[..] {
+        [Operand::Literal(value)] => Ok(Statement::Constant(name, value)),
+        _ => Err((ParseError::Syntax("expected a number or a character".to_owned()), span)),
+    }
+}
+
+// which dialects the statement could have come from
+fn feature(pair: &Pair<Rule>) -> Option<Feature> {
+    match pair.as_rule() {
+        Rule::label_def if pair.as_str().starts_with(':') => Some(Feature::ColonFirstLabel),
+        Rule::label_def => Some(Feature::ColonLastLabel),
+        Rule::data | Rule::constant => match pair.clone().into_inner().next().map(|p| p.as_rule()) {
+            Some(Rule::op_dat) => Some(Feature::Dat),
+            Some(Rule::op_dw) => Some(Feature::Dw),
+            Some(Rule::op_define) => Some(Feature::Define),
+            Some(Rule::op_equ) => Some(Feature::Equ),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// friendlier names for what pest expected to see
+fn describe(rule: &Rule) -> String {
+    match *rule {
+        Rule::label_def => "a label",
+        Rule::opcode_double | Rule::opcode_single | Rule::pseudo | Rule::data | Rule::constant => "an instruction",
+        Rule::operand => "an operand",
+        Rule::push_operand => "PUSH",
+        Rule::pop_operand => "POP",
+        Rule::register | Rule::pc | Rule::sp | Rule::ex => "a register",
+        Rule::int_literal | Rule::hex_literal => "a number",
+        Rule::string => "a string",
+        Rule::chr => "a character",
+        Rule::ident => "a label name",
+        Rule::deref => "`[`",
+        Rule::EOI => "the end of the input",
+        _ => return format!("{:?}", rule),
+    }.to_owned()
+}
+
+fn one_of(rules: &[Rule]) -> String {
+    let mut names: Vec<String> = vec![];
+    for name in rules.iter().map(describe) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+    }
+}
+
+fn syntax_error(error: Error<Rule>) -> (ParseError, Span) {
+    let span = match error.location {
+        InputLocation::Pos(pos) => Span { start: pos, end: pos },
+        InputLocation::Span((start, end)) => Span { start, end },
+    };
+    let message = match error.variant {
+        ErrorVariant::ParsingError { ref positives, ref negatives } if negatives.is_empty() =>
+            format!("expected {}", one_of(positives)),
+        ErrorVariant::ParsingError { ref positives, ref negatives } if positives.is_empty() =>
+            format!("unexpected {}", one_of(negatives)),
+        ErrorVariant::ParsingError { ref positives, ref negatives } =>
+            format!("unexpected {}; expected {}", one_of(negatives), one_of(positives)),
+        ErrorVariant::CustomError { ref message } => message.clone(),
+    };
+    (ParseError::Syntax(message), span)
+}
+
+// where the next statement could start: past whitespace and comments
+fn skip_blank(src: &str, mut pos: usize) -> usize {
+    let mut in_comment = false;
+    for c in src[pos..].chars() {
+        match c {
+            '\n' => in_comment = false,
+            ';' => in_comment = true,
+            ' ' | '\t' | '\r' => (),
+            _ if in_comment => (),
+            _ => break,
+        }
+        pos += c.len_utf8();
+    }
+    pos
+}
+
+fn emit_statement(pair: Pair<Rule>) -> Result<Statement, (ParseError, Span)> {
+    match pair.as_rule() {
+        Rule::label_def => {
+            Ok(emit_label_def(pair))
+        },
+        Rule::opcode_double => {
+            emit_opcode_double(pair)
+        },
+        Rule::opcode_single => {
+            emit_opcode_single(pair)
+        },
+        Rule::pseudo => {
+            emit_pseudo(pair)
+        },
+        Rule::data => {
+            emit_data(pair)
+        },
+        Rule::constant => {
+            emit_constant(pair)
+        },
+        unknown_term => Err((ParseError::UnexpectedRule(unknown_term), pair.as_span().into()))
+    }
+}
+
+// everything that parsed, and everything that didn't
+#[derive(Debug, Default, PartialEq)]
+pub struct Parsed {
+    pub statements: Vec<(Statement, Span)>,
+    pub errors: Vec<Located<ParseError>>,
+    // the dialect it was parsed as. for Auto, the narrowest one that has
+    // everything the file used, or Auto if none of them do
+    pub dialect: Dialect,
+}
+
+// parses a statement at a time, so after a syntax error it can pick up again
+// on the next line and report every error in the file
+pub fn parse_file(file: Option<&str>, src: &str) -> Parsed {
+    parse_dialect(file, src, Dialect::Auto)
+}
+
+// like parse_file, but anything from outside `dialect` is an error
+pub fn parse_dialect(file: Option<&str>, src: &str, dialect: Dialect) -> Parsed {
+    let mut parsed = Parsed { dialect, ..Parsed::default() };
+    let mut features = vec![];
+    let mut pos = skip_blank(src, 0);
+    while pos < src.len() {
+        match DcpuParser::parse(Rule::statement, &src[pos..]) {
+            Ok(mut pairs) => {
+                let pair = pairs.next().expect("statements are never empty");
+                let span = Span::from(pair.as_span()).offset(pos);
+                let feature = feature(&pair);
+                match (emit_statement(pair), feature) {
+                    (Ok(_), Some(f)) if !dialect.allows(f) =>
+                        parsed.errors.push(Located::new(ParseError::NotInDialect(f, dialect), file, src, span)),
+                    (Ok(statement), _) => {
+                        features.extend(feature);
+                        parsed.statements.push((statement, span));
+                    },
+                    (Err((e, at)), _) => parsed.errors.push(Located::new(e, file, src, at.offset(pos))),
+                }
+                pos = span.end;
             },
-            Rule::opcode_single => {
-                ret.push(emit_opcode_single(pair)?)
+            Err(e) => {
+                let (e, at) = syntax_error(e);
+                let at = at.offset(pos);
+                // give up on the rest of the line
+                pos = src[at.start..].find('\n').map_or(src.len(), |i| at.start + i + 1);
+                parsed.errors.push(Located::new(e, file, src, at));
             },
-            Rule::EOI => { break; }
-            unknown_term => panic!("Unknown term encountered in {}@{}:{}: {:#?}", file!(), line!(), column!(), unknown_term)
         }
+        pos = skip_blank(src, pos);
     }
+    if dialect == Dialect::Auto {
+        parsed.dialect = Dialect::detect(&features);
+    }
+    parsed
+}
 
-    Ok(ret)
+pub fn parse(src: &str) -> Result<Vec<Statement>, ParseError> {
+    let parsed = parse_file(None, src);
+    match parsed.errors.into_iter().next() {
+        Some(e) => Err(e.error),
+        None => Ok(parsed.statements.into_iter().map(|(s, _)| s).collect()),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::Span;
     use pest::*;
     #[test]
     fn int_literal() {
@@ -553,6 +887,12 @@ mod tests {
         
         pairs = DcpuParser::parse(Rule::operand, "[SP]").unwrap();
         assert_eq!(emit_operand(pairs.next().unwrap()), Ok(Operand::Peek));
+
+        pairs = DcpuParser::parse(Rule::operand, "[b]").unwrap();
+        assert_eq!(emit_operand(pairs.next().unwrap()), Ok(Operand::RegisterDeref(VMRegister::B)));
+
+        pairs = DcpuParser::parse(Rule::operand, "[SP + 0x3]").unwrap();
+        assert_eq!(emit_operand(pairs.next().unwrap()), Ok(Operand::Pick(3)));
         
         pairs = DcpuParser::parse(Rule::operand, "[0x1234]").unwrap();
         assert_eq!(emit_operand(pairs.next().unwrap()), Ok(Operand::LiteralDeref(0x1234)));
@@ -639,4 +979,87 @@ mod tests {
                                                 Operand::Literal(123))),
             Statement::Instruction(Opcode::JSR(Operand::Label("start".to_string())))]);
     }
+
+    #[test]
+    fn spans() {
+        let parsed = parse_file(None, ":start\n  JSR start ; again");
+        assert_eq!(parsed.statements[0].1, Span { start: 0, end: 6 });
+        assert_eq!(parsed.statements[1].1, Span { start: 9, end: 18 });
+        assert_eq!(parsed.errors, vec![]);
+    }
+
+    #[test]
+    fn recovers_from_errors() {
+        let src = "SET A, 1\nSET A, 65536 ; too big\nSET ,\n:end SET [PC], 2\n  SET PC, end";
+        let parsed = parse_file(Some("test.dasm"), src);
+        let errors: Vec<_> = parsed.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "test.dasm:2:8: Literal 65536 exceeds maximum size 65535",
+            "test.dasm:3:5: Syntax error: expected PUSH or an operand",
+            "test.dasm:4:10: Invalid deref on PC",
+        ]);
+        assert_eq!(parsed.errors[0].location.span, Span { start: 16, end: 21 });
+        assert_eq!(parsed.statements.iter().map(|s| &s.0).collect::<Vec<_>>(), vec![
+            &Statement::Instruction(Opcode::SET(Operand::Register(VMRegister::A), Operand::Literal(1))),
+            &Statement::LabelDef("end".to_string()),
+            &Statement::Instruction(Opcode::SET(Operand::Pc, Operand::Label("end".to_string()))),
+        ]);
+        assert_eq!(parse(src), Err(ParseError::ExceedsLiteralSize(65536)));
+    }
+
+    #[test]
+    fn dialects() {
+        let a = || Operand::Register(VMRegister::A);
+        let notch = parse_file(None, ":start set a, count\n  add a, 1\n  ias start\n  iaq 1\n  dat \"h;\\n\", 'x', 0x10, start");
+        assert_eq!(notch.errors, vec![]);
+        assert_eq!(notch.dialect, Dialect::Notch);
+        assert_eq!(notch.statements.into_iter().map(|s| s.0).collect::<Vec<_>>(), vec![
+            Statement::LabelDef("start".to_string()),
+            Statement::Instruction(Opcode::SET(a(), Operand::Label("count".to_string()))),
+            Statement::Instruction(Opcode::ADD(a(), Operand::Literal(1))),
+            Statement::Instruction(Opcode::IAS(Operand::Label("start".to_string()))),
+            Statement::Instruction(Opcode::IAQ(Operand::Literal(1))),
+            Statement::Data(vec![Operand::Literal(0x68), Operand::Literal(0x3b), Operand::Literal(0x0a),
+                                 Operand::Literal(0x78), Operand::Literal(0x10), Operand::Label("start".to_string())]),
+        ]);
+
+        let src = "loop: SET [table+A], 1\n.dw 1, 2\n#define SIZE 0x20\n.equ LIMIT, 'z'\n";
+        let organic = parse_file(None, src);
+        assert_eq!(organic.errors, vec![]);
+        assert_eq!(organic.dialect, Dialect::Organic);
+        assert_eq!(organic.statements.into_iter().map(|s| s.0).collect::<Vec<_>>(), vec![
+            Statement::LabelDef("loop".to_string()),
+            Statement::Instruction(Opcode::SET(Operand::RegisterPlusLabelDeref(VMRegister::A, "table".to_string()),
+                                               Operand::Literal(1))),
+            Statement::Data(vec![Operand::Literal(1), Operand::Literal(2)]),
+            Statement::Constant("SIZE".to_string(), 0x20),
+            Statement::Constant("LIMIT".to_string(), 0x7a),
+        ]);
+        assert_eq!(parse_file(None, "loop: .dw 1\n#define X 1").dialect, Dialect::Dasm16);
+
+        // the same source read strictly
+        let errors: Vec<_> = parse_dialect(None, src, Dialect::Notch).errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, vec![
+            "<input>:1:1: `label:` isn't part of the Notch dialect",
+            "<input>:2:1: `.dw` isn't part of the Notch dialect",
+            "<input>:3:1: `#define` isn't part of the Notch dialect",
+            "<input>:4:1: `.equ` isn't part of the Notch dialect",
+        ]);
+        assert_eq!(parse_dialect(None, src, Dialect::Dasm16).errors.len(), 1);
+        assert_eq!(parse("dat \"\\q\""), Err(ParseError::Syntax("expected a string, a character, a number or a label name".to_string())));
+    }
+
+    #[test]
+    fn pseudo_ops() {
+        let statements = parse("jmp loop\nRET\nPUSH [A+1]\nPOP X\nNOP\nHLT\nBRK").unwrap();
+        assert_eq!(statements, vec![
+            Statement::Instruction(Opcode::SET(Operand::Pc, Operand::Label("loop".to_string()))),
+            Statement::Instruction(Opcode::SET(Operand::Pc, Operand::Pop)),
+            Statement::Instruction(Opcode::SET(Operand::Push, Operand::RegisterPlusDeref(VMRegister::A, 1))),
+            Statement::Instruction(Opcode::SET(Operand::Register(VMRegister::X), Operand::Pop)),
+            Statement::Nop,
+            Statement::Instruction(Opcode::ADD(Operand::Pc, Operand::Literal(0xffff))),
+            Statement::Instruction(Opcode::SET(Operand::Pc, Operand::Pc)),
+        ]);
+    }
 }
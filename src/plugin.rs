@@ -0,0 +1,274 @@
+// devices loaded from shared libraries through a C ABI, so they can be built
+// with anything that can export C functions. a plugin exports:
+//
+//   uint32_t dcpu_plugin_abi_version(void);
+//   const DcpuDeviceType *dcpu_plugin_devices(size_t *count);
+//
+// and every device type it lists is registered under its name
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::fmt::{Formatter, Error as FmtError};
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::rc::Rc;
+use libloading::{Library, Symbol};
+use thiserror::Error;
+use hardware::{Hardware, HardwareInfo};
+use registry::{DeviceRegistry, DeviceParams, DeviceInstance, RegistryError};
+use virtual_machine::{VMExposed, Register};
+
+pub const PLUGIN_ABI_VERSION: u32 = 2;
+
+const REGISTERS: [Register; 8] = [Register::A, Register::B, Register::C, Register::X,
+                                  Register::Y, Register::Z, Register::I, Register::J];
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("Couldn't load plugin: {}", .0)]
+    Load(#[from] libloading::Error),
+    #[error("Plugin was built for ABI version {}, this is version {}", .0, PLUGIN_ABI_VERSION)]
+    AbiVersion(u32),
+    #[error("Plugin device names must be UTF-8")]
+    InvalidName,
+    #[error("{}", .0)]
+    Registry(#[from] RegistryError),
+}
+
+// the CPU as a device sees it during a call. registers are copied back when
+// the call returns. RAM is all 0x10000 words, to read; writes go through
+// `write`, so the CPU knows which instructions it has to decode again
+#[repr(C)]
+pub struct DcpuCpu {
+    pub registers: [u16; 8],
+    pub ram: *const u16,
+    pub cycles: u64,
+    pub clock_rate: u64,
+    pub context: *mut c_void,
+    pub interrupt: extern "C" fn(context: *mut c_void, message: u16),
+    pub write: extern "C" fn(context: *mut c_void, address: u16, value: u16),
+}
+
+// one device made by a plugin. `state` is handed back on every call and to
+// `destroy` when the device is dropped
+#[repr(C)]
+pub struct DcpuDevice {
+    pub state: *mut c_void,
+    pub manufacturer: u32,
+    pub model: u32,
+    pub version: u16,
+    // returns the cycles the interrupt took
+    pub interrupt: extern "C" fn(state: *mut c_void, cpu: *mut DcpuCpu) -> u32,
+    pub update: extern "C" fn(state: *mut c_void, cpu: *mut DcpuCpu),
+    pub destroy: extern "C" fn(state: *mut c_void),
+}
+
+// a kind of device a plugin can make. `params` is the device's parameters as
+// a JSON object, `create` fills in `out` and returns false if it can't
+#[repr(C)]
+pub struct DcpuDeviceType {
+    pub name: *const c_char,
+    pub manufacturer: u32,
+    pub model: u32,
+    pub create: extern "C" fn(params: *const c_char, out: *mut DcpuDevice) -> bool,
+}
+
+extern "C" fn raise_interrupt(context: *mut c_void, message: u16) {
+    let vm = unsafe { &mut *(context as *mut VMExposed) };
+    vm.interrupt(message);
+}
+
+extern "C" fn write_ram(context: *mut c_void, address: u16, value: u16) {
+    let vm = unsafe { &mut *(context as *mut VMExposed) };
+    vm.write_ram(address as usize, &[value], 1);
+}
+
+pub struct PluginDevice {
+    info: HardwareInfo,
+    raw: DcpuDevice,
+    // keeps the library loaded for as long as its devices are around
+    _library: Rc<dyn Any>,
+}
+
+impl PluginDevice {
+    fn call<T, F: FnOnce(&DcpuDevice, *mut DcpuCpu) -> T>(&mut self, vm: &mut VMExposed, f: F) -> T {
+        let mut registers = [0u16; 8];
+        for (value, reg) in registers.iter_mut().zip(REGISTERS.iter()) {
+            *value = vm.read_register(*reg).0;
+        }
+        let mut cpu = DcpuCpu {
+            registers,
+            ram: vm.ram().as_ptr(),
+            cycles: vm.get_cycles() as u64,
+            clock_rate: vm.get_clock_rate() as u64,
+            context: vm as *mut VMExposed as *mut c_void,
+            interrupt: raise_interrupt,
+            write: write_ram,
+        };
+        let result = f(&self.raw, &mut cpu);
+        for (value, reg) in cpu.registers.iter().zip(REGISTERS.iter()) {
+            vm.write_register(*reg, *value);
+        }
+        result
+    }
+}
+
+impl Drop for PluginDevice {
+    fn drop(&mut self) {
+        (self.raw.destroy)(self.raw.state);
+    }
+}
+
+impl Hardware for PluginDevice {
+    fn info(&self) -> &HardwareInfo {
+        &self.info
+    }
+
+    fn hardware_interrupt(&mut self, vm: &mut VMExposed) -> usize {
+        self.call(vm, |raw, cpu| (raw.interrupt)(raw.state, cpu) as usize)
+    }
+
+    fn update(&mut self, vm: &mut VMExposed) {
+        self.call(vm, |raw, cpu| (raw.update)(raw.state, cpu))
+    }
+
+    fn debug_dump_state(&self, fmt: &mut Formatter) -> Result<(), FmtError> {
+        fmt.write_fmt(format_args!("plugin state: {:p}", self.raw.state))
+    }
+}
+
+fn register_type(registry: &mut DeviceRegistry, device: &DcpuDeviceType,
+                 library: Rc<dyn Any>) -> Result<(), PluginError> {
+    let name = unsafe { CStr::from_ptr(device.name) }.to_str()
+        .map_err(|_| PluginError::InvalidName)?
+        .to_owned();
+    let create = device.create;
+    let error_name = name.clone();
+    registry.register(&name, device.manufacturer, device.model, move |params: &DeviceParams| {
+        let json = serde_json::to_string(params.values()).expect("JSON values always serialize");
+        let json = CString::new(json).map_err(|e| RegistryError::Custom(e.to_string()))?;
+        let mut raw = DcpuDevice {
+            state: ::std::ptr::null_mut(),
+            manufacturer: 0,
+            model: 0,
+            version: 0,
+            interrupt: no_interrupt,
+            update: no_update,
+            destroy: no_destroy,
+        };
+        if !create(json.as_ptr(), &mut raw) {
+            return Err(RegistryError::Custom(format!("plugin couldn't create {}", error_name)));
+        }
+        Ok(DeviceInstance::new(Box::new(PluginDevice {
+            info: HardwareInfo {
+                manufacturer: raw.manufacturer,
+                model: raw.model,
+                version: raw.version,
+            },
+            raw,
+            _library: library.clone(),
+        })))
+    })?;
+    Ok(())
+}
+
+extern "C" fn no_interrupt(_: *mut c_void, _: *mut DcpuCpu) -> u32 { 0 }
+extern "C" fn no_update(_: *mut c_void, _: *mut DcpuCpu) {}
+extern "C" fn no_destroy(_: *mut c_void) {}
+
+// registers every device the plugin at `path` provides, returning how many
+// there were
+pub fn load_plugin<P: AsRef<Path>>(registry: &mut DeviceRegistry, path: P) -> Result<usize, PluginError> {
+    unsafe {
+        let library = Library::new(path.as_ref().as_os_str())?;
+        let version: Symbol<extern "C" fn() -> u32> = library.get(b"dcpu_plugin_abi_version\0")?;
+        let version = version();
+        if version != PLUGIN_ABI_VERSION {
+            return Err(PluginError::AbiVersion(version));
+        }
+        let devices: Symbol<extern "C" fn(*mut usize) -> *const DcpuDeviceType> =
+            library.get(b"dcpu_plugin_devices\0")?;
+        let mut count = 0;
+        let types = devices(&mut count);
+        let types = if types.is_null() { &[][..] } else { ::std::slice::from_raw_parts(types, count) };
+
+        let library: Rc<dyn Any> = Rc::new(library);
+        for device in types {
+            register_type(registry, device, library.clone())?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtual_machine::VirtualMachine;
+    use std::cell::Cell;
+
+    thread_local! {
+        static DESTROYED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // B = A + 1, [0x100] = 0xbeef, then raises interrupt 7
+    extern "C" fn interrupt(state: *mut c_void, cpu: *mut DcpuCpu) -> u32 {
+        let cpu = unsafe { &mut *cpu };
+        let step = unsafe { *(state as *const u16) };
+        cpu.registers[1] = cpu.registers[0] + step;
+        (cpu.write)(cpu.context, 0x100, 0xbeef);
+        (cpu.interrupt)(cpu.context, 7);
+        3
+    }
+
+    extern "C" fn destroy(state: *mut c_void) {
+        drop(unsafe { Box::from_raw(state as *mut u16) });
+        DESTROYED.with(|d| d.set(d.get() + 1));
+    }
+
+    extern "C" fn create(params: *const c_char, out: *mut DcpuDevice) -> bool {
+        let params = unsafe { CStr::from_ptr(params) }.to_str().unwrap();
+        let step: u16 = if params.contains("\"step\":2") { 2 } else { 1 };
+        unsafe {
+            *out = DcpuDevice {
+                state: Box::into_raw(Box::new(step)) as *mut c_void,
+                manufacturer: 0x12345678,
+                model: 0x9abcdef0,
+                version: 1,
+                interrupt,
+                update: no_update,
+                destroy,
+            };
+        }
+        true
+    }
+
+    #[test]
+    fn c_abi_devices() {
+        let device = DcpuDeviceType {
+            name: b"adder\0".as_ptr() as *const c_char,
+            manufacturer: 0x12345678,
+            model: 0x9abcdef0,
+            create,
+        };
+        let mut registry = DeviceRegistry::new();
+        register_type(&mut registry, &device, Rc::new(())).unwrap();
+
+        let mut params = serde_json::Map::new();
+        params.insert("step".to_owned(), 2.into());
+        let instance = registry.create("adder", &DeviceParams::new(params)).unwrap();
+        assert_eq!(instance.device.info().model, 0x9abcdef0);
+
+        let mut vm = VirtualMachine::new().attach_hardware(instance.device).unwrap();
+        *vm.get_ia() = 0x200;
+        vm.interrupts_mut().set_queueing(true);
+        // HWI 0
+        vm.get_ram()[0] = 0x8640;
+        vm.get_registers()[Register::A as usize] = 40;
+        assert_eq!(vm.step().unwrap(), 4 + 3);
+        assert_eq!(vm.get_registers()[Register::B as usize], 42);
+        assert_eq!(vm.get_ram()[0x100], 0xbeef);
+        assert_eq!(vm.interrupts().pending().collect::<Vec<_>>(), vec![&7]);
+
+        assert!(vm.unplug(0).is_some());
+        assert_eq!(DESTROYED.with(|d| d.get()), 1);
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt::{Debug, Formatter, Error};
+use std::convert::TryInto;
+use disassemble::DcpuDisassmError;
+use opcodes::{HLT, BRK};
+
+// an operand with everything it needs to be read or written without looking
+// at the instruction again. registers are indexes into the register file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Arg {
+    Reg(u8),
+    Deref(u8),
+    Indexed(u8, u16),
+    // PUSH only ever comes as b and POP as a
+    Push,
+    Pop,
+    Peek,
+    Pick(u16),
+    Sp,
+    Pc,
+    Ex,
+    Mem(u16),
+    Lit(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Set, Add, Sub, Mul, Mli, Div, Dvi, Mod, Mdi, And, Bor, Xor, Shr, Asr, Shl,
+    Ifb, Ifc, Ife, Ifn, Ifg, Ifa, Ifl, Ifu, Adx, Sbx, Sti, Std,
+    Jsr, Int, Iag, Ias, Rfi, Iaq, Hwn, Hwq, Hwi,
+}
+
+// a decoded instruction. special ops only have a, and b is left as a
+// literal 0
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MicroOp {
+    pub op: Op,
+    pub b: Arg,
+    pub a: Arg,
+    // how many next words the operands took
+    pub next: u8,
+}
+
+const BASIC: [Option<Op>; 32] = [
+    None, Some(Op::Set), Some(Op::Add), Some(Op::Sub), Some(Op::Mul), Some(Op::Mli), Some(Op::Div), Some(Op::Dvi),
+    Some(Op::Mod), Some(Op::Mdi), Some(Op::And), Some(Op::Bor), Some(Op::Xor), Some(Op::Shr), Some(Op::Asr), Some(Op::Shl),
+    Some(Op::Ifb), Some(Op::Ifc), Some(Op::Ife), Some(Op::Ifn), Some(Op::Ifg), Some(Op::Ifa), Some(Op::Ifl), Some(Op::Ifu),
+    None, None, Some(Op::Adx), Some(Op::Sbx), None, None, Some(Op::Sti), Some(Op::Std),
+];
+
+const SPECIAL: [Option<Op>; 32] = [
+    None, Some(Op::Jsr), None, None, None, None, None, None,
+    Some(Op::Int), Some(Op::Iag), Some(Op::Ias), Some(Op::Rfi), Some(Op::Iaq), None, None, None,
+    Some(Op::Hwn), Some(Op::Hwq), Some(Op::Hwi), None, None, None, None, None,
+    None, None, None, None, None, None, None, None,
+];
+
+// decodes the instruction at `pc`, going round to 0 for next words past the
+// end of memory like the CPU does
+pub fn decode(ram: &[u16], pc: u16) -> Result<MicroOp, DcpuDisassmError> {
+    let inst = ram[pc as usize];
+    let mut next = 0u8;
+    let mut operand = |field: u16, is_a: bool| {
+        let mut word = || {
+            next += 1;
+            ram[pc.wrapping_add(next as u16) as usize]
+        };
+        match field {
+            0x00..=0x07 => Arg::Reg(field as u8),
+            0x08..=0x0f => Arg::Deref(field as u8 - 0x08),
+            0x10..=0x17 => Arg::Indexed(field as u8 - 0x10, word()),
+            0x18 if is_a => Arg::Pop,
+            0x18 => Arg::Push,
+            0x19 => Arg::Peek,
+            0x1a => Arg::Pick(word()),
+            0x1b => Arg::Sp,
+            0x1c => Arg::Pc,
+            0x1d => Arg::Ex,
+            0x1e => Arg::Mem(word()),
+            0x1f => Arg::Lit(word()),
+            _ => Arg::Lit(field.wrapping_sub(0x21)),
+        }
+    };
+
+    let (b, a) = (inst >> 5 & 0x1f, inst >> 10);
+    let reserved = DcpuDisassmError::ReservedOpcode { op: inst };
+    let (op, b, a) = match inst & 0x1f {
+        0 => {
+            let op = SPECIAL[b as usize].ok_or(reserved)?;
+            (op, Arg::Lit(0), operand(a, true))
+        },
+        code => {
+            let op = BASIC[code as usize].ok_or(reserved)?;
+            // a's next word comes first
+            let a = operand(a, true);
+            (op, operand(b, false), a)
+        },
+    };
+    Ok(MicroOp { op, b, a, next })
+}
+
+// an operand run can get at without a match: a register, a constant or the
+// two added up, and whether that's the value or where it is in RAM
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Loc {
+    // 8 for no register
+    pub reg: u8,
+    pub mem: bool,
+    pub k: u16,
+}
+
+const NOWHERE: Loc = Loc { reg: 8, mem: false, k: 0 };
+
+impl Loc {
+    fn of(arg: Arg) -> Option<Loc> {
+        Some(match arg {
+            Arg::Reg(reg) => Loc { reg, mem: false, k: 0 },
+            Arg::Deref(reg) => Loc { reg, mem: true, k: 0 },
+            Arg::Indexed(reg, k) => Loc { reg, mem: true, k },
+            Arg::Mem(k) => Loc { reg: 8, mem: true, k },
+            Arg::Lit(k) => Loc { reg: 8, mem: false, k },
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FastOp {
+    // HLT, BRK and anything that goes near interrupts or devices, which run
+    // has to check for between instructions
+    Leave,
+    // everything else that doesn't fit, which goes the long way round
+    Exec,
+    // SET PC to a literal, where the PC after it already goes
+    Jump,
+    Set, Add, Sub, Mul, And, Bor, Xor, Shr, Shl,
+    Ifb, Ifc, Ife, Ifn, Ifg, Ifa, Ifl, Ifu,
+}
+
+// the most common instructions, with operands that are all Locs and the
+// cycles they take worked out, for run to go through without checking
+// anything between them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Fast {
+    pub op: FastOp,
+    pub cycles: u8,
+    pub b: Loc,
+    pub a: Loc,
+}
+
+impl Fast {
+    fn of(inst: u16, op: &MicroOp) -> Fast {
+        let other = |op| Fast { op, cycles: 0, b: NOWHERE, a: NOWHERE };
+        let (fast, cycles) = match op.op {
+            _ if inst == HLT || inst == BRK => return other(FastOp::Leave),
+            Op::Set if op.b == Arg::Pc => match op.a {
+                Arg::Lit(_) => (FastOp::Jump, 1),
+                _ => return other(FastOp::Exec),
+            },
+            Op::Set => (FastOp::Set, 1),
+            Op::Add => (FastOp::Add, 2),
+            Op::Sub => (FastOp::Sub, 2),
+            Op::Mul => (FastOp::Mul, 2),
+            Op::And => (FastOp::And, 1),
+            Op::Bor => (FastOp::Bor, 1),
+            Op::Xor => (FastOp::Xor, 1),
+            Op::Shr => (FastOp::Shr, 1),
+            Op::Shl => (FastOp::Shl, 1),
+            Op::Ifb => (FastOp::Ifb, 2),
+            Op::Ifc => (FastOp::Ifc, 2),
+            Op::Ife => (FastOp::Ife, 2),
+            Op::Ifn => (FastOp::Ifn, 2),
+            Op::Ifg => (FastOp::Ifg, 2),
+            Op::Ifa => (FastOp::Ifa, 2),
+            Op::Ifl => (FastOp::Ifl, 2),
+            Op::Ifu => (FastOp::Ifu, 2),
+            Op::Int | Op::Iag | Op::Ias | Op::Rfi | Op::Iaq | Op::Hwn | Op::Hwq | Op::Hwi => return other(FastOp::Leave),
+            _ => return other(FastOp::Exec),
+        };
+        let (b, a) = match (fast, Loc::of(op.b), Loc::of(op.a)) {
+            (FastOp::Jump, _, Some(a)) => (NOWHERE, a),
+            // a literal is no good as b to anything but IFs, which don't
+            // write to it
+            (FastOp::Ifb | FastOp::Ifc | FastOp::Ife | FastOp::Ifn
+             | FastOp::Ifg | FastOp::Ifa | FastOp::Ifl | FastOp::Ifu, Some(b), Some(a)) => (b, a),
+            (_, Some(b), Some(a)) if b.mem || b.reg < 8 => (b, a),
+            _ => return other(FastOp::Exec),
+        };
+        Fast { op: fast, cycles: op.next + cycles, b, a }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Slot {
+    // the cache's epoch when the op was decoded. anything else is stale
+    epoch: u32,
+    fast: Fast,
+}
+
+const EMPTY: Slot = Slot {
+    epoch: 0,
+    fast: Fast { op: FastOp::Exec, cycles: 0, b: NOWHERE, a: NOWHERE },
+};
+
+const EMPTY_OP: MicroOp = MicroOp { op: Op::Set, b: Arg::Lit(0), a: Arg::Lit(0), next: 0 };
+
+#[derive(Clone)]
+struct Slots {
+    slots: Box<[Slot; 0x10000]>,
+    ops: Box<[MicroOp; 0x10000]>,
+    // where PC goes after each op, kept apart so that getting on to the next
+    // one only has to wait for a load of two bytes
+    afters: Box<[u16; 0x10000]>,
+}
+
+// decoded instructions by address. whoever writes to RAM has to say so, with
+// invalidate for a word or clear for the lot, so self modifying code works as
+// it would without it
+#[derive(Clone)]
+pub struct Cache {
+    // allocated on the first fetch, so VMs that never run don't pay for it
+    slots: Option<Slots>,
+    // bumped by clear, so it doesn't have to touch every slot. never 0
+    epoch: u32,
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache { slots: None, epoch: 1 }
+    }
+}
+
+impl Cache {
+    #[inline(always)]
+    pub fn fetch(&mut self, ram: &[u16], pc: u16) -> Result<MicroOp, DcpuDisassmError> {
+        if let Some(ref slots) = self.slots {
+            if slots.slots[pc as usize].epoch == self.epoch {
+                return Ok(slots.ops[pc as usize]);
+            }
+        }
+        self.miss(ram, pc)
+    }
+
+    // the fast form of the op at `pc` and where PC goes after it, if it's
+    // been decoded already
+    #[inline(always)]
+    pub fn fast(&self, pc: u16) -> Option<(Fast, u16)> {
+        match self.slots {
+            Some(ref slots) if slots.slots[pc as usize].epoch == self.epoch => {
+                Some((slots.slots[pc as usize].fast, slots.afters[pc as usize]))
+            },
+            _ => None,
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn miss(&mut self, ram: &[u16], pc: u16) -> Result<MicroOp, DcpuDisassmError> {
+        let slots = self.slots.get_or_insert_with(|| Slots {
+            slots: vec![EMPTY; 0x10000].into_boxed_slice().try_into().expect("there's a slot for every address"),
+            ops: vec![EMPTY_OP; 0x10000].into_boxed_slice().try_into().expect("there's an op for every address"),
+            afters: vec![0; 0x10000].into_boxed_slice().try_into().expect("there's a PC for every address"),
+        });
+        let op = decode(ram, pc)?;
+        let fast = Fast::of(ram[pc as usize], &op);
+        slots.slots[pc as usize] = Slot { epoch: self.epoch, fast };
+        slots.ops[pc as usize] = op;
+        slots.afters[pc as usize] = match fast.op {
+            // so run knows where it's going without waiting to read the jump
+            FastOp::Jump => fast.a.k,
+            _ => pc.wrapping_add(op.next as u16 + 1),
+        };
+        Ok(op)
+    }
+
+    // the word at `address` has been written, so the instructions that could
+    // have it as their first, second or third word are decoded again
+    #[inline(always)]
+    pub fn invalidate(&mut self, address: u16) {
+        if let Some(ref mut slots) = self.slots {
+            slots.slots[address as usize].epoch = 0;
+            slots.slots[address.wrapping_sub(1) as usize].epoch = 0;
+            slots.slots[address.wrapping_sub(2) as usize].epoch = 0;
+        }
+    }
+
+    // for when any of RAM could have changed
+    pub fn clear(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            // so long since the slots were last emptied that old epochs could
+            // look current again
+            self.slots = None;
+            self.epoch = 1;
+        }
+    }
+}
+
+impl Debug for Cache {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let decoded = self.slots.iter().flat_map(|slots| slots.slots.iter()).filter(|slot| slot.epoch == self.epoch).count();
+        fmt.write_fmt(format_args!("Cache {{ decoded: {} }}", decoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_operands() {
+        let mut ram = vec![0u16; 0x10000];
+        // SET [B+5], [0x1000] with a's next word first, then JSR POP and
+        // IAG with a short literal
+        ram[..6].copy_from_slice(&[0x7a21, 0x1000, 5, 0x6020, 0xa120, 0]);
+        assert_eq!(decode(&ram, 0).unwrap(), MicroOp {
+            op: Op::Set, b: Arg::Indexed(1, 5), a: Arg::Mem(0x1000), next: 2,
+        });
+        assert_eq!(decode(&ram, 3).unwrap(), MicroOp { op: Op::Jsr, b: Arg::Lit(0), a: Arg::Pop, next: 0 });
+        assert_eq!(decode(&ram, 4).unwrap().a, Arg::Lit(7));
+        assert!(decode(&ram, 5).is_err());
+
+        // next words go round the end of memory
+        ram[0xffff] = 0x7c01;
+        ram[0] = 0x1234;
+        assert_eq!(decode(&ram, 0xffff).unwrap().a, Arg::Lit(0x1234));
+    }
+
+    #[test]
+    fn decodes_again_after_writes() {
+        let mut ram = vec![0u16; 0x10000];
+        let mut cache = Cache::default();
+        ram[..2].copy_from_slice(&[0x7c01, 1]);
+        assert_eq!(cache.fetch(&ram, 0).unwrap().a, Arg::Lit(1));
+        // nobody said
+        ram[1] = 2;
+        assert_eq!(cache.fetch(&ram, 0).unwrap().a, Arg::Lit(1));
+        cache.invalidate(1);
+        assert_eq!(cache.fetch(&ram, 0).unwrap().a, Arg::Lit(2));
+        ram[0] = 0x8801;
+        cache.clear();
+        assert_eq!(cache.fetch(&ram, 0).unwrap().a, Arg::Lit(1));
+
+        // three words back, and round the end of memory
+        ram[0xfffe..].copy_from_slice(&[0x7e21, 5]);
+        ram[0] = 6;
+        assert_eq!(cache.fetch(&ram, 0xfffe).unwrap().b, Arg::Indexed(1, 6));
+        ram[0] = 7;
+        cache.invalidate(0);
+        assert_eq!(cache.fetch(&ram, 0xfffe).unwrap().b, Arg::Indexed(1, 7));
+        cache.invalidate(1);
+        assert_eq!(cache.fetch(&ram, 0xfffe).unwrap().b, Arg::Indexed(1, 7));
+    }
+
+    #[test]
+    fn fast_forms() {
+        let mut ram = vec![0u16; 0x10000];
+        let mut cache = Cache::default();
+        // SET [B+5], [0x1000], HLT, JSR POP, SET PC, 0x1234, then a literal
+        // as b to SET and to IFE
+        ram[..11].copy_from_slice(&[0x7a21, 0x1000, 5, HLT, 0x6020, 0x7f81, 0x1234, 0x03e1, 5, 0x03f2, 5]);
+        let fast = |cache: &mut Cache, pc| {
+            cache.fetch(&ram, pc).unwrap();
+            cache.fast(pc).unwrap()
+        };
+        assert_eq!(fast(&mut cache, 0), (Fast {
+            op: FastOp::Set, cycles: 3, b: Loc { reg: 1, mem: true, k: 5 }, a: Loc { reg: 8, mem: true, k: 0x1000 },
+        }, 3));
+        assert_eq!(fast(&mut cache, 3).0.op, FastOp::Leave);
+        assert_eq!(fast(&mut cache, 4).0.op, FastOp::Exec);
+        // straight to where it's going
+        assert_eq!(fast(&mut cache, 5), (Fast { op: FastOp::Jump, cycles: 2, b: NOWHERE, a: Loc { reg: 8, mem: false, k: 0x1234 } }, 0x1234));
+        assert_eq!(fast(&mut cache, 7).0.op, FastOp::Exec);
+        assert_eq!(fast(&mut cache, 9).0, Fast { op: FastOp::Ife, cycles: 3, b: Loc { reg: 8, mem: false, k: 5 }, a: Loc { reg: 0, mem: false, k: 0 } });
+    }
+}
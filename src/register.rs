@@ -0,0 +1,48 @@
+// in a file of its own, so build.rs can take the assembler without the VM
+use std::fmt::{Display, Formatter, Error};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Register {
+    A = 0,
+    B,
+    C,
+    X,
+    Y,
+    Z,
+    I,
+    J,
+}
+
+impl Register {
+    pub fn from_str(s: &String) -> Option<Register> {
+        if s.len() > 1 || s.len() == 0 { return None }
+        let u = s.to_uppercase();
+        match u.as_str().as_bytes()[0] {
+            b'A' => Some(Register::A),
+            b'B' => Some(Register::B),
+            b'C' => Some(Register::C),
+            b'X' => Some(Register::X),
+            b'Y' => Some(Register::Y),
+            b'Z' => Some(Register::Z),
+            b'I' => Some(Register::I),
+            b'J' => Some(Register::J),
+            _ => None
+        }
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            Register::A => fmt.write_str("A"),
+            Register::B => fmt.write_str("B"),
+            Register::C => fmt.write_str("C"),
+            Register::X => fmt.write_str("X"),
+            Register::Y => fmt.write_str("Y"),
+            Register::Z => fmt.write_str("Z"),
+            Register::I => fmt.write_str("I"),
+            Register::J => fmt.write_str("J"),
+        }
+    }
+}
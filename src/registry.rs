@@ -0,0 +1,313 @@
+use std::fmt::{Debug, Formatter, Error as FmtError};
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use thiserror::Error;
+use hardware::{Hardware, Clock, Keyboard, KeyboardInput, M35fd, Hmd2043, Sped3, Sped3Frame,
+               Lem1802, Lem1802Screen, SerialPort, StreamBackend, MemoryBackend, Disk, MediaSlot, MediaError,
+               FLOPPY_SECTORS, SECTOR_SIZE};
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("There's no device called {}", .0)]
+    UnknownDevice(String),
+    #[error("A device called {} is already registered", .0)]
+    DuplicateDevice(String),
+    #[error("Invalid parameter {}: {}", .0, .1)]
+    InvalidParameter(String, #[source] serde_json::Error),
+    #[error("Couldn't open {}: {}", .0.display(), .1)]
+    Media(PathBuf, #[source] MediaError),
+    #[error("Couldn't open {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] ::std::io::Error),
+    #[error("{}", .0)]
+    Custom(String),
+}
+
+// what's left to get at a device once it's been boxed up and attached
+#[derive(Clone, Debug)]
+pub enum DeviceHandle {
+    None,
+    Keyboard(KeyboardInput),
+    Media(MediaSlot),
+    Sped3(Sped3Frame),
+    Lem1802(Lem1802Screen),
+    Serial(MemoryBackend),
+}
+
+// the parameters a device was given in a machine config, everything but its
+// type. paths are relative to the config they came from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceParams {
+    values: Map<String, Value>,
+    base: PathBuf,
+}
+
+impl DeviceParams {
+    pub fn new(values: Map<String, Value>) -> DeviceParams {
+        DeviceParams { values, base: PathBuf::new() }
+    }
+
+    pub fn base(mut self, base: &Path) -> Self {
+        self.base = base.to_owned();
+        self
+    }
+
+    pub fn values(&self) -> &Map<String, Value> {
+        &self.values
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, RegistryError> {
+        match self.values.get(key) {
+            Some(value) => T::deserialize(value)
+                .map(Some)
+                .map_err(|e| RegistryError::InvalidParameter(key.to_owned(), e)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> Result<T, RegistryError> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    pub fn path(&self, key: &str) -> Result<Option<PathBuf>, RegistryError> {
+        Ok(self.get::<PathBuf>(key)?.map(|p| self.base.join(p)))
+    }
+}
+
+pub struct DeviceInstance {
+    pub device: Box<dyn Hardware>,
+    pub handle: DeviceHandle,
+}
+
+impl DeviceInstance {
+    pub fn new(device: Box<dyn Hardware>) -> DeviceInstance {
+        DeviceInstance { device, handle: DeviceHandle::None }
+    }
+
+    pub fn handle(mut self, handle: DeviceHandle) -> Self {
+        self.handle = handle;
+        self
+    }
+}
+
+pub type DeviceFactory = Box<dyn Fn(&DeviceParams) -> Result<DeviceInstance, RegistryError>>;
+
+pub struct DeviceEntry {
+    pub name: String,
+    pub manufacturer: u32,
+    pub model: u32,
+    factory: DeviceFactory,
+}
+
+impl Debug for DeviceEntry {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), FmtError> {
+        fmt.write_fmt(format_args!("{} ({:08x}:{:08x})", self.name, self.manufacturer, self.model))
+    }
+}
+
+// the devices machine configs can name. out of tree crates register their
+// own next to the built in ones
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    entries: Vec<DeviceEntry>,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry::default()
+    }
+
+    pub fn with_builtins() -> DeviceRegistry {
+        let mut registry = DeviceRegistry::new();
+        for &(name, manufacturer, model, factory) in BUILTINS {
+            registry.register(name, manufacturer, model, factory)
+                .expect("built in devices have unique names");
+        }
+        registry
+    }
+
+    pub fn register<F>(&mut self, name: &str, manufacturer: u32, model: u32, factory: F)
+        -> Result<(), RegistryError>
+        where F: Fn(&DeviceParams) -> Result<DeviceInstance, RegistryError> + 'static {
+        if self.get(name).is_some() {
+            return Err(RegistryError::DuplicateDevice(name.to_owned()));
+        }
+        self.entries.push(DeviceEntry {
+            name: name.to_owned(),
+            manufacturer,
+            model,
+            factory: Box::new(factory),
+        });
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DeviceEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    pub fn find_by_id(&self, manufacturer: u32, model: u32) -> Option<&DeviceEntry> {
+        self.entries.iter().find(|e| e.manufacturer == manufacturer && e.model == model)
+    }
+
+    pub fn entries(&self) -> &[DeviceEntry] {
+        &self.entries
+    }
+
+    pub fn create(&self, name: &str, params: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+        match self.get(name) {
+            Some(entry) => (entry.factory)(params),
+            None => Err(RegistryError::UnknownDevice(name.to_owned()))
+        }
+    }
+}
+
+type Builtin = (&'static str, u32, u32, fn(&DeviceParams) -> Result<DeviceInstance, RegistryError>);
+
+const BUILTINS: &[Builtin] = &[
+    ("clock", 0x904b3115, 0x12d0b402, clock),
+    ("keyboard", 0x904b3115, 0x30cf7406, keyboard),
+    ("m35fd", 0x1eb37e91, 0x4fd524c5, m35fd),
+    ("hmd2043", 0x21544948, 0x74fa4cae, hmd2043),
+    ("sped3", 0x1eb37e91, 0x42babf3c, sped3),
+    ("lem1802", 0x1c6c8b36, 0x7349f615, lem1802),
+    ("serial", 0x4d44454b, 0x55415254, serial),
+];
+
+fn clock(_: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    Ok(DeviceInstance::new(Box::new(Clock::new())))
+}
+
+// script: typed in one key every `interval` cycles
+fn keyboard(params: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    let mut keyboard = Keyboard::new();
+    if let Some(script) = params.get::<String>("script")? {
+        keyboard = keyboard.script(&script, params.get_or("interval", 1000)?);
+    }
+    let input = keyboard.input();
+    Ok(DeviceInstance::new(Box::new(keyboard)).handle(DeviceHandle::Keyboard(input)))
+}
+
+// disk: image file, left out to start with the drive empty
+fn media(params: &DeviceParams) -> Result<MediaSlot, RegistryError> {
+    let write_protect = params.get_or("write_protect", false)?;
+    Ok(match params.path("disk")? {
+        Some(path) => {
+            let disk = Disk::open(&path, FLOPPY_SECTORS, SECTOR_SIZE, write_protect)
+                .map_err(|e| RegistryError::Media(path, e))?;
+            MediaSlot::with(disk)
+        },
+        None => MediaSlot::new(),
+    })
+}
+
+fn m35fd(params: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    let slot = media(params)?;
+    Ok(DeviceInstance::new(Box::new(M35fd::new(slot.clone()))).handle(DeviceHandle::Media(slot)))
+}
+
+fn hmd2043(params: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    let slot = media(params)?;
+    Ok(DeviceInstance::new(Box::new(Hmd2043::new(slot.clone()))).handle(DeviceHandle::Media(slot)))
+}
+
+fn sped3(_: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    let sped = Sped3::new();
+    let frame = sped.frame();
+    Ok(DeviceInstance::new(Box::new(sped)).handle(DeviceHandle::Sped3(frame)))
+}
+
+fn lem1802(_: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    let lem = Lem1802::new();
+    let screen = lem.screen();
+    Ok(DeviceInstance::new(Box::new(lem)).handle(DeviceHandle::Lem1802(screen)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SerialBackendParam {
+    Stdio,
+    Memory,
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+// backend: "stdio", "memory" or { unix = "/path/to/socket" }
+fn serial(params: &DeviceParams) -> Result<DeviceInstance, RegistryError> {
+    Ok(match params.get_or("backend", SerialBackendParam::Stdio)? {
+        SerialBackendParam::Stdio => {
+            DeviceInstance::new(Box::new(SerialPort::new(Box::new(StreamBackend::stdio()))))
+        },
+        SerialBackendParam::Memory => {
+            let memory = MemoryBackend::new();
+            DeviceInstance::new(Box::new(SerialPort::new(Box::new(memory.clone()))))
+                .handle(DeviceHandle::Serial(memory))
+        },
+        #[cfg(unix)]
+        SerialBackendParam::Unix(path) => {
+            let path = params.base.join(path);
+            let backend = StreamBackend::unix_socket(&path)
+                .map_err(|e| RegistryError::Io(path, e))?;
+            DeviceInstance::new(Box::new(SerialPort::new(Box::new(backend))))
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hardware::{HardwareInfo};
+    use virtual_machine::VMExposed;
+    use serde_json::json;
+
+    struct Toaster {
+        info: HardwareInfo,
+    }
+
+    impl Hardware for Toaster {
+        fn info(&self) -> &HardwareInfo { &self.info }
+        fn hardware_interrupt(&mut self, _vm: &mut VMExposed) -> usize { 0 }
+        fn update(&mut self, _vm: &mut VMExposed) {}
+        fn debug_dump_state(&self, _fmt: &mut Formatter) -> Result<(), FmtError> { Ok(()) }
+    }
+
+    fn params(value: Value) -> DeviceParams {
+        match value {
+            Value::Object(map) => DeviceParams::new(map),
+            _ => unreachable!()
+        }
+    }
+
+    #[test]
+    fn third_party_devices() {
+        let mut registry = DeviceRegistry::with_builtins();
+        registry.register("toaster", 0xcafe, 0xb0a7, |params: &DeviceParams| {
+            let slices: u32 = params.get_or("slices", 2)?;
+            Ok(DeviceInstance::new(Box::new(Toaster {
+                info: HardwareInfo { manufacturer: 0xcafe, model: 0xb0a7, version: slices as u16 },
+            })))
+        }).unwrap();
+        assert!(registry.register("toaster", 0, 0, clock).is_err());
+
+        let toaster = registry.create("toaster", &params(json!({ "slices": 4 }))).unwrap();
+        assert_eq!(toaster.device.info().version, 4);
+        assert!(registry.create("toaster", &params(json!({ "slices": "many" }))).is_err());
+        assert_eq!(registry.find_by_id(0xcafe, 0xb0a7).unwrap().name, "toaster");
+        assert_eq!(registry.find_by_id(0x1eb37e91, 0x4fd524c5).unwrap().name, "m35fd");
+        match registry.create("blender", &DeviceParams::default()) {
+            Err(RegistryError::UnknownDevice(name)) => assert_eq!(name, "blender"),
+            _ => panic!("expected an unknown device")
+        }
+    }
+
+    #[test]
+    fn builtin_ids_match_the_devices() {
+        let registry = DeviceRegistry::with_builtins();
+        let params = params(json!({ "backend": "memory" }));
+        for entry in registry.entries() {
+            let instance = registry.create(&entry.name, &params).unwrap();
+            let info = instance.device.info();
+            assert_eq!((info.manufacturer, info.model), (entry.manufacturer, entry.model), "{}", entry.name);
+        }
+    }
+}
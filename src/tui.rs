@@ -0,0 +1,376 @@
+// a terminal front end for a machine: registers, code, memory, the stack,
+// devices and the LEM1802 all on one screen. keys the UI doesn't use itself
+// are typed into the keyboard
+use std::io;
+use std::time::Duration;
+use ratatui::{Terminal, Frame};
+use ratatui::backend::Backend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use config::Machine;
+use registry::{DeviceRegistry, DeviceHandle};
+use hardware::{KeyboardInput, Lem1802Screen, key_for_char, LEM1802_WIDTH,
+               KEY_BACKSPACE, KEY_RETURN, KEY_INSERT, KEY_DELETE, KEY_UP, KEY_DOWN, KEY_LEFT, KEY_RIGHT};
+use virtual_machine::{VirtualMachine, Register, StopReason};
+use disassemble::{disassm_one, pseudo};
+use mem_iterator::MemIterator;
+
+const FRAME: Duration = Duration::from_millis(16);
+const FRAMES_PER_SECOND: usize = 60;
+const HEX_COLUMNS: usize = 8;
+const HELP: &str = "F5 run/pause  F10 step  F2 pseudo-ops  PgUp/PgDn memory  Home memory at PC  Ctrl-Q quit";
+
+pub struct Tui {
+    machine: Machine,
+    names: Vec<String>,
+    keyboard: Option<KeyboardInput>,
+    screen: Option<Lem1802Screen>,
+    running: bool,
+    memory: u16,
+    // show JMP, RET and so on in the code
+    pseudo: bool,
+    status: String,
+}
+
+fn lem_color(color: u16) -> Color {
+    let channel = |shift: u16| ((color >> shift) & 0xf) as u8 * 17;
+    Color::Rgb(channel(8), channel(4), channel(0))
+}
+
+impl Tui {
+    pub fn new(mut machine: Machine, registry: &DeviceRegistry) -> Tui {
+        let names = (0..machine.vm.hardware_count())
+            .map(|i| {
+                let info = machine.vm.hardware(i).unwrap().info();
+                registry.find_by_id(info.manufacturer, info.model)
+                    .map(|e| e.name.clone())
+                    .unwrap_or_else(|| "?".to_owned())
+            })
+            .collect();
+        let keyboard = machine.devices().iter().filter_map(|d| match *d {
+            DeviceHandle::Keyboard(ref input) => Some(input.clone()),
+            _ => None
+        }).next();
+        let screen = machine.devices().iter().filter_map(|d| match *d {
+            DeviceHandle::Lem1802(ref screen) => Some(screen.clone()),
+            _ => None
+        }).next();
+        let memory = *machine.vm.get_pc();
+        Tui {
+            machine,
+            names,
+            keyboard,
+            screen,
+            running: false,
+            memory,
+            pseudo: false,
+            status: "paused".to_owned(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // takes over the terminal until the user quits
+    pub fn run(mut self) -> io::Result<Machine> {
+        let mut terminal = ratatui::init();
+        let result = self.event_loop(&mut terminal);
+        ratatui::restore();
+        result.map(|_| self.machine)
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        loop {
+            terminal.draw(|f| self.draw(f))?;
+            if event::poll(FRAME)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+            if self.running {
+                self.run_frame();
+            }
+        }
+    }
+
+    // returns false when it's time to quit
+    pub fn key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::F(5) => {
+                self.running = !self.running;
+                self.status = if self.running { "running" } else { "paused" }.to_owned();
+            },
+            KeyCode::F(10) if !self.running => self.step(),
+            KeyCode::F(2) => self.pseudo = !self.pseudo,
+            KeyCode::PageUp => self.memory = self.memory.wrapping_sub(0x40),
+            KeyCode::PageDown => self.memory = self.memory.wrapping_add(0x40),
+            KeyCode::Home => self.memory = *self.machine.vm.get_pc(),
+            code => {
+                let key = match code {
+                    KeyCode::Char(c) => key_for_char(c),
+                    KeyCode::Enter => Some(KEY_RETURN),
+                    KeyCode::Backspace => Some(KEY_BACKSPACE),
+                    KeyCode::Insert => Some(KEY_INSERT),
+                    KeyCode::Delete => Some(KEY_DELETE),
+                    KeyCode::Up => Some(KEY_UP),
+                    KeyCode::Down => Some(KEY_DOWN),
+                    KeyCode::Left => Some(KEY_LEFT),
+                    KeyCode::Right => Some(KEY_RIGHT),
+                    _ => None
+                };
+                if let (Some(key), Some(keyboard)) = (key, self.keyboard.as_ref()) {
+                    keyboard.type_key(key);
+                }
+            }
+        }
+        true
+    }
+
+    pub fn step(&mut self) {
+        let vm = &mut self.machine.vm;
+        self.status = match vm.step() {
+            Ok(cycles) => {
+                vm.update_hardware();
+                format!("stepped, {} cycles", cycles)
+            },
+            Err(e) => format!("error: {}", e),
+        };
+    }
+
+    // runs as many cycles as the clock rate gets through in a frame
+    pub fn run_frame(&mut self) {
+        let vm = &mut self.machine.vm;
+        let mut limit = vm.get_cycles() + vm.get_clock_rate() / FRAMES_PER_SECOND;
+        if let Some(cycle_limit) = self.machine.cycle_limit {
+            limit = limit.min(cycle_limit);
+        }
+        match vm.run(&self.machine.breakpoints, Some(limit)) {
+            // just the end of the frame
+            Ok(StopReason::CycleLimit) if self.machine.cycle_limit.is_none_or(|l| vm.get_cycles() < l) => {},
+            Ok(reason) => {
+                self.running = false;
+                self.status = format!("stopped: {}", reason);
+            },
+            Err(e) => {
+                self.running = false;
+                self.status = format!("error: {}", e);
+            }
+        }
+    }
+
+    pub fn draw(&mut self, f: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(f.area());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(44), Constraint::Min(0)])
+            .split(rows[0]);
+        let left = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(6), Constraint::Min(6), Constraint::Length(10)])
+            .split(columns[0]);
+        let right = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(14), Constraint::Min(4), Constraint::Length(6)])
+            .split(columns[1]);
+
+        self.draw_registers(f, left[0]);
+        self.draw_code(f, left[1]);
+        self.draw_stack(f, left[2]);
+        self.draw_screen(f, right[0]);
+        self.draw_memory(f, right[1]);
+        self.draw_devices(f, right[2]);
+
+        let status = format!(" {} | {}", self.status, HELP);
+        f.render_widget(Paragraph::new(status).style(Style::default().add_modifier(Modifier::REVERSED)), rows[1]);
+    }
+
+    fn draw_registers(&mut self, f: &mut Frame, area: Rect) {
+        let vm = &mut self.machine.vm;
+        let regs = [Register::A, Register::B, Register::C, Register::X,
+                    Register::Y, Register::Z, Register::I, Register::J];
+        let values: Vec<u16> = regs.iter().map(|r| vm.get_registers()[*r as usize]).collect();
+        let line = |range: ::std::ops::Range<usize>| {
+            Line::from(range.map(|i| format!("{}: {:04x}  ", regs[i], values[i])).collect::<String>())
+        };
+        let (pc, sp, ex, ia) = (*vm.get_pc(), *vm.get_sp(), *vm.get_ex(), *vm.get_ia());
+        let lines = vec![
+            line(0..4),
+            line(4..8),
+            Line::from(format!("PC: {:04x}  SP: {:04x}  EX: {:04x}  IA: {:04x}", pc, sp, ex, ia)),
+            Line::from(format!("cycles: {}  queued: {}", vm.get_cycles(), vm.interrupts().pending_count())),
+        ];
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Registers")), area);
+    }
+
+    fn draw_code(&mut self, f: &mut Frame, area: Rect) {
+        let vm = &mut self.machine.vm;
+        let pc = *vm.get_pc();
+        let lines = disassemble(vm, pc, area.height.saturating_sub(2) as usize, self.pseudo).into_iter()
+            .map(|(addr, text)| {
+                let marker = if self.machine.breakpoints.contains(&addr) { '*' } else { ' ' };
+                let line = Line::from(format!("{}{:04x}  {}", marker, addr, text));
+                if addr == pc { line.style(Style::default().add_modifier(Modifier::REVERSED)) } else { line }
+            })
+            .collect::<Vec<_>>();
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Code")), area);
+    }
+
+    fn draw_stack(&mut self, f: &mut Frame, area: Rect) {
+        let vm = &mut self.machine.vm;
+        let sp = *vm.get_sp() as usize;
+        // SP 0 means nothing's been pushed yet
+        let depth = if sp == 0 { 0 } else { 0x10000 - sp };
+        let lines = (0..depth.min(area.height.saturating_sub(2) as usize))
+            .map(|i| Line::from(format!("{:04x}: {:04x}", sp + i, vm.ram()[sp + i])))
+            .collect::<Vec<_>>();
+        let title = format!("Stack ({})", depth);
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn draw_memory(&mut self, f: &mut Frame, area: Rect) {
+        let memory = self.memory as usize;
+        let ram = self.machine.vm.ram();
+        let lines = (0..area.height.saturating_sub(2) as usize)
+            .map(|row| {
+                let start = (memory + row * HEX_COLUMNS) & 0xffff;
+                let words = (0..HEX_COLUMNS)
+                    .map(|i| format!(" {:04x}", ram[(start + i) & 0xffff]))
+                    .collect::<String>();
+                Line::from(format!("{:04x}:{}", start, words))
+            })
+            .collect::<Vec<_>>();
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Memory")), area);
+    }
+
+    fn draw_devices(&mut self, f: &mut Frame, area: Rect) {
+        let vm = &self.machine.vm;
+        let lines = self.names.iter().enumerate()
+            .filter_map(|(i, name)| vm.hardware(i).map(|hw| (i, name, hw.info())))
+            .map(|(i, name, info)| Line::from(format!("{}: {:<8} {:08x} {:08x} v{:04x}",
+                                                      i, name, info.manufacturer, info.model, info.version)))
+            .collect::<Vec<_>>();
+        f.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Devices")), area);
+    }
+
+    fn draw_screen(&mut self, f: &mut Frame, area: Rect) {
+        let screen = match self.screen {
+            Some(ref screen) if screen.is_connected() => screen,
+            _ => {
+                let block = Block::default().borders(Borders::ALL).title("LEM1802");
+                f.render_widget(Paragraph::new("no signal").block(block), area);
+                return;
+            }
+        };
+        let palette = screen.palette();
+        let blink = screen.blink();
+        let lines = screen.cells().chunks(LEM1802_WIDTH)
+            .map(|row| {
+                Line::from(row.iter().map(|&cell| {
+                    let c = (cell & 0x7f) as u8;
+                    let hidden = cell & 0x80 != 0 && blink;
+                    let c = if (0x20..0x7f).contains(&c) && !hidden { c as char } else { ' ' };
+                    Span::styled(c.to_string(), Style::default()
+                        .fg(lem_color(palette[(cell >> 12) as usize]))
+                        .bg(lem_color(palette[((cell >> 8) & 0xf) as usize])))
+                }).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        let block = Block::default().borders(Borders::ALL).title("LEM1802")
+            .border_style(Style::default().fg(lem_color(screen.border())));
+        f.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+// `count` instructions starting at `pc`, with anything that won't decode
+// shown as a DAT
+fn disassemble(vm: &VirtualMachine, pc: u16, count: usize, pseudo_ops: bool) -> Vec<(u16, String)> {
+    let ram = vm.ram();
+    let mut addr = pc;
+    let mut lines = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut itr = MemIterator::new(ram, addr as usize, 0xffff).peekable();
+        let inst = *itr.next().unwrap();
+        let (text, words) = match disassm_one(inst, &mut itr) {
+            Ok((op, words)) if pseudo_ops => (pseudo(&op), words + 1),
+            Ok((op, words)) => (op.to_string(), words + 1),
+            Err(_) => (format!("DAT {:#06x}", inst), 1),
+        };
+        lines.push((addr, text));
+        addr = addr.wrapping_add(words as u16);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::MachineConfig;
+    use ratatui::backend::TestBackend;
+
+    fn contents(terminal: &Terminal<TestBackend>) -> String {
+        let buffer = terminal.backend().buffer();
+        let width = buffer.area.width as usize;
+        buffer.content.chunks(width)
+            .map(|row| row.iter().map(|c| c.symbol()).collect::<String>() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn draws_the_machine() {
+        let config = MachineConfig::from_toml(r#"
+            breakpoints = [4]
+
+            [[devices]]
+            type = "lem1802"
+
+            [[devices]]
+            type = "keyboard"
+        "#).unwrap();
+        let registry = DeviceRegistry::with_builtins();
+        let mut machine = config.build_with(&registry).unwrap();
+        // SET A, 0; SET B, 0x8000; HWI 0; SUB PC, 1
+        let program = [0x8401, 0x7c21, 0x8000, 0x8640, 0x8b83];
+        machine.vm.get_ram()[..program.len()].copy_from_slice(&program);
+        machine.vm.get_ram()[0x8000] = 0xf000 | b'o' as u16;
+        machine.vm.get_ram()[0x8001] = 0xf000 | b'k' as u16;
+        let mut tui = Tui::new(machine, &registry);
+
+        tui.key(KeyEvent::from(KeyCode::F(5)));
+        assert!(tui.is_running());
+        tui.run_frame();
+        assert!(!tui.is_running());
+        // the screen only refreshes every frame, so give it one
+        tui.machine.vm.run(&Default::default(), Some(2000)).unwrap();
+        tui.key(KeyEvent::from(KeyCode::F(10)));
+
+        tui.key(KeyEvent::from(KeyCode::Char('h')));
+        match tui.machine().device(1) {
+            Some(DeviceHandle::Keyboard(keys)) => assert_eq!(keys.buffered(), 1),
+            other => panic!("expected a keyboard, got {:?}", other)
+        }
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 40)).unwrap();
+        terminal.draw(|f| tui.draw(f)).unwrap();
+        let text = contents(&terminal);
+        assert!(text.contains("stepped"), "{}", text);
+        assert!(text.contains("PC: 0004"), "{}", text);
+        assert!(text.contains("0004  SUB PC, 0x1"), "{}", text);
+        assert!(text.contains("│ok"), "{}", text);
+        assert!(text.contains("lem1802  1c6c8b36 7349f615"), "{}", text);
+        assert!(text.contains("0000: 8401 7c21 8000 8640 8b83"), "{}", text);
+    }
+}
@@ -1,10 +1,16 @@
 use std::fmt::{Display, Formatter, Error};
-use opcodes::{Opcode, Operand};
-use disassemble::{disassm_one, DcpuDisassmError};
+use std::collections::BTreeSet;
+use opcodes::{Opcode, Operand, HLT, BRK};
+use disassemble::{disassm_one, next_words, is_conditional, DcpuDisassmError};
 use mem_iterator::MemIterator;
-use hardware::{Hardware};
+use predecode::{Arg, Cache, MicroOp, Op, Loc, FastOp};
+use image::Image;
+use hardware::{Hardware, DeviceContext, DeviceEvents, DeviceState, StateError};
+use interrupts::{InterruptController, FirePolicy};
 use thiserror::Error;
 
+pub use register::Register;
+
 #[derive(Debug, Error)]
 pub enum DcpuVMError {
     #[error("Invalid operand A. Cannot put PUSH there.")]
@@ -17,65 +23,69 @@ pub enum DcpuVMError {
     OutOfBoundsMemory,
     #[error("Shit's on fire, yo!")]
     OnFire,
+    #[error("Can't attach more than 65535 devices")]
+    TooMuchHardware,
     #[error("Someone passed in an empty iterator!")]
     EmptyIterator,
     #[error("Disassembly error: {}", .0)]
-    DisassemblyFailed(#[from]DcpuDisassmError)
+    DisassemblyFailed(#[from]DcpuDisassmError),
+    #[error("{} words don't fit at {:#06x}", .0, .1)]
+    SegmentOverflow(usize, u16),
+    #[error("The clock rate can't be 0")]
+    ZeroClockRate,
 }
 
-#[repr(C)]
+// what loading does with words that would go past 0xffff
 #[derive(Copy, Clone, Debug, PartialEq)]
-pub enum Register {
-    A = 0,
-    B,
-    C,
-    X,
-    Y,
-    Z,
-    I,
-    J,
+pub enum Overflow {
+    // carry on from 0
+    Wrap,
+    // load nothing and give a SegmentOverflow
+    Error,
 }
 
-impl Register {
-    pub fn from_str(s: &String) -> Option<Register> {
-        if s.len() > 1 || s.len() == 0 { return None }
-        let u = s.to_uppercase();
-        match u.as_str().as_bytes()[0] {
-            b'A' => Some(Register::A),
-            b'B' => Some(Register::B),
-            b'C' => Some(Register::C),
-            b'X' => Some(Register::X),
-            b'Y' => Some(Register::Y),
-            b'Z' => Some(Register::Z),
-            b'I' => Some(Register::I),
-            b'J' => Some(Register::J),
-            _ => None
-        }
+fn check_fits(origin: u16, len: usize, overflow: Overflow) -> Result<(), DcpuVMError> {
+    if len > 0x10000 || (origin as usize + len > 0x10000 && overflow == Overflow::Error) {
+        return Err(DcpuVMError::SegmentOverflow(len, origin));
     }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    CycleLimit,
+    // a device asked the VM to stop, by its index
+    Device(usize),
+    // got to a HLT or BRK, at this address
+    Halted(u16),
+    Break(u16),
+    // caught fire under FirePolicy::Halt, so it won't run anything again
+    OnFire,
 }
 
-impl Display for Register {
+impl Display for StopReason {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         match *self {
-            Register::A => fmt.write_str("A"),
-            Register::B => fmt.write_str("B"),
-            Register::C => fmt.write_str("C"),
-            Register::X => fmt.write_str("X"),
-            Register::Y => fmt.write_str("Y"),
-            Register::Z => fmt.write_str("Z"),
-            Register::I => fmt.write_str("I"),
-            Register::J => fmt.write_str("J"),
+            StopReason::Breakpoint(pc) => fmt.write_fmt(format_args!("breakpoint at {:#06x}", pc)),
+            StopReason::CycleLimit => fmt.write_str("cycle limit reached"),
+            StopReason::Device(index) => fmt.write_fmt(format_args!("stopped by device {}", index)),
+            StopReason::Halted(pc) => fmt.write_fmt(format_args!("halted at {:#06x}", pc)),
+            StopReason::Break(pc) => fmt.write_fmt(format_args!("BRK at {:#06x}", pc)),
+            StopReason::OnFire => fmt.write_str("on fire"),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct VMExposed {
-    registers: Box<[u16]>,
-    ram: Box<Vec<u16>>,
-    interrupts: Vec<u16>,
+    registers: [u16; 8],
+    ram: Vec<u16>,
+    interrupts: InterruptController,
     cycles: usize,
     clock_rate: usize,
+    // instructions decoded from ram, which has to hear about every write
+    cache: Cache,
 }
 
 #[derive(Debug)]
@@ -83,13 +93,17 @@ pub struct VirtualMachine {
     exposed: VMExposed,
     pc: u16,
     sp: u16,
-    ia: u16,
     ex: u16,
     dead_zone: u16, //where writing to literals goes to die
-    hardware: Vec<Box<dyn Hardware>>,
-    in_interrupt: bool,
-    iaq: bool,
-    on_fire: bool
+    hardware: Vec<Attached>,
+    events: DeviceEvents,
+    predecode: bool,
+}
+
+#[derive(Debug)]
+struct Attached {
+    device: Box<dyn Hardware>,
+    last_tick: usize,
 }
 
 fn rollover_inc(i: u16) -> u16 {
@@ -107,27 +121,27 @@ impl<'r> VirtualMachine {
     pub fn new() -> Self {
         VirtualMachine{
             exposed: VMExposed {
-                registers: vec![0u16; 8].into_boxed_slice(),
-                ram: Box::<Vec<u16>>::new(vec![0u16; 65536]),
-                interrupts: Vec::<u16>::new(),
+                registers: [0u16; 8],
+                ram: vec![0u16; 65536],
+                interrupts: InterruptController::new(),
                 cycles: 0,
                 clock_rate: 100000, // default to 100KHz
+                cache: Cache::default(),
             },
             pc: 0,
             sp: 0,
-            ia: 0,
             ex: 0,
             dead_zone: 0,
-            hardware: Vec::<Box<dyn Hardware>>::new(),
-            iaq: false,
-            in_interrupt: false,
-            on_fire: false
+            hardware: Vec::new(),
+            events: DeviceEvents::default(),
+            predecode: true,
         }
     }
 
     fn push_stack(&mut self, data: u16) {
         self.sp = rollover_dec(self.sp);
         self.exposed.ram[self.sp as usize] = data;
+        self.exposed.cache.invalidate(self.sp);
     }
 
     fn pop_stack(&mut self) -> u16 {
@@ -136,41 +150,40 @@ impl<'r> VirtualMachine {
         ret
     }
 
-    fn resolve_memory_read(&mut self, op: &Operand) -> Result<(u16, usize), DcpuVMError> {
+    fn resolve_memory_read(&mut self, op: &Operand) -> Result<u16, DcpuVMError> {
         match *op {
-            Operand::Register(reg) => Ok(((*(self.exposed.registers))[reg as usize], 0)),
+            Operand::Register(reg) => Ok(self.exposed.registers[reg as usize]),
             Operand::RegisterDeref(reg) => {
-                let addr = (*(self.exposed.registers))[reg as usize];
-                Ok(((*(self.exposed.ram))[addr as usize], 0))
+                let addr = self.exposed.registers[reg as usize];
+                Ok(self.exposed.ram[addr as usize])
             },
             Operand::RegisterPlusDeref(reg, plus) => {
-                let addr = (*(self.exposed.registers))[reg as usize] + plus;
-                Ok(((*(self.exposed.ram))[addr as usize], 1))
+                let addr = self.exposed.registers[reg as usize].wrapping_add(plus);
+                Ok(self.exposed.ram[addr as usize])
             },
             Operand::Peek => {
-                Ok((self.exposed.ram[self.sp as usize], 0))
+                Ok(self.exposed.ram[self.sp as usize])
             },
             Operand::Pick(n) => {
-                Ok((self.exposed.ram[((self.sp + n) & 0xFFFF) as usize], 1))
+                Ok(self.exposed.ram[self.sp.wrapping_add(n) as usize])
             },
             Operand::Pc => {
-                Ok((self.pc, 0))
+                Ok(self.pc)
             },
             Operand::Sp => {
-                Ok((self.sp, 0))
+                Ok(self.sp)
             },
             Operand::Ex => {
-                Ok((self.ex, 0))
+                Ok(self.ex)
             },
             Operand::LiteralDeref(n) => {
-                Ok(((*(self.exposed.ram))[n as usize], 1))
+                Ok(self.exposed.ram[n as usize])
             },
             Operand::Literal(n) => {
-                Ok((n, 1))
+                Ok(n)
             },
             Operand::Pop => {
-                let n = self.pop_stack();
-                Ok((n, 0))
+                Ok(self.pop_stack())
             },
             Operand::Push => {
                 Err(DcpuVMError::PushInAOp)
@@ -179,96 +192,172 @@ impl<'r> VirtualMachine {
         }
     }
 
-    fn resolve_memory_write(&'r mut self, op: &Operand) -> Result<(&'r mut u16, usize), DcpuVMError> {
+    fn resolve_memory_write(&'r mut self, op: &Operand) -> Result<&'r mut u16, DcpuVMError> {
         match *op {
-            Operand::Register(reg) => Ok((&mut (*(self.exposed.registers))[reg as usize], 0)),
+            Operand::Register(reg) => Ok(&mut self.exposed.registers[reg as usize]),
             Operand::RegisterDeref(reg) => {
-                let addr = (*(self.exposed.registers))[reg as usize];
-                Ok((&mut(*(self.exposed.ram))[addr as usize], 0))
+                let addr = self.exposed.registers[reg as usize];
+                Ok(&mut self.exposed.ram[addr as usize])
             },
             Operand::RegisterPlusDeref(reg, plus) => {
-                let addr = (*(self.exposed.registers))[reg as usize] + plus;
-                Ok((&mut (*(self.exposed.ram))[addr as usize], 1))
+                let addr = self.exposed.registers[reg as usize].wrapping_add(plus);
+                Ok(&mut self.exposed.ram[addr as usize])
             },
             Operand::Peek => {
-                Ok((&mut self.exposed.ram[self.sp as usize], 0))
+                Ok(&mut self.exposed.ram[self.sp as usize])
             },
             Operand::Pick(n) => {
-                Ok((&mut self.exposed.ram[((self.sp as u32 + n as u32) & 0xFFFF) as usize], 1))
+                Ok(&mut self.exposed.ram[self.sp.wrapping_add(n) as usize])
             },
             Operand::Pc => {
-                Ok((&mut self.pc, 0))
+                Ok(&mut self.pc)
             },
             Operand::Sp => {
-                Ok((&mut self.sp, 0))
+                Ok(&mut self.sp)
             },
             Operand::Ex => {
-                Ok((&mut self.ex, 0))
+                Ok(&mut self.ex)
             },
             Operand::LiteralDeref(n) => {
-                Ok((&mut (*(self.exposed.ram))[n as usize], 1))
+                Ok(&mut self.exposed.ram[n as usize])
             },
-            Operand::Literal(_) => {
-                Ok((&mut self.dead_zone, 1))
+            Operand::Literal(n) => {
+                // reads through b still need to see the literal
+                self.dead_zone = n;
+                Ok(&mut self.dead_zone)
             },
             Operand::Pop => {
                 Err(DcpuVMError::PopInBOp)
             },
             Operand::Push => {
-                self.sp = ((self.sp as u32 - 1) & 0xFFFF) as u16;
-                let ret = &mut self.exposed.ram[self.sp as usize];
-                Ok((ret, 0))
+                self.sp = rollover_dec(self.sp);
+                Ok(&mut self.exposed.ram[self.sp as usize])
             },
             _ => unreachable!()
         }
     }
 
-    fn skip(&'r mut self, off: usize) -> Result<(usize, usize), DcpuVMError> {
-        let mut skipped:usize = 0;
-        let mut count:usize = 0;
-        let mut itr = MemIterator::new(&*self.exposed.ram, off + self.pc as usize, 0xFFFF).peekable();
+    // a is always resolved before b
+    fn resolve_operands(&mut self, b: &Operand, a: &Operand) -> Result<(u16, u16), DcpuVMError> {
+        let src = self.resolve_memory_read(a)?;
+        let dst = *self.resolve_memory_write(b)?;
+        Ok((dst, src))
+    }
 
-        loop {
-            let inst = match itr.next() {
-                Some(i) => *i,
-                None => return Err(DcpuVMError::EmptyIterator)
-            };
-            let o = inst & 0x1f;
-            let (_, c) = disassm_one(inst, &mut itr)?;
-            count += c + 1;
-            skipped += 1;
-            if o < 0x10 && o > 0x17 { break; }
-        }
+    #[inline(always)]
+    fn read_arg(&mut self, arg: Arg) -> Result<u16, DcpuVMError> {
+        let regs = &self.exposed.registers;
+        let ram = &mut self.exposed.ram;
+        Ok(match arg {
+            Arg::Reg(reg) => regs[reg as usize],
+            Arg::Deref(reg) => ram[regs[reg as usize] as usize],
+            Arg::Indexed(reg, plus) => ram[regs[reg as usize].wrapping_add(plus) as usize],
+            Arg::Peek => ram[self.sp as usize],
+            Arg::Pick(n) => ram[self.sp.wrapping_add(n) as usize],
+            Arg::Pc => self.pc,
+            Arg::Sp => self.sp,
+            Arg::Ex => self.ex,
+            Arg::Mem(n) => ram[n as usize],
+            Arg::Lit(n) => n,
+            Arg::Pop => self.pop_stack(),
+            Arg::Push => return Err(DcpuVMError::PushInAOp),
+        })
+    }
+
+    // the word an instruction writes to. memory it hands out is taken to
+    // have been written
+    #[inline(always)]
+    fn write_arg(&mut self, arg: Arg) -> Result<&mut u16, DcpuVMError> {
+        let regs = &mut self.exposed.registers;
+        let address = match arg {
+            Arg::Reg(reg) => return Ok(&mut regs[reg as usize]),
+            Arg::Pc => return Ok(&mut self.pc),
+            Arg::Sp => return Ok(&mut self.sp),
+            Arg::Ex => return Ok(&mut self.ex),
+            Arg::Lit(n) => {
+                self.dead_zone = n;
+                return Ok(&mut self.dead_zone);
+            },
+            Arg::Pop => return Err(DcpuVMError::PopInBOp),
+            Arg::Deref(reg) => regs[reg as usize],
+            Arg::Indexed(reg, plus) => regs[reg as usize].wrapping_add(plus),
+            Arg::Peek => self.sp,
+            Arg::Pick(n) => self.sp.wrapping_add(n),
+            Arg::Mem(n) => n,
+            Arg::Push => {
+                self.sp = rollover_dec(self.sp);
+                self.sp
+            },
+        };
+        self.exposed.cache.invalidate(address);
+        Ok(&mut self.exposed.ram[address as usize])
+    }
 
-        Ok((skipped, count))
+    // a is always read before b. b is only written to if it's PUSH
+    #[inline(always)]
+    fn read_args(&mut self, b: Arg, a: Arg) -> Result<(u16, u16), DcpuVMError> {
+        let src = self.read_arg(a)?;
+        let dst = match b {
+            Arg::Push => *self.write_arg(b)?,
+            b => self.read_arg(b)?,
+        };
+        Ok((dst, src))
     }
 
-    fn handle_interrupts(&'r mut self) -> Result<usize, DcpuVMError> {
-        if self.ia == 0  || self.iaq {
-            return Ok(0)
-        }
+    // skips the instruction at PC, carrying on through chained conditionals.
+    // returns the extra cycles taken: one for failing plus one per skipped IF
+    fn skip(&mut self) -> usize {
+        let mut cycles:usize = 1;
 
-        if self.on_fire {
-            return Err(DcpuVMError::OnFire)
+        for _ in 0..0x10000 {
+            let inst = self.exposed.ram[self.pc as usize];
+            self.pc = self.pc.wrapping_add(next_words(inst) as u16 + 1);
+            if !is_conditional(inst) { break; }
+            cycles += 1;
         }
 
-        if self.exposed.interrupts.len() == 0 {
-            return Ok(0)
+        cycles
+    }
+
+    fn branch(&mut self, pass: bool) -> usize {
+        if pass {
+            return 0
         }
+        self.skip()
+    }
+
+    // at most one interrupt is triggered between instructions
+    #[inline]
+    fn handle_interrupts(&'r mut self) {
+        let int = match self.exposed.interrupts.trigger() {
+            Some(int) => int,
+            None => return
+        };
 
-        let int = self.exposed.interrupts.remove(0);
         let a = self.exposed.registers[Register::A as usize];
         let pc = self.pc;
         self.push_stack(pc);
         self.push_stack(a);
-        self.pc = self.ia;
+        self.pc = self.exposed.interrupts.ia();
         self.exposed.registers[Register::A as usize] = int;
-        self.in_interrupt = true;
-        self.step()
+    }
+
+    // returns Some when the fire stops the step from running at all
+    fn burn(&'r mut self) -> Option<Result<usize, DcpuVMError>> {
+        match self.exposed.interrupts.policy() {
+            FirePolicy::Error => Some(Err(DcpuVMError::OnFire)),
+            FirePolicy::Halt => Some(Ok(0)),
+            FirePolicy::CorruptMemory => {
+                let r = self.exposed.interrupts.next_random();
+                self.exposed.ram[(r & 0xFFFF) as usize] ^= (r >> 16) as u16 | 1;
+                self.exposed.cache.invalidate(r as u16);
+                None
+            }
+        }
     }
 
     fn get_instruction(&'r mut self) -> Result<(Opcode, usize), DcpuVMError> {
-        let mut itr = MemIterator::new(&*self.exposed.ram, self.pc as usize, 0xFFFF).peekable();
+        let mut itr = MemIterator::new(&self.exposed.ram, self.pc as usize, 0xFFFF).peekable();
         let inst = match itr.next() {
             Some(i) => *i,
             None => return Err(DcpuVMError::EmptyIterator)
@@ -276,433 +365,564 @@ impl<'r> VirtualMachine {
         Ok(disassm_one(inst, &mut itr)?)
     }
 
+    #[inline]
     pub fn step(&'r mut self) -> Result<usize, DcpuVMError> {
-        let mut cycles:usize = 0;
+        self.execute()
+    }
+
+    // step, inlined into run's loop
+    #[inline(always)]
+    fn execute(&mut self) -> Result<usize, DcpuVMError> {
+        // a device halted the CPU, so it sits out the whole halt in one go
+        if self.events.halt > 0 {
+            let halt = self.events.halt;
+            self.events.halt = 0;
+            self.exposed.cycles += halt;
+            return Ok(halt);
+        }
+
+        if self.exposed.interrupts.is_on_fire() {
+            if let Some(res) = self.burn() {
+                return res;
+            }
+        }
+
+        let cycles = if self.predecode { self.execute_cached()? } else { self.execute_decoded()? };
+        self.exposed.cycles += cycles;
+
+        self.handle_interrupts();
+        Ok(cycles)
+    }
+
+    // runs the instruction at PC from the cache, returning the cycles it took
+    #[inline(always)]
+    fn execute_cached(&mut self) -> Result<usize, DcpuVMError> {
+        let MicroOp { op, b, a, next } = self.exposed.cache.fetch(&self.exposed.ram, self.pc)?;
+        // every next word costs a cycle to look up
+        let mut cycles = next as usize;
+        self.pc = self.pc.wrapping_add(next as u16 + 1);
+
+        match op {
+            Op::Set => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? = src;
+                cycles += 1;
+            },
+            Op::Add => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = *dst as u32 + src as u32;
+                *dst = res as u16;
+                self.ex = if res > 0xFFFF { 1 } else { 0 };
+                cycles += 2;
+            },
+            Op::Sub => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = *dst as i32 - src as i32;
+                *dst = res as u16;
+                self.ex = if res < 0 { 0xFFFF } else { 0 };
+                cycles += 2;
+            },
+            Op::Mul => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = *dst as u32 * src as u32;
+                *dst = res as u16;
+                self.ex = (res >> 16) as u16;
+                cycles += 2;
+            },
+            Op::Mli => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = (*dst as i16) as i32 * (src as i16) as i32;
+                *dst = res as u16;
+                self.ex = (res >> 16) as u16;
+                cycles += 2;
+            },
+            Op::Div => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = ((*dst as u32) << 16).checked_div(src as u32).unwrap_or(0);
+                *dst = dst.checked_div(src).unwrap_or(0);
+                self.ex = res as u16;
+                cycles += 3;
+            },
+            Op::Dvi => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let (b, a) = ((*dst as i16) as i64, (src as i16) as i64);
+                let res = if a == 0 { 0 } else { (b << 16) / a };
+                *dst = if a == 0 { 0 } else { (b / a) as u16 };
+                self.ex = res as u16;
+                cycles += 3;
+            },
+            Op::Mod => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                *dst = if src == 0 { 0 } else { *dst % src };
+                cycles += 3;
+            },
+            Op::Mdi => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                *dst = if src == 0 { 0 } else { (*dst as i16).wrapping_rem(src as i16) as u16 };
+                cycles += 3;
+            },
+            Op::And => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? &= src;
+                cycles += 1;
+            },
+            Op::Bor => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? |= src;
+                cycles += 1;
+            },
+            Op::Xor => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? ^= src;
+                cycles += 1;
+            },
+            Op::Shr => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = (*dst as u64) << 16 >> (src as u64).min(63);
+                *dst = (res >> 16) as u16;
+                self.ex = res as u16;
+                cycles += 1;
+            },
+            Op::Asr => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = ((*dst as i16) as i64) << 16 >> (src as i64).min(63);
+                *dst = (res >> 16) as u16;
+                self.ex = res as u16;
+                cycles += 1;
+            },
+            Op::Shl => {
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = (*dst as u64).checked_shl(src as u32).unwrap_or(0);
+                *dst = res as u16;
+                self.ex = (res >> 16) as u16;
+                cycles += 1;
+            },
+            Op::Ifb => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b & a != 0);
+            },
+            Op::Ifc => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b & a == 0);
+            },
+            Op::Ife => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b == a);
+            },
+            Op::Ifn => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b != a);
+            },
+            Op::Ifg => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b > a);
+            },
+            Op::Ifa => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch((b as i16) > (a as i16));
+            },
+            Op::Ifl => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch(b < a);
+            },
+            Op::Ifu => {
+                let (b, a) = self.read_args(b, a)?;
+                cycles += 2 + self.branch((b as i16) < (a as i16));
+            },
+            Op::Adx => {
+                let ex = self.ex as u32;
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = ex + *dst as u32 + src as u32;
+                *dst = res as u16;
+                self.ex = if res > 0xFFFF { 1 } else { 0 };
+                cycles += 3;
+            },
+            Op::Sbx => {
+                let ex = (self.ex as i16) as i32;
+                let src = self.read_arg(a)?;
+                let dst = self.write_arg(b)?;
+                let res = ex + *dst as i32 - src as i32;
+                *dst = res as u16;
+                self.ex = if res < 0 { 0xFFFF } else { 0 };
+                cycles += 3;
+            },
+            Op::Sti => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? = src;
+                let regs = &mut self.exposed.registers;
+                regs[Register::I as usize] = rollover_inc(regs[Register::I as usize]);
+                regs[Register::J as usize] = rollover_inc(regs[Register::J as usize]);
+                cycles += 2;
+            },
+            Op::Std => {
+                let src = self.read_arg(a)?;
+                *self.write_arg(b)? = src;
+                let regs = &mut self.exposed.registers;
+                regs[Register::I as usize] = rollover_dec(regs[Register::I as usize]);
+                regs[Register::J as usize] = rollover_dec(regs[Register::J as usize]);
+                cycles += 2;
+            },
+            Op::Jsr => {
+                let src = self.read_arg(a)?;
+                let ret = self.pc;
+                self.push_stack(ret);
+                self.pc = src;
+                cycles += 3;
+            },
+            Op::Int => {
+                let src = self.read_arg(a)?;
+                self.exposed.interrupt(src);
+                cycles += 4;
+            },
+            Op::Iag => {
+                let ia = self.exposed.interrupts.ia();
+                *self.write_arg(a)? = ia;
+                cycles += 1;
+            },
+            Op::Ias => {
+                let ia = self.read_arg(a)?;
+                self.exposed.interrupts.set_ia(ia);
+                cycles += 1;
+            },
+            Op::Rfi => {
+                self.read_arg(a)?;
+                self.exposed.interrupts.set_queueing(false);
+                self.exposed.registers[Register::A as usize] = self.pop_stack();
+                self.pc = self.pop_stack();
+                cycles += 3;
+            },
+            Op::Iaq => {
+                let queueing = self.read_arg(a)? != 0;
+                self.exposed.interrupts.set_queueing(queueing);
+                cycles += 2;
+            },
+            Op::Hwn => {
+                let hw_count = self.hardware.len() as u16;
+                *self.write_arg(a)? = hw_count;
+                cycles += 2;
+            },
+            Op::Hwq => {
+                let src = self.read_arg(a)?;
+                cycles += 4;
+                if let Some(hw) = self.hardware.get(src as usize) {
+                    let hw_info = hw.device.info();
+                    let regs = &mut self.exposed.registers;
+                    regs[Register::A as usize] = (hw_info.model & 0xFFFF) as u16;
+                    regs[Register::B as usize] = (hw_info.model >> 16) as u16;
+                    regs[Register::C as usize] = hw_info.version;
+                    regs[Register::X as usize] = (hw_info.manufacturer & 0xFFFF) as u16;
+                    regs[Register::Y as usize] = (hw_info.manufacturer >> 16) as u16;
+                }
+            },
+            Op::Hwi => {
+                let src = self.read_arg(a)?;
+                cycles += 4;
+                if let Some(hw) = self.hardware.get_mut(src as usize) {
+                    let elapsed = self.exposed.cycles - hw.last_tick;
+                    let mut ctx = DeviceContext::new(&mut self.exposed, &mut self.events, src as usize, elapsed);
+                    cycles += hw.device.handle_interrupt(&mut ctx);
+                }
+            },
+        }
+        Ok(cycles)
+    }
+
+    // the same, decoding the instruction afresh every time. it's how the VM
+    // ran before there was a cache, and is kept to check the cache against
+    #[inline(never)]
+    fn execute_decoded(&'r mut self) -> Result<usize, DcpuVMError> {
         let (op, count) = self.get_instruction()?;
+        // every next word costs a cycle to look up
+        let mut cycles:usize = count;
+        self.pc = self.pc.wrapping_add(count as u16 + 1);
+
         match op {
             Opcode::SET(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                *dst = src;
-                cycles += c + 1;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? = src;
+                cycles += 1;
             },
             Opcode::ADD(ref b, ref a) => {
                 let res:u32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     res = *dst as u32 + src as u32;
-                    *dst = (res & 0xFFFF) as u16;
-                }
-
-                if res > 0xFFFF {
-                    self.ex = 1;
+                    *dst = res as u16;
                 }
+                self.ex = if res > 0xFFFF { 1 } else { 0 };
+                cycles += 2;
             },
             Opcode::SUB(ref b, ref a) => {
                 let res:i32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     res = *dst as i32 - src as i32;
-                    *dst = (res & 0xFFFF) as u16;
-                }
-
-                if res < 0 {
-                    self.ex = 0xFFFF;
+                    *dst = res as u16;
                 }
+                self.ex = if res < 0 { 0xFFFF } else { 0 };
+                cycles += 2;
             },
             Opcode::MUL(ref b, ref a) => {
                 let res:u32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     res = *dst as u32 * src as u32;
-                    *dst = (res & 0xFFFF) as u16;
+                    *dst = res as u16;
                 }
-                self.ex = (res>>16) as u16;
+                self.ex = (res >> 16) as u16;
+                cycles += 2;
             },
             Opcode::MLI(ref b, ref a) => {
                 let res:i32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    res = (*dst as i16) as i32 * ((src as i16) as i32);
-                    *dst = (res & 0xFFFF) as u16;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
+                    res = (*dst as i16) as i32 * (src as i16) as i32;
+                    *dst = res as u16;
                 }
-                self.ex = ((res >> 16) & 0xFFFF) as u16;
+                self.ex = (res >> 16) as u16;
+                cycles += 2;
             },
             Opcode::DIV(ref b, ref a) => {
                 let res:u32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 3;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     if src == 0 {
                         res = 0;
                         *dst = 0;
                     }
                     else {
-                        res = (((*dst as u32) << 16)/src as u32)&0xFFFF;
-                        *dst = (*dst as u32 / src as u32) as u16;
+                        res = ((*dst as u32) << 16) / src as u32;
+                        *dst /= src;
                     }
                 }
                 self.ex = res as u16;
+                cycles += 3;
             },
             Opcode::DVI(ref b, ref a) => {
-                let res:i32;
-
+                // i64 so that -0x8000 / -1 can't overflow
+                let res:i64;
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 3;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     if src == 0 {
                         res = 0;
                         *dst = 0;
                     }
                     else {
-                        res = ((((*dst as i16) as i32) << 16)/((src as i16) as i32))&0xFFFF;
-                        *dst = ((*dst as i16) as i32 / ((src as i16) as i32)) as u16;
+                        let (b, a) = ((*dst as i16) as i64, (src as i16) as i64);
+                        res = (b << 16) / a;
+                        *dst = (b / a) as u16;
                     }
                 }
                 self.ex = res as u16;
+                cycles += 3;
             },
             Opcode::MOD(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                cycles += c + 3;
+                let src = self.resolve_memory_read(a)?;
+                let dst = self.resolve_memory_write(b)?;
                 if src == 0 {
                     *dst = 0;
                 }
-                else
-                {
-                    *dst = *dst % src;
+                else {
+                    *dst %= src;
                 }
+                cycles += 3;
             },
             Opcode::MDI(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                cycles += c + 3;
+                let src = self.resolve_memory_read(a)?;
+                let dst = self.resolve_memory_write(b)?;
                 if src == 0 {
                     *dst = 0;
                 }
-                else
-                {
-                    *dst = (*dst as i16 % src as i16) as u16;
+                else {
+                    *dst = (*dst as i16).wrapping_rem(src as i16) as u16;
                 }
+                cycles += 3;
             },
             Opcode::AND(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                cycles += c + 1;
-                *dst = *dst & src;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? &= src;
+                cycles += 1;
             },
             Opcode::BOR(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                cycles += c + 1;
-                *dst = *dst | src;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? |= src;
+                cycles += 1;
             },
             Opcode::XOR(ref b, ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c;
-                let (dst, c) = self.resolve_memory_write(b)?;
-                cycles += c + 1;
-                *dst = *dst ^ src;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? ^= src;
+                cycles += 1;
             },
+            // shifts are done in 64 bits with the amount clamped, so shifting
+            // by 16 or more gives the spec's results rather than overflowing
             Opcode::SHR(ref b, ref a) => {
-                let res:u32;
+                let res:u64;
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 1;
-                    *dst = *dst >> src;
-                    res = ((*dst as u32) << 16)>> (src as u32) & 0xFFFF;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
+                    let shift = (src as u64).min(63);
+                    res = (*dst as u64) << 16 >> shift;
+                    *dst = (res >> 16) as u16;
                 }
                 self.ex = res as u16;
+                cycles += 1;
             },
             Opcode::ASR(ref b, ref a) => {
-                let res:i32;
+                let res:i64;
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 1;
-                    *dst = ((*dst as i16) >> src) as u16;
-                    res = (((*dst as i32) << src as i32)>> 16) & 0xFFFF;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
+                    let shift = (src as i64).min(63);
+                    res = ((*dst as i16) as i64) << 16 >> shift;
+                    *dst = (res >> 16) as u16;
                 }
                 self.ex = res as u16;
+                cycles += 1;
             },
             Opcode::SHL(ref b, ref a) => {
-                let res:u32;
+                let res:u64;
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 1;
-                    *dst = *dst << src;
-                    res = (((*dst as u32) << (src as u32)) >> 16) & 0xFFFF;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
+                    res = (*dst as u64).checked_shl(src as u32).unwrap_or(0);
+                    *dst = res as u16;
                 }
-                self.ex = res as u16;
-
+                self.ex = (res >> 16) as u16;
+                cycles += 1;
             },
             Opcode::IFB(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst & src != 0;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b & a != 0);
             },
             Opcode::IFC(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst & src == 0;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b & a == 0);
             },
             Opcode::IFE(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst == src;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b == a);
             },
             Opcode::IFN(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst != src;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b != a);
             },
             Opcode::IFG(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst > src;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b > a);
             },
             Opcode::IFA(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = (*dst as i16) > (src as i16);
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch((b as i16) > (a as i16));
             },
             Opcode::IFL(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = *dst < src;
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch(b < a);
             },
             Opcode::IFU(ref b, ref a) => {
-                let pass:bool;
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    pass = (*dst as i16) < (src as i16);
-                }
-
-                if !pass {
-                    let (skip, c) = self.skip(count + 1)?;
-
-                    cycles += skip + 1; // +1 cause failed
-                    self.pc =  (self.pc + (c as u16)) & 0xFFFF;
-                }
+                let (b, a) = self.resolve_operands(b, a)?;
+                cycles += 2 + self.branch((b as i16) < (a as i16));
             },
             Opcode::ADX(ref b, ref a) => {
                 let mut res:u32 = self.ex as u32;
-
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 3;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
                     res += *dst as u32 + src as u32;
-                    *dst = (res & 0xFFFF) as u16;
-                }
-
-                if res > 0xFFFF {
-                    self.ex = 1;
+                    *dst = res as u16;
                 }
+                self.ex = if res > 0xFFFF { 1 } else { 0 };
+                cycles += 3;
             },
             Opcode::SBX(ref b, ref a) => {
-                let mut res:i32 = self.ex as i32;
-
+                // EX is signed here so the 0xFFFF borrow left by SUB chains
+                let mut res:i32 = (self.ex as i16) as i32;
                 {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 3;
-                    res = *dst as i32 - (src as i32 + res);
-                    *dst = (res & 0xFFFF) as u16;
-                }
-
-                if res < 0 {
-                    self.ex = 0xFFFF;
+                    let src = self.resolve_memory_read(a)?;
+                    let dst = self.resolve_memory_write(b)?;
+                    res += *dst as i32 - src as i32;
+                    *dst = res as u16;
                 }
+                self.ex = if res < 0 { 0xFFFF } else { 0 };
+                cycles += 3;
             },
             Opcode::STI(ref b, ref a) => {
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    *dst = src;
-                }
-                self.exposed.registers[Register::I as usize] += 1;
-                self.exposed.registers[Register::J as usize] += 1;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? = src;
+                let regs = &mut self.exposed.registers;
+                regs[Register::I as usize] = rollover_inc(regs[Register::I as usize]);
+                regs[Register::J as usize] = rollover_inc(regs[Register::J as usize]);
+                cycles += 2;
             },
             Opcode::STD(ref b, ref a) => {
-                {
-                    let (src, c) = self.resolve_memory_read(a)?;
-                    cycles += c;
-                    let (dst, c) = self.resolve_memory_write(b)?;
-                    cycles += c + 2;
-                    *dst = src;
-                }
-                self.exposed.registers[Register::I as usize] -= 1;
-                self.exposed.registers[Register::J as usize] -= 1;
+                let src = self.resolve_memory_read(a)?;
+                *self.resolve_memory_write(b)? = src;
+                let regs = &mut self.exposed.registers;
+                regs[Register::I as usize] = rollover_dec(regs[Register::I as usize]);
+                regs[Register::J as usize] = rollover_dec(regs[Register::J as usize]);
+                cycles += 2;
             },
             Opcode::JSR(ref a) => {
-                let x:u16 = ((self.pc as u32 + count as u32 + 1) & 0xFFFF) as u16;
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 3;
-                self.push_stack(x);
+                let src = self.resolve_memory_read(a)?;
+                let ret = self.pc;
+                self.push_stack(ret);
                 self.pc = src;
+                cycles += 3;
             },
             Opcode::INT(ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 4;
+                let src = self.resolve_memory_read(a)?;
                 self.interrupt(src);
+                cycles += 4;
             },
             Opcode::IAG(ref a) => {
-                let ia:u16 = self.ia;
-                let (dst, c) = self.resolve_memory_write(a)?;
-                cycles += c + 1;
-                *dst = ia;
+                let ia:u16 = self.exposed.interrupts.ia();
+                *self.resolve_memory_write(a)? = ia;
+                cycles += 1;
             },
             Opcode::IAS(ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 1;
-                self.ia = src;
+                let ia = self.resolve_memory_read(a)?;
+                self.exposed.interrupts.set_ia(ia);
+                cycles += 1;
             },
-            Opcode::RFI(_) => {
-                self.iaq = false;
+            Opcode::RFI(ref a) => {
+                self.resolve_memory_read(a)?;
+                self.exposed.interrupts.set_queueing(false);
                 self.exposed.registers[Register::A as usize] = self.pop_stack();
                 self.pc = self.pop_stack();
-                self.in_interrupt = false;
                 cycles += 3;
             },
             Opcode::IAQ(ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 2;
-                self.iaq = src != 0;
+                let queueing = self.resolve_memory_read(a)? != 0;
+                self.exposed.interrupts.set_queueing(queueing);
+                cycles += 2;
             },
             Opcode::HWN(ref a) => {
                 let hw_count:u16 = self.hardware.len() as u16;
-                let (dst, c) = self.resolve_memory_write(a)?;
-                cycles += c + 2;
-                *dst = hw_count;
+                *self.resolve_memory_write(a)? = hw_count;
+                cycles += 2;
             },
             Opcode::HWQ(ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 4;
+                let src = self.resolve_memory_read(a)?;
+                cycles += 4;
                 if (src as usize) < self.hardware.len() {
-                    let hw_info = self.hardware[src as usize].info();
+                    let hw_info = self.hardware[src as usize].device.info();
                     self.exposed.registers[Register::A as usize] = (hw_info.model & 0xFFFF) as u16;
                     self.exposed.registers[Register::B as usize] = (hw_info.model >> 16) as u16;
                     self.exposed.registers[Register::C as usize] = hw_info.version;
@@ -711,17 +931,16 @@ impl<'r> VirtualMachine {
                 }
             },
             Opcode::HWI(ref a) => {
-                let (src, c) = self.resolve_memory_read(a)?;
-                cycles += c + 4;
-                cycles += self.hardware[src as usize].hardware_interrupt(&mut self.exposed);
+                let src = self.resolve_memory_read(a)?;
+                cycles += 4;
+                // like HWQ, there's nothing to do for a device that isn't there
+                if let Some(hw) = self.hardware.get_mut(src as usize) {
+                    let elapsed = self.exposed.cycles - hw.last_tick;
+                    let mut ctx = DeviceContext::new(&mut self.exposed, &mut self.events, src as usize, elapsed);
+                    cycles += hw.device.handle_interrupt(&mut ctx);
+                }
             },
         }
-        self.exposed.cycles += cycles;
-        self.pc = ((self.pc as u32 + (count as u32) + 1) & 0xFFFF) as u16;
-
-        if !self.in_interrupt {
-            cycles += self.handle_interrupts()?;
-        }
         Ok(cycles)
     }
 
@@ -735,31 +954,105 @@ impl<'r> VirtualMachine {
         self
     }
 
+    // resets the VM, then puts the program at `org`, going round to 0 if it
+    // runs past the end of memory
     pub fn load_program(mut self, program: &Vec<u16>, org: usize) -> Self {
         self.reset();
-        let mut i = org;
-
-        for word in program {
-            self.exposed.ram[i] = *word;
-            i = i + 1;
+        for (i, word) in program.iter().enumerate() {
+            self.exposed.ram[(org + i) & 0xffff] = *word;
         }
         self
     }
 
-    pub fn attach_hardware(mut self, hardware: Box<dyn Hardware>) -> Self {
+    // writes words to memory from `origin`, leaving the rest of it and
+    // everything else alone. more words than memory holds is always an error
+    pub fn load_segment(&mut self, origin: u16, words: &[u16], overflow: Overflow) -> Result<(), DcpuVMError> {
+        check_fits(origin, words.len(), overflow)?;
+        let (head, tail) = words.split_at(words.len().min(0x10000 - origin as usize));
+        self.exposed.ram[origin as usize..origin as usize + head.len()].copy_from_slice(head);
+        self.exposed.ram[..tail.len()].copy_from_slice(tail);
+        self.exposed.cache.clear();
+        Ok(())
+    }
+
+    // loads every segment of an image and starts at its entry point, or
+    // its first segment. if any segment doesn't fit, none are loaded
+    pub fn load_image(&mut self, image: &Image, overflow: Overflow) -> Result<(), DcpuVMError> {
+        for segment in image.segments.iter() {
+            check_fits(segment.origin, segment.words.len(), overflow)?;
+        }
+        for segment in image.segments.iter() {
+            self.load_segment(segment.origin, &segment.words, overflow)?;
+        }
+        self.pc = image.start();
+        Ok(())
+    }
+
+    // plug for building a VM, failing with TooMuchHardware past 0xffff devices
+    pub fn attach_hardware(mut self, hardware: Box<dyn Hardware>) -> Result<Self, DcpuVMError> {
+        self.plug(hardware)?;
+        Ok(self)
+    }
+
+    // attaches a device while the VM is running, returning the index HWI
+    // reaches it at
+    pub fn plug(&mut self, mut hardware: Box<dyn Hardware>) -> Result<usize, DcpuVMError> {
         if self.hardware.len() == 0xFFFF {
-            return self
+            return Err(DcpuVMError::TooMuchHardware);
         }
-        self.hardware.push(hardware);
-        self
+        let index = self.hardware.len();
+        hardware.attached(&mut DeviceContext::new(&mut self.exposed, &mut self.events, index, 0));
+        self.hardware.push(Attached { device: hardware, last_tick: self.exposed.cycles });
+        Ok(index)
+    }
+
+    // detaches a device while the VM is running. devices after it move down
+    // an index, like they would on a real bus after a rescan
+    pub fn unplug(&mut self, index: usize) -> Option<Box<dyn Hardware>> {
+        if index >= self.hardware.len() {
+            return None
+        }
+        let mut hardware = self.hardware.remove(index).device;
+        hardware.detached(&mut DeviceContext::new(&mut self.exposed, &mut self.events, index, 0));
+        self.events.unplugged(index);
+        Some(hardware)
+    }
+
+    pub fn hardware_count(&self) -> usize {
+        self.hardware.len()
+    }
+
+    pub fn hardware(&self, index: usize) -> Option<&dyn Hardware> {
+        self.hardware.get(index).map(|hw| &*hw.device)
+    }
+
+    // None when there's no such device or it can't save its state
+    pub fn save_device(&mut self, index: usize) -> Option<DeviceState> {
+        let hw = self.hardware.get(index)?;
+        let elapsed = self.exposed.cycles - hw.last_tick;
+        hw.device.save_state(&DeviceContext::new(&mut self.exposed, &mut self.events, index, elapsed))
+    }
+
+    pub fn load_device(&mut self, index: usize, state: &DeviceState) -> Result<(), StateError> {
+        let hw = self.hardware.get_mut(index).ok_or(StateError::NoDevice(index))?;
+        self.events.forget(index);
+        let mut ctx = DeviceContext::new(&mut self.exposed, &mut self.events, index, 0);
+        hw.last_tick = ctx.cycles();
+        hw.device.load_state(&mut ctx, state)
+    }
+
+    // all of RAM, to read without the cache having to start again
+    pub fn ram(&self) -> &[u16] {
+        &self.exposed.ram
     }
 
     pub fn get_ram(&'r mut self) -> &'r mut Vec<u16> {
-        &mut *self.exposed.ram
+        self.exposed.cache.clear();
+        &mut self.exposed.ram
     }
 
     pub fn get_registers(&'r mut self) -> &'r mut [u16] {
-        &mut *self.exposed.registers
+        &mut self.exposed.registers
     }
 
     pub fn get_pc(&'r mut self) -> &'r mut u16 {
@@ -774,10 +1067,36 @@ impl<'r> VirtualMachine {
         &mut self.sp
     }
 
+    pub fn get_ia(&'r mut self) -> &'r mut u16 {
+        self.exposed.interrupts.ia_mut()
+    }
+
     pub fn get_clock_rate(&'r self) -> usize {
         self.exposed.clock_rate
     }
 
+    // whether to run from decoded instructions cached by address, rather
+    // than decoding every instruction as it's run. on by default
+    pub fn predecode(mut self, predecode: bool) -> Self {
+        self.predecode = predecode;
+        // it wasn't kept up to date while it was off
+        self.exposed.cache.clear();
+        self
+    }
+
+    pub fn fire_policy(mut self, policy: FirePolicy) -> Self {
+        self.exposed.interrupts.set_policy(policy);
+        self
+    }
+
+    pub fn interrupts(&'r self) -> &'r InterruptController {
+        &self.exposed.interrupts
+    }
+
+    pub fn interrupts_mut(&'r mut self) -> &'r mut InterruptController {
+        &mut self.exposed.interrupts
+    }
+
     pub fn clock_rate(mut self, cr: usize) -> Self {
         self.exposed.clock_rate = cr;
         self
@@ -787,39 +1106,223 @@ impl<'r> VirtualMachine {
         self.exposed.cycles
     }
 
+    // the device that asked to stop since run last returned, if one did.
+    // for anything driving the VM with step and update_hardware instead
+    pub fn take_stop(&mut self) -> Option<usize> {
+        self.events.stop.take()
+    }
+
+    // steps and updates the hardware until a breakpoint, HLT or BRK is
+    // reached, a device asks to stop or the VM has run for `cycle_limit`
+    // cycles in total. those only stop the VM when it gets there, not when it
+    // starts on one, so running again carries on past a BRK
+    pub fn run(&mut self, breakpoints: &BTreeSet<u16>, cycle_limit: Option<usize>)
+        -> Result<StopReason, DcpuVMError> {
+        loop {
+            if cycle_limit.is_some_and(|limit| self.exposed.cycles >= limit) {
+                return Ok(StopReason::CycleLimit);
+            }
+            self.execute()?;
+            // with no devices there's nothing to update
+            if !self.hardware.is_empty() || self.events.has_callbacks() {
+                self.update_hardware();
+            }
+            if let Some(index) = self.take_stop() {
+                return Ok(StopReason::Device(index));
+            }
+            // steps would take no cycles from now on, so the limit never comes
+            if self.exposed.interrupts.is_on_fire() && self.exposed.interrupts.policy() == FirePolicy::Halt {
+                return Ok(StopReason::OnFire);
+            }
+            if breakpoints.contains(&self.pc) {
+                return Ok(StopReason::Breakpoint(self.pc));
+            }
+            // with nothing to check between instructions, as many as can go
+            // without the checks above run back to back
+            if self.predecode && self.hardware.is_empty() && !self.events.has_callbacks()
+                && breakpoints.is_empty() && self.exposed.interrupts.is_idle() {
+                self.run_fast(cycle_limit.unwrap_or(usize::MAX))?;
+            }
+            match self.exposed.ram[self.pc as usize] {
+                HLT => return Ok(StopReason::Halted(self.pc)),
+                BRK => return Ok(StopReason::Break(self.pc)),
+                _ => (),
+            }
+        }
+    }
+
+    // runs decoded instructions until one that run has to check for, one
+    // that hasn't been decoded yet or the cycle limit. PC and the cycle count
+    // stay in locals, and only the ops that don't fit a Fast go through
+    // execute_cached
+    fn run_fast(&mut self, limit: usize) -> Result<(), DcpuVMError> {
+        let mut pc = self.pc;
+        let mut cycles = self.exposed.cycles;
+        let res = loop {
+            if cycles >= limit {
+                break Ok(());
+            }
+            let (fast, after) = match self.exposed.cache.fast(pc) {
+                Some(fast) => fast,
+                None => break Ok(()),
+            };
+            let (b, a) = (fast.b, fast.a);
+            match fast.op {
+                FastOp::Leave => break Ok(()),
+                FastOp::Exec => {
+                    self.pc = pc;
+                    let res = self.execute_cached();
+                    pc = self.pc;
+                    match res {
+                        Ok(taken) => cycles += taken,
+                        Err(err) => break Err(err),
+                    }
+                    continue;
+                },
+                FastOp::Jump => (),
+                FastOp::Set => {
+                    let src = self.load(a);
+                    *self.place(b) = src;
+                },
+                FastOp::Add => {
+                    let src = self.load(a);
+                    let dst = self.place(b);
+                    let res = *dst as u32 + src as u32;
+                    *dst = res as u16;
+                    self.ex = if res > 0xFFFF { 1 } else { 0 };
+                },
+                FastOp::Sub => {
+                    let src = self.load(a);
+                    let dst = self.place(b);
+                    let res = *dst as i32 - src as i32;
+                    *dst = res as u16;
+                    self.ex = if res < 0 { 0xFFFF } else { 0 };
+                },
+                FastOp::Mul => {
+                    let src = self.load(a);
+                    let dst = self.place(b);
+                    let res = *dst as u32 * src as u32;
+                    *dst = res as u16;
+                    self.ex = (res >> 16) as u16;
+                },
+                FastOp::And => {
+                    let src = self.load(a);
+                    *self.place(b) &= src;
+                },
+                FastOp::Bor => {
+                    let src = self.load(a);
+                    *self.place(b) |= src;
+                },
+                FastOp::Xor => {
+                    let src = self.load(a);
+                    *self.place(b) ^= src;
+                },
+                FastOp::Shr => {
+                    let src = self.load(a);
+                    let dst = self.place(b);
+                    let res = (*dst as u64) << 16 >> (src as u64).min(63);
+                    *dst = (res >> 16) as u16;
+                    self.ex = res as u16;
+                },
+                FastOp::Shl => {
+                    let src = self.load(a);
+                    let dst = self.place(b);
+                    let res = (*dst as u64).checked_shl(src as u32).unwrap_or(0);
+                    *dst = res as u16;
+                    self.ex = (res >> 16) as u16;
+                },
+                FastOp::Ifb | FastOp::Ifc | FastOp::Ife | FastOp::Ifn
+                | FastOp::Ifg | FastOp::Ifa | FastOp::Ifl | FastOp::Ifu => {
+                    let (a, b) = (self.load(a), self.load(b));
+                    let pass = match fast.op {
+                        FastOp::Ifb => b & a != 0,
+                        FastOp::Ifc => b & a == 0,
+                        FastOp::Ife => b == a,
+                        FastOp::Ifn => b != a,
+                        FastOp::Ifg => b > a,
+                        FastOp::Ifa => (b as i16) > (a as i16),
+                        FastOp::Ifl => b < a,
+                        _ => (b as i16) < (a as i16),
+                    };
+                    if !pass {
+                        self.pc = after;
+                        cycles += fast.cycles as usize + self.skip();
+                        pc = self.pc;
+                        continue;
+                    }
+                },
+            }
+            pc = after;
+            cycles += fast.cycles as usize;
+        };
+        self.pc = pc;
+        self.exposed.cycles = cycles;
+        res
+    }
+
+    #[inline(always)]
+    fn load(&self, loc: Loc) -> u16 {
+        let base = if loc.reg < 8 { self.exposed.registers[loc.reg as usize & 7] } else { 0 };
+        let address = base.wrapping_add(loc.k);
+        if loc.mem { self.exposed.ram[address as usize] } else { address }
+    }
+
+    #[inline(always)]
+    fn place(&mut self, loc: Loc) -> &mut u16 {
+        if !loc.mem {
+            return &mut self.exposed.registers[loc.reg as usize & 7];
+        }
+        let base = if loc.reg < 8 { self.exposed.registers[loc.reg as usize & 7] } else { 0 };
+        let address = base.wrapping_add(loc.k);
+        self.exposed.cache.invalidate(address);
+        &mut self.exposed.ram[address as usize]
+    }
+
+    // ticks every device, then runs whichever callbacks have come due
     pub fn update_hardware(&mut self) {
-        for mut hw in &mut self.hardware {
-            hw.update(&mut self.exposed);
+        let now = self.exposed.cycles;
+        for (index, hw) in self.hardware.iter_mut().enumerate() {
+            let elapsed = now - hw.last_tick;
+            hw.last_tick = now;
+            hw.device.tick(&mut DeviceContext::new(&mut self.exposed, &mut self.events, index, elapsed));
+        }
+        while let Some((index, token)) = self.events.next_due(self.exposed.cycles) {
+            let hw = &mut self.hardware[index];
+            let elapsed = self.exposed.cycles - hw.last_tick;
+            hw.device.callback(&mut DeviceContext::new(&mut self.exposed, &mut self.events, index, elapsed), token);
         }
     }
 
     pub fn interrupt(&'r mut self, msg: u16) {
-        if self.ia != 0 && self.on_fire == false {
-            self.exposed.interrupt(msg);
-        }
+        self.exposed.interrupt(msg);
     }
 
     pub fn reset(&'r mut self) {
         self.pc = 0;
         self.sp = 0;
-        self.ia = 0;
         self.ex = 0;
-        for b in (*self.exposed.registers).iter_mut() {
+        for b in self.exposed.registers.iter_mut() {
             *b = 0;
         }
-        for b in (*self.exposed.ram).iter_mut() {
+        for b in self.exposed.ram.iter_mut() {
             *b = 0;
         }
+        self.exposed.cache.clear();
         self.exposed.cycles = 0;
-        self.exposed.interrupts.clear();
-        self.in_interrupt = false;
-        self.iaq = false;
+        self.exposed.interrupts.reset();
+        // devices keep their callbacks, they'd have no way to know to
+        // schedule them again
+        self.events.halt = 0;
+        self.events.stop = None;
+        for hw in self.hardware.iter_mut() {
+            hw.last_tick = 0;
+        }
     }
 }
 
 impl VMExposed {
     pub fn interrupt(&mut self, msg: u16) {
-        self.interrupts.push(msg);
+        self.interrupts.raise(msg);
     }
 
     pub fn get_cycles(&self) -> usize {
@@ -842,21 +1345,151 @@ impl VMExposed {
     }
 
     pub fn read_ram<'r> (&'r mut self, pos: usize, size: usize) -> Result<(&'r [u16], usize), DcpuVMError> {
-        if pos + size > 0xFFFF {
+        if pos + size > 0x10000 {
             return Err(DcpuVMError::OutOfBoundsMemory);
         }
         Ok((&self.ram[(pos) .. (pos + size)], size * 3))
     }
 
+    // all of RAM at once, to read
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
     pub fn write_ram(&mut self, mut pos: usize, data: &[u16], size: usize) -> usize {
-        let mut i = 0;
-        pos = pos & 0xFFFF;
-        loop {
-            self.ram[pos] = data[i];
+        pos &= 0xFFFF;
+        for word in &data[..size] {
+            self.ram[pos] = *word;
+            self.cache.invalidate(pos as u16);
             pos = (pos + 1) & 0xFFFF;
-            i += 1;
-            if i == size { break; }
         }
-        i * 3 //SET [NEXT], LITERAL
+        size * 3 //SET [NEXT], LITERAL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opcodes::NOP;
+
+    #[test]
+    fn stops_at_hlt_and_brk() {
+        let mut vm = VirtualMachine::new();
+        // SUB PC, 1 is a wait for interrupts, not a halt
+        vm.get_ram()[..5].copy_from_slice(&[NOP, BRK, NOP, HLT, 0x8b83]);
+        assert_eq!(vm.run(&Default::default(), None).unwrap(), StopReason::Break(1));
+        // carries on past the BRK, which does nothing
+        assert_eq!(vm.run(&Default::default(), None).unwrap(), StopReason::Halted(3));
+        assert_eq!(vm.run(&Default::default(), None).unwrap(), StopReason::Halted(3));
+        *vm.get_pc() = 4;
+        assert_eq!(vm.run(&Default::default(), Some(100)).unwrap(), StopReason::CycleLimit);
+    }
+
+    #[test]
+    fn predecoding_matches_decoding() {
+        // random words make plenty of code that writes over itself
+        let mut rng = 0x2545f491u32;
+        let mut random = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng as u16
+        };
+        let words: Vec<u16> = (0..0x10000).map(|_| random()).collect();
+        let mut cached = VirtualMachine::new().load_program(&words, 0);
+        let mut decoded = VirtualMachine::new().predecode(false).load_program(&words, 0);
+        for i in 0..50000 {
+            // jumping around now and then, so it doesn't sit in one loop
+            if i % 50 == 0 {
+                let pc = random();
+                *cached.get_pc() = pc;
+                *decoded.get_pc() = pc;
+            }
+            let (c, d) = (cached.step(), decoded.step());
+            assert_eq!(c.as_ref().map_err(|e| e.to_string()), d.as_ref().map_err(|e| e.to_string()), "step {}", i);
+            if c.is_err() {
+                // a reserved opcode, so go on past it
+                cached.pc = cached.pc.wrapping_add(1);
+                decoded.pc = decoded.pc.wrapping_add(1);
+            }
+            assert_eq!((cached.pc, cached.sp, cached.ex, cached.get_registers().to_vec()),
+                       (decoded.pc, decoded.sp, decoded.ex, decoded.get_registers().to_vec()), "step {}", i);
+        }
+        assert_eq!(cached.get_cycles(), decoded.get_cycles());
+        assert!(cached.get_ram() == decoded.get_ram());
+    }
+
+    #[test]
+    fn running_fast_matches_decoding() {
+        let mut rng = 0x9e3779b9u32;
+        let mut random = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng as u16
+        };
+        // random words, with a loop over an array that runs the Fast ops
+        // over and over in among them
+        let mut words: Vec<u16> = (0..0x10000).map(|_| random()).collect();
+        let sieve = ::assemble_file(None, "
+            SET A, 2
+            :outer SET B, A
+            MUL B, A
+            IFG B, 0x3ff
+                SET PC, 0x100
+            IFE [A+0x8000], 0
+                SET PC, next
+            :strike SET [B+0x8000], 1
+            ADD B, A
+            IFL B, 0x400
+                SET PC, strike
+            :next ADD A, 1
+            SHL C, 1
+            XOR C, [B+0x7fff]
+            IFB C, 0
+                SET PC, outer
+            SET PC, outer", 0).unwrap();
+        words[..sieve.len()].copy_from_slice(&sieve);
+        let mut fast = VirtualMachine::new().load_program(&words, 0);
+        let mut decoded = VirtualMachine::new().predecode(false).load_program(&words, 0);
+        let mut limit = 0;
+        for i in 0..5000 {
+            limit += random() as usize % 500;
+            let (f, d) = (fast.run(&BTreeSet::new(), Some(limit)), decoded.run(&BTreeSet::new(), Some(limit)));
+            assert_eq!(f.as_ref().map_err(|e| e.to_string()), d.as_ref().map_err(|e| e.to_string()), "run {}", i);
+            assert_eq!((fast.pc, fast.sp, fast.ex, fast.get_registers().to_vec(), fast.get_cycles()),
+                       (decoded.pc, decoded.sp, decoded.ex, decoded.get_registers().to_vec(), decoded.get_cycles()), "run {}", i);
+            // somewhere else after a halt or an error, and now and then anyway
+            if !matches!(f, Ok(StopReason::CycleLimit)) || i % 50 == 0 {
+                let pc = if i % 100 == 0 { 0 } else { random() };
+                *fast.get_pc() = pc;
+                *decoded.get_pc() = pc;
+            }
+        }
+        assert!(fast.get_ram() == decoded.get_ram());
+    }
+
+    #[test]
+    fn loads_segments_without_clearing() {
+        let mut vm = VirtualMachine::new().load_program(&vec![1, 2], 0xffff);
+        assert_eq!((vm.get_ram()[0xffff], vm.get_ram()[0]), (1, 2));
+
+        // code, data up high and a font, loaded over what's there
+        let image = Image::new(0x10, vec![0x8b83]).segment(0xf000, vec![7; 4]).segment(0x8180, vec![9; 2]);
+        vm.load_image(&image.entry(0x10), Overflow::Error).unwrap();
+        assert_eq!(*vm.get_pc(), 0x10);
+        assert_eq!((vm.get_ram()[0xffff], vm.get_ram()[0x10], vm.get_ram()[0xf003], vm.get_ram()[0x8181]), (1, 0x8b83, 7, 9));
+
+        assert!(matches!(vm.load_segment(0xfffe, &[3, 4, 5], Overflow::Error),
+                         Err(DcpuVMError::SegmentOverflow(3, 0xfffe))));
+        assert_eq!(vm.get_ram()[0xfffe], 0);
+        vm.load_segment(0xfffe, &[3, 4, 5], Overflow::Wrap).unwrap();
+        assert_eq!((vm.get_ram()[0xfffe], vm.get_ram()[0xffff], vm.get_ram()[0]), (3, 4, 5));
+        assert!(vm.load_segment(0, &vec![0; 0x10001], Overflow::Wrap).is_err());
+
+        // nothing is loaded if a later segment doesn't fit
+        let image = Image::new(0x20, vec![6]).segment(0xffff, vec![1, 2]);
+        assert!(vm.load_image(&image, Overflow::Error).is_err());
+        assert_eq!(vm.get_ram()[0x20], 0);
     }
 }